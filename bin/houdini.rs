@@ -11,6 +11,8 @@ use clap::StructOpt;
 use houdini::{Cli, CONFIG};
 use std::{fs::DirBuilder, os::unix::fs::DirBuilderExt};
 
+// human-panic still refers to the deprecated `PanicInfo` alias.
+#[allow(deprecated)]
 #[tokio::main]
 async fn main() -> Result<()> {
     // Parse command line arguments.
@@ -27,7 +29,7 @@ async fn main() -> Result<()> {
     tracing::debug!(args = ?&args, "cli args");
     tracing::debug!(config = ?&*CONFIG, "houdini config");
 
-    init().context("failed to initialize environment")?;
+    init(&args).context("failed to initialize environment")?;
 
     // After parsing arguments, we can consume them and run the corresponding subcommand.
     match args.run().await {
@@ -42,9 +44,9 @@ async fn main() -> Result<()> {
     }
 }
 
-fn init() -> Result<()> {
+fn init(args: &Cli) -> Result<()> {
     // Create reports dir
-    let dir = &CONFIG.reports.dir;
+    let dir = args.report_dir();
     DirBuilder::new()
         .recursive(true)
         .mode(0o755)
@@ -10,63 +10,199 @@
 
 pub mod client;
 
+mod active;
 mod middleware;
 mod uds;
 
-use std::path::Path;
+use std::{
+    fmt::Display,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
 
 use anyhow::{Context as _, Result};
 use axum::{
     debug_handler,
+    extract::{BodyStream, Extension},
     handler::Handler,
     response::IntoResponse,
     routing::{get, post},
     Json, Router,
 };
-use hyper::StatusCode;
-use tokio::net::UnixListener;
+use hyper::{body::Bytes, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::{TcpListener, UnixListener},
+    signal::unix::{signal, SignalKind},
+};
 use tower::ServiceBuilder;
 
+pub use self::active::ActiveTrick;
+use self::active::ActiveTricks;
 use crate::{
-    tricks::{report::TrickReport, Trick},
+    config,
+    docker::context,
+    tricks::{parse, report::TrickReport},
     CONFIG,
 };
 
-pub async fn serve(socket: Option<&Path>) -> Result<()> {
-    let socket = if let Some(socket) = socket {
-        socket
-    } else {
-        &CONFIG.api.socket
+/// A handle to a build context uploaded to the server.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ContextHandle {
+    /// Opaque handle to reference from a trick's `build.context`.
+    pub handle: String,
+}
+
+/// An address that the API server listens on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Socket {
+    /// A Unix domain socket at the given path, written as `unix://<path>` or just
+    /// `<path>`.
+    Unix(PathBuf),
+    /// A TCP socket, written as `tcp://<host>:<port>`.
+    Tcp(SocketAddr),
+}
+
+impl FromStr for Socket {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(addr) = s.strip_prefix("tcp://") {
+            let addr = addr
+                .parse()
+                .context(format!("invalid TCP socket address {}", addr))?;
+            return Ok(Socket::Tcp(addr));
+        }
+        if s.starts_with("vsock://") {
+            anyhow::bail!("vsock sockets are not supported");
+        }
+        Ok(Socket::Unix(s.strip_prefix("unix://").unwrap_or(s).into()))
+    }
+}
+
+impl Display for Socket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Socket::Unix(path) => write!(f, "unix://{}", path.display()),
+            Socket::Tcp(addr) => write!(f, "tcp://{}", addr),
+        }
+    }
+}
+
+/// Serve the API on every socket in `sockets`, or on `api.socket` from Houdini's config
+/// if there are none.
+pub async fn serve(sockets: &[Socket]) -> Result<()> {
+    serve_with_auth(sockets, CONFIG.api.auth_token.clone()).await
+}
+
+/// Serve the API, requiring a matching bearer token on mutating endpoints if
+/// `auth_token` is set. Every socket shares the same routes, and the server fails as
+/// soon as serving on any one of them fails. TCP sockets are refused without
+/// `auth_token`, since anyone who can reach them could run commands on the host.
+async fn serve_with_auth(sockets: &[Socket], auth_token: Option<String>) -> Result<()> {
+    let default = [Socket::Unix(CONFIG.api.socket.clone())];
+    let sockets = match sockets.is_empty() {
+        true => &default[..],
+        false => sockets,
     };
 
-    let _ = tokio::fs::remove_file(socket).await;
-    if let Some(parent) = &CONFIG.api.socket.parent() {
-        tokio::fs::create_dir_all(parent)
-            .await
-            .context("failed to create parent directory for Houdini socket")?
+    if auth_token.is_none() {
+        if let Some(socket) = sockets.iter().find(|s| matches!(s, Socket::Tcp(_))) {
+            anyhow::bail!(
+                "refusing to serve on {} without api.authToken set in Houdini configs",
+                socket
+            );
+        }
     }
 
-    let uds = UnixListener::bind(socket).context("failed to bind to Houdini socket")?;
+    // Reload runtime config on SIGHUP
+    let mut hangup = signal(SignalKind::hangup()).context("failed to install SIGHUP handler")?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            tracing::info!("received SIGHUP, reloading config");
+            if let Err(e) = config::reload() {
+                tracing::warn!(err = ?e, "failed to reload config");
+            }
+        }
+    });
+
+    let app = router(auth_token);
 
-    // Add routes
+    let mut servers = vec![];
+    for socket in sockets {
+        let server = match socket {
+            Socket::Unix(path) => serve_uds(path, app.clone()).await?,
+            Socket::Tcp(addr) => serve_tcp(addr, app.clone()).await?,
+        };
+        tracing::info!("server listening on {}...", socket);
+        servers.push(server);
+    }
+
+    futures::future::try_join_all(servers)
+        .await
+        .context("failed to start Houdini API server")?;
+    Ok(())
+}
+
+/// The API's routes. Mutating endpoints require `auth_token`, if it is set.
+fn router(auth_token: Option<String>) -> Router {
+    let mutating = Router::new()
+        .route("/trick", post(run_trick))
+        .route("/context", post(upload_context))
+        .route_layer(axum::middleware::from_fn(move |req, next| {
+            middleware::require_token(auth_token.clone(), req, next)
+        }));
     let app = Router::new()
         .route("/", get(ping))
         .route("/ping", get(ping))
-        .route("/trick", post(run_trick));
+        .route("/active", get(active_tricks))
+        .merge(mutating)
+        .layer(Extension(Arc::new(ActiveTricks::default())));
 
     // Add fallback handler
     let app = app.fallback(not_found.into_service());
 
     // Add middleware
-    let app = app.route_layer(
+    app.route_layer(
         ServiceBuilder::new().layer(axum::middleware::from_fn(middleware::log_connection)),
-    );
+    )
+}
+
+/// Bind `app` to a Unix domain socket at `path`, replacing any existing socket there.
+async fn serve_uds(
+    path: &Path,
+    app: Router,
+) -> Result<futures::future::BoxFuture<'static, hyper::Result<()>>> {
+    let _ = tokio::fs::remove_file(path).await;
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .context("failed to create parent directory for Houdini socket")?
+    }
 
-    tracing::info!("server listening on {:?}...", socket);
-    axum::Server::builder(uds::ServerAccept { uds })
-        .serve(app.into_make_service_with_connect_info::<uds::UdsConnectInfo>())
+    let uds = UnixListener::bind(path).context("failed to bind to Houdini socket")?;
+    Ok(Box::pin(
+        axum::Server::builder(uds::ServerAccept { uds })
+            .serve(app.into_make_service_with_connect_info::<uds::UdsConnectInfo>()),
+    ))
+}
+
+/// Bind `app` to a TCP socket at `addr`.
+async fn serve_tcp(
+    addr: &SocketAddr,
+    app: Router,
+) -> Result<futures::future::BoxFuture<'static, hyper::Result<()>>> {
+    let tcp = TcpListener::bind(addr)
         .await
-        .context("failed to start Houdini API server")
+        .context(format!("failed to bind to {}", addr))?;
+    let server =
+        axum::Server::from_tcp(tcp.into_std()?).context(format!("failed to serve on {}", addr))?;
+    Ok(Box::pin(server.serve(
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )))
 }
 
 async fn ping() -> &'static str {
@@ -75,12 +211,33 @@ async fn ping() -> &'static str {
 
 #[debug_handler]
 async fn run_trick(
-    Json(trick): Json<Trick>,
-) -> Result<Json<TrickReport>, (StatusCode, &'static str)> {
-    let report = trick.run().await;
+    Extension(active): Extension<Arc<ActiveTricks>>,
+    body: Bytes,
+) -> Result<Json<TrickReport>, (StatusCode, String)> {
+    // Parsed by hand rather than with `Json` so that errors point at the problem
+    let trick = parse::from_json(&body, "request body")
+        .map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, format!("{:#}", e)))?;
+    let (_registration, progress) = active.register(&trick.name);
+    let report = trick.run_watched(Some(&progress), None).await;
     Ok(Json(report))
 }
 
+async fn active_tricks(Extension(active): Extension<Arc<ActiveTricks>>) -> Json<Vec<ActiveTrick>> {
+    Json(active.snapshot())
+}
+
+async fn upload_context(
+    body: BodyStream,
+) -> Result<Json<ContextHandle>, (StatusCode, &'static str)> {
+    match context::store(body).await {
+        Ok(handle) => Ok(Json(ContextHandle { handle })),
+        Err(e) => {
+            tracing::warn!(err = ?e, "failed to store build context");
+            Err((StatusCode::BAD_REQUEST, "failed to store build context"))
+        }
+    }
+}
+
 async fn not_found() -> impl IntoResponse {
     (StatusCode::NOT_FOUND, "bad endpoint")
 }
@@ -90,6 +247,7 @@ mod tests {
     use std::{sync::Arc, time::Duration};
 
     use super::*;
+    use crate::{testutils::assert_valid_trick_report, tricks::Trick};
     use serial_test::serial;
     use tracing_test::traced_test;
 
@@ -102,12 +260,15 @@ mod tests {
             .into_temp_path()
             .to_path_buf();
 
-        let jh =
-            tokio::spawn(async move { serve(Some(&path)).await.expect("server should serve") });
+        let jh = tokio::spawn(async move {
+            serve(&[Socket::Unix(path)])
+                .await
+                .expect("server should serve")
+        });
         tokio::time::sleep(Duration::from_secs(1)).await;
 
         assert!(!jh.is_finished());
-        let _ = jh.abort();
+        jh.abort();
     }
 
     #[tokio::test]
@@ -122,14 +283,23 @@ mod tests {
         );
 
         let p = path.clone();
-        let jh = tokio::spawn(async move { serve(Some(&p)).await.expect("server should serve") });
+        let jh = tokio::spawn(async move {
+            serve(&[Socket::Unix(p.to_path_buf())])
+                .await
+                .expect("server should serve")
+        });
         tokio::time::sleep(Duration::from_secs(1)).await;
 
         let client = client::HoudiniClient::new(Some(&path)).expect("client should connect");
         client.ping().await.expect("ping should succeed");
+        assert!(logs_contain(concat!(
+            "user_agent=Some(\"houdini/",
+            env!("CARGO_PKG_VERSION"),
+            "\")"
+        )));
 
         assert!(!jh.is_finished());
-        let _ = jh.abort();
+        jh.abort();
     }
 
     #[tokio::test]
@@ -144,7 +314,11 @@ mod tests {
         );
 
         let p = path.clone();
-        let jh = tokio::spawn(async move { serve(Some(&p)).await.expect("server should serve") });
+        let jh = tokio::spawn(async move {
+            serve(&[Socket::Unix(p.to_path_buf())])
+                .await
+                .expect("server should serve")
+        });
         tokio::time::sleep(Duration::from_secs(1)).await;
 
         let client = client::HoudiniClient::new(Some(&path)).expect("client should connect");
@@ -156,10 +330,267 @@ mod tests {
         let trick = serde_yaml::from_str(yaml).expect("trick should deserialize");
 
         let report = client.trick(&trick).await.expect("trick should succeed");
+        assert_valid_trick_report(&report);
         assert_eq!(report.name, "foo");
         assert_eq!(report.steps.len(), 0);
 
         assert!(!jh.is_finished());
-        let _ = jh.abort();
+        jh.abort();
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    #[serial]
+    async fn test_api_auth_token() {
+        let path = Arc::new(
+            tempfile::NamedTempFile::new()
+                .unwrap()
+                .into_temp_path()
+                .to_path_buf(),
+        );
+
+        let p = path.clone();
+        let jh = tokio::spawn(async move {
+            serve_with_auth(&[Socket::Unix(p.to_path_buf())], Some("hunter2".into()))
+                .await
+                .expect("server should serve")
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let yaml = r#"
+            name: foo
+            steps: []
+            "#;
+        let trick = serde_yaml::from_str(yaml).expect("trick should deserialize");
+
+        let client = client::HoudiniClient::new(Some(&path)).expect("client should connect");
+        client
+            .ping()
+            .await
+            .expect("ping should not require a token");
+        let err = client
+            .trick(&trick)
+            .await
+            .expect_err("trick without a token should be rejected");
+        assert!(err.to_string().contains("401"), "{}", err);
+
+        let client = client::HoudiniClient::new(Some(&path))
+            .and_then(|c| c.with_token("wrong"))
+            .expect("client should connect");
+        let err = client
+            .trick(&trick)
+            .await
+            .expect_err("trick with the wrong token should be rejected");
+        assert!(err.to_string().contains("401"), "{}", err);
+
+        let client = client::HoudiniClient::new(Some(&path))
+            .and_then(|c| c.with_token("hunter2"))
+            .expect("client should connect");
+        let report = client
+            .trick(&trick)
+            .await
+            .expect("trick with the right token should succeed");
+        assert_valid_trick_report(&report);
+        assert_eq!(report.name, "foo");
+
+        assert!(!jh.is_finished());
+        jh.abort();
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    #[serial]
+    async fn test_api_context() {
+        let path = Arc::new(
+            tempfile::NamedTempFile::new()
+                .unwrap()
+                .into_temp_path()
+                .to_path_buf(),
+        );
+
+        let p = path.clone();
+        let jh = tokio::spawn(async move {
+            serve(&[Socket::Unix(p.to_path_buf())])
+                .await
+                .expect("server should serve")
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let client = client::HoudiniClient::new(Some(&path)).expect("client should connect");
+
+        let mut d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("testdata/imgbuild");
+
+        let handle = client
+            .upload_context(&d)
+            .await
+            .expect("upload should succeed");
+        let dir = context::resolve(&handle).expect("handle should resolve");
+        assert!(dir.join("Dockerfile").is_file());
+
+        let yaml = format!(
+            r#"
+            name: context
+            steps:
+            - spawnContainer:
+                name: context
+                image: houdini-context-test
+                cmd: "true"
+                imagePolicy:
+                    build:
+                        dockerfile: Dockerfile
+                        context: {}
+                success: exploitSuccess
+            "#,
+            handle
+        );
+        let trick = serde_yaml::from_str(&yaml).expect("trick should deserialize");
+
+        let report = client.trick(&trick).await.expect("trick should succeed");
+        assert_valid_trick_report(&report);
+        assert_eq!(
+            report.status,
+            crate::tricks::status::Status::ExploitSuccess,
+            "image should build from uploaded context"
+        );
+        assert!(!dir.exists(), "context should be removed after the trick");
+
+        assert!(!jh.is_finished());
+        jh.abort();
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    #[serial]
+    async fn test_api_active() {
+        let path = Arc::new(
+            tempfile::NamedTempFile::new()
+                .unwrap()
+                .into_temp_path()
+                .to_path_buf(),
+        );
+
+        let p = path.clone();
+        let jh = tokio::spawn(async move {
+            serve(&[Socket::Unix(p.to_path_buf())])
+                .await
+                .expect("server should serve")
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let client = client::HoudiniClient::new(Some(&path)).expect("client should connect");
+        assert!(client
+            .active()
+            .await
+            .expect("active should succeed")
+            .is_empty());
+
+        let yaml = r#"
+            name: slow
+            steps:
+            - host:
+                script: ["true"]
+            - host:
+                script: ["sleep 2"]
+                success: exploitSuccess
+              id: nap
+            "#;
+        let trick: Trick = serde_yaml::from_str(yaml).expect("trick should deserialize");
+        let p = path.clone();
+        let running = tokio::spawn(async move {
+            let client = client::HoudiniClient::new(Some(&p)).expect("client should connect");
+            client.trick(&trick).await.expect("trick should succeed")
+        });
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let active = client.active().await.expect("active should succeed");
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].name, "slow");
+        assert_eq!(
+            active[0].current_step,
+            Some(crate::tricks::CurrentStep {
+                index: 1,
+                kind: "host".into(),
+                id: Some("nap".into()),
+            })
+        );
+
+        let report = running.await.expect("trick task should not panic");
+        assert_valid_trick_report(&report);
+        assert_eq!(report.name, "slow");
+        assert!(client
+            .active()
+            .await
+            .expect("active should succeed")
+            .is_empty());
+
+        assert!(!jh.is_finished());
+        jh.abort();
+    }
+
+    #[tokio::test]
+    async fn test_tcp_requires_auth_token() {
+        let err = serve_with_auth(&[Socket::Tcp("127.0.0.1:0".parse().unwrap())], None)
+            .await
+            .expect_err("TCP socket without a token should be refused");
+        assert!(err.to_string().contains("authToken"), "{:#}", err);
+    }
+
+    #[test]
+    fn test_socket_from_str() {
+        assert_eq!(
+            "/run/houdini.sock".parse::<Socket>().unwrap(),
+            Socket::Unix("/run/houdini.sock".into())
+        );
+        assert_eq!(
+            "unix:///run/houdini.sock".parse::<Socket>().unwrap(),
+            Socket::Unix("/run/houdini.sock".into())
+        );
+        assert_eq!(
+            "tcp://127.0.0.1:8080".parse::<Socket>().unwrap(),
+            Socket::Tcp("127.0.0.1:8080".parse().unwrap())
+        );
+        "tcp://localhost"
+            .parse::<Socket>()
+            .expect_err("TCP socket needs a port");
+        "vsock://3:8080"
+            .parse::<Socket>()
+            .expect_err("vsock is unsupported");
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    #[serial]
+    async fn test_api_multiple_sockets() {
+        let path = tempfile::NamedTempFile::new()
+            .unwrap()
+            .into_temp_path()
+            .to_path_buf();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|l| l.local_addr())
+            .expect("should find a free port");
+
+        let sockets = [Socket::Unix(path.clone()), Socket::Tcp(addr)];
+        let jh = tokio::spawn(async move {
+            serve_with_auth(&sockets, Some("hunter2".into()))
+                .await
+                .expect("server should serve")
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let client = client::HoudiniClient::new(Some(&path)).expect("client should connect");
+        client.ping().await.expect("ping over UDS should succeed");
+
+        let uri = format!("http://{}/ping", addr).parse().unwrap();
+        let res = hyper::Client::new()
+            .get(uri)
+            .await
+            .expect("ping over TCP should succeed");
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"pong");
+
+        assert!(!jh.is_finished());
+        jh.abort();
     }
 }
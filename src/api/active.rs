@@ -0,0 +1,134 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+//
+
+//! Tracking of the tricks that the server is currently running.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::tricks::CurrentStep;
+
+/// A trick that the server is running.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ActiveTrick {
+    /// ID of this run of the trick, unique to the server.
+    pub id: String,
+    /// Name of the trick.
+    pub name: String,
+    /// When the server started running the trick.
+    pub started_at: DateTime<Utc>,
+    /// The step the trick most recently started, if it has started one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub current_step: Option<CurrentStep>,
+}
+
+/// A trick registered with [`ActiveTricks`], along with a way to watch its progress.
+struct Entry {
+    id: String,
+    name: String,
+    started_at: DateTime<Utc>,
+    progress: watch::Receiver<Option<CurrentStep>>,
+}
+
+/// The tricks that the server is currently running, shared between its handlers.
+#[derive(Default)]
+pub(super) struct ActiveTricks {
+    /// Registered tricks, keyed by the order they were registered in.
+    entries: Mutex<BTreeMap<u64, Entry>>,
+    next: AtomicU64,
+}
+
+impl ActiveTricks {
+    /// Register a trick named `name` as running, returning a guard that deregisters it
+    /// once dropped, and the sender to report its progress on.
+    pub fn register(&self, name: &str) -> (Registration<'_>, watch::Sender<Option<CurrentStep>>) {
+        let key = self.next.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = watch::channel(None);
+        self.entries.lock().expect("active tricks poisoned").insert(
+            key,
+            Entry {
+                id: uuid::Uuid::new_v4().simple().to_string(),
+                name: name.to_owned(),
+                started_at: Utc::now(),
+                progress: rx,
+            },
+        );
+        (Registration { active: self, key }, tx)
+    }
+
+    /// The tricks that are running, oldest first.
+    pub fn snapshot(&self) -> Vec<ActiveTrick> {
+        self.entries
+            .lock()
+            .expect("active tricks poisoned")
+            .values()
+            .map(|entry| ActiveTrick {
+                id: entry.id.clone(),
+                name: entry.name.clone(),
+                started_at: entry.started_at,
+                current_step: entry.progress.borrow().clone(),
+            })
+            .collect()
+    }
+}
+
+/// Deregisters a trick from [`ActiveTricks`] when dropped, including when the request
+/// that is running it is cancelled.
+pub(super) struct Registration<'a> {
+    active: &'a ActiveTricks,
+    key: u64,
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut entries) = self.active.entries.lock() {
+            entries.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_active_tricks() {
+        let active = ActiveTricks::default();
+        let (foo, foo_progress) = active.register("foo");
+        let (bar, _) = active.register("bar");
+
+        foo_progress.send_replace(Some(CurrentStep {
+            index: 1,
+            kind: "host".into(),
+            id: None,
+        }));
+        let snapshot = active.snapshot();
+        assert_eq!(
+            snapshot.iter().map(|t| &*t.name).collect::<Vec<_>>(),
+            vec!["foo", "bar"]
+        );
+        assert_eq!(snapshot[0].current_step.as_ref().map(|s| s.index), Some(1));
+        assert_eq!(snapshot[1].current_step, None);
+        assert_ne!(snapshot[0].id, snapshot[1].id);
+
+        drop(foo);
+        assert_eq!(active.snapshot()[0].name, "bar");
+        drop(bar);
+        assert!(active.snapshot().is_empty());
+    }
+}
@@ -11,20 +11,33 @@
 use std::path::Path;
 
 use anyhow::{Context, Result};
-use hyper::{Body, Request};
+use hyper::{
+    header::{HeaderName, HeaderValue, AUTHORIZATION, USER_AGENT},
+    http::request::Builder,
+    Body, HeaderMap, Method, Request,
+};
 use hyperlocal::{UnixClientExt, UnixConnector, Uri};
 
+use super::{ActiveTrick, ContextHandle};
 use crate::{
+    docker::context,
     tricks::{report::TrickReport, Trick},
     CONFIG,
 };
 
+/// User agent that identifies Houdini's requests to the server.
+const HOUDINI_USER_AGENT: &str = concat!("houdini/", env!("CARGO_PKG_VERSION"));
+
 pub struct HoudiniClient<'a> {
     client: hyper::client::Client<UnixConnector>,
     socket: &'a Path,
+    headers: HeaderMap,
 }
 
 impl<'a> HoudiniClient<'a> {
+    /// Construct a new client. Every request carries a `houdini/<version>` user agent,
+    /// the headers in `api.headers` from Houdini's config, and `api.authToken` as a bearer
+    /// token if it is set.
     pub fn new(socket: Option<&'a Path>) -> Result<Self> {
         let socket = if let Some(socket) = socket {
             socket
@@ -34,19 +47,58 @@ impl<'a> HoudiniClient<'a> {
 
         let client = hyper::client::Client::unix();
 
-        Ok(Self { socket, client })
+        let mut headers = HeaderMap::new();
+        headers.insert(USER_AGENT, HeaderValue::from_static(HOUDINI_USER_AGENT));
+        let mut client = Self {
+            socket,
+            client,
+            headers,
+        };
+        for (name, value) in &CONFIG.api.headers {
+            client = client.with_header(name, value)?;
+        }
+        if let Some(token) = &CONFIG.api.auth_token {
+            client = client.with_token(token)?;
+        }
+
+        Ok(client)
+    }
+
+    /// Add a header to every request the client sends, e.g. an auth token. This replaces
+    /// any existing header with the same name.
+    pub fn with_header(mut self, name: &str, value: &str) -> Result<Self> {
+        let name = HeaderName::try_from(name).context(format!("invalid header name {}", name))?;
+        let value =
+            HeaderValue::try_from(value).context(format!("invalid value for header {}", name))?;
+        self.headers.insert(name, value);
+        Ok(self)
+    }
+
+    /// Authenticate every request the client sends with a bearer token.
+    pub fn with_token(self, token: &str) -> Result<Self> {
+        self.with_header(AUTHORIZATION.as_str(), &format!("Bearer {}", token))
     }
 
     fn uri<S: AsRef<str>>(&self, endpoint: S) -> hyper::Uri {
         Uri::new(self.socket, endpoint.as_ref()).into()
     }
 
+    /// Start building a request to `endpoint`, with the client's headers set.
+    fn request<S: AsRef<str>>(&self, method: Method, endpoint: S) -> Builder {
+        let mut builder = Request::builder().method(method).uri(self.uri(endpoint));
+        if let Some(headers) = builder.headers_mut() {
+            headers.extend(self.headers.clone());
+        }
+        builder
+    }
+
     pub async fn ping(&self) -> Result<()> {
-        let res = self
-            .client
-            .get(self.uri("/ping"))
-            .await
-            .context("ping failed")?;
+        let req = self
+            .request(Method::GET, "/ping")
+            .body(Body::empty())
+            .expect("request builder");
+
+        let res = self.client.request(req).await.context("ping failed")?;
 
         if !res.status().is_success() {
             anyhow::bail!("ping failed with status code {}", res.status())
@@ -57,10 +109,9 @@ impl<'a> HoudiniClient<'a> {
     }
 
     pub async fn trick(&self, trick: &Trick) -> Result<TrickReport> {
-        let req = Request::builder()
+        let req = self
+            .request(Method::POST, "/trick")
             .header("content-type", "application/json")
-            .method("POST")
-            .uri(self.uri("/trick"))
             .body(Body::from(
                 serde_json::to_vec(trick).context("failed to serialize trick")?,
             ))
@@ -79,4 +130,60 @@ impl<'a> HoudiniClient<'a> {
         let body = hyper::body::to_bytes(res.into_body()).await?.to_vec();
         serde_json::from_slice(body.as_slice()).context("failed to deserialize response")
     }
+
+    /// Get the tricks that the server is currently running, oldest first.
+    pub async fn active(&self) -> Result<Vec<ActiveTrick>> {
+        let req = self
+            .request(Method::GET, "/active")
+            .body(Body::empty())
+            .expect("request builder");
+
+        let res = self
+            .client
+            .request(req)
+            .await
+            .context("active tricks request failed")?;
+
+        if !res.status().is_success() {
+            anyhow::bail!("request failed with status code {}", res.status())
+        }
+
+        let body = hyper::body::to_bytes(res.into_body()).await?.to_vec();
+        serde_json::from_slice(body.as_slice()).context("failed to deserialize response")
+    }
+
+    /// Archive a local build context and upload it to the server, returning a handle
+    /// that tricks can use to build images from it.
+    pub async fn upload_context(&self, build_root: &Path) -> Result<String> {
+        let root = build_root.to_owned();
+        let buf = tokio::task::spawn_blocking(move || context::archive(&root))
+            .await
+            .context("context archive task panicked")?
+            .context(format!(
+                "failed to archive build context {}",
+                build_root.display()
+            ))?;
+
+        let req = self
+            .request(Method::POST, "/context")
+            .header("content-type", "application/gzip")
+            .body(Body::from(buf))
+            .expect("request builder");
+
+        let res = self
+            .client
+            .request(req)
+            .await
+            .context("context upload failed")?;
+
+        if !res.status().is_success() {
+            anyhow::bail!("request failed with status code {}", res.status())
+        }
+
+        let body = hyper::body::to_bytes(res.into_body()).await?.to_vec();
+        let handle: ContextHandle =
+            serde_json::from_slice(body.as_slice()).context("failed to deserialize response")?;
+
+        Ok(handle.handle)
+    }
 }
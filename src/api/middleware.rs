@@ -8,25 +8,63 @@
 
 //! Middleware for the Houdini API.
 
+use std::net::SocketAddr;
+
 use crate::api::uds::UdsConnectInfo;
 use axum::{
     extract::{ConnectInfo, RequestParts},
-    http::Request,
+    http::{
+        header::{AUTHORIZATION, USER_AGENT},
+        Request, StatusCode,
+    },
     middleware::Next,
-    response::Response,
+    response::{IntoResponse, Response},
 };
 
 pub async fn log_connection<B>(request: Request<B>, next: Next<B>) -> Response
 where
     B: Send,
 {
+    let user_agent = request
+        .headers()
+        .get(USER_AGENT)
+        .map(|ua| String::from_utf8_lossy(ua.as_bytes()).into_owned());
     let mut parts = RequestParts::new(request);
 
-    match parts.extract::<ConnectInfo<UdsConnectInfo>>().await {
-        Ok(info) => tracing::info!("new connection from {:?}", info),
-        Err(e) => tracing::warn!(err = ?e, "failed to extract connection info"),
-    };
+    // Connections carry Unix or TCP peer info, depending on the socket they came in on
+    if let Ok(info) = parts.extract::<ConnectInfo<UdsConnectInfo>>().await {
+        tracing::info!(user_agent = ?user_agent, "new connection from {:?}", info);
+    } else {
+        match parts.extract::<ConnectInfo<SocketAddr>>().await {
+            Ok(info) => tracing::info!(user_agent = ?user_agent, "new connection from {:?}", info),
+            Err(e) => tracing::warn!(err = ?e, "failed to extract connection info"),
+        }
+    }
 
     let request = parts.try_into_request().expect("body extracted");
     next.run(request).await
 }
+
+/// Reject requests that do not carry `Authorization: Bearer <token>`, if `token` is set.
+pub async fn require_token<B>(token: Option<String>, request: Request<B>, next: Next<B>) -> Response
+where
+    B: Send,
+{
+    let token = match token {
+        Some(token) => token,
+        None => return next.run(request).await,
+    };
+
+    let authorized = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| given == token);
+    if !authorized {
+        tracing::warn!(uri = ?request.uri(), "rejecting request with missing or bad token");
+        return (StatusCode::UNAUTHORIZED, "missing or bad auth token").into_response();
+    }
+
+    next.run(request).await
+}
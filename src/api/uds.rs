@@ -40,6 +40,7 @@ impl Accept for ServerAccept {
     }
 }
 
+#[allow(dead_code)]
 pub struct ClientConnection {
     stream: UnixStream,
 }
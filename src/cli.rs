@@ -10,16 +10,27 @@
 //! entrypoint logic. Its public interface is [`Cli::run()`], which consumes [`Cli`]
 //! and executes the corresponding subcommand.
 
-use std::path::PathBuf;
-use tokio::fs::File;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+};
 
 use anyhow::{Context, Result};
-use clap_derive::Parser;
+use clap_derive::{ArgEnum, Parser};
+use futures::{stream::FuturesUnordered, StreamExt};
 
 use crate::{
     api,
+    docker::{self, check_daemon, image_present, image_size, lint},
     logging::LoggingFormat,
-    tricks::{report::Report, Trick},
+    tricks::{
+        bundle, remote,
+        report::{self, diff::ReportDiff, Report},
+        starter::{starter_trick, StarterKind},
+        status::Status,
+        Trick,
+    },
+    CONFIG,
 };
 
 /// Describes Houdini's command line interface.
@@ -36,6 +47,9 @@ pub struct Cli {
     /// otherwise.
     #[clap(global = true, arg_enum, long, short, default_value = "auto")]
     pub format: LoggingFormat,
+    /// Directory to write exploit reports to. Defaults to the value in Houdini configs.
+    #[clap(global = true, long)]
+    report_dir: Option<PathBuf>,
 }
 
 /// Enumerates Houdini's various subcommands.
@@ -43,18 +57,154 @@ pub struct Cli {
 enum Cmd {
     /// Run one or more container exploits and test whether they complete successfully.
     Run {
-        /// The exploits to run.
-        #[clap(min_values = 1, required = true)]
+        /// The exploits to run. These are files, or HTTP(S) URLs if --allow-remote is set.
+        #[clap(min_values = 1, required_unless_present = "rerun-failed")]
         tricks: Vec<PathBuf>,
+        /// Also run the tricks that failed in this prior report, loading each from the
+        /// file it was originally loaded from.
+        #[clap(long, value_name = "REPORT")]
+        rerun_failed: Option<PathBuf>,
+        /// Warn instead of erroring when two or more tricks share the same name.
+        #[clap(long)]
+        allow_duplicate_names: bool,
+        /// Stop running tricks after the first one that ends in exploit success.
+        #[clap(long)]
+        stop_on_success: bool,
+        /// Print a one-line explanation of each trick's final status.
+        #[clap(long)]
+        explain: bool,
+        /// After each trick, fail it if any container that Houdini spawned is left behind.
+        #[clap(long)]
+        assert_clean: bool,
+        /// Allow tricks to be fetched from HTTP(S) URLs.
+        #[clap(long)]
+        allow_remote: bool,
+        /// Print a summary of how many tricks attempted each technique, and how many of
+        /// those succeeded.
+        #[clap(long)]
+        coverage: bool,
+        /// Print a summary of how many tricks succeeded, and how many successful exploits
+        /// there were of each severity, in this format.
+        #[clap(arg_enum, long, value_name = "FORMAT")]
+        summary_format: Option<SummaryFormat>,
+        /// Override a build argument for every image built by the tricks. May be
+        /// repeated.
+        #[clap(long = "build-arg", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+        build_args: Vec<(String, String)>,
+        /// Run every trick once per image in this comma-separated list, spawning all of
+        /// its containers from that image.
+        #[clap(long, value_name = "IMAGES", value_delimiter = ',')]
+        image_matrix: Vec<String>,
+        /// Override the default value of a trick parameter. May be repeated.
+        #[clap(long = "param", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+        params: Vec<(String, String)>,
+        /// Also write the report in SARIF format to this path, for code scanning
+        /// dashboards.
+        #[clap(long, value_name = "PATH")]
+        sarif: Option<PathBuf>,
+        /// Run the steps of a trick concurrently once the steps they depend on have
+        /// completed. Tricks that do not declare dependencies between their steps still
+        /// run one step at a time.
+        #[clap(long)]
+        parallel_steps: bool,
+        /// Most image pulls, container spawns, and execs to issue to the Docker daemon at
+        /// once, across every trick. Overrides `docker.maxConcurrentOps` in Houdini
+        /// configs.
+        #[clap(long, value_name = "N")]
+        concurrency: Option<usize>,
+        /// Also write the log messages of each trick to a file of its own, under `logs` in
+        /// the report directory.
+        #[clap(long)]
+        per_trick_logs: bool,
+        /// Exit with an error if any trick was skipped, e.g. because the host did not
+        /// match its version checks.
+        #[clap(long)]
+        strict_skip: bool,
+        /// Most tricks to run at once. When more than one, the tricks in the report are
+        /// sorted by name, then by `--image-matrix` image, rather than listed in the order
+        /// they were given.
+        #[clap(long, short, value_name = "N", default_value = "1")]
+        jobs: usize,
     },
     /// The Houdini API.
     Api {
         /// The subcommand to run.
         #[clap(subcommand)]
         subcmd: ApiCmd,
-        /// The path to the Houdini socket. Defaults to the value in Houdini configs.
-        #[clap(global = true, long, short)]
-        socket: Option<PathBuf>,
+        /// The Houdini socket, as a path, `unix://<path>`, or `tcp://<host>:<port>`.
+        /// The server listens on every socket given; the client connects to a single
+        /// Unix socket. Defaults to the value in Houdini configs.
+        #[clap(global = true, long = "socket", short, multiple_occurrences = true)]
+        sockets: Vec<api::Socket>,
+    },
+    /// Debugging utilities.
+    Debug {
+        /// The subcommand to run.
+        #[clap(subcommand)]
+        subcmd: DebugCmd,
+    },
+}
+
+/// Subcommands for debugging Houdini.
+#[derive(Parser, Debug)]
+enum DebugCmd {
+    /// Check that the environment is set up correctly by pulling an image, spawning a
+    /// container, and running a command inside it.
+    SelfTest,
+    /// Check a Dockerfile for common issues, such as unpinned base images or running as
+    /// root.
+    DockerfileLint {
+        /// The Dockerfile to check.
+        path: PathBuf,
+    },
+    /// Print the JSON schema that exploit reports conform to.
+    ReportSchema,
+    /// Check that an exploit report conforms to the report schema.
+    ValidateReport {
+        /// The report to check.
+        path: PathBuf,
+    },
+    /// List the images that tricks spawn containers from, whether each is present
+    /// locally, and the policy used to acquire it.
+    Images {
+        /// Trick files, or directories to search for trick files.
+        #[clap(min_values = 1, required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// Estimate what running tricks will take: how many containers they spawn from each
+    /// image, and how much of each image is already present locally. The sizes of
+    /// images that are not present are unknown until they are pulled.
+    Estimate {
+        /// Trick files, or directories to search for trick files.
+        #[clap(min_values = 1, required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// Print a commented starter trick to fill in.
+    Init {
+        /// Kind of exploit the trick is for.
+        #[clap(arg_enum, default_value = "escape")]
+        kind: StarterKind,
+        /// Write the trick to this file instead of stdout.
+        #[clap(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Compare an exploit report against a baseline, printing tricks whose status
+    /// changed and tricks that were added or removed. Fails if any exploit that
+    /// succeeded in the baseline no longer succeeds.
+    DiffReports {
+        /// The report to compare against.
+        baseline: PathBuf,
+        /// The report to compare.
+        current: PathBuf,
+    },
+    /// Run a trick and bundle it, the Dockerfiles and seccomp profiles it refers to, and
+    /// its report into a single gzipped tarball, so that the exploit can be shared and
+    /// reproduced.
+    Bundle {
+        /// The trick to run and bundle.
+        trick: PathBuf,
+        /// Path to write the archive to, e.g. `exploit.tar.gz`.
+        out: PathBuf,
     },
 }
 
@@ -81,59 +231,322 @@ enum ClientOperation {
         /// The exploit to run.
         trick: PathBuf,
     },
+    /// Upload a build context to the server and print its handle.
+    Context {
+        /// The directory to upload.
+        dir: PathBuf,
+    },
+    /// Print the tricks that the server is currently running.
+    Active,
+}
+
+/// Format to print a run's summary in.
+#[derive(Debug, ArgEnum, Clone, Copy)]
+enum SummaryFormat {
+    /// Human-readable lines
+    Text,
+    /// A JSON object
+    Json,
 }
 
 impl Cli {
+    /// The directory that exploit reports should be written to.
+    pub fn report_dir(&self) -> &Path {
+        self.report_dir.as_deref().unwrap_or(&CONFIG.reports.dir)
+    }
+
     /// Consume the CLI object and run the corresponding subcommand.
     pub async fn run(self) -> Result<()> {
+        let report_dir = self.report_dir().to_owned();
+
         match self.subcmd {
-            Cmd::Run { tricks } => {
-                let mut report = Report::new();
+            Cmd::Run {
+                mut tricks,
+                rerun_failed,
+                allow_duplicate_names,
+                stop_on_success,
+                explain,
+                coverage,
+                summary_format,
+                assert_clean,
+                allow_remote,
+                build_args,
+                image_matrix,
+                params,
+                sarif,
+                parallel_steps,
+                concurrency,
+                per_trick_logs,
+                strict_skip,
+                jobs,
+            } => {
+                if jobs == 0 {
+                    anyhow::bail!("--jobs must be at least 1");
+                }
+                // Leftover containers could not be told apart from those of tricks that
+                // are still running
+                if jobs > 1 && assert_clean {
+                    anyhow::bail!("--assert-clean cannot be used with --jobs greater than 1");
+                }
+                if let Some(max) = concurrency {
+                    docker::limit_daemon_ops(max)?;
+                }
+
+                let build_args = build_args.into_iter().collect::<HashMap<_, _>>();
+                let params = params.into_iter().collect::<HashMap<_, _>>();
 
+                if let Some(path) = rerun_failed {
+                    let failed = failed_tricks(&Report::read_from(&path)?);
+                    if failed.is_empty() && tricks.is_empty() {
+                        tracing::info!(report = ?path, "no failed tricks to re-run");
+                        return Ok(());
+                    }
+                    tricks.extend(failed);
+                }
+
+                let mut loaded = Vec::with_capacity(tricks.len());
                 for file in tricks {
-                    let f = File::open(&file)
-                        .await
-                        .context(format!("could not open trick file {}", &file.display()))?;
+                    let mut trick = load_trick(&file, allow_remote, &params).await?;
+                    trick.override_build_args(&build_args);
+                    trick.parallel_steps = parallel_steps;
+                    trick.evidence_dir = Some(report_dir.join("evidence"));
+                    if per_trick_logs {
+                        trick.log_dir = Some(report_dir.join("logs"));
+                    }
+                    loaded.push((file, trick));
+                }
+
+                let duplicates = find_duplicate_names(&loaded);
+                for (name, files) in &duplicates {
+                    if allow_duplicate_names {
+                        tracing::warn!(name = ?name, files = ?files, "duplicate trick name");
+                    } else {
+                        tracing::error!(name = ?name, files = ?files, "duplicate trick name");
+                    }
+                }
+                if !duplicates.is_empty() && !allow_duplicate_names {
+                    anyhow::bail!(
+                        "found {} duplicate trick name(s); pass --allow-duplicate-names to run anyway",
+                        duplicates.len()
+                    );
+                }
+
+                // Fail early rather than deep inside the first container step
+                if loaded.iter().any(|(_, trick)| !trick.images().is_empty()) {
+                    check_daemon().await?;
+                }
 
-                    let trick: Trick = serde_yaml::from_reader(f.into_std().await)
-                        .context(format!("failed to parse trick {}", &file.display()))?;
+                let tricks = loaded.into_iter().map(|(_, trick)| trick);
+                let tricks = expand_image_matrix(tricks, &image_matrix);
+                let report = run_tricks(tricks, jobs, stop_on_success, assert_clean).await;
 
-                    report.add(trick.run().await);
+                if explain {
+                    for trick in &report.exploits {
+                        println!("{}", trick.explain());
+                    }
+                }
+
+                if coverage {
+                    let mut coverage = report
+                        .coverage_by_technique()
+                        .into_iter()
+                        .collect::<Vec<_>>();
+                    coverage.sort_by(|a, b| a.0.cmp(&b.0));
+                    for (technique, coverage) in coverage {
+                        println!(
+                            "{}: {}/{} tricks succeeded",
+                            technique, coverage.succeeded, coverage.tricks
+                        );
+                    }
+                }
+
+                let mut summary = report.summary();
+                summary.failed = check_skipped(&report, strict_skip).is_err();
+                match summary_format {
+                    Some(SummaryFormat::Text) if atty::is(atty::Stream::Stdout) => {
+                        print!("{:#}", summary)
+                    }
+                    Some(SummaryFormat::Text) => print!("{}", summary),
+                    Some(SummaryFormat::Json) => println!(
+                        "{}",
+                        serde_json::to_string_pretty(&summary)
+                            .context("failed to serialize summary")?
+                    ),
+                    None => {}
+                }
+
+                if let Some(path) = sarif {
+                    let buf = serde_json::to_vec_pretty(&report.to_sarif())
+                        .context("failed to serialize SARIF report")?;
+                    tokio::fs::write(&path, buf)
+                        .await
+                        .context(format!("failed to write SARIF report {}", path.display()))?;
                 }
 
                 report
-                    .write_to_disk()
+                    .write_to_disk(&report_dir)
                     .await
                     .context("failed to write report to disk")?;
+
+                check_skipped(&report, strict_skip)?;
+            }
+            Cmd::Debug {
+                subcmd: DebugCmd::SelfTest,
+            } => {
+                let report = Trick::self_test().run().await;
+
+                for (i, step) in report.steps.iter().enumerate() {
+                    let ok = !matches!(
+                        step.status(),
+                        Status::SetupFailure | Status::ExploitFailure | Status::HarnessError
+                    );
+                    if ok {
+                        tracing::info!(stage = i, kind = step.kind(), "self-test stage passed");
+                    } else {
+                        tracing::error!(
+                            stage = i,
+                            kind = step.kind(),
+                            status = ?step.status(),
+                            hint = self_test_hint(step.kind()),
+                            "self-test stage failed"
+                        );
+                    }
+                }
+
+                if report.status != Status::ExploitSuccess {
+                    anyhow::bail!("self-test failed with status {:?}", report.status);
+                }
+                tracing::info!("self-test passed, all is well");
+            }
+            Cmd::Debug {
+                subcmd: DebugCmd::DockerfileLint { path },
+            } => {
+                let warnings = lint::lint_file(&path)?;
+                for warning in &warnings {
+                    println!("{}: {}", path.display(), warning);
+                }
+                if warnings.is_empty() {
+                    tracing::info!(path = ?path, "no issues found");
+                }
+            }
+            Cmd::Debug {
+                subcmd: DebugCmd::ReportSchema,
+            } => {
+                println!("{}", serde_json::to_string_pretty(&report::schema())?);
+            }
+            Cmd::Debug {
+                subcmd: DebugCmd::ValidateReport { path },
+            } => {
+                let f = std::fs::File::open(&path)
+                    .context(format!("could not open report {}", path.display()))?;
+                let json: serde_json::Value = serde_json::from_reader(f)
+                    .context(format!("failed to parse report {}", path.display()))?;
+
+                report::validate(&json)?;
+                tracing::info!(path = ?path, "report is valid");
+            }
+            Cmd::Debug {
+                subcmd: DebugCmd::Images { paths },
+            } => {
+                let mut tricks = vec![];
+                for file in find_trick_files(&paths) {
+                    tricks.push(load_trick(&file, false, &HashMap::new()).await?);
+                }
+
+                let images =
+                    image_summary(&tricks, |image| async move { image_present(&image).await })
+                        .await?;
+                print!("{}", format_image_table(&images));
+            }
+            Cmd::Debug {
+                subcmd: DebugCmd::Estimate { paths },
+            } => {
+                let mut tricks = vec![];
+                for file in find_trick_files(&paths) {
+                    tricks.push(load_trick(&file, false, &HashMap::new()).await?);
+                }
+
+                let images =
+                    estimate(&tricks, |image| async move { image_size(&image).await }).await?;
+                print!("{}", format_estimate_table(&images));
+            }
+            Cmd::Debug {
+                subcmd: DebugCmd::Init { kind, output },
+            } => {
+                let trick = starter_trick(kind);
+                match output {
+                    Some(path) => std::fs::write(&path, trick)
+                        .context(format!("failed to write {}", path.display()))?,
+                    None => print!("{}", trick),
+                }
+            }
+            Cmd::Debug {
+                subcmd: DebugCmd::DiffReports { baseline, current },
+            } => {
+                let diff = ReportDiff::new(
+                    &Report::read_from(&baseline)?,
+                    &Report::read_from(&current)?,
+                );
+                print!("{}", diff);
+
+                let regressions = diff.regressions().count();
+                if regressions > 0 {
+                    anyhow::bail!("{} exploit(s) regressed", regressions);
+                }
+            }
+            Cmd::Debug {
+                subcmd: DebugCmd::Bundle { trick: file, out },
+            } => {
+                let trick = load_trick(&file, false, &HashMap::new()).await?;
+                let mut report = Report::new();
+                report.add(trick.run().await);
+
+                bundle::write(&out, &file, &trick, &report)?;
+                tracing::info!(trick = ?file, bundle = ?out, "wrote bundle");
             }
             Cmd::Api {
                 subcmd: ApiCmd::Serve,
-                socket,
+                sockets,
             } => {
-                api::serve(socket.as_deref()).await?;
+                check_daemon().await?;
+                api::serve(&sockets).await?;
             }
             Cmd::Api {
                 subcmd: ApiCmd::Client { operation },
-                socket,
+                sockets,
             } => {
-                let client = api::client::HoudiniClient::new(socket.as_deref())
+                let socket = match sockets.as_slice() {
+                    [] => None,
+                    [api::Socket::Unix(path)] => Some(path.as_path()),
+                    [socket @ api::Socket::Tcp(_)] => {
+                        anyhow::bail!(
+                            "the client can only connect to Unix sockets, not {}",
+                            socket
+                        )
+                    }
+                    _ => anyhow::bail!("the client can only connect to a single socket"),
+                };
+                let client = api::client::HoudiniClient::new(socket)
                     .context("failed to parse API socket URL")?;
 
                 match operation {
                     ClientOperation::Ping => client.ping().await?,
                     ClientOperation::Trick { trick } => {
-                        let f = File::open(&trick)
-                            .await
-                            .context(format!("could not open trick file {}", &trick.display()))?;
-
-                        let trick: Trick = serde_yaml::from_reader(f.into_std().await)
-                            .context(format!("failed to parse trick {}", &trick.display()))?;
+                        let trick = load_trick(&trick, false, &HashMap::new()).await?;
 
                         let report = client.trick(&trick).await?;
                         let out = serde_json::to_string_pretty(&report)?;
 
                         println!("{}", out);
                     }
+                    ClientOperation::Context { dir } => {
+                        let handle = client.upload_context(&dir).await?;
+                        println!("{}", handle);
+                    }
+                    ClientOperation::Active => {
+                        let active = client.active().await?;
+                        println!("{}", serde_json::to_string_pretty(&active)?);
+                    }
                 }
             }
         }
@@ -141,3 +554,918 @@ impl Cli {
         Ok(())
     }
 }
+
+/// Run a batch of tricks, collecting their results into a report. If `stop_on_success`
+/// is set, stop after the first trick that ends in [`Status::ExploitSuccess`]. On ctrl-c,
+/// the tricks that are running are cancelled and no more are started, so that the report
+/// still covers what ran.
+async fn run_tricks(
+    tricks: impl IntoIterator<Item = Trick>,
+    jobs: usize,
+    stop_on_success: bool,
+    assert_clean: bool,
+) -> Report {
+    let mut tricks = tricks.into_iter();
+    let mut running = FuturesUnordered::new();
+    let mut reports = vec![];
+    let mut stopped = false;
+    let (cancel, cancelled) = tokio::sync::watch::channel(false);
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+
+    loop {
+        while !stopped && running.len() < jobs.max(1) {
+            match tricks.next() {
+                Some(trick) => running.push(run_trick(trick, assert_clean, cancelled.clone())),
+                None => break,
+            }
+        }
+
+        let trick_report = tokio::select! {
+            trick_report = running.next() => match trick_report {
+                Some(trick_report) => trick_report,
+                None => break,
+            },
+            _ = &mut ctrl_c, if !*cancel.borrow() => {
+                tracing::warn!("interrupted, cancelling running tricks");
+                cancel.send_replace(true);
+                stopped = true;
+                continue;
+            }
+        };
+        if stop_on_success && trick_report.status == Status::ExploitSuccess && !stopped {
+            tracing::info!(name = ?&trick_report.name, "exploit succeeded, stopping early");
+            stopped = true;
+        }
+        reports.push(trick_report);
+    }
+
+    // Tricks finish in whichever order they happen to when run concurrently
+    if jobs > 1 {
+        reports.sort_by(|a, b| (&a.name, &a.matrix_image).cmp(&(&b.name, &b.matrix_image)));
+    }
+
+    let mut report = Report::new();
+    for trick_report in reports {
+        report.add(trick_report);
+    }
+    report
+}
+
+/// Run `trick` until `cancel` is set. The trick cleans up after itself. If
+/// `assert_clean` is set, the trick errors if any container that Houdini spawned is left
+/// behind afterwards.
+async fn run_trick(
+    trick: Trick,
+    assert_clean: bool,
+    cancel: tokio::sync::watch::Receiver<bool>,
+) -> report::TrickReport {
+    let mut trick_report = trick.run_watched(None, Some(cancel)).await;
+    if assert_clean {
+        if let Err(e) = docker::assert_clean().await {
+            tracing::error!(name = ?&trick.name, err = ?e, "trick did not clean up");
+            trick_report.set_reason(format!("{:#}", e));
+            trick_report.set_status(Status::HarnessError);
+        }
+    }
+    trick_report
+}
+
+/// Expand each trick into one trick per image in `images`. If `images` is empty, the
+/// tricks are returned as they are.
+fn expand_image_matrix(tricks: impl IntoIterator<Item = Trick>, images: &[String]) -> Vec<Trick> {
+    tricks
+        .into_iter()
+        .flat_map(|trick| match images {
+            [] => vec![trick],
+            images => images.iter().map(|image| trick.with_image(image)).collect(),
+        })
+        .collect()
+}
+
+/// An actionable hint for a failed self-test stage.
+fn self_test_hint(kind: &str) -> String {
+    match kind {
+        "spawnContainer" => format!(
+            "check that the Docker daemon is running, that {} is accessible, and that the \
+             `bash` image can be pulled from Docker Hub",
+            CONFIG.docker.socket.display()
+        ),
+        "container" => "check that the container runtime supports `docker exec`".into(),
+        _ => "consult the logs for more details".into(),
+    }
+}
+
+/// Load and parse a trick from a YAML file, substituting its parameters with `params`
+/// overriding their defaults. If `allow_remote` is set, `file` may also be an HTTP(S) URL
+/// to fetch the trick from.
+async fn load_trick(
+    file: &Path,
+    allow_remote: bool,
+    params: &HashMap<String, String>,
+) -> Result<Trick> {
+    if let Some(url) = file.to_str().filter(|f| remote::is_remote(f)) {
+        if !allow_remote {
+            anyhow::bail!(
+                "refusing to fetch remote trick {}; pass --allow-remote to run it",
+                url
+            );
+        }
+        let mut trick = remote::fetch_trick(url, params).await?;
+        trick.source_path = Some(file.to_owned());
+        return Ok(trick);
+    }
+
+    Trick::from_path(file, params).await
+}
+
+/// Expand a list of trick files and directories into trick files. Directories are
+/// searched recursively for YAML files.
+fn find_trick_files(paths: &[PathBuf]) -> Vec<PathBuf> {
+    let mut files = vec![];
+    for path in paths {
+        if !path.is_dir() {
+            files.push(path.to_owned());
+            continue;
+        }
+        files.extend(
+            jwalk::WalkDir::new(path)
+                .sort(true)
+                .into_iter()
+                .filter_map(Result::ok)
+                .map(|e| e.path())
+                .filter(|p| p.is_file())
+                .filter(|p| matches!(p.extension().and_then(|e| e.to_str()), Some("yaml" | "yml"))),
+        );
+    }
+    files
+}
+
+/// An image referenced by one or more tricks.
+#[derive(Debug, PartialEq, Eq)]
+struct ImageSummary {
+    /// Name of the image.
+    image: String,
+    /// Whether the image is present locally.
+    present: bool,
+    /// Kind of policy used to acquire the image.
+    policy: &'static str,
+}
+
+/// Summarize the images referenced by `tricks`, in the order they are first referenced,
+/// checking whether each is present with `inspect`. An image referenced with the same
+/// kind of policy more than once is only listed once.
+async fn image_summary<F, Fut>(tricks: &[Trick], inspect: F) -> Result<Vec<ImageSummary>>
+where
+    F: Fn(String) -> Fut,
+    Fut: std::future::Future<Output = Result<bool>>,
+{
+    let mut seen = HashSet::new();
+    let mut images = vec![];
+
+    for (image, policy) in tricks.iter().flat_map(Trick::images) {
+        if !seen.insert((image, policy.kind())) {
+            continue;
+        }
+        images.push(ImageSummary {
+            image: image.to_owned(),
+            present: inspect(image.to_owned()).await?,
+            policy: policy.kind(),
+        });
+    }
+
+    Ok(images)
+}
+
+/// Format image summaries as a table with a header row.
+fn format_image_table(images: &[ImageSummary]) -> String {
+    let width = images
+        .iter()
+        .map(|i| i.image.len())
+        .chain(std::iter::once("IMAGE".len()))
+        .max()
+        .unwrap_or_default();
+
+    let mut table = format!(
+        "{:width$}  {:7}  POLICY\n",
+        "IMAGE",
+        "PRESENT",
+        width = width
+    );
+    for i in images {
+        let present = if i.present { "yes" } else { "no" };
+        table += &format!(
+            "{:width$}  {:7}  {}\n",
+            i.image,
+            present,
+            i.policy,
+            width = width
+        );
+    }
+    table
+}
+
+/// An estimate of what running tricks will take for one image.
+#[derive(Debug, PartialEq, Eq)]
+struct ImageEstimate {
+    /// Name of the image.
+    image: String,
+    /// Kind of policy used to acquire the image.
+    policy: &'static str,
+    /// Number of containers spawned from the image.
+    spawns: usize,
+    /// Size of the image in bytes, if it is present locally.
+    local_size: Option<u64>,
+}
+
+impl ImageEstimate {
+    /// Will the image need to be pulled?
+    fn needs_pull(&self) -> bool {
+        self.policy == "pull" && self.local_size.is_none()
+    }
+}
+
+/// Estimate the containers that `tricks` spawn from each image, in the order the images
+/// are first referenced, looking up each image's local size with `inspect`.
+async fn estimate<F, Fut>(tricks: &[Trick], inspect: F) -> Result<Vec<ImageEstimate>>
+where
+    F: Fn(String) -> Fut,
+    Fut: std::future::Future<Output = Result<Option<u64>>>,
+{
+    let mut images: Vec<ImageEstimate> = vec![];
+
+    for (image, policy) in tricks.iter().flat_map(Trick::images) {
+        match images
+            .iter_mut()
+            .find(|i| i.image == image && i.policy == policy.kind())
+        {
+            Some(estimate) => estimate.spawns += 1,
+            None => images.push(ImageEstimate {
+                image: image.to_owned(),
+                policy: policy.kind(),
+                spawns: 1,
+                local_size: inspect(image.to_owned()).await?,
+            }),
+        }
+    }
+
+    Ok(images)
+}
+
+/// Format image estimates as a table with a header row and a totals row.
+fn format_estimate_table(images: &[ImageEstimate]) -> String {
+    let width = images
+        .iter()
+        .map(|i| i.image.len())
+        .chain(std::iter::once("IMAGE".len()))
+        .max()
+        .unwrap_or_default();
+
+    let mut table = format!(
+        "{:width$}  {:6}  {:6}  LOCAL SIZE\n",
+        "IMAGE",
+        "POLICY",
+        "SPAWNS",
+        width = width
+    );
+    for i in images {
+        let size = match i.local_size {
+            Some(size) => format!("{:.1} MB", size as f64 / 1e6),
+            None => "-".into(),
+        };
+        table += &format!(
+            "{:width$}  {:6}  {:<6}  {}\n",
+            i.image,
+            i.policy,
+            i.spawns,
+            size,
+            width = width
+        );
+    }
+
+    let spawns: usize = images.iter().map(|i| i.spawns).sum();
+    let pulls = images.iter().filter(|i| i.needs_pull()).count();
+    let local: u64 = images.iter().filter_map(|i| i.local_size).sum();
+    table += &format!(
+        "{} container(s) from {} image(s), {} to pull, {:.1} MB present locally\n",
+        spawns,
+        images.len(),
+        pulls,
+        local as f64 / 1e6
+    );
+    table
+}
+
+/// Parse a `KEY=VALUE` argument.
+fn parse_key_value(arg: &str) -> Result<(String, String)> {
+    let (key, value) = arg
+        .split_once('=')
+        .with_context(|| format!("`{}` is not of the form KEY=VALUE", arg))?;
+    Ok((key.to_owned(), value.to_owned()))
+}
+
+/// Fail if any trick in `report` was skipped and `strict_skip` is set, so that the
+/// process exits non-zero. Skipped tricks are fine otherwise.
+fn check_skipped(report: &Report, strict_skip: bool) -> Result<()> {
+    let skipped = report.skipped();
+    if strict_skip && skipped > 0 {
+        anyhow::bail!("{} trick(s) were skipped with --strict-skip set", skipped);
+    }
+    Ok(())
+}
+
+/// The files of the tricks that failed in `report`, in the order they ran. Tricks that
+/// failed but were not loaded from a file are skipped with a warning.
+fn failed_tricks(report: &Report) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = vec![];
+    for exploit in &report.exploits {
+        let failed = matches!(
+            exploit.status,
+            Status::Undecided
+                | Status::SetupFailure
+                | Status::ExploitFailure
+                | Status::HarnessError
+        );
+        if !failed {
+            continue;
+        }
+
+        match &exploit.source_path {
+            Some(path) if !files.contains(path) => files.push(path.to_owned()),
+            Some(_) => {}
+            None => tracing::warn!(
+                name = ?exploit.name,
+                "cannot re-run failed trick that was not loaded from a file"
+            ),
+        }
+    }
+    files
+}
+
+/// Find trick names that are shared by more than one file, mapping each duplicated name
+/// to the offending file paths.
+fn find_duplicate_names(tricks: &[(PathBuf, Trick)]) -> BTreeMap<&str, Vec<&Path>> {
+    let mut names: BTreeMap<&str, Vec<&Path>> = BTreeMap::new();
+    for (file, trick) in tricks {
+        names.entry(&trick.name).or_default().push(file);
+    }
+    names.retain(|_, files| files.len() > 1);
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use clap::Parser as _;
+
+    use super::*;
+    use crate::{testutils::assert_valid_report, tricks::report::TrickReport};
+
+    fn write_trick(name: &str) -> tempfile::NamedTempFile {
+        let mut f = tempfile::NamedTempFile::new().unwrap();
+        write!(f, "name: {}\nsteps: []\n", name).unwrap();
+        f
+    }
+
+    fn host_trick(name: &str, command: &str, success: &str) -> Trick {
+        let yaml = format!(
+            r#"
+            name: {}
+            steps:
+            - host:
+                script:
+                - command: "{}"
+                  args: []
+                success: {}
+                failure: exploitFailure
+            "#,
+            name, command, success
+        );
+        serde_yaml::from_str(&yaml).expect("trick should deserialize")
+    }
+
+    #[tokio::test]
+    async fn test_stop_on_success() {
+        let tricks = || {
+            vec![
+                host_trick("first", "false", "exploitSuccess"),
+                host_trick("second", "true", "exploitSuccess"),
+                host_trick("third", "true", "exploitSuccess"),
+            ]
+        };
+
+        let report = run_tricks(tricks(), 1, true, false).await;
+        assert_valid_report(&report);
+        let names = report.exploits.iter().map(|r| &*r.name).collect::<Vec<_>>();
+        assert_eq!(names, vec!["first", "second"], "third trick should not run");
+        assert_eq!(report.exploits[1].status, Status::ExploitSuccess);
+
+        let report = run_tricks(tricks(), 1, false, false).await;
+        assert_eq!(report.exploits.len(), 3, "all tricks should run");
+    }
+
+    #[tokio::test]
+    async fn test_jobs() {
+        let cli = Cli::try_parse_from(["houdini", "run", "-j", "2", "trick.yaml"])
+            .expect("cli should parse");
+        match cli.subcmd {
+            Cmd::Run { jobs, .. } => assert_eq!(jobs, 2),
+            cmd => panic!("unexpected subcommand {:?}", cmd),
+        }
+        let cli = Cli::try_parse_from(["houdini", "run", "trick.yaml"]).expect("cli should parse");
+        match cli.subcmd {
+            Cmd::Run { jobs, .. } => {
+                assert_eq!(jobs, 1, "tricks should run one at a time by default")
+            }
+            cmd => panic!("unexpected subcommand {:?}", cmd),
+        }
+
+        let sleeper = |name: &str| -> Trick {
+            let yaml = format!(
+                r#"
+                name: {}
+                steps:
+                - host:
+                    script: ["sleep 1"]
+                    success: exploitSuccess
+                "#,
+                name
+            );
+            serde_yaml::from_str(&yaml).expect("trick should deserialize")
+        };
+        let tricks = || vec![sleeper("second"), sleeper("first")];
+
+        let start = std::time::Instant::now();
+        let report = run_tricks(tricks(), 2, false, false).await;
+        let elapsed = start.elapsed();
+        assert_valid_report(&report);
+        assert!(
+            elapsed < std::time::Duration::from_millis(1800),
+            "tricks should run concurrently, took {:?}",
+            elapsed
+        );
+        let names = report.exploits.iter().map(|r| &*r.name).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec!["first", "second"],
+            "report should be sorted by name"
+        );
+        assert!(report
+            .exploits
+            .iter()
+            .all(|r| r.status == Status::ExploitSuccess));
+
+        let report = run_tricks(tricks(), 1, false, false).await;
+        let names = report.exploits.iter().map(|r| &*r.name).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec!["second", "first"],
+            "order should be kept with -j 1"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_image_matrix() {
+        let cli = Cli::try_parse_from([
+            "houdini",
+            "run",
+            "--image-matrix",
+            "bash:5.1,bash:5.2",
+            "trick.yaml",
+        ])
+        .expect("cli should parse");
+        let images = match cli.subcmd {
+            Cmd::Run { image_matrix, .. } => image_matrix,
+            cmd => panic!("unexpected subcommand {:?}", cmd),
+        };
+        assert_eq!(images, vec!["bash:5.1", "bash:5.2"]);
+
+        let trick: Trick = serde_yaml::from_str(
+            r#"
+            name: matrix
+            steps:
+            - spawnContainer:
+                name: bash
+                image: bash
+            "#,
+        )
+        .expect("trick should deserialize");
+        let expanded = expand_image_matrix([trick], &images);
+        let expanded_images = expanded.iter().map(|t| t.images()[0].0).collect::<Vec<_>>();
+        assert_eq!(expanded_images, vec!["bash:5.1", "bash:5.2"]);
+
+        let tricks = expand_image_matrix([host_trick("foo", "true", "exploitSuccess")], &images);
+        let report = run_tricks(tricks, 1, false, false).await;
+        assert_valid_report(&report);
+        let labels = report
+            .exploits
+            .iter()
+            .map(|r| (&*r.name, r.matrix_image.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            labels,
+            vec![("foo", Some("bash:5.1")), ("foo", Some("bash:5.2"))]
+        );
+
+        let images = ["bash:5.2".to_owned(), "bash:5.1".to_owned()];
+        let tricks = expand_image_matrix(
+            [
+                host_trick("foo", "sleep 0.2", "exploitSuccess"),
+                host_trick("bar", "true", "exploitSuccess"),
+            ],
+            &images,
+        );
+        let report = run_tricks(tricks, 4, false, false).await;
+        let labels = report
+            .exploits
+            .iter()
+            .map(|r| (&*r.name, r.matrix_image.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            labels,
+            vec![
+                ("bar", Some("bash:5.1")),
+                ("bar", Some("bash:5.2")),
+                ("foo", Some("bash:5.1")),
+                ("foo", Some("bash:5.2")),
+            ],
+            "concurrent report should be sorted by name and image"
+        );
+
+        let tricks = expand_image_matrix([host_trick("foo", "true", "exploitSuccess")], &[]);
+        assert_eq!(tricks.len(), 1);
+        assert_eq!(tricks[0].matrix_image, None);
+    }
+
+    #[tokio::test]
+    async fn test_rerun_failed() {
+        let cli = Cli::try_parse_from(["houdini", "run", "--rerun-failed", "report.json"])
+            .expect("cli should parse without tricks");
+        match cli.subcmd {
+            Cmd::Run {
+                tricks,
+                rerun_failed,
+                ..
+            } => {
+                assert!(tricks.is_empty());
+                assert_eq!(rerun_failed, Some(PathBuf::from("report.json")));
+            }
+            cmd => panic!("unexpected subcommand {:?}", cmd),
+        }
+        assert!(Cli::try_parse_from(["houdini", "run"]).is_err());
+
+        let files = ["passed", "failed", "errored", "skipped"].map(write_trick);
+        let mut prior = Report::new();
+        for (file, status) in files.iter().zip([
+            Status::ExploitSuccess,
+            Status::ExploitFailure,
+            Status::HarnessError,
+            Status::Skip,
+        ]) {
+            let mut exploit = TrickReport::new("trick");
+            exploit.source_path = Some(file.path().to_owned());
+            exploit.set_status(status);
+            prior.add(exploit);
+        }
+        // Failed again over another image, and failed without a file to re-run
+        let mut exploit = TrickReport::new("trick");
+        exploit.source_path = Some(files[1].path().to_owned());
+        exploit.set_status(Status::SetupFailure);
+        prior.add(exploit);
+        let mut exploit = TrickReport::new("inline");
+        exploit.set_status(Status::ExploitFailure);
+        prior.add(exploit);
+
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let path = prior.write_to_disk(dir.path()).await.unwrap();
+        let failed = failed_tricks(&Report::read_from(&path).unwrap());
+        assert_eq!(failed, vec![files[1].path(), files[2].path()]);
+
+        let mut tricks = vec![];
+        for file in &failed {
+            tricks.push(load_trick(file, false, &HashMap::new()).await.unwrap());
+        }
+        let report = run_tricks(tricks, 1, false, false).await;
+        assert_valid_report(&report);
+        let names = report.exploits.iter().map(|r| &*r.name).collect::<Vec<_>>();
+        assert_eq!(names, vec!["failed", "errored"]);
+    }
+
+    #[tokio::test]
+    async fn test_source_path() {
+        let file = write_trick("foo");
+        let trick = load_trick(file.path(), false, &HashMap::new())
+            .await
+            .expect("trick should load");
+
+        let report = run_tricks([trick], 1, false, false).await;
+        assert_valid_report(&report);
+        assert_eq!(report.exploits[0].source_path.as_deref(), Some(file.path()));
+
+        let report = run_tricks(
+            [host_trick("bar", "true", "exploitSuccess")],
+            1,
+            false,
+            false,
+        )
+        .await;
+        assert_eq!(report.exploits[0].source_path, None);
+    }
+
+    #[tokio::test]
+    async fn test_image_summary() {
+        let yaml = r#"
+            name: images
+            steps:
+            - spawnContainer:
+                name: a
+                image: bash
+            - spawnContainer:
+                name: b
+                image: houdini-missing:1.0
+                imagePolicy: never
+            - spawnContainer:
+                name: c
+                image: bash
+            "#;
+        let trick: Trick = serde_yaml::from_str(yaml).expect("trick should deserialize");
+
+        let images = image_summary(&[trick], |image| async move { Ok(image == "bash") })
+            .await
+            .expect("summary should succeed");
+        assert_eq!(
+            images,
+            vec![
+                ImageSummary {
+                    image: "bash".into(),
+                    present: true,
+                    policy: "pull",
+                },
+                ImageSummary {
+                    image: "houdini-missing:1.0".into(),
+                    present: false,
+                    policy: "never",
+                },
+            ]
+        );
+
+        assert_eq!(
+            format_image_table(&images),
+            "IMAGE                PRESENT  POLICY\n\
+             bash                 yes      pull\n\
+             houdini-missing:1.0  no       never\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_estimate() {
+        let yaml = r#"
+            name: first
+            steps:
+            - spawnContainer:
+                name: a
+                image: bash
+            - spawnContainer:
+                name: b
+                image: houdini-missing:1.0
+            "#;
+        let first: Trick = serde_yaml::from_str(yaml).expect("trick should deserialize");
+        let yaml = r#"
+            name: second
+            steps:
+            - spawnContainer:
+                name: a
+                image: bash
+            - host:
+                script: ["true"]
+            - spawnContainer:
+                name: c
+                image: bash
+            "#;
+        let second: Trick = serde_yaml::from_str(yaml).expect("trick should deserialize");
+
+        let images = estimate(&[first, second], |image| async move {
+            Ok((image == "bash").then_some(12_500_000))
+        })
+        .await
+        .expect("estimate should succeed");
+        assert_eq!(
+            images,
+            vec![
+                ImageEstimate {
+                    image: "bash".into(),
+                    policy: "pull",
+                    spawns: 3,
+                    local_size: Some(12_500_000),
+                },
+                ImageEstimate {
+                    image: "houdini-missing:1.0".into(),
+                    policy: "pull",
+                    spawns: 1,
+                    local_size: None,
+                },
+            ]
+        );
+
+        assert_eq!(
+            format_estimate_table(&images),
+            "IMAGE                POLICY  SPAWNS  LOCAL SIZE\n\
+             bash                 pull    3       12.5 MB\n\
+             houdini-missing:1.0  pull    1       -\n\
+             4 container(s) from 2 image(s), 1 to pull, 12.5 MB present locally\n"
+        );
+    }
+
+    #[test]
+    fn test_find_trick_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("nested")).unwrap();
+        for file in ["b.yaml", "nested/a.yml", "README.md"] {
+            std::fs::write(dir.path().join(file), "").unwrap();
+        }
+
+        let files = find_trick_files(&[dir.path().to_owned(), "other.yaml".into()]);
+        assert_eq!(
+            files,
+            vec![
+                dir.path().join("b.yaml"),
+                dir.path().join("nested/a.yml"),
+                PathBuf::from("other.yaml"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_trick_params() {
+        let mut f = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            f,
+            r#"
+            name: templated
+            parameters:
+                tag: "5.2"
+            steps:
+            - spawnContainer:
+                name: bash
+                image: "bash:{{{{ tag }}}}"
+            "#
+        )
+        .unwrap();
+
+        let trick = load_trick(f.path(), false, &HashMap::new())
+            .await
+            .expect("trick should load");
+        assert_eq!(trick.images()[0].0, "bash:5.2");
+
+        let cli = Cli::try_parse_from([
+            "houdini",
+            "run",
+            "--param",
+            "tag=5.1",
+            &f.path().display().to_string(),
+        ])
+        .expect("cli should parse");
+        let params = match cli.subcmd {
+            Cmd::Run { params, .. } => params.into_iter().collect::<HashMap<_, _>>(),
+            cmd => panic!("unexpected subcommand {:?}", cmd),
+        };
+        let trick = load_trick(f.path(), false, &params)
+            .await
+            .expect("trick should load");
+        assert_eq!(trick.images()[0].0, "bash:5.1");
+
+        // Tricks without parameters are not rendered, so Go templates survive
+        let mut f = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            f,
+            r#"
+            name: untemplated
+            steps:
+            - host:
+                script: ["docker inspect -f '{{{{.State.Pid}}}}' {{{{ bash }}}}"]
+            "#
+        )
+        .unwrap();
+        load_trick(f.path(), false, &params)
+            .await
+            .expect("trick without parameters should load");
+    }
+
+    #[tokio::test]
+    async fn test_allow_remote() {
+        let url = Path::new("http://127.0.0.1:1/trick.yaml");
+        let err = load_trick(url, false, &HashMap::new())
+            .await
+            .expect_err("remote trick should require --allow-remote");
+        assert!(err.to_string().contains("--allow-remote"));
+
+        let err = load_trick(url, true, &HashMap::new())
+            .await
+            .expect_err("unreachable remote trick should fail");
+        assert!(format!("{:#}", err).contains("failed to fetch trick"));
+    }
+
+    #[tokio::test]
+    async fn test_find_duplicate_names() {
+        let files = [write_trick("foo"), write_trick("bar"), write_trick("foo")];
+
+        let mut tricks = vec![];
+        for f in &files {
+            let trick = load_trick(f.path(), false, &HashMap::new())
+                .await
+                .expect("trick should load");
+            tricks.push((f.path().to_owned(), trick));
+        }
+
+        let duplicates = find_duplicate_names(&tricks);
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(
+            duplicates.get("foo"),
+            Some(&vec![files[0].path(), files[2].path()])
+        );
+
+        let duplicates = find_duplicate_names(&tricks[..2]);
+        assert!(duplicates.is_empty());
+    }
+
+    #[test]
+    fn test_build_arg_override() {
+        let cli = Cli::try_parse_from([
+            "houdini",
+            "run",
+            "--build-arg",
+            "VERSION=2.0",
+            "--build-arg",
+            "EXTRA=yes",
+            "trick.yaml",
+        ])
+        .expect("cli should parse");
+        let build_args = match cli.subcmd {
+            Cmd::Run { build_args, .. } => build_args.into_iter().collect::<HashMap<_, _>>(),
+            cmd => panic!("unexpected subcommand {:?}", cmd),
+        };
+
+        let trick = |args: &str| -> Trick {
+            let yaml = format!(
+                r#"
+                name: build
+                steps:
+                - spawnContainer:
+                    name: foo
+                    image: foo
+                    imagePolicy:
+                        build:
+                            dockerfile: Dockerfile
+                            buildArgs: {}
+                "#,
+                args
+            );
+            serde_yaml::from_str(&yaml).expect("trick should deserialize")
+        };
+
+        let mut t = trick("{VERSION: '1.0', OTHER: kept}");
+        t.override_build_args(&build_args);
+        assert_eq!(t, trick("{VERSION: '2.0', OTHER: kept, EXTRA: 'yes'}"));
+
+        assert!(
+            Cli::try_parse_from(["houdini", "run", "--build-arg", "NOVALUE", "x.yaml"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_strict_skip() {
+        let mut report = Report::new();
+        report.add(TrickReport {
+            status: Status::ExploitFailure,
+            ..TrickReport::new("foo")
+        });
+        assert!(check_skipped(&report, true).is_ok());
+
+        report.add(TrickReport {
+            status: Status::Skip,
+            ..TrickReport::new("bar")
+        });
+        assert!(check_skipped(&report, false).is_ok());
+        assert!(check_skipped(&report, true).is_err());
+
+        let cli = Cli::parse_from(["houdini", "run", "foo.yaml", "--strict-skip"]);
+        assert!(matches!(
+            cli.subcmd,
+            Cmd::Run {
+                strict_skip: true,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_report_dir() {
+        let cli = Cli::try_parse_from(["houdini", "run", "trick.yaml"]).expect("cli should parse");
+        assert_eq!(cli.report_dir(), CONFIG.reports.dir);
+
+        let cli = Cli::try_parse_from(["houdini", "run", "--report-dir", "/tmp/ci", "trick.yaml"])
+            .expect("cli should parse");
+        assert_eq!(cli.report_dir(), Path::new("/tmp/ci"));
+    }
+}
@@ -8,15 +8,81 @@
 
 //! The logic used to configure Houdini.
 
-use anyhow::Result;
+use anyhow::{Context as _, Result};
+use arc_swap::ArcSwap;
 use directories::ProjectDirs;
 use lazy_static::lazy_static;
 use serde::Deserialize;
-use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use crate::docker::{BackendKind, Flavor};
 
 lazy_static! {
     /// The shared configuration object for Houdini.
     pub static ref CONFIG: Config = Config::new().expect("Failed to initialize config");
+
+    /// The subset of Houdini's configuration that can be reloaded at runtime. Initialized
+    /// from [`CONFIG`] and updated by [`reload`].
+    pub static ref RUNTIME: ArcSwap<RuntimeConfig> =
+        ArcSwap::from_pointee(RuntimeConfig::from(&*CONFIG));
+}
+
+/// Settings that can be hot-reloaded without restarting Houdini.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeConfig {
+    /// Log file verbosity.
+    pub log_level: LevelFilter,
+}
+
+impl From<&Config> for RuntimeConfig {
+    fn from(config: &Config) -> Self {
+        Self {
+            log_level: config.log.level,
+        }
+    }
+}
+
+/// Re-read Houdini's config file and swap in any settings that can be hot-reloaded.
+/// Settings that cannot be hot-reloaded are left untouched, and a warning is logged if
+/// they have changed.
+pub fn reload() -> Result<()> {
+    reload_from(get_config_file().as_deref())
+}
+
+/// Like [`reload`], but reads the config from a specific file.
+fn reload_from(file: Option<&Path>) -> Result<()> {
+    let config = Config::from_file(file).context("failed to reload config")?;
+
+    if config.api.socket != CONFIG.api.socket {
+        tracing::warn!(
+            old = ?&CONFIG.api.socket,
+            new = ?&config.api.socket,
+            "API socket path changed, restart required for this to take effect"
+        );
+    }
+    if config.docker.socket != CONFIG.docker.socket {
+        tracing::warn!(
+            old = ?&CONFIG.docker.socket,
+            new = ?&config.docker.socket,
+            "Docker socket path changed, restart required for this to take effect"
+        );
+    }
+    if config.log.file != CONFIG.log.file {
+        tracing::warn!(
+            old = ?&CONFIG.log.file,
+            new = ?&config.log.file,
+            "log file changed, restart required for this to take effect"
+        );
+    }
+
+    let runtime = RuntimeConfig::from(&config);
+    tracing::info!(config = ?&runtime, "reloaded runtime config");
+    RUNTIME.store(runtime.into());
+
+    Ok(())
 }
 
 /// The base level config for Houdini.
@@ -35,17 +101,70 @@ pub struct Config {
 
 /// Configuration specific to Docker.
 #[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase", deny_unknown_fields)]
+#[serde(from = "DockerConfigRepr")]
 pub struct DockerConfig {
-    /// Name of the Docker client binary.
+    /// Which engine serves the Docker API on `socket`: `docker` or `podman`. This
+    /// decides the default `socket` and `client`, and works around the engine's
+    /// quirks. Default is `docker`.
+    pub flavor: Flavor,
+    /// Name of the Docker client binary. Default depends on `flavor`.
     pub client: String,
     /// Name of the Docker daemon binary.
     pub daemon: String,
     /// Name of the container runtime binary.
     pub runtime: String,
-    /// Full path to the Docker socket.
-    #[serde(deserialize_with = "serde_helpers::expand_pathbuf")]
+    /// Full path to the Docker socket. Default depends on `flavor`.
     pub socket: PathBuf,
+    /// How to spawn, exec into, and kill containers. `cli` shells out to `client`
+    /// instead of using the Docker API.
+    pub backend: BackendKind,
+    /// Shell used to wrap container exec commands by default, e.g. `sh -lc`.
+    pub default_exec_shell: Option<String>,
+    /// Most image acquisitions, container spawns, and execs to issue to the daemon at
+    /// once, across every running trick. An exec only counts while it is created and
+    /// started, and execs through the `cli` backend do not count. Default is no limit.
+    pub max_concurrent_ops: Option<usize>,
+}
+
+/// [`DockerConfig`] as written in a config file, before the defaults that depend on
+/// `flavor` are filled in.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct DockerConfigRepr {
+    #[serde(default)]
+    flavor: Flavor,
+    #[serde(default)]
+    client: Option<String>,
+    daemon: String,
+    runtime: String,
+    #[serde(default)]
+    #[serde(deserialize_with = "serde_helpers::expand_option_pathbuf")]
+    socket: Option<PathBuf>,
+    #[serde(default)]
+    backend: BackendKind,
+    #[serde(default)]
+    default_exec_shell: Option<String>,
+    #[serde(default)]
+    max_concurrent_ops: Option<usize>,
+}
+
+impl From<DockerConfigRepr> for DockerConfig {
+    fn from(repr: DockerConfigRepr) -> Self {
+        Self {
+            client: repr
+                .client
+                .unwrap_or_else(|| repr.flavor.default_client().to_owned()),
+            socket: repr
+                .socket
+                .unwrap_or_else(|| repr.flavor.default_socket().to_owned()),
+            flavor: repr.flavor,
+            daemon: repr.daemon,
+            runtime: repr.runtime,
+            backend: repr.backend,
+            default_exec_shell: repr.default_exec_shell,
+            max_concurrent_ops: repr.max_concurrent_ops,
+        }
+    }
 }
 
 /// Configuration specific to Houdini's logger.
@@ -68,6 +187,18 @@ pub struct ReportConfig {
     /// Path to the exploit reports dir.
     #[serde(deserialize_with = "serde_helpers::expand_pathbuf")]
     pub dir: PathBuf,
+    /// Embed log messages at or above this level in each trick's report. Default is not
+    /// to embed logs.
+    #[serde(default)]
+    pub embed_logs: Option<LevelFilter>,
+    /// Name report files by the ID of the run that produced them, rather than by a hash
+    /// of their date. Default is false.
+    #[serde(default)]
+    pub name_by_run_id: bool,
+    /// Snapshot the host's enabled LSMs, the mounts of key paths, and whether user
+    /// namespaces are enabled into each trick's report. Default is false.
+    #[serde(default)]
+    pub host_state: bool,
 }
 
 /// Configuration specific to Houdini's API server.
@@ -77,26 +208,28 @@ pub struct HoudiniApiConfig {
     /// Path to the Houdini API Unix socket.
     #[serde(deserialize_with = "serde_helpers::expand_pathbuf")]
     pub socket: PathBuf,
+    /// Extra headers the API client sends with every request.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Bearer token that the server requires on mutating endpoints such as `/trick`, and
+    /// that the client sends. Default is no authentication.
+    #[serde(default)]
+    pub auth_token: Option<String>,
 }
 
 /// Level filter for logging.
-#[derive(Deserialize, Debug, Clone, Copy)]
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
 #[serde(rename_all = "camelCase", deny_unknown_fields)]
 #[allow(missing_docs)]
 pub enum LevelFilter {
     Trace,
     Debug,
+    #[default]
     Info,
     Warn,
     Error,
 }
 
-impl Default for LevelFilter {
-    fn default() -> Self {
-        LevelFilter::Info
-    }
-}
-
 impl From<LevelFilter> for tracing::metadata::LevelFilter {
     fn from(f: LevelFilter) -> Self {
         match f {
@@ -112,6 +245,11 @@ impl From<LevelFilter> for tracing::metadata::LevelFilter {
 impl Config {
     /// Construct a new Config.
     fn new() -> Result<Self> {
+        Self::from_file(get_config_file().as_deref())
+    }
+
+    /// Construct a new Config from the defaults, overridden by `config_file` if given.
+    fn from_file(config_file: Option<&Path>) -> Result<Self> {
         let builder = config::Config::builder();
 
         // Add defaults
@@ -120,7 +258,7 @@ impl Config {
             config::FileFormat::Toml,
         ));
         // Add config file if it exists
-        let builder = if let Some(config_file) = get_config_file() {
+        let builder = if let Some(config_file) = config_file {
             let config_file = config_file.to_string_lossy();
             tracing::info!(file = debug(&config_file), "Reading config file");
             builder.add_source(config::File::with_name(&config_file).required(false))
@@ -153,6 +291,73 @@ fn get_config_file() -> Option<PathBuf> {
         .and_then(|p| p.canonicalize().ok())
 }
 
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    #[serial_test::serial]
+    fn test_reload_log_level() {
+        let mut f = tempfile::Builder::new().suffix(".toml").tempfile().unwrap();
+        write!(f, "[log]\nlevel = \"trace\"\n").unwrap();
+
+        reload_from(Some(f.path())).expect("config should reload");
+        assert_eq!(RUNTIME.load().log_level, LevelFilter::Trace);
+        assert!(crate::logging::file_level_enabled(&tracing::Level::TRACE));
+
+        let mut f = tempfile::Builder::new().suffix(".toml").tempfile().unwrap();
+        write!(f, "[log]\nlevel = \"warn\"\n").unwrap();
+
+        reload_from(Some(f.path())).expect("config should reload");
+        assert_eq!(RUNTIME.load().log_level, LevelFilter::Warn);
+        assert!(!crate::logging::file_level_enabled(&tracing::Level::INFO));
+        assert!(crate::logging::file_level_enabled(&tracing::Level::WARN));
+
+        let mut f = tempfile::Builder::new().suffix(".toml").tempfile().unwrap();
+        write!(f, "[log]\nlevel = \"bogus\"\n").unwrap();
+
+        reload_from(Some(f.path())).expect_err("invalid config should not reload");
+        assert_eq!(
+            RUNTIME.load().log_level,
+            LevelFilter::Warn,
+            "failed reload should keep old settings"
+        );
+
+        RUNTIME.store(RuntimeConfig::from(&*CONFIG).into());
+    }
+
+    #[test]
+    fn test_docker_flavor_defaults() {
+        let config = |toml: &str| {
+            let mut f = tempfile::Builder::new().suffix(".toml").tempfile().unwrap();
+            write!(f, "{}", toml).unwrap();
+            Config::from_file(Some(f.path())).expect("config should load")
+        };
+
+        let docker = config("");
+        assert_eq!(docker.docker.flavor, Flavor::Docker);
+        assert_eq!(docker.docker.socket, Path::new("/var/run/docker.sock"));
+        assert_eq!(docker.docker.client, "docker");
+
+        let podman = config("[docker]\nflavor = \"podman\"\n");
+        assert_eq!(podman.docker.flavor, Flavor::Podman);
+        assert_eq!(podman.docker.socket, Path::new("/run/podman/podman.sock"));
+        assert_eq!(podman.docker.client, "podman");
+
+        let podman = config(
+            "[docker]\nflavor = \"podman\"\nsocket = \"/run/user/1000/podman/podman.sock\"\n\
+             client = \"/usr/local/bin/podman\"\n",
+        );
+        assert_eq!(
+            podman.docker.socket,
+            Path::new("/run/user/1000/podman/podman.sock")
+        );
+        assert_eq!(podman.docker.client, "/usr/local/bin/podman");
+    }
+}
+
 mod serde_helpers {
     use serde::{Deserialize, Deserializer};
     use std::path::PathBuf;
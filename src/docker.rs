@@ -7,9 +7,30 @@
 
 //! Houdini's interaction with the Docker API.
 
+pub mod context;
+pub mod lint;
+
+mod backend;
 mod container;
+mod flavor;
 mod image;
+mod limit;
+mod network;
 mod util;
+mod volume;
 
-pub use container::{kill_container, reap_container, run_command, spawn_container};
-pub use image::ImagePullPolicy;
+pub use backend::BackendKind;
+pub use container::{
+    assert_clean, commit_container, copy_from_container, copy_to_container, kill_container,
+    reap_container, run_command, spawn_container, CgroupParent, ExecOpts, ExecResult, HostMount,
+    SpawnOpts, Ulimit, UlimitName, UsernsMode,
+};
+pub use flavor::Flavor;
+pub use image::{
+    image_present, image_provenance, image_size, ImageInfo, ImageProvenance, ImagePullPolicy,
+    Platform,
+};
+pub use limit::limit_daemon_ops;
+pub use network::{remove_network, NetworkOpts};
+pub use util::check_daemon;
+pub use volume::{named_volume, remove_volume};
@@ -0,0 +1,64 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+
+//! Backends that perform container operations on Houdini's behalf. By default, Houdini
+//! talks to the Docker API directly, but it can instead shell out to the Docker client
+//! binary, which works better in some environments (e.g. rootless Docker).
+
+mod api;
+mod cli;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use super::{
+    container::{ExecOutput, SpawnOpts},
+    ExecOpts, NetworkOpts,
+};
+use crate::CONFIG;
+
+/// Which backend to use for container operations.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub enum BackendKind {
+    /// Use the Docker API over the configured socket.
+    #[default]
+    Api,
+    /// Shell out to the configured Docker client binary.
+    Cli,
+}
+
+/// Operations that a container backend must support.
+#[async_trait]
+pub(super) trait DockerBackend: Send + Sync {
+    /// Create and start a new container. The image must already be present.
+    async fn spawn(&self, opts: &SpawnOpts<'_>) -> Result<()>;
+
+    /// Run `argv` in a running container and wait for it to complete. The command and
+    /// arguments in `opts` are ignored in favour of `argv`.
+    async fn exec(&self, argv: &[&str], opts: &ExecOpts<'_>) -> Result<ExecOutput>;
+
+    /// Kill a running container.
+    async fn kill(&self, name: &str) -> Result<()>;
+
+    /// Create a network, unless one with the same name already exists. The network is
+    /// labelled as managed by Houdini.
+    async fn create_network(&self, network: &NetworkOpts) -> Result<()>;
+
+    /// Create a named volume, unless one with the same name already exists. The volume is
+    /// labelled as managed by Houdini.
+    async fn create_volume(&self, name: &str) -> Result<()>;
+}
+
+/// Get the container backend selected by `docker.backend` in Houdini's config.
+pub(super) fn backend() -> Box<dyn DockerBackend> {
+    match CONFIG.docker.backend {
+        BackendKind::Api => Box::new(api::ApiBackend),
+        BackendKind::Cli => Box::new(cli::CliBackend::new(&CONFIG.docker.client)),
+    }
+}
@@ -0,0 +1,258 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+
+//! A container backend that uses the Docker API.
+
+use std::time::Duration;
+
+use anyhow::{Context as _, Result};
+use async_trait::async_trait;
+use bollard::{
+    container::{Config, CreateContainerOptions},
+    exec::{CreateExecOptions, StartExecOptions, StartExecResults},
+    models::{ExecInspectResponse, HostConfig, ResourcesUlimits},
+    network::CreateNetworkOptions,
+    volume::CreateVolumeOptions,
+    Docker,
+};
+use futures::StreamExt;
+use tokio::time::Instant;
+
+use super::DockerBackend;
+use crate::{
+    docker::{
+        container::{ExecOutput, SpawnOpts},
+        limit::limited,
+        network::MTU_OPTION,
+        util::{client, MANAGED_LABEL},
+        ExecOpts, ExecResult, NetworkOpts,
+    },
+    CONFIG,
+};
+
+/// How often to check whether an exec has exited, while waiting for it to.
+const EXEC_EXIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Performs container operations using the Docker API.
+pub(super) struct ApiBackend;
+
+#[async_trait]
+impl DockerBackend for ApiBackend {
+    async fn spawn(&self, opts: &SpawnOpts<'_>) -> Result<()> {
+        let name = opts.name;
+
+        let client = client()?;
+
+        let host_config = HostConfig {
+            binds: Some(opts.volumes.to_owned()),
+            auto_remove: Some(opts.auto_remove),
+            security_opt: Some(opts.security_options.to_owned()),
+            // mounts: todo!(),
+            // cap_add: todo!(),
+            // cap_drop: todo!(),
+            privileged: Some(opts.privileged),
+            // publish_all_ports: todo!(),
+            network_mode: opts.network.map(|network| network.name.to_owned()),
+            dns: opts.network.map(|network| network.dns.to_owned()),
+            cgroup_parent: opts.cgroup_parent.map(|parent| parent.as_str().to_owned()),
+            ulimits: Some(
+                opts.ulimits
+                    .iter()
+                    .map(|ulimit| ResourcesUlimits {
+                        name: Some(ulimit.name.to_string()),
+                        soft: Some(ulimit.soft),
+                        hard: Some(ulimit.hard),
+                    })
+                    .collect(),
+            ),
+            userns_mode: opts
+                .userns_mode
+                .map(|mode| CONFIG.docker.flavor.userns_mode(mode).to_owned()),
+            ..Default::default()
+        };
+        let config = Config {
+            env: Some(opts.env.iter().map(String::as_str).collect()),
+            cmd: opts.cmd.map(|cmd| cmd.split_whitespace().collect()),
+            image: Some(opts.image),
+            // working_dir: todo!(),
+            // entrypoint: todo!(),
+            labels: Some([(MANAGED_LABEL, "true")].into()),
+            // shell: todo!(),
+            host_config: Some(host_config),
+            // networking_config: todo!(),
+            ..Default::default()
+        };
+
+        client
+            .create_container(Some(CreateContainerOptions { name }), config)
+            .await
+            .context("failed to create container")?;
+
+        client
+            .start_container::<&str>(name, None)
+            .await
+            .context("failed to start container")
+    }
+
+    async fn exec(&self, argv: &[&str], opts: &ExecOpts<'_>) -> Result<ExecOutput> {
+        let name = opts.name;
+        let client = client()?;
+
+        let opts = CreateExecOptions {
+            attach_stdin: Some(false),
+            attach_stdout: Some(true),
+            attach_stderr: Some(true),
+            tty: Some(opts.tty),
+            cmd: Some(argv.to_vec()),
+            env: Some(opts.env.iter().map(|e| e.as_str()).collect()),
+            detach_keys: opts.detach_keys,
+            privileged: Some(opts.privileged),
+            ..Default::default()
+        };
+
+        // Only creating and starting the exec count towards the daemon operation limit,
+        // so that long-running commands do not hold up other steps' execs
+        let (exec, results) = limited(async {
+            let exec = client
+                .create_exec(name, opts)
+                .await
+                .context("failed to create exec object")?
+                .id;
+
+            let opts = StartExecOptions {
+                detach: false,
+                ..Default::default()
+            };
+
+            let results = client
+                .start_exec(&exec, Some(opts))
+                .await
+                .context("failed to start exec")?;
+            anyhow::Ok((exec, results))
+        })
+        .await?;
+
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+
+        match results {
+            StartExecResults::Attached { mut output, .. } => {
+                while let Some(Ok(output)) = output.next().await {
+                    match output {
+                        bollard::container::LogOutput::StdErr { message } => {
+                            stderr.append(&mut message.iter().cloned().collect())
+                        }
+                        bollard::container::LogOutput::StdOut { message } => {
+                            stdout.append(&mut message.iter().cloned().collect())
+                        }
+                        _ => continue,
+                    }
+                }
+            }
+            StartExecResults::Detached => unreachable!(),
+        }
+
+        let inspect = inspect_exited_exec(&client, &exec).await?;
+
+        // The container state tells us whether the kernel OOM killed something inside it
+        let state = match client.inspect_container(name, None).await {
+            Ok(container) => container.state,
+            Err(e) => {
+                tracing::warn!(err = ?e, name = ?name, "failed to inspect container after exec");
+                None
+            }
+        };
+
+        Ok(ExecOutput {
+            result: ExecResult::from_inspect(&inspect, state.as_ref()),
+            stdout,
+            stderr,
+        })
+    }
+
+    async fn kill(&self, name: &str) -> Result<()> {
+        let client = client()?;
+
+        client
+            .kill_container::<&str>(name, None)
+            .await
+            .context("failed to kill container")
+    }
+
+    async fn create_network(&self, network: &NetworkOpts) -> Result<()> {
+        let client = client()?;
+
+        if client
+            .inspect_network::<&str>(&network.name, None)
+            .await
+            .is_ok()
+        {
+            tracing::debug!(name = ?network.name, "network already exists");
+            return Ok(());
+        }
+
+        let mtu = network.mtu.map(|mtu| mtu.to_string());
+        let opts = CreateNetworkOptions {
+            name: network.name.as_str(),
+            check_duplicate: true,
+            driver: "bridge",
+            options: mtu
+                .as_deref()
+                .map(|mtu| (MTU_OPTION, mtu))
+                .into_iter()
+                .collect(),
+            labels: [(MANAGED_LABEL, "true")].into(),
+            ..Default::default()
+        };
+        client
+            .create_network(opts)
+            .await
+            .context("failed to create network")?;
+
+        Ok(())
+    }
+
+    async fn create_volume(&self, name: &str) -> Result<()> {
+        let client = client()?;
+
+        if client.inspect_volume(name).await.is_ok() {
+            tracing::debug!(name = ?name, "volume already exists");
+            return Ok(());
+        }
+
+        let opts = CreateVolumeOptions {
+            name,
+            labels: [(MANAGED_LABEL, "true")].into(),
+            ..Default::default()
+        };
+        client
+            .create_volume(opts)
+            .await
+            .context("failed to create volume")?;
+
+        Ok(())
+    }
+}
+
+/// Inspect an exec whose output has closed. If the engine is known to report such execs
+/// as running for a while, wait for it to report that the exec exited, up to a grace
+/// period, so that its exit code is known.
+async fn inspect_exited_exec(client: &Docker, exec: &str) -> Result<ExecInspectResponse> {
+    let grace = CONFIG.docker.flavor.exec_exit_grace().unwrap_or_default();
+    let deadline = Instant::now() + grace;
+    loop {
+        let inspect = client
+            .inspect_exec(exec)
+            .await
+            .context("failed to inspect exec result")?;
+        if inspect.running != Some(true) || Instant::now() >= deadline {
+            return Ok(inspect);
+        }
+        tracing::debug!(exec = ?exec, "exec still running after its output closed");
+        tokio::time::sleep(EXEC_EXIT_POLL_INTERVAL).await;
+    }
+}
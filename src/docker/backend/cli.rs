@@ -0,0 +1,487 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+
+//! A container backend that shells out to the Docker client binary.
+
+use std::process::{Output, Stdio};
+
+use anyhow::{Context as _, Result};
+use async_trait::async_trait;
+use tokio::process::Command;
+
+use super::DockerBackend;
+use crate::{
+    docker::{
+        container::{signal_from_code, ExecOutput, SpawnOpts},
+        network::MTU_OPTION,
+        util::MANAGED_LABEL,
+        ExecOpts, ExecResult, NetworkOpts,
+    },
+    CONFIG,
+};
+
+/// Performs container operations by running the Docker client binary.
+pub(super) struct CliBackend {
+    /// Name or path of the Docker client binary.
+    client: String,
+}
+
+impl CliBackend {
+    pub fn new(client: &str) -> Self {
+        Self {
+            client: client.to_owned(),
+        }
+    }
+
+    /// Run the client with `args`, capturing its output.
+    async fn output(&self, args: &[String]) -> Result<Output> {
+        tracing::debug!(client = ?self.client, args = ?args, "running docker client");
+
+        Command::new(&self.client)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .output()
+            .await
+            .context(format!("failed to run docker client {}", self.client))
+    }
+
+    /// Run the client with `args`, failing if it exits unsuccessfully.
+    async fn run(&self, args: &[String]) -> Result<()> {
+        let out = self.output(args).await?;
+        if !out.status.success() {
+            anyhow::bail!(
+                "docker client failed with {}: {}",
+                out.status,
+                String::from_utf8_lossy(&out.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl DockerBackend for CliBackend {
+    async fn spawn(&self, opts: &SpawnOpts<'_>) -> Result<()> {
+        self.run(&spawn_args(opts))
+            .await
+            .context("failed to spawn container")
+    }
+
+    async fn exec(&self, argv: &[&str], opts: &ExecOpts<'_>) -> Result<ExecOutput> {
+        let name = opts.name;
+        let out = self
+            .output(&exec_args(argv, opts))
+            .await
+            .context("failed to exec in container")?;
+        if let Some(err) = client_exec_error(out.status.code(), &out.stderr) {
+            anyhow::bail!("failed to exec in container: {}", err);
+        }
+
+        // Unlike the API, the client folds its own failures into the exit code, so ask
+        // for the container's state separately
+        let oom_killed = match self.output(&oom_killed_args(name)).await {
+            Ok(inspect) if inspect.status.success() => {
+                String::from_utf8_lossy(&inspect.stdout).trim() == "true"
+            }
+            res => {
+                tracing::warn!(res = ?res, name = ?name, "failed to inspect container after exec");
+                false
+            }
+        };
+
+        let code = out.status.code().map(i64::from);
+        Ok(ExecOutput {
+            result: ExecResult {
+                code,
+                signal: code.and_then(signal_from_code),
+                oom_killed,
+            },
+            stdout: out.stdout,
+            stderr: out.stderr,
+        })
+    }
+
+    async fn kill(&self, name: &str) -> Result<()> {
+        self.run(&kill_args(name))
+            .await
+            .context("failed to kill container")
+    }
+
+    async fn create_network(&self, network: &NetworkOpts) -> Result<()> {
+        let inspect = self
+            .output(&["network".into(), "inspect".into(), network.name.to_owned()])
+            .await?;
+        if inspect.status.success() {
+            tracing::debug!(name = ?network.name, "network already exists");
+            return Ok(());
+        }
+
+        self.run(&network_create_args(network))
+            .await
+            .context("failed to create network")
+    }
+
+    async fn create_volume(&self, name: &str) -> Result<()> {
+        let inspect = self
+            .output(&["volume".into(), "inspect".into(), name.to_owned()])
+            .await?;
+        if inspect.status.success() {
+            tracing::debug!(name = ?name, "volume already exists");
+            return Ok(());
+        }
+
+        self.run(&volume_create_args(name))
+            .await
+            .context("failed to create volume")
+    }
+}
+
+/// Client arguments for spawning a detached container.
+fn spawn_args(opts: &SpawnOpts<'_>) -> Vec<String> {
+    let mut args = vec!["run".to_owned(), "--detach".to_owned()];
+    args.extend(["--name".to_owned(), opts.name.to_owned()]);
+    args.extend(["--label".to_owned(), format!("{}=true", MANAGED_LABEL)]);
+    if opts.auto_remove {
+        args.push("--rm".into());
+    }
+    if opts.privileged {
+        args.push("--privileged".into());
+    }
+    for volume in opts.volumes {
+        args.extend(["--volume".to_owned(), volume.to_owned()]);
+    }
+    for security in opts.security_options {
+        args.extend(["--security-opt".to_owned(), security.to_owned()]);
+    }
+    for env in opts.env {
+        args.extend(["--env".to_owned(), env.to_owned()]);
+    }
+    if let Some(parent) = opts.cgroup_parent {
+        args.extend(["--cgroup-parent".to_owned(), parent.as_str().to_owned()]);
+    }
+    for ulimit in opts.ulimits {
+        args.extend([
+            "--ulimit".to_owned(),
+            format!("{}={}:{}", ulimit.name, ulimit.soft, ulimit.hard),
+        ]);
+    }
+    if let Some(mode) = opts.userns_mode {
+        let mode = CONFIG.docker.flavor.userns_mode(mode);
+        if !mode.is_empty() {
+            args.extend(["--userns".to_owned(), mode.to_owned()]);
+        }
+    }
+    if let Some(network) = opts.network {
+        args.extend(["--network".to_owned(), network.name.to_owned()]);
+        for dns in &network.dns {
+            args.extend(["--dns".to_owned(), dns.to_owned()]);
+        }
+    }
+    args.push(opts.image.to_owned());
+    args.extend(
+        opts.cmd
+            .into_iter()
+            .flat_map(str::split_whitespace)
+            .map(ToOwned::to_owned),
+    );
+    args
+}
+
+/// Client arguments for running a command in a container.
+fn exec_args(argv: &[&str], opts: &ExecOpts<'_>) -> Vec<String> {
+    let mut args = vec!["exec".to_owned()];
+    if opts.privileged {
+        args.push("--privileged".into());
+    }
+    if opts.tty {
+        args.push("--tty".into());
+    }
+    for env in opts.env {
+        args.extend(["--env".to_owned(), env.to_owned()]);
+    }
+    if let Some(keys) = opts.detach_keys {
+        args.extend(["--detach-keys".to_owned(), keys.to_owned()]);
+    }
+    args.push(opts.name.to_owned());
+    args.extend(argv.iter().map(|a| (*a).to_owned()));
+    args
+}
+
+/// Prefixes of the messages that the client and the runtime write to stderr when they,
+/// rather than the command, fail an exec.
+const CLIENT_ERROR_PREFIXES: &[&str] = &[
+    "Error response from daemon:",
+    "OCI runtime exec failed",
+    "Error:",
+];
+
+/// The error that the client failed an exec with, if the exit code `code` and `stderr`
+/// of `docker exec` show that it was the client's rather than the command's. The client
+/// exits with 125 when it fails itself, and with 126 or 127 when the command cannot be
+/// run, which commands may also exit with, so those also need one of the client's
+/// messages.
+fn client_exec_error(code: Option<i32>, stderr: &[u8]) -> Option<String> {
+    let stderr = String::from_utf8_lossy(stderr).trim().to_owned();
+    let from_client = CLIENT_ERROR_PREFIXES
+        .iter()
+        .any(|prefix| stderr.starts_with(prefix));
+    match code {
+        Some(125) => Some(stderr),
+        Some(126 | 127) if from_client => Some(stderr),
+        _ => None,
+    }
+}
+
+/// Client arguments for checking whether a container was OOM killed.
+fn oom_killed_args(name: &str) -> Vec<String> {
+    vec![
+        "inspect".into(),
+        "--format".into(),
+        "{{.State.OOMKilled}}".into(),
+        name.into(),
+    ]
+}
+
+/// Client arguments for creating a network.
+fn network_create_args(network: &NetworkOpts) -> Vec<String> {
+    let mut args = vec!["network".to_owned(), "create".to_owned()];
+    args.extend(["--label".to_owned(), format!("{}=true", MANAGED_LABEL)]);
+    if let Some(mtu) = network.mtu {
+        args.extend(["--opt".to_owned(), format!("{}={}", MTU_OPTION, mtu)]);
+    }
+    args.push(network.name.to_owned());
+    args
+}
+
+/// Client arguments for creating a volume.
+fn volume_create_args(name: &str) -> Vec<String> {
+    vec![
+        "volume".into(),
+        "create".into(),
+        "--label".into(),
+        format!("{}=true", MANAGED_LABEL),
+        name.into(),
+    ]
+}
+
+/// Client arguments for killing a container.
+fn kill_args(name: &str) -> Vec<String> {
+    vec!["kill".into(), name.into()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::docker::{CgroupParent, ImagePullPolicy, Ulimit, UlimitName, UsernsMode};
+
+    #[test]
+    fn test_spawn_args() {
+        let policy = ImagePullPolicy::Never;
+        let parent = CgroupParent::try_from("/houdini".to_owned()).unwrap();
+        let network = NetworkOpts {
+            name: "houdini-net".into(),
+            dns: vec!["1.1.1.1".into()],
+            mtu: Some(1400),
+            external: false,
+        };
+        let opts = SpawnOpts {
+            name: "foo",
+            image: "bash",
+            image_policy: &policy,
+            cmd: Some("sleep infinity"),
+            volumes: &["/tmp:/host/tmp:ro".into()],
+            privileged: true,
+            security_options: &["apparmor=unconfined".into()],
+            auto_remove: true,
+            network: Some(&network),
+            env: &["LD_PRELOAD=/tmp/evil.so".into()],
+            cgroup_parent: Some(&parent),
+            ulimits: &[Ulimit {
+                name: UlimitName::Nofile,
+                soft: 1024,
+                hard: 2048,
+            }],
+            userns_mode: Some(UsernsMode::Host),
+        };
+        assert_eq!(
+            spawn_args(&opts),
+            vec![
+                "run",
+                "--detach",
+                "--name",
+                "foo",
+                "--label",
+                "houdini.managed=true",
+                "--rm",
+                "--privileged",
+                "--volume",
+                "/tmp:/host/tmp:ro",
+                "--security-opt",
+                "apparmor=unconfined",
+                "--env",
+                "LD_PRELOAD=/tmp/evil.so",
+                "--cgroup-parent",
+                "/houdini",
+                "--ulimit",
+                "nofile=1024:2048",
+                "--userns",
+                "host",
+                "--network",
+                "houdini-net",
+                "--dns",
+                "1.1.1.1",
+                "bash",
+                "sleep",
+                "infinity",
+            ]
+        );
+
+        let opts = SpawnOpts {
+            cmd: None,
+            volumes: &[],
+            privileged: false,
+            security_options: &[],
+            auto_remove: false,
+            network: None,
+            env: &[],
+            cgroup_parent: None,
+            ulimits: &[],
+            userns_mode: None,
+            ..opts
+        };
+        assert_eq!(
+            spawn_args(&opts),
+            vec![
+                "run",
+                "--detach",
+                "--name",
+                "foo",
+                "--label",
+                "houdini.managed=true",
+                "bash"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_exec_args() {
+        let opts = ExecOpts {
+            name: "foo",
+            cmd: "",
+            args: &[],
+            privileged: true,
+            tty: true,
+            shell: None,
+            wrapper: &[],
+            env: &["FOO=bar baz".into()],
+            detach_keys: Some("ctrl-x"),
+        };
+        assert_eq!(
+            exec_args(&["sh", "-c", "echo hi"], &opts),
+            vec![
+                "exec",
+                "--privileged",
+                "--tty",
+                "--env",
+                "FOO=bar baz",
+                "--detach-keys",
+                "ctrl-x",
+                "foo",
+                "sh",
+                "-c",
+                "echo hi"
+            ]
+        );
+
+        let opts = ExecOpts {
+            privileged: false,
+            tty: false,
+            env: &[],
+            detach_keys: None,
+            ..opts
+        };
+        assert_eq!(exec_args(&["true"], &opts), vec!["exec", "foo", "true"]);
+    }
+
+    #[test]
+    fn test_network_create_args() {
+        let network = NetworkOpts {
+            name: "houdini-net".into(),
+            dns: vec![],
+            mtu: Some(1400),
+            external: false,
+        };
+        assert_eq!(
+            network_create_args(&network),
+            vec![
+                "network",
+                "create",
+                "--label",
+                "houdini.managed=true",
+                "--opt",
+                "com.docker.network.driver.mtu=1400",
+                "houdini-net"
+            ]
+        );
+
+        let network = NetworkOpts {
+            mtu: None,
+            ..network
+        };
+        assert_eq!(
+            network_create_args(&network),
+            vec![
+                "network",
+                "create",
+                "--label",
+                "houdini.managed=true",
+                "houdini-net"
+            ]
+        );
+        assert_eq!(
+            volume_create_args("houdini-data"),
+            vec![
+                "volume",
+                "create",
+                "--label",
+                "houdini.managed=true",
+                "houdini-data"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_client_exec_error() {
+        let not_found = b"OCI runtime exec failed: exec failed: unable to start container \
+            process: exec: \"nope\": executable file not found in $PATH: unknown\n";
+        assert!(client_exec_error(Some(127), not_found).is_some());
+        assert!(client_exec_error(Some(126), b"Error: crun: nope: Permission denied\n").is_some());
+        assert!(client_exec_error(
+            Some(125),
+            b"unknown flag: --bogus\nSee 'docker exec --help'.\n"
+        )
+        .is_some());
+
+        // The command's own exit codes are left to the caller
+        assert_eq!(client_exec_error(Some(127), b"sh: nope: not found\n"), None);
+        assert_eq!(client_exec_error(Some(126), b""), None);
+        assert_eq!(client_exec_error(Some(1), not_found), None);
+        assert_eq!(client_exec_error(Some(0), b""), None);
+        assert_eq!(client_exec_error(None, b""), None);
+    }
+
+    #[test]
+    fn test_kill_args() {
+        assert_eq!(kill_args("foo"), vec!["kill", "foo"]);
+        assert_eq!(
+            oom_killed_args("foo"),
+            vec!["inspect", "--format", "{{.State.OOMKilled}}", "foo"]
+        );
+    }
+}
@@ -9,14 +9,193 @@
 
 use anyhow::{Context as _, Result};
 use bollard::{
-    container::{Config, CreateContainerOptions, RemoveContainerOptions, WaitContainerOptions},
-    exec::{CreateExecOptions, StartExecOptions, StartExecResults},
-    models::HostConfig,
+    container::{
+        Config, DownloadFromContainerOptions, ListContainersOptions, RemoveContainerOptions,
+        UploadToContainerOptions, WaitContainerOptions,
+    },
+    image::CommitContainerOptions,
+    models::{ContainerState, ExecInspectResponse},
 };
 use futures::StreamExt;
-use std::ops::Deref;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{fmt::Display, future::Future, io::Read as _, path::Path};
 
-use super::{util::client, ImagePullPolicy};
+use super::{
+    backend::backend,
+    image::{image_present, is_image_corrupt},
+    limit::limited,
+    named_volume,
+    util::{client, MANAGED_LABEL},
+    ImageInfo, ImagePullPolicy, NetworkOpts,
+};
+use crate::{error::SetupError, CONFIG};
+
+/// Well-known host paths that can be bind mounted into a container.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub enum HostMount {
+    /// The host's `/proc/sysrq-trigger`, mounted read-only at `/host/proc/sysrq-trigger`.
+    ProcSysrq,
+    /// The host's cgroup filesystem, mounted read-only at `/host/sys/fs/cgroup`.
+    CgroupFs,
+    /// The configured Docker socket, mounted read-only at `/var/run/docker.sock`.
+    /// Note that a read-only bind does not prevent connecting to the socket.
+    DockerSock,
+}
+
+impl HostMount {
+    /// Expand the mount into a Docker bind spec.
+    pub fn bind(&self) -> String {
+        match self {
+            HostMount::ProcSysrq => "/proc/sysrq-trigger:/host/proc/sysrq-trigger:ro".into(),
+            HostMount::CgroupFs => "/sys/fs/cgroup:/host/sys/fs/cgroup:ro".into(),
+            HostMount::DockerSock => {
+                format!("{}:/var/run/docker.sock:ro", CONFIG.docker.socket.display())
+            }
+        }
+    }
+}
+
+/// A cgroup to place a container under, either a cgroupfs path such as `/houdini` or,
+/// with the systemd cgroup driver, a slice such as `houdini.slice`.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone)]
+#[serde(try_from = "String", into = "String")]
+pub struct CgroupParent(String);
+
+impl CgroupParent {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl TryFrom<String> for CgroupParent {
+    type Error = String;
+
+    fn try_from(parent: String) -> Result<Self, Self::Error> {
+        if parent.trim_matches('/').is_empty() {
+            return Err("cgroup parent must not be empty".into());
+        }
+        if let Some(c) = parent
+            .chars()
+            .find(|c| !(c.is_ascii_alphanumeric() || "/-_.@:".contains(*c)))
+        {
+            return Err(format!(
+                "invalid character {:?} in cgroup parent {}",
+                c, parent
+            ));
+        }
+        if parent.split('/').any(|part| part == "." || part == "..") {
+            return Err(format!(
+                "cgroup parent {} must not contain `.` or `..` components",
+                parent
+            ));
+        }
+        if parent.ends_with(".slice") && parent.contains('/') {
+            return Err(format!(
+                "cgroup parent {} must be either a path or a systemd slice name",
+                parent
+            ));
+        }
+        Ok(Self(parent))
+    }
+}
+
+impl From<CgroupParent> for String {
+    fn from(parent: CgroupParent) -> Self {
+        parent.0
+    }
+}
+
+/// A resource limit to set in a container, as with `ulimit`.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Ulimit {
+    /// The resource to limit, e.g. `nofile`.
+    pub name: UlimitName,
+    /// Limit that processes may raise up to the hard limit. `-1` is unlimited.
+    pub soft: i64,
+    /// Limit that only privileged processes may raise. `-1` is unlimited.
+    pub hard: i64,
+}
+
+impl Ulimit {
+    /// Check that the soft limit does not exceed the hard limit.
+    pub fn validate(&self) -> Result<()> {
+        let unlimited = |limit: i64| limit < 0;
+        if unlimited(self.hard) || (!unlimited(self.soft) && self.soft <= self.hard) {
+            return Ok(());
+        }
+        anyhow::bail!(
+            "soft {} limit {} exceeds hard limit {}",
+            self.name,
+            self.soft,
+            self.hard
+        )
+    }
+}
+
+/// Resources that Docker can set ulimits for.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum UlimitName {
+    Core,
+    Cpu,
+    Data,
+    Fsize,
+    Locks,
+    Memlock,
+    Msgqueue,
+    Nice,
+    Nofile,
+    Nproc,
+    Rss,
+    Rtprio,
+    Rttime,
+    Sigpending,
+    Stack,
+}
+
+impl UlimitName {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UlimitName::Core => "core",
+            UlimitName::Cpu => "cpu",
+            UlimitName::Data => "data",
+            UlimitName::Fsize => "fsize",
+            UlimitName::Locks => "locks",
+            UlimitName::Memlock => "memlock",
+            UlimitName::Msgqueue => "msgqueue",
+            UlimitName::Nice => "nice",
+            UlimitName::Nofile => "nofile",
+            UlimitName::Nproc => "nproc",
+            UlimitName::Rss => "rss",
+            UlimitName::Rtprio => "rtprio",
+            UlimitName::Rttime => "rttime",
+            UlimitName::Sigpending => "sigpending",
+            UlimitName::Stack => "stack",
+        }
+    }
+}
+
+impl Display for UlimitName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// User namespace to run a container in.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum UsernsMode {
+    /// Share the host's user namespace, even if the daemon remaps users.
+    Host,
+    /// A user namespace of the container's own, remapped by the daemon.
+    Private,
+    /// Whatever the daemon does by default. Written as an empty string.
+    #[serde(rename = "")]
+    Default,
+}
 
 /// Clean up a container by removing it and waiting for it.
 pub async fn reap_container(name: &str) -> Result<()> {
@@ -27,7 +206,14 @@ pub async fn reap_container(name: &str) -> Result<()> {
         force: true,
         link: false,
     };
-    client.remove_container(name, Some(opts)).await?;
+    match client.remove_container(name, Some(opts)).await {
+        // The container was auto-removed or never created, so there is nothing to reap
+        Err(e) if is_not_found(&e) => {
+            tracing::debug!(name = ?name, "container already removed");
+            return Ok(());
+        }
+        res => res?,
+    }
 
     let opts = WaitContainerOptions {
         condition: "removed",
@@ -42,170 +228,867 @@ pub async fn reap_container(name: &str) -> Result<()> {
     Ok(())
 }
 
-/// Spawn a new container.
-pub async fn spawn_container(
-    name: &str,
+/// Fail if any container that Houdini spawned still exists, listing their names. This is
+/// meant to be run after cleanup, to catch containers that were leaked.
+pub async fn assert_clean() -> Result<()> {
+    let client = client()?;
+
+    let opts = ListContainersOptions {
+        all: true,
+        filters: [("label", vec![MANAGED_LABEL])].into(),
+        ..Default::default()
+    };
+    let mut leftover = client
+        .list_containers(Some(opts))
+        .await
+        .context("failed to list containers")?
+        .into_iter()
+        .flat_map(|c| c.names.unwrap_or_default())
+        .map(|name| name.trim_start_matches('/').to_owned())
+        .collect::<Vec<_>>();
+    leftover.sort();
+
+    if !leftover.is_empty() {
+        anyhow::bail!(
+            "containers left behind after cleanup: {}",
+            leftover.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+/// Is this error Docker telling us that a container does not exist?
+pub(super) fn is_not_found(err: &bollard::errors::Error) -> bool {
+    matches!(
+        err,
+        bollard::errors::Error::DockerResponseServerError {
+            status_code: 404,
+            ..
+        }
+    )
+}
+
+/// Did a Docker API call fail because the object is in use?
+pub(super) fn is_conflict(err: &bollard::errors::Error) -> bool {
+    matches!(
+        err,
+        bollard::errors::Error::DockerResponseServerError {
+            status_code: 409,
+            ..
+        }
+    )
+}
+
+/// Options for spawning a new container.
+#[derive(Debug, Clone, Copy)]
+pub struct SpawnOpts<'a> {
+    /// Name to assign the container.
+    pub name: &'a str,
+    /// Container image to use.
+    pub image: &'a str,
+    /// Policy for acquiring the container image.
+    pub image_policy: &'a ImagePullPolicy,
+    /// Command to run in the container.
+    pub cmd: Option<&'a str>,
+    /// Bind specs for the container.
+    pub volumes: &'a [String],
+    /// Spawn the container with extra privileges.
+    pub privileged: bool,
+    /// Security options for the container.
+    pub security_options: &'a [String],
+    /// Have Docker remove the container as soon as it exits.
+    pub auto_remove: bool,
+    /// Network to attach the container to, instead of the default bridge.
+    pub network: Option<&'a NetworkOpts>,
+    /// Environment variables to set in the container, as `KEY=VALUE` pairs.
+    pub env: &'a [String],
+    /// Cgroup to place the container under, instead of Docker's default.
+    pub cgroup_parent: Option<&'a CgroupParent>,
+    /// Resource limits to set in the container, instead of Docker's defaults.
+    pub ulimits: &'a [Ulimit],
+    /// User namespace to run the container in, instead of the daemon's default.
+    pub userns_mode: Option<UsernsMode>,
+}
+
+/// Spawn a new container, creating its network and named volumes first if needed. If
+/// this fails because the local copy of the image is corrupted, and the image policy
+/// allows it, the container is removed in case it was created before the failure, and
+/// the image is removed and pulled again before retrying once.
+///
+/// Returns what acquiring the image found out about it.
+pub async fn spawn_container(opts: &SpawnOpts<'_>) -> Result<ImageInfo> {
+    retry_corrupt_image(
+        opts.image,
+        opts.image_policy,
+        || try_spawn_container(opts),
+        || reap_container(opts.name),
+        || opts.image_policy.refresh_image(opts.image),
+    )
+    .await
+}
+
+/// Run `spawn`, and if it fails because the local copy of `image` is corrupted and
+/// `policy` allows it to be pulled again, `reap` whatever the failed spawn left behind,
+/// `refresh` the image, and run `spawn` once more.
+async fn retry_corrupt_image<T, S, SFut, C, CFut, R, RFut, U>(
     image: &str,
-    image_policy: &ImagePullPolicy,
-    cmd: Option<&str>,
-    volumes: &[String],
-    privileged: bool,
-    security_options: &[String],
-) -> Result<()> {
-    image_policy
-        .acquire_image(image)
+    policy: &ImagePullPolicy,
+    mut spawn: S,
+    reap: C,
+    refresh: R,
+) -> Result<T>
+where
+    S: FnMut() -> SFut,
+    SFut: Future<Output = Result<T>>,
+    C: FnOnce() -> CFut,
+    CFut: Future<Output = Result<()>>,
+    R: FnOnce() -> RFut,
+    RFut: Future<Output = Result<U>>,
+{
+    match spawn().await {
+        Err(e) if policy.can_repull() && is_image_corrupt(&e) => {
+            tracing::warn!(
+                err = ?e,
+                image = ?image,
+                "local image appears corrupted, removing it and pulling it again"
+            );
+            // Corruption often only shows when the container starts, after it was created
+            reap()
+                .await
+                .context("failed to remove container before retrying spawn")?;
+            refresh()
+                .await
+                .context(format!("failed to re-pull corrupted image {}", image))?;
+            tracing::info!(image = ?image, "re-pulled image, retrying spawn");
+            spawn().await.context(format!(
+                "failed to spawn container after re-pulling {}",
+                image
+            ))
+        }
+        res => res,
+    }
+}
+
+/// Acquire the container's image and spawn it.
+async fn try_spawn_container(opts: &SpawnOpts<'_>) -> Result<ImageInfo> {
+    if let Some(mode) = opts.userns_mode {
+        check_userns_mode(mode)
+            .await
+            .context(SetupError("unsupported user namespace mode"))?;
+    }
+
+    let image = spawn_image_name(opts.image, opts.image_policy).await?;
+    let opts = &SpawnOpts {
+        image: &image,
+        ..*opts
+    };
+
+    let info = opts
+        .image_policy
+        .acquire_image(opts.image)
         .await
         .context("failed to acquire container image")?;
 
+    limited(async {
+        let backend = backend();
+        if let Some(network) = opts.network.filter(|network| !network.is_external()) {
+            backend
+                .create_network(network)
+                .await
+                .context(format!("failed to create network {}", network.name))?;
+        }
+        for volume in opts.volumes.iter().filter_map(|v| named_volume(v)) {
+            backend
+                .create_volume(volume)
+                .await
+                .context(format!("failed to create volume {}", volume))?;
+        }
+        backend.spawn(opts).await
+    })
+    .await?;
+    Ok(info)
+}
+
+/// The name to spawn `image` by. Images that `policy` pulls are qualified the way the
+/// engine needs, unless an image of that name already exists locally. Images from other
+/// policies, e.g. built or committed ones, are local and keep their names.
+async fn spawn_image_name(image: &str, policy: &ImagePullPolicy) -> Result<String> {
+    let qualified = CONFIG.docker.flavor.qualify_image(image);
+    if qualified == image
+        || !matches!(policy, ImagePullPolicy::Pull(_))
+        || image_present(image).await?
+    {
+        return Ok(image.to_owned());
+    }
+    Ok(qualified)
+}
+
+/// Check that the engine can run a container in `mode`, asking it whether it remaps
+/// users if that matters.
+async fn check_userns_mode(mode: UsernsMode) -> Result<()> {
+    let flavor = CONFIG.docker.flavor;
+    if flavor.supports_userns_mode(mode, &[]) {
+        return Ok(());
+    }
+
+    let info = client()?
+        .info()
+        .await
+        .context("failed to get container engine info")?;
+    if !flavor.supports_userns_mode(mode, &info.security_options.unwrap_or_default()) {
+        anyhow::bail!(
+            "userns mode {:?} requires the Docker daemon to run with userns-remap",
+            mode
+        );
+    }
+    Ok(())
+}
+
+/// Kill a container.
+pub async fn kill_container(name: &str) -> Result<()> {
+    backend().kill(name).await
+}
+
+/// Commit a container's current state to a new image tagged `tag`, returning the new
+/// image's ID.
+pub async fn commit_container(name: &str, tag: &str) -> Result<String> {
     let client = client()?;
 
-    let opts = CreateContainerOptions { name };
-    let host_config = HostConfig {
-        binds: Some(volumes.to_owned()),
-        auto_remove: Some(true),
-        security_opt: Some(security_options.to_owned()),
-        // mounts: todo!(),
-        // cap_add: todo!(),
-        // cap_drop: todo!(),
-        privileged: Some(privileged),
-        // publish_all_ports: todo!(),
-        ..Default::default()
+    let (repo, tag_part) = match tag.rsplit_once(':') {
+        Some((repo, t)) if !t.contains('/') => (repo, t),
+        _ => (tag, "latest"),
     };
-    let config = Config {
-        // env: todo!(),
-        cmd: cmd.map(|cmd| cmd.split_whitespace().collect()),
-        image: Some(image),
-        // working_dir: todo!(),
-        // entrypoint: todo!(),
-        // labels: todo!(),
-        // shell: todo!(),
-        host_config: Some(host_config),
-        // networking_config: todo!(),
+
+    let opts = CommitContainerOptions {
+        container: name,
+        repo,
+        tag: tag_part,
+        pause: true,
         ..Default::default()
     };
-
     client
-        .create_container(Some(opts), config)
+        .commit_container(opts, Config::<String>::default())
         .await
-        .context("failed to create container")?;
+        .context("failed to commit container")?;
 
+    // Look up the ID through the tag, since older daemons disagree on the commit
+    // response's shape
     client
-        .start_container::<&str>(name, None)
+        .inspect_image(tag)
         .await
-        .context("failed to start container")
+        .context("failed to inspect committed image")?
+        .id
+        .context("committed image has no ID")
 }
 
-/// Kill a container.
-pub async fn kill_container(name: &str) -> Result<()> {
+/// Read the contents and permissions of the regular file at `path` in a container.
+pub async fn copy_from_container(name: &str, path: &Path) -> Result<(Vec<u8>, u32)> {
     let client = client()?;
 
-    client
-        .kill_container::<&str>(name, None)
-        .await
-        .context("failed to kill container")
+    let opts = DownloadFromContainerOptions {
+        path: path.to_string_lossy(),
+    };
+    let mut stream = client.download_from_container(name, Some(opts));
+    let mut archive = vec![];
+    while let Some(chunk) = stream.next().await {
+        archive.extend(chunk.context("failed to download file from container")?);
+    }
+
+    // Docker sends the file as a tar archive
+    let mut archive = tar::Archive::new(archive.as_slice());
+    let mut entry = archive
+        .entries()
+        .context("failed to read archive")?
+        .next()
+        .context("archive is empty")?
+        .context("failed to read archive entry")?;
+    anyhow::ensure!(
+        entry.header().entry_type().is_file(),
+        "{} is not a regular file",
+        path.display()
+    );
+
+    let mode = entry
+        .header()
+        .mode()
+        .context("failed to read file permissions from archive")?;
+    let mut buf = vec![];
+    entry
+        .read_to_end(&mut buf)
+        .context("failed to read file from archive")?;
+    Ok((buf, mode & 0o7777))
 }
 
-/// Run a command in a container.
-pub async fn run_command(
-    name: &str,
-    cmd: &str,
-    args: &[&str],
-    privileged: bool,
-    tty: bool,
-) -> Result<()> {
+/// Write `contents` to a regular file at `path` in a container with permissions `mode`,
+/// replacing the file if it already exists. The directory that `path` is in must exist.
+pub async fn copy_to_container(name: &str, path: &Path, contents: &[u8], mode: u32) -> Result<()> {
     let client = client()?;
 
-    let opts = CreateExecOptions {
-        attach_stdin: Some(false),
-        attach_stdout: Some(true),
-        attach_stderr: Some(true),
-        tty: Some(tty),
-        cmd: Some(
-            std::iter::once(cmd)
-                .chain(args.iter().copied())
-                .collect::<Vec<&str>>(),
-        ),
-        privileged: Some(privileged),
-        ..Default::default()
+    let (dir, file_name) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(file_name)) if path.is_absolute() => (dir, file_name),
+        _ => anyhow::bail!("{} is not an absolute path to a file", path.display()),
     };
 
-    let exec = client
-        .create_exec(name, opts)
-        .await
-        .context("failed to create exec object")?
-        .id;
+    // Docker expects the file as a tar archive to extract into its directory
+    let mut header = tar::Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(mode);
+    header.set_cksum();
+    let mut archive = tar::Builder::new(vec![]);
+    archive
+        .append_data(&mut header, file_name, contents)
+        .context("failed to build archive")?;
+    let archive = archive.into_inner().context("failed to build archive")?;
 
-    let opts = StartExecOptions {
-        detach: false,
+    let opts = UploadToContainerOptions {
+        path: dir.to_string_lossy(),
         ..Default::default()
     };
+    client
+        .upload_to_container(name, Some(opts), archive.into())
+        .await
+        .context("failed to upload file to container")
+}
+
+/// Options for running a command in a container.
+#[derive(Debug, Clone, Copy)]
+pub struct ExecOpts<'a> {
+    /// Name of the container to run the command in.
+    pub name: &'a str,
+    /// Command to run.
+    pub cmd: &'a str,
+    /// Arguments to the command.
+    pub args: &'a [&'a str],
+    /// Run the command with extra privileges.
+    pub privileged: bool,
+    /// Allocate a TTY for the command.
+    pub tty: bool,
+    /// Shell used to wrap the command, e.g. `sh -lc`.
+    pub shell: Option<&'a str>,
+    /// Arguments prepended to the final command line, e.g. to run it under a tracer.
+    pub wrapper: &'a [&'a str],
+    /// Environment variables to set for the command, as `KEY=VALUE` pairs.
+    pub env: &'a [String],
+    /// Key sequence for detaching from the command, e.g. `ctrl-p,ctrl-q`. Defaults to
+    /// Docker's default.
+    pub detach_keys: Option<&'a str>,
+}
+
+/// Check that a container is running before execing into it, since Docker's error for
+/// execing into a stopped container is unhelpful.
+async fn ensure_running(name: &str) -> Result<()> {
+    let client = client()?;
 
-    let results = client
-        .start_exec(&exec, Some(opts))
+    let state = client
+        .inspect_container(name, None)
         .await
-        .context("failed to start exec")?;
-
-    let mut stdout = Vec::new();
-    let mut stderr = Vec::new();
-
-    match results {
-        StartExecResults::Attached { mut output, .. } => {
-            while let Some(Ok(output)) = output.next().await {
-                match output {
-                    bollard::container::LogOutput::StdErr { message } => {
-                        stderr.append(&mut message.iter().cloned().collect())
-                    }
-                    bollard::container::LogOutput::StdOut { message } => {
-                        stdout.append(&mut message.iter().cloned().collect())
-                    }
-                    _ => continue,
-                }
-            }
-        }
-        StartExecResults::Detached => unreachable!(),
+        .context(format!("failed to inspect container {}", name))?
+        .state
+        .unwrap_or_default();
+    if !state.running.unwrap_or(false) {
+        let status = state
+            .status
+            .map_or_else(|| "unknown".to_owned(), |status| status.to_string());
+        anyhow::bail!("container {} is not running (state: {})", name, status);
     }
 
-    match String::from_utf8(stdout) {
+    Ok(())
+}
+
+/// Run a command in a container, returning its output. It is up to the caller to decide
+/// whether the command's exit code indicates success.
+pub async fn run_command(opts: &ExecOpts<'_>) -> Result<ExecOutput> {
+    let (cmd, args) = (opts.cmd, opts.args);
+
+    let wrapped;
+    let command = match opts.shell {
+        Some(shell) => {
+            wrapped = shell_command(cmd, args);
+            shell
+                .split_whitespace()
+                .chain(std::iter::once(wrapped.as_str()))
+                .collect::<Vec<&str>>()
+        }
+        None => std::iter::once(cmd)
+            .chain(args.iter().copied())
+            .collect::<Vec<&str>>(),
+    };
+    let argv = opts
+        .wrapper
+        .iter()
+        .copied()
+        .chain(command)
+        .collect::<Vec<&str>>();
+
+    ensure_running(opts.name).await?;
+    let out = backend().exec(&argv, opts).await?;
+
+    match std::str::from_utf8(&out.stdout) {
         Ok(stdout) => tracing::debug!(cmd = ?cmd, args = ?args, "command stdout:\n{}", stdout),
         Err(e) => {
             tracing::debug!(err = ?e, cmd = ?cmd, args = ?args, "failed to parse command stdout")
         }
     }
 
-    match String::from_utf8(stderr) {
+    match std::str::from_utf8(&out.stderr) {
         Ok(stderr) => tracing::debug!(cmd = ?cmd, args = ?args, "command stderr:\n{}", stderr),
         Err(e) => {
             tracing::debug!(err = ?e, cmd = ?cmd, args = ?args, "failed to parse command stderr")
         }
     }
 
-    let inspect = client
-        .inspect_exec(&exec)
-        .await
-        .context("failed to inspect exec result")?;
-    let code = inspect.exit_code.map(ExitCode);
+    Ok(out)
+}
+
+/// The output of a command run in a container.
+#[derive(Debug)]
+pub struct ExecOutput {
+    /// How the command exited.
+    pub result: ExecResult,
+    /// Raw stdout of the command.
+    pub stdout: Vec<u8>,
+    /// Raw stderr of the command.
+    pub stderr: Vec<u8>,
+}
 
-    match code {
-        None => anyhow::bail!("unknown exit status"),
-        Some(c) if !c.success() => anyhow::bail!("command failed with {}", *c),
-        Some(_) => Ok(()),
+impl ExecOutput {
+    /// Return `Ok` if the command exited with one of `ok_exit_codes`, or `Err` otherwise.
+    pub fn check(&self, ok_exit_codes: &[i64]) -> Result<()> {
+        if !self.result.success(ok_exit_codes) {
+            anyhow::bail!("command failed with {}", self.result)
+        }
+        Ok(())
     }
 }
 
-/// Wraps an exit code for a container exec.
-pub struct ExitCode(pub i64);
+/// Build a single shell command line from a command and its arguments. The command is
+/// passed through verbatim so that it may use shell syntax, while each argument is
+/// single-quoted.
+fn shell_command(cmd: &str, args: &[&str]) -> String {
+    std::iter::once(cmd.to_owned())
+        .chain(
+            args.iter()
+                .map(|arg| format!("'{}'", arg.replace('\'', r#"'\''"#))),
+        )
+        .collect::<Vec<_>>()
+        .join(" ")
+}
 
-impl ExitCode {
-    /// Was the command successful?
-    pub fn success(&self) -> bool {
-        self.0 == 0
+/// Describes how a command exited.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ExecResult {
+    /// Exit code of the command, if known.
+    pub code: Option<i64>,
+    /// Signal that terminated the command, if any. For container commands, this is
+    /// inferred from an exit code of 128 plus the signal number.
+    pub signal: Option<i32>,
+    /// Was the command's container killed for running out of memory?
+    pub oom_killed: bool,
+}
+
+impl ExecResult {
+    /// Build an exec result from Docker's exec inspect response, along with the state of
+    /// the container the command ran in, if known.
+    pub fn from_inspect(exec: &ExecInspectResponse, container: Option<&ContainerState>) -> Self {
+        Self {
+            code: exec.exit_code,
+            signal: exec.exit_code.and_then(signal_from_code),
+            oom_killed: container.and_then(|s| s.oom_killed).unwrap_or(false),
+        }
+    }
+
+    /// Was the command successful, given a set of acceptable exit codes? An OOM kill
+    /// is never successful.
+    pub fn success(&self, ok_exit_codes: &[i64]) -> bool {
+        !self.oom_killed && self.code.is_some_and(|c| ok_exit_codes.contains(&c))
     }
 }
 
-impl Deref for ExitCode {
-    type Target = i64;
+/// Infer the signal that killed a command from its exit code, following the shell
+/// convention of 128 plus the signal number.
+pub(super) fn signal_from_code(code: i64) -> Option<i32> {
+    (129..=128 + 64)
+        .contains(&code)
+        .then(|| (code - 128) as i32)
+}
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+impl Display for ExecResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.code, self.signal) {
+            (Some(code), Some(signal)) => write!(f, "signal {} (exit code {})", signal, code)?,
+            (Some(code), None) => write!(f, "exit code {}", code)?,
+            (None, Some(signal)) => write!(f, "signal {}", signal)?,
+            (None, None) => write!(f, "unknown exit status")?,
+        }
+        if self.oom_killed {
+            write!(f, ", OOM killed")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::assert_yaml_deserialize;
+
+    #[test]
+    fn test_host_mount_bind() {
+        assert_eq!(
+            HostMount::ProcSysrq.bind(),
+            "/proc/sysrq-trigger:/host/proc/sysrq-trigger:ro"
+        );
+        assert_eq!(
+            HostMount::CgroupFs.bind(),
+            "/sys/fs/cgroup:/host/sys/fs/cgroup:ro"
+        );
+        assert!(HostMount::DockerSock
+            .bind()
+            .ends_with(":/var/run/docker.sock:ro"));
+    }
+
+    #[test]
+    fn test_host_mount_serde() {
+        let mounts: Vec<HostMount> = assert_yaml_deserialize("[procSysrq, cgroupFs, dockerSock]");
+        assert_eq!(
+            mounts,
+            vec![
+                HostMount::ProcSysrq,
+                HostMount::CgroupFs,
+                HostMount::DockerSock
+            ]
+        );
+    }
+
+    #[test]
+    fn test_is_not_found() {
+        assert!(is_not_found(
+            &bollard::errors::Error::DockerResponseServerError {
+                status_code: 404,
+                message: "No such container: foo".into(),
+            }
+        ));
+        assert!(!is_not_found(
+            &bollard::errors::Error::DockerResponseServerError {
+                status_code: 500,
+                message: "oops".into(),
+            }
+        ));
+        assert!(!is_not_found(&bollard::errors::Error::RequestTimeoutError));
+    }
+
+    #[test]
+    fn test_cgroup_parent_serde() {
+        for parent in ["/houdini", "houdini/escapes", "houdini.slice"] {
+            let yaml = format!("{:?}", parent);
+            let deserialized: CgroupParent = assert_yaml_deserialize(&yaml);
+            assert_eq!(deserialized.as_str(), parent);
+            assert_eq!(
+                serde_yaml::to_string(&deserialized).unwrap().trim(),
+                format!("---\n{}", parent)
+            );
+        }
+
+        for parent in [
+            "",
+            "/",
+            "/houdini/../escape",
+            "houdini escapes",
+            "/system.slice/houdini.slice",
+        ] {
+            let yaml = format!("{:?}", parent);
+            serde_yaml::from_str::<CgroupParent>(&yaml)
+                .expect_err(&format!("{:?} should be an invalid cgroup parent", parent));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_corrupt_image() {
+        use std::cell::Cell;
+
+        let pull = ImagePullPolicy::default();
+        let corrupt = || anyhow::anyhow!("failed to register layer: unexpected EOF");
+
+        // A layer that errors once is re-pulled and the spawn retried, after removing
+        // the container that the failed spawn created
+        let (spawns, reaps, refreshes) = (Cell::new(0), Cell::new(0), Cell::new(0));
+        let spawn = || {
+            spawns.set(spawns.get() + 1);
+            let first = spawns.get() == 1;
+            async move {
+                match first {
+                    true => Err(corrupt()).context("failed to create container"),
+                    false => Ok(()),
+                }
+            }
+        };
+        let reap = || {
+            reaps.set(reaps.get() + 1);
+            async { Ok(()) }
+        };
+        let refresh = || {
+            refreshes.set(refreshes.get() + 1);
+            async { Ok(()) }
+        };
+        retry_corrupt_image("bash", &pull, spawn, reap, refresh)
+            .await
+            .expect("spawn should succeed after re-pulling");
+        assert_eq!((spawns.get(), reaps.get(), refreshes.get()), (2, 1, 1));
+
+        // Images that cannot be pulled again, and unrelated errors, are not retried
+        for (policy, err) in [
+            (ImagePullPolicy::Never, corrupt()),
+            (pull.clone(), anyhow::anyhow!("no such image")),
+        ] {
+            let (spawns, refreshes) = (Cell::new(0), Cell::new(0));
+            let err = Cell::new(Some(err));
+            let spawn = || {
+                spawns.set(spawns.get() + 1);
+                let res = err.take().map_or(Ok(()), Err);
+                async move { res }
+            };
+            let refresh = || {
+                refreshes.set(refreshes.get() + 1);
+                async { Ok(()) }
+            };
+            retry_corrupt_image("bash", &policy, spawn, || async { Ok(()) }, refresh)
+                .await
+                .expect_err("spawn should not be retried");
+            assert_eq!((spawns.get(), refreshes.get()), (1, 0));
+        }
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_cgroup_parent() {
+        let client = client().expect("failed to get client");
+        let policy = ImagePullPolicy::default();
+        let parent = CgroupParent::try_from("/houdini".to_owned()).unwrap();
+
+        let opts = SpawnOpts {
+            name: "houdini-cgroup-parent",
+            image: "bash",
+            image_policy: &policy,
+            cmd: Some("sleep infinity"),
+            volumes: &[],
+            privileged: false,
+            security_options: &[],
+            auto_remove: false,
+            network: None,
+            env: &[],
+            cgroup_parent: Some(&parent),
+            ulimits: &[],
+            userns_mode: None,
+        };
+        spawn_container(&opts)
+            .await
+            .expect("container should spawn");
+
+        let inspect = client
+            .inspect_container(opts.name, None)
+            .await
+            .expect("container should exist");
+        reap_container(opts.name)
+            .await
+            .expect("container should reap");
+        assert_eq!(
+            inspect.host_config.and_then(|config| config.cgroup_parent),
+            Some("/houdini".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_auto_remove() {
+        let client = client().expect("failed to get client");
+        let policy = ImagePullPolicy::default();
+
+        let opts = SpawnOpts {
+            name: "houdini-no-auto-remove",
+            image: "bash",
+            image_policy: &policy,
+            cmd: Some("true"),
+            volumes: &[],
+            privileged: false,
+            security_options: &[],
+            auto_remove: false,
+            network: None,
+            env: &[],
+            cgroup_parent: None,
+            ulimits: &[],
+            userns_mode: None,
+        };
+        spawn_container(&opts)
+            .await
+            .expect("container should spawn");
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        client
+            .inspect_container(opts.name, None)
+            .await
+            .expect("exited container should survive until reaped");
+        reap_container(opts.name)
+            .await
+            .expect("container should reap");
+        client
+            .inspect_container(opts.name, None)
+            .await
+            .expect_err("container should be gone after reaping");
+
+        let opts = SpawnOpts {
+            name: "houdini-auto-remove",
+            auto_remove: true,
+            ..opts
+        };
+        spawn_container(&opts)
+            .await
+            .expect("container should spawn");
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        client
+            .inspect_container(opts.name, None)
+            .await
+            .expect_err("exited container should be auto-removed");
+        reap_container(opts.name)
+            .await
+            .expect("reaping an auto-removed container should be ok");
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_exec_stopped_container() {
+        let policy = ImagePullPolicy::default();
+        let opts = SpawnOpts {
+            name: "houdini-stopped",
+            image: "bash",
+            image_policy: &policy,
+            cmd: Some("true"),
+            volumes: &[],
+            privileged: false,
+            security_options: &[],
+            auto_remove: false,
+            network: None,
+            env: &[],
+            cgroup_parent: None,
+            ulimits: &[],
+            userns_mode: None,
+        };
+        spawn_container(&opts)
+            .await
+            .expect("container should spawn");
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+
+        let err = run_command(&ExecOpts {
+            name: opts.name,
+            cmd: "true",
+            args: &[],
+            privileged: false,
+            tty: false,
+            shell: None,
+            wrapper: &[],
+            env: &[],
+            detach_keys: None,
+        })
+        .await
+        .expect_err("exec into a stopped container should fail");
+        assert_eq!(
+            err.to_string(),
+            "container houdini-stopped is not running (state: exited)"
+        );
+        assert!(!crate::error::is_harness_error(&err));
+
+        reap_container(opts.name)
+            .await
+            .expect("container should reap");
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_assert_clean() {
+        let policy = ImagePullPolicy::default();
+        let opts = SpawnOpts {
+            name: "houdini-leaked",
+            image: "bash",
+            image_policy: &policy,
+            cmd: Some("sleep infinity"),
+            volumes: &[],
+            privileged: false,
+            security_options: &[],
+            auto_remove: false,
+            network: None,
+            env: &[],
+            cgroup_parent: None,
+            ulimits: &[],
+            userns_mode: None,
+        };
+        spawn_container(&opts)
+            .await
+            .expect("container should spawn");
+
+        let err = assert_clean()
+            .await
+            .expect_err("leaked container should fail the check");
+        assert!(err.to_string().contains("houdini-leaked"), "{}", err);
+
+        reap_container(opts.name)
+            .await
+            .expect("container should reap");
+        assert_clean()
+            .await
+            .expect("no containers should be left after reaping");
+    }
+
+    #[test]
+    fn test_exec_result() {
+        let exec = ExecInspectResponse {
+            exit_code: Some(137),
+            ..Default::default()
+        };
+        let state = ContainerState {
+            oom_killed: Some(true),
+            ..Default::default()
+        };
+
+        let res = ExecResult::from_inspect(&exec, Some(&state));
+        assert_eq!(
+            res,
+            ExecResult {
+                code: Some(137),
+                signal: Some(9),
+                oom_killed: true,
+            }
+        );
+        assert!(!res.success(&[137]), "OOM kill should never be a success");
+        assert_eq!(res.to_string(), "signal 9 (exit code 137), OOM killed");
+
+        let exec = ExecInspectResponse {
+            exit_code: Some(1),
+            ..Default::default()
+        };
+        let res = ExecResult::from_inspect(&exec, None);
+        assert_eq!(
+            res,
+            ExecResult {
+                code: Some(1),
+                signal: None,
+                oom_killed: false,
+            }
+        );
+        assert!(res.success(&[0, 1]));
+        assert_eq!(res.to_string(), "exit code 1");
+    }
+
+    #[test]
+    fn test_shell_command() {
+        assert_eq!(shell_command("echo", &[]), "echo");
+        assert_eq!(
+            shell_command("echo", &["hello world", "it's"]),
+            r#"echo 'hello world' 'it'\''s'"#
+        );
+        assert_eq!(
+            shell_command("cd /tmp && pwd", &[]),
+            "cd /tmp && pwd",
+            "command should be passed through verbatim"
+        );
     }
 }
@@ -0,0 +1,216 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+
+//! Helpers for archiving, storing, and resolving Docker build contexts. Build contexts
+//! uploaded to the Houdini API server are unpacked under a temporary directory and
+//! referenced by an opaque handle.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context as _, Result};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use futures::{Stream, StreamExt};
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+/// Root directory under which uploaded build contexts are stored.
+fn contexts_dir() -> PathBuf {
+    std::env::temp_dir().join("houdini-contexts")
+}
+
+/// Resolve a build context handle to the directory it was unpacked into.
+pub fn resolve(handle: &str) -> Result<PathBuf> {
+    let handle = Uuid::parse_str(handle).context(format!("invalid context handle {}", handle))?;
+
+    let dir = contexts_dir().join(handle.to_string());
+    if !dir.is_dir() {
+        anyhow::bail!("no build context found for handle {}", handle);
+    }
+
+    Ok(dir)
+}
+
+/// Resolve `path`, relative to the root of the build context referenced by `handle`, to
+/// the file it names. The file must stay inside the context once `..` components and
+/// symlinks are followed.
+pub fn resolve_file(handle: &str, path: &Path) -> Result<PathBuf> {
+    let root = resolve(handle)?
+        .canonicalize()
+        .context(format!("failed to resolve build context {}", handle))?;
+    let file = root.join(path).canonicalize().context(format!(
+        "no file {} in build context {}",
+        path.display(),
+        handle
+    ))?;
+    if !file.starts_with(&root) {
+        anyhow::bail!("{} is outside build context {}", path.display(), handle);
+    }
+
+    Ok(file)
+}
+
+/// Remove the build context referenced by `handle`, if it still exists.
+pub async fn remove(handle: &str) -> Result<()> {
+    let handle = Uuid::parse_str(handle).context(format!("invalid context handle {}", handle))?;
+
+    let dir = contexts_dir().join(handle.to_string());
+    match tokio::fs::remove_dir_all(&dir).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).context(format!("failed to remove context dir {}", dir.display()))
+        }
+        _ => {
+            tracing::info!(handle = ?handle, "removed build context");
+            Ok(())
+        }
+    }
+}
+
+/// Store a gzipped tarball, received as a stream of chunks, as a new build context and
+/// return its handle.
+pub async fn store<S, B, E>(mut body: S) -> Result<String>
+where
+    S: Stream<Item = Result<B, E>> + Unpin,
+    B: AsRef<[u8]>,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let handle = Uuid::new_v4().to_string();
+    let dir = contexts_dir().join(&handle);
+    let archive = contexts_dir().join(format!("{}.tar.gz", handle));
+
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .context(format!("failed to create context dir {}", dir.display()))?;
+
+    let mut file = tokio::fs::File::create(&archive)
+        .await
+        .context(format!("failed to create archive {}", archive.display()))?;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.context("failed to read context upload")?;
+        file.write_all(chunk.as_ref())
+            .await
+            .context("failed to write context archive")?;
+    }
+    file.flush()
+        .await
+        .context("failed to flush context archive")?;
+    drop(file);
+
+    let unpack_dir = dir.clone();
+    let unpack_archive = archive.clone();
+    let res = tokio::task::spawn_blocking(move || -> Result<()> {
+        let file = std::fs::File::open(&unpack_archive)?;
+        tar::Archive::new(GzDecoder::new(file))
+            .unpack(&unpack_dir)
+            .context("failed to unpack context archive")
+    })
+    .await
+    .context("context unpack task panicked")?;
+
+    let _ = tokio::fs::remove_file(&archive).await;
+    if let Err(e) = res {
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+        return Err(e);
+    }
+
+    tracing::info!(handle = ?handle, dir = ?dir, "stored build context");
+
+    Ok(handle)
+}
+
+/// Create a gzipped tarball of a build root, honouring its `.dockerignore` if present.
+pub fn archive(build_root: &Path) -> Result<Vec<u8>> {
+    let dockerignore = build_root.join(".dockerignore");
+    let ignore = dockerignore
+        .exists()
+        .then(|| gitignore::File::new(&dockerignore).ok())
+        .flatten();
+
+    let mut buf = Vec::new();
+    let enc = GzEncoder::new(&mut buf, Compression::default());
+    let mut tar = tar::Builder::new(enc);
+
+    jwalk::WalkDir::new(build_root)
+    .skip_hidden(false)
+    .follow_links(false)
+    .into_iter()
+    .filter_map(Result::ok)
+    .filter_map(|p| {
+        let p = p.path();
+        let name = p.strip_prefix(build_root).unwrap_or(&p);
+        if name.components().take(1).next().is_none() {
+            tracing::trace!(host_path = ?p, tar_path = ?name, "skipping empty filename");
+            return None;
+        }
+        if let Some(ref ignore) = ignore {
+            match ignore.is_excluded(&p) {
+                Ok(true) => {
+                    tracing::trace!(host_path = ?p, tar_path = ?name, "skipping ignored filename");
+                    return None;
+                },
+                Err(e) => return Some(Err(anyhow::Error::from(e))),
+                _ => {}
+            }
+        }
+        tracing::debug!(host_path = ?p, tar_path = ?name, "adding file to archive");
+        Some(
+            tar.append_path_with_name(&p, name)
+                .with_context(|| format!("failed to add file to tar archive {:?}", &p)),
+        )
+    })
+    .collect::<Result<()>>()?;
+
+    tar.append_dir_all(".", build_root)
+        .context("failed to add buildroot to tar archive")?;
+
+    tar.into_inner()
+        .and_then(|t| t.finish())
+        .context("failed to write to tar archive")?;
+
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_store_and_resolve() {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("testdata/imgbuild");
+
+        let buf = archive(&d).expect("context should archive");
+        let chunks = buf
+            .chunks(16)
+            .map(|c| Ok::<_, std::io::Error>(c.to_vec()))
+            .collect::<Vec<_>>();
+
+        let handle = store(futures::stream::iter(chunks))
+            .await
+            .expect("context should store");
+
+        let dir = resolve(&handle).expect("handle should resolve");
+        assert!(dir.join("Dockerfile").is_file());
+
+        resolve("../../etc").expect_err("non-uuid handle should not resolve");
+        resolve(&Uuid::new_v4().to_string()).expect_err("unknown handle should not resolve");
+
+        let dockerfile =
+            resolve_file(&handle, Path::new("Dockerfile")).expect("Dockerfile should resolve");
+        assert!(dockerfile.starts_with(dir.canonicalize().unwrap()));
+        resolve_file(&handle, Path::new("../../etc/passwd"))
+            .expect_err("path outside the context should not resolve");
+        resolve_file(&handle, Path::new("/etc/passwd"))
+            .expect_err("absolute path outside the context should not resolve");
+
+        remove(&handle).await.expect("context should be removed");
+        assert!(!dir.exists());
+        resolve(&handle).expect_err("removed handle should not resolve");
+        remove(&handle)
+            .await
+            .expect("removing a missing context should succeed");
+    }
+}
@@ -0,0 +1,153 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+
+//! Differences between the engines that serve the Docker API. Podman emulates the API
+//! over its compatibility socket closely enough for Houdini, apart from the few quirks
+//! handled here.
+
+use std::{path::Path, time::Duration};
+
+use serde::Deserialize;
+
+use super::UsernsMode;
+
+/// Which container engine is listening on the configured socket.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub enum Flavor {
+    /// The Docker daemon.
+    #[default]
+    Docker,
+    /// Podman's Docker-compatible API service.
+    Podman,
+}
+
+impl Flavor {
+    /// Socket the engine listens on, if none is configured.
+    pub fn default_socket(&self) -> &'static Path {
+        match self {
+            Flavor::Docker => Path::new("/var/run/docker.sock"),
+            Flavor::Podman => Path::new("/run/podman/podman.sock"),
+        }
+    }
+
+    /// Name of the engine's client binary, if none is configured.
+    pub fn default_client(&self) -> &'static str {
+        match self {
+            Flavor::Docker => "docker",
+            Flavor::Podman => "podman",
+        }
+    }
+
+    /// The fully qualified name of `image`. Podman refuses to guess the registry of a
+    /// short name like `bash` when it cannot prompt, so images spawned through it are
+    /// qualified the way Docker would resolve them. Docker's images are left as is. Only
+    /// names that will be pulled should be qualified, since local images, e.g. built or
+    /// committed ones, may exist under the short name alone.
+    pub fn qualify_image(&self, image: &str) -> String {
+        if *self == Flavor::Docker {
+            return image.to_owned();
+        }
+
+        match image.split_once('/') {
+            // Official images live under `library`
+            None => format!("docker.io/library/{}", image),
+            Some((registry, _)) if registry.contains(['.', ':']) || registry == "localhost" => {
+                image.to_owned()
+            }
+            Some(_) => format!("docker.io/{}", image),
+        }
+    }
+
+    /// Can the engine run a container in `mode`, given the `security_options` it reports
+    /// in its system info? Docker has no mode for a private user namespace, and only
+    /// gives containers one when the daemon remaps users, which it reports as the
+    /// `name=userns` security option.
+    pub fn supports_userns_mode(&self, mode: UsernsMode, security_options: &[String]) -> bool {
+        match (self, mode) {
+            (Flavor::Docker, UsernsMode::Private) => security_options
+                .iter()
+                .any(|option| option.split(',').any(|field| field == "name=userns")),
+            _ => true,
+        }
+    }
+
+    /// The engine's name for `mode`, as set in `HostConfig.UsernsMode`. Docker only
+    /// accepts `host`, so it is given an empty mode for a private user namespace instead,
+    /// which callers must first check it supports with [`Flavor::supports_userns_mode`].
+    pub fn userns_mode(&self, mode: UsernsMode) -> &'static str {
+        match (self, mode) {
+            (_, UsernsMode::Host) => "host",
+            (Flavor::Podman, UsernsMode::Private) => "private",
+            (Flavor::Docker, UsernsMode::Private) | (_, UsernsMode::Default) => "",
+        }
+    }
+
+    /// How long to keep waiting for an exec whose output has closed to be reported as
+    /// exited. Podman can report an exec as still running, with no exit code, for a
+    /// moment after its output closes. Docker has always exited by then.
+    pub fn exec_exit_grace(&self) -> Option<Duration> {
+        match self {
+            Flavor::Docker => None,
+            Flavor::Podman => Some(Duration::from_secs(5)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_qualify_image() {
+        let podman = Flavor::Podman;
+        assert_eq!(podman.qualify_image("bash"), "docker.io/library/bash");
+        assert_eq!(
+            podman.qualify_image("bash:5.1"),
+            "docker.io/library/bash:5.1"
+        );
+        assert_eq!(
+            podman.qualify_image("bash@sha256:deadbeef"),
+            "docker.io/library/bash@sha256:deadbeef"
+        );
+        assert_eq!(
+            podman.qualify_image("willfindlay/houdini"),
+            "docker.io/willfindlay/houdini"
+        );
+        assert_eq!(
+            podman.qualify_image("quay.io/podman/stable"),
+            "quay.io/podman/stable"
+        );
+        assert_eq!(
+            podman.qualify_image("localhost:5000/foo"),
+            "localhost:5000/foo"
+        );
+        assert_eq!(podman.qualify_image("localhost/foo"), "localhost/foo");
+
+        assert_eq!(Flavor::Docker.qualify_image("bash"), "bash");
+    }
+
+    #[test]
+    fn test_userns_mode() {
+        assert_eq!(Flavor::Docker.userns_mode(UsernsMode::Host), "host");
+        assert_eq!(Flavor::Docker.userns_mode(UsernsMode::Private), "");
+        assert_eq!(Flavor::Podman.userns_mode(UsernsMode::Private), "private");
+        assert_eq!(Flavor::Podman.userns_mode(UsernsMode::Default), "");
+    }
+
+    #[test]
+    fn test_supports_userns_mode() {
+        let remapped = ["name=seccomp,profile=default".into(), "name=userns".into()];
+        let plain = ["name=seccomp,profile=default".into()];
+
+        assert!(Flavor::Docker.supports_userns_mode(UsernsMode::Private, &remapped));
+        assert!(!Flavor::Docker.supports_userns_mode(UsernsMode::Private, &plain));
+        assert!(!Flavor::Docker.supports_userns_mode(UsernsMode::Private, &[]));
+        assert!(Flavor::Docker.supports_userns_mode(UsernsMode::Host, &plain));
+        assert!(Flavor::Podman.supports_userns_mode(UsernsMode::Private, &[]));
+    }
+}
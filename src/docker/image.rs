@@ -7,16 +7,30 @@
 
 //! Helpers for managing container images during exploit setup.
 
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
 
 use anyhow::{bail, Context as _, Result};
-use bollard::image::BuildImageOptions;
-use flate2::{write::GzEncoder, Compression};
+use bollard::{
+    auth::DockerCredentials,
+    image::{BuildImageOptions, RemoveImageOptions},
+    models::{DistributionInspect, ImageInspect, OciPlatform},
+};
 use futures::StreamExt;
+use hyperlocal::{UnixClientExt as _, Uri};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+use super::{
+    container::{is_conflict, is_not_found},
+    limit::limited,
+};
+use crate::CONFIG;
+
 /// Defines policy for what to do about acquiring a container image for an exploit step.
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone)]
 #[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub enum ImagePullPolicy {
     /// Never build or pull the image.
@@ -26,33 +40,307 @@ pub enum ImagePullPolicy {
     Pull(PullOpts),
     /// Build the container image from a local Dockerfile.
     Build(BuildOpts),
+    /// Use an image created by an earlier `commitContainer` step, failing if it has not
+    /// been committed.
+    Committed,
 }
 
 impl Default for ImagePullPolicy {
     fn default() -> Self {
-        Self::Pull(PullOpts {
-            always: false,
-            sha256sum: None,
-            repo: None,
-        })
+        Self::Pull(PullOpts::default())
     }
 }
 
 impl ImagePullPolicy {
+    /// Override or add build arguments if this policy builds the image. Other policies
+    /// are left untouched.
+    pub fn override_build_args(&mut self, args: &HashMap<String, String>) {
+        if let ImagePullPolicy::Build(opts) = self {
+            opts.build_args
+                .extend(args.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+    }
+
+    /// The kind of policy, as it is named in trick files.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ImagePullPolicy::Never => "never",
+            ImagePullPolicy::Pull(_) => "pull",
+            ImagePullPolicy::Build(_) => "build",
+            ImagePullPolicy::Committed => "committed",
+        }
+    }
+
+    /// The local Dockerfile that this policy builds the image from, if any. Dockerfiles
+    /// in uploaded build contexts are not local, so they are not returned.
+    pub fn dockerfile(&self) -> Option<&Path> {
+        match self {
+            ImagePullPolicy::Build(opts) if opts.context.is_none() => Some(&opts.dockerfile),
+            _ => None,
+        }
+    }
+
+    /// The handle of the uploaded build context that this policy builds the image from,
+    /// if any.
+    pub fn build_context(&self) -> Option<&str> {
+        match self {
+            ImagePullPolicy::Build(opts) => opts.context.as_deref(),
+            _ => None,
+        }
+    }
+
     /// Acquire a Docker image according to the ImagePullPolicy.
-    pub async fn acquire_image(&self, image: &str) -> Result<()> {
+    pub async fn acquire_image(&self, image: &str) -> Result<ImageInfo> {
+        limited(self.do_acquire_image(image)).await
+    }
+
+    async fn do_acquire_image(&self, image: &str) -> Result<ImageInfo> {
         match self {
-            ImagePullPolicy::Never => Ok(()),
+            ImagePullPolicy::Never => Ok(ImageInfo::default()),
             ImagePullPolicy::Pull(opts) => opts.pull(image).await.context("failed to pull image"),
             ImagePullPolicy::Build(opts) => {
-                opts.build(image).await.context("failed to build image")
+                opts.build(image).await.context("failed to build image")?;
+                Ok(ImageInfo::default())
+            }
+            ImagePullPolicy::Committed => {
+                super::util::client()?
+                    .inspect_image(image)
+                    .await
+                    .context(format!("image {} has not been committed", image))?;
+                Ok(ImageInfo::default())
             }
         }
     }
+
+    /// Does the policy allow a corrupted local copy of the image to be pulled again?
+    pub fn can_repull(&self) -> bool {
+        matches!(self, ImagePullPolicy::Pull(_))
+    }
+
+    /// Remove the local copy of the image and pull it again. Only policies that
+    /// [`ImagePullPolicy::can_repull`] support this.
+    pub async fn refresh_image(&self, image: &str) -> Result<ImageInfo> {
+        limited(self.do_refresh_image(image)).await
+    }
+
+    async fn do_refresh_image(&self, image: &str) -> Result<ImageInfo> {
+        let opts = match self {
+            ImagePullPolicy::Pull(opts) => opts,
+            _ => bail!("image policy {} does not allow re-pulling", self.kind()),
+        };
+
+        // Not forced, so that the image is not untagged from under containers that other
+        // tricks are running from it. The pull still fetches whatever layers are missing.
+        let remove = RemoveImageOptions {
+            force: false,
+            noprune: false,
+        };
+        match super::util::client()?
+            .remove_image(image, Some(remove), None)
+            .await
+        {
+            Ok(_) => {}
+            Err(e) if is_not_found(&e) => {}
+            Err(e) if is_conflict(&e) => {
+                tracing::warn!(image = ?image, err = ?e, "image is in use, pulling it again without removing it");
+            }
+            Err(e) => return Err(e).context(format!("failed to remove image {}", image)),
+        }
+
+        PullOpts {
+            always: true,
+            ..opts.clone()
+        }
+        .pull(image)
+        .await
+        .context("failed to pull image")
+    }
+}
+
+/// Messages in Docker errors that mean the local copy of an image is corrupted, e.g. a
+/// layer is missing from disk or fails verification.
+const CORRUPT_IMAGE_ERRORS: &[&str] = &[
+    "layer does not exist",
+    "failed to register layer",
+    "filesystem layer verification failed",
+    "error creating overlay mount",
+];
+
+/// Did an operation fail because the local copy of an image is corrupted?
+pub(super) fn is_image_corrupt(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        let cause = cause.to_string();
+        CORRUPT_IMAGE_ERRORS.iter().any(|msg| cause.contains(msg))
+    })
+}
+
+/// Is the image present locally?
+pub async fn image_present(image: &str) -> Result<bool> {
+    match super::util::client()?.inspect_image(image).await {
+        Ok(_) => Ok(true),
+        Err(e) if is_not_found(&e) => Ok(false),
+        Err(e) => Err(e).context(format!("failed to inspect image {}", image)),
+    }
+}
+
+/// The size of the image in bytes, if it is present locally.
+pub async fn image_size(image: &str) -> Result<Option<u64>> {
+    match super::util::client()?.inspect_image(image).await {
+        Ok(inspect) => Ok(Some(inspect.size.unwrap_or_default().max(0) as u64)),
+        Err(e) if is_not_found(&e) => Ok(None),
+        Err(e) => Err(e).context(format!("failed to inspect image {}", image)),
+    }
+}
+
+/// What acquiring an image found out about it.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct ImageInfo {
+    /// Platforms the image is published for, if it was pulled from a registry that
+    /// serves a manifest list for it.
+    pub available_platforms: Vec<Platform>,
+}
+
+/// A platform that an image can run on.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Platform {
+    /// Operating system, e.g. `linux`.
+    pub os: String,
+    /// CPU architecture, e.g. `arm64`.
+    pub architecture: String,
+    /// Variant of the CPU architecture, e.g. `v8`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+}
+
+impl Platform {
+    fn new(
+        os: Option<String>,
+        architecture: Option<String>,
+        variant: Option<String>,
+    ) -> Option<Self> {
+        Some(Self {
+            os: os.filter(|os| !os.is_empty())?,
+            architecture: architecture.filter(|arch| !arch.is_empty())?,
+            variant: variant.filter(|variant| !variant.is_empty()),
+        })
+    }
+}
+
+impl std::fmt::Display for Platform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+        Ok(())
+    }
+}
+
+/// The platforms listed in a registry's distribution inspect response for an image.
+fn available_platforms(inspect: DistributionInspect) -> Vec<Platform> {
+    inspect
+        .platforms
+        .into_iter()
+        .filter_map(
+            |OciPlatform {
+                 os,
+                 architecture,
+                 variant,
+                 ..
+             }| Platform::new(os, architecture, variant),
+        )
+        .collect()
+}
+
+/// Ask the daemon which platforms the registry publishes `image` for. Bollard does
+/// not wrap this endpoint, so it is queried over the Docker socket directly.
+async fn distribution_platforms(image: &str) -> Result<Vec<Platform>> {
+    let uri = Uri::new(
+        &CONFIG.docker.socket,
+        &format!("/distribution/{}/json", image),
+    );
+    let res = hyper::Client::unix()
+        .get(uri.into())
+        .await
+        .context("failed to send distribution inspect request")?;
+
+    let status = res.status();
+    let body = hyper::body::to_bytes(res.into_body())
+        .await
+        .context("failed to read distribution inspect response")?;
+    if !status.is_success() {
+        bail!(
+            "distribution inspect failed with {}: {}",
+            status,
+            String::from_utf8_lossy(&body).trim()
+        );
+    }
+
+    let inspect: DistributionInspect =
+        serde_json::from_slice(&body).context("failed to parse distribution inspect response")?;
+    Ok(available_platforms(inspect))
+}
+
+/// Prefix of the standard OCI annotations recorded in an [`ImageProvenance`].
+const OCI_LABEL_PREFIX: &str = "org.opencontainers.image.";
+
+/// Where an image came from, trimmed down from its inspect output.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone, Default)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ImageProvenance {
+    /// ID of the image, e.g. `sha256:...`.
+    pub id: String,
+    /// Repo digest of the image, if it was pulled from a registry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    /// When the image was created.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    /// The image's `org.opencontainers.image.*` labels, such as its source and revision.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    /// Platform of the image that was used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub selected_platform: Option<Platform>,
+    /// Platforms the image is published for, if it was pulled from a registry that
+    /// serves a manifest list for it.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub available_platforms: Vec<Platform>,
+}
+
+impl ImageProvenance {
+    fn from_inspect(inspect: ImageInspect) -> Self {
+        let labels = inspect
+            .config
+            .and_then(|config| config.labels)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(key, _)| key.starts_with(OCI_LABEL_PREFIX))
+            .collect();
+
+        Self {
+            id: inspect.id.unwrap_or_default(),
+            digest: inspect.repo_digests.and_then(|d| d.into_iter().next()),
+            created: inspect.created,
+            labels,
+            selected_platform: Platform::new(inspect.os, inspect.architecture, inspect.variant),
+            available_platforms: vec![],
+        }
+    }
+}
+
+/// Inspect a local image to find where it came from.
+pub async fn image_provenance(image: &str) -> Result<ImageProvenance> {
+    let inspect = super::util::client()?
+        .inspect_image(image)
+        .await
+        .context(format!("failed to inspect image {}", image))?;
+    Ok(ImageProvenance::from_inspect(inspect))
 }
 
 /// Options for pulling an image.
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone, Default)]
 #[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct PullOpts {
     #[serde(default = "crate::serde_defaults::default_true")]
@@ -63,16 +351,87 @@ pub struct PullOpts {
     sha256sum: Option<String>,
     /// Name of the container repo to use. Defaults to docker hub.
     repo: Option<String>,
+    /// User to log in to the registry as. Default is to pull anonymously.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    /// Password to log in to the registry with. Prefer `passwordEnv`, which keeps the
+    /// secret out of the trick. This is never written to reports, nor sent with tricks
+    /// that are run on the API server.
+    #[serde(default, skip_serializing)]
+    password: Option<String>,
+    /// Environment variable to read the password to log in to the registry with from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password_env: Option<String>,
+    /// Token to log in to the registry with, instead of a username and password. Like
+    /// `password`, this is never serialized.
+    #[serde(default, skip_serializing)]
+    identity_token: Option<String>,
+}
+
+/// Keeps registry secrets out of the logs.
+impl std::fmt::Debug for PullOpts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let redact = |secret: &Option<String>| secret.as_ref().map(|_| "<redacted>");
+        f.debug_struct("PullOpts")
+            .field("always", &self.always)
+            .field("sha256sum", &self.sha256sum)
+            .field("repo", &self.repo)
+            .field("username", &self.username)
+            .field("password", &redact(&self.password))
+            .field("password_env", &self.password_env)
+            .field("identity_token", &redact(&self.identity_token))
+            .finish()
+    }
 }
 
 impl PullOpts {
-    pub async fn pull(&self, image: &str) -> Result<()> {
+    /// Credentials to log in to the registry with, or `None` to pull anonymously.
+    fn credentials(&self) -> Result<Option<DockerCredentials>> {
+        self.credentials_from_vars(|var| std::env::var(var).ok())
+    }
+
+    /// Like [`PullOpts::credentials`], but looking up `passwordEnv` with `var`.
+    fn credentials_from_vars(
+        &self,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Option<DockerCredentials>> {
+        if self.password.is_some() && self.password_env.is_some() {
+            bail!("only one of password and passwordEnv may be set");
+        }
+
+        let password = match &self.password_env {
+            Some(name) => Some(
+                var(name).context(format!("failed to read registry password from ${}", name))?,
+            ),
+            None => self.password.clone(),
+        };
+        if self.username.is_none() && password.is_none() && self.identity_token.is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(DockerCredentials {
+            username: self.username.clone(),
+            password,
+            identitytoken: self.identity_token.clone(),
+            ..Default::default()
+        }))
+    }
+
+    /// Pull the image, unless it is present locally and `always` is not set. When the
+    /// image is pulled, the platforms it is published for are looked up as well.
+    pub async fn pull(&self, image: &str) -> Result<ImageInfo> {
         let tag = image.split_once(':').map(|x| x.1).unwrap_or("latest");
 
         let client = super::util::client()?;
 
-        if client.inspect_image(image).await.is_ok() && !self.always {
-            return Ok(());
+        if !self.always {
+            if let Ok(inspect) = client.inspect_image(image).await {
+                // A poisoned local image should not get a free pass just because we
+                // skipped the pull
+                verify_digest(self.sha256sum.as_deref(), inspect.repo_digests)
+                    .context("local image failed verification")?;
+                return Ok(ImageInfo::default());
+            }
         }
 
         let opts = bollard::image::CreateImageOptions {
@@ -83,7 +442,8 @@ impl PullOpts {
             platform: "",
         };
 
-        let mut stream = client.create_image(Some(opts), None, None);
+        let credentials = self.credentials()?;
+        let mut stream = client.create_image(Some(opts), None, credentials);
         while let Some(res) = stream.next().await {
             let info = res.context("failed to send request")?;
             if let Some(err) = info.error {
@@ -106,55 +466,90 @@ impl PullOpts {
             .await
             .context("image inspect error after pull")?;
 
-        let digest = inspect
-            .repo_digests
-            .and_then(|l| l.get(0).cloned())
-            .and_then(|s| {
-                if let Some((_, digest)) = s.split_once("sha256:") {
-                    Some(digest.to_owned())
-                } else {
-                    None
-                }
-            });
-
-        match (&self.sha256sum, digest.as_ref()) {
-            (Some(d), None) => {
-                bail!("expected image digest {} but found none", d)
-            }
-            (Some(d1), Some(d2)) if d1 != d2 => {
-                bail!("image digest {} does not match expected digest {}", d2, d1)
-            }
-            _ => {
-                // Digest matches expected or no expected digest provided
-            }
-        }
+        verify_digest(self.sha256sum.as_deref(), inspect.repo_digests)?;
 
-        Ok(())
+        // Platforms are informational, so failing to find them should not fail the pull
+        let available_platforms = distribution_platforms(image).await.unwrap_or_else(|e| {
+            tracing::debug!(err = ?e, image = ?image, "failed to look up image platforms");
+            vec![]
+        });
+        Ok(ImageInfo {
+            available_platforms,
+        })
+    }
+}
+
+/// Verify an image's repo digests against an expected SHA256 digest. Verification
+/// always passes when no digest is expected.
+fn verify_digest(expected: Option<&str>, repo_digests: Option<Vec<String>>) -> Result<()> {
+    let expected = match expected {
+        Some(expected) => expected,
+        None => return Ok(()),
+    };
+
+    let digest = repo_digests
+        .and_then(|l| l.first().cloned())
+        .and_then(|s| s.split_once("sha256:").map(|(_, digest)| digest.to_owned()));
+
+    match digest {
+        None => bail!("expected image digest {} but found none", expected),
+        Some(d) if d != expected => {
+            bail!(
+                "image digest {} does not match expected digest {}",
+                d,
+                expected
+            )
+        }
+        Some(_) => Ok(()),
     }
 }
 
 /// Options for building an image.
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone)]
 #[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct BuildOpts {
-    /// Path to Dockerfile.
+    /// Path to Dockerfile. When `context` is set, this path is relative to the root of
+    /// the uploaded build context.
     dockerfile: PathBuf,
+    /// Handle of a build context previously uploaded to the Houdini API server. The
+    /// context is removed once the trick that builds from it ends.
+    #[serde(default)]
+    context: Option<String>,
     /// Arguments to pass to Docker build command.
     #[serde(default)]
     build_args: HashMap<String, String>,
+    /// Lint the Dockerfile before building and log any warnings. Default is false.
+    #[serde(default = "crate::serde_defaults::default_false")]
+    lint: bool,
 }
 
 impl BuildOpts {
     async fn build(&self, image: &str) -> Result<()> {
         let client = super::util::client()?;
 
+        let dockerfile = match &self.context {
+            Some(handle) => super::context::resolve_file(handle, &self.dockerfile)?,
+            None => self.dockerfile.clone(),
+        };
+
+        if self.lint {
+            for warning in super::lint::lint_file(&dockerfile)? {
+                tracing::warn!(
+                    dockerfile = ?dockerfile,
+                    rule = ?warning.rule,
+                    line = warning.line,
+                    "{}",
+                    warning.message
+                );
+            }
+        }
+
         let image_options = BuildImageOptions {
-            dockerfile: self
-                .dockerfile
+            dockerfile: dockerfile
                 .file_name()
                 .and_then(|f| f.to_str())
                 .ok_or_else(|| {
-                    anyhow::anyhow!("dockerfile path invalid `{}`", self.dockerfile.display())
+                    anyhow::anyhow!("dockerfile path invalid `{}`", dockerfile.display())
                 })?,
             t: image,
             q: false,
@@ -171,62 +566,14 @@ impl BuildOpts {
             ..Default::default()
         };
 
-        let build_root = self.dockerfile.parent().ok_or_else(|| {
+        let build_root = dockerfile.parent().ok_or_else(|| {
             anyhow::anyhow!(
                 "unable to get build root for dockerfile `{}`",
-                self.dockerfile.display()
+                dockerfile.display()
             )
         })?;
 
-        let dockerignore = build_root.join(".dockerignore");
-        let ignore = dockerignore
-            .exists()
-            .then(|| gitignore::File::new(&dockerignore).ok())
-            .flatten();
-
-        let mut buf = Vec::new();
-        let enc = GzEncoder::new(&mut buf, Compression::default());
-        let mut tar = tar::Builder::new(enc);
-
-        jwalk::WalkDir::new(build_root)
-        .skip_hidden(false)
-        .follow_links(false)
-        .into_iter()
-        .filter_map(Result::ok)
-        .filter_map(|p| {
-            let p = p.path();
-            let name = p.strip_prefix(&build_root).unwrap_or(&p);
-            if name.components().take(1).next().is_none() {
-                tracing::trace!(host_path = ?p, tar_path = ?name, "skipping empty filename");
-                return None;
-            }
-            if let Some(ref ignore) = ignore {
-                match ignore.is_excluded(&p) {
-                    Ok(true) => {
-                        tracing::trace!(host_path = ?p, tar_path = ?name, "skipping ignored filename");
-                        return None;
-                    },
-                    Err(e) => return Some(Err(e).map_err(anyhow::Error::from)),
-                    _ => {}
-                }
-            }
-            tracing::debug!(host_path = ?p, tar_path = ?name, "adding file to archive");
-            Some(
-                tar.append_path_with_name(&p, &name)
-                    .with_context(|| format!("failed to add file to tar archive {:?}", &p)),
-            )
-        })
-        .collect::<Result<_>>()?;
-
-        tar.append_dir_all(".", build_root)
-            .context("failed to add buildroot to tar archive")?;
-
-        // FIXME: would be nice if we didn't have to clone here
-        let buf = tar
-            .into_inner()
-            .and_then(|t| t.finish())
-            .context("failed to write to tar archive")?
-            .clone();
+        let buf = super::context::archive(build_root)?;
 
         let mut stream = client.build_image(image_options, None, Some(buf.into()));
         while let Some(res) = stream.next().await {
@@ -257,12 +604,27 @@ mod tests {
     use super::*;
     use tracing_test::traced_test;
 
+    #[test]
+    fn test_is_image_corrupt() {
+        let err = anyhow::anyhow!("failed to register layer: open /var/lib/docker/foo")
+            .context("failed to acquire container image");
+        assert!(is_image_corrupt(&err));
+        let err = anyhow::anyhow!("No such image: bash").context("failed to create container");
+        assert!(!is_image_corrupt(&err));
+        // Network hiccups say nothing about the local copy of the image
+        let err = anyhow::anyhow!("unexpected EOF").context("failed to pull image");
+        assert!(!is_image_corrupt(&err));
+    }
+
     #[test]
     #[traced_test]
     fn test_image_pull_policy_serde() {
         let p = "never";
         assert_yaml_deserialize::<ImagePullPolicy>(p);
 
+        let p = "committed";
+        assert_yaml_deserialize::<ImagePullPolicy>(p);
+
         let p = "
         pull:
             always: true
@@ -280,6 +642,82 @@ mod tests {
                 baz: qux
         ";
         assert_yaml_deserialize::<ImagePullPolicy>(p);
+
+        let p = "
+        build:
+            dockerfile: Dockerfile
+            context: 936da01f-9abd-4d9d-80c7-02af85c822a8
+            lint: true
+        ";
+        assert_yaml_deserialize::<ImagePullPolicy>(p);
+    }
+
+    #[test]
+    fn test_pull_credentials() {
+        let p = "
+        pull:
+            repo: registry.example.com/houdini
+            username: houdini
+            passwordEnv: HOUDINI_TEST_REGISTRY_PASSWORD
+        ";
+        let opts = match assert_yaml_deserialize::<ImagePullPolicy>(p) {
+            ImagePullPolicy::Pull(opts) => opts,
+            policy => panic!("expected a pull policy, got {:?}", policy),
+        };
+        assert_eq!(opts.username.as_deref(), Some("houdini"));
+        assert_eq!(
+            opts.password_env.as_deref(),
+            Some("HOUDINI_TEST_REGISTRY_PASSWORD")
+        );
+
+        opts.credentials_from_vars(|_| None)
+            .expect_err("unset password variable should fail");
+        let credentials = opts
+            .credentials_from_vars(|var| {
+                (var == "HOUDINI_TEST_REGISTRY_PASSWORD").then(|| "hunter2".into())
+            })
+            .expect("credentials should resolve")
+            .expect("credentials should be set");
+        assert_eq!(credentials.username.as_deref(), Some("houdini"));
+        assert_eq!(credentials.password.as_deref(), Some("hunter2"));
+
+        let p = "
+        pull:
+            password: hunter2
+            identityToken: token
+        ";
+        // Not round-tripped, since the secrets are not serialized
+        let opts = match serde_yaml::from_str::<ImagePullPolicy>(p).unwrap() {
+            ImagePullPolicy::Pull(opts) => opts,
+            policy => panic!("expected a pull policy, got {:?}", policy),
+        };
+        let debug = format!("{:?}", opts);
+        assert!(
+            !debug.contains("hunter2") && !debug.contains("token\""),
+            "{}",
+            debug
+        );
+        let yaml = serde_yaml::to_string(&opts).unwrap();
+        assert!(
+            !yaml.contains("hunter2") && !yaml.contains("token"),
+            "{}",
+            yaml
+        );
+
+        let opts = PullOpts {
+            password_env: Some("HOUDINI_TEST_REGISTRY_PASSWORD".into()),
+            ..opts
+        };
+        opts.credentials_from_vars(|_| Some("hunter2".into()))
+            .expect_err("password and passwordEnv should be exclusive");
+
+        // Omitting credentials pulls anonymously, as before
+        let opts: PullOpts = assert_yaml_deserialize("always: false");
+        assert!(opts.credentials().unwrap().is_none());
+        assert_eq!(
+            serde_yaml::to_string(&opts).unwrap(),
+            "---\nalways: false\nsha256sum: ~\nrepo: ~\n"
+        );
     }
 
     #[tokio::test]
@@ -291,7 +729,9 @@ mod tests {
 
         let opts = BuildOpts {
             dockerfile: d,
+            context: None,
             build_args: HashMap::default(),
+            lint: true,
         };
 
         opts.build("foo").await.expect("image should build");
@@ -302,5 +742,127 @@ mod tests {
             .inspect_image("foo")
             .await
             .expect("image should exist");
+
+        let provenance = image_provenance("foo")
+            .await
+            .expect("image should have provenance");
+        assert!(provenance.id.starts_with("sha256:"), "{:?}", provenance);
+        assert!(provenance.created.is_some(), "{:?}", provenance);
+        assert_eq!(
+            provenance.labels,
+            BTreeMap::from([
+                (
+                    "org.opencontainers.image.source".to_owned(),
+                    "https://github.com/willfindlay/houdini".to_owned()
+                ),
+                (
+                    "org.opencontainers.image.title".to_owned(),
+                    "houdini-test".to_owned()
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_available_platforms() {
+        let json = r#"{
+            "Descriptor": {
+                "mediaType": "application/vnd.docker.distribution.manifest.list.v2+json",
+                "digest": "sha256:deadbeef",
+                "size": 1234
+            },
+            "Platforms": [
+                {"architecture": "amd64", "os": "linux"},
+                {"architecture": "arm", "os": "linux", "variant": "v7"},
+                {"architecture": "arm64", "os": "linux", "variant": "v8"},
+                {"architecture": "unknown", "os": ""}
+            ]
+        }"#;
+        let inspect: DistributionInspect =
+            serde_json::from_str(json).expect("response should parse");
+
+        let platforms = available_platforms(inspect);
+        assert_eq!(
+            platforms
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            vec!["linux/amd64", "linux/arm/v7", "linux/arm64/v8"]
+        );
+    }
+
+    #[test]
+    fn test_image_provenance_from_inspect() {
+        let inspect = ImageInspect {
+            id: Some("sha256:1234".into()),
+            repo_digests: Some(vec![
+                "bash@sha256:deadbeef".into(),
+                "mirror/bash@sha256:deadbeef".into(),
+            ]),
+            created: Some("2022-08-01T00:00:00Z".into()),
+            os: Some("linux".into()),
+            architecture: Some("arm64".into()),
+            variant: Some("v8".into()),
+            config: Some(bollard::models::ContainerConfig {
+                labels: Some(HashMap::from([
+                    (
+                        "org.opencontainers.image.revision".to_owned(),
+                        "abc123".to_owned(),
+                    ),
+                    ("maintainer".to_owned(), "someone".to_owned()),
+                ])),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            ImageProvenance::from_inspect(inspect),
+            ImageProvenance {
+                id: "sha256:1234".into(),
+                digest: Some("bash@sha256:deadbeef".into()),
+                created: Some("2022-08-01T00:00:00Z".into()),
+                labels: BTreeMap::from([(
+                    "org.opencontainers.image.revision".to_owned(),
+                    "abc123".to_owned()
+                )]),
+                selected_platform: Some(Platform {
+                    os: "linux".into(),
+                    architecture: "arm64".into(),
+                    variant: Some("v8".into()),
+                }),
+                available_platforms: vec![],
+            }
+        );
+
+        let provenance = ImageProvenance::from_inspect(ImageInspect::default());
+        assert_eq!(provenance, ImageProvenance::default());
+    }
+
+    #[test]
+    fn test_verify_digest() {
+        let digests = || Some(vec!["bash@sha256:deadbeef".to_owned()]);
+
+        verify_digest(None, digests()).expect("no expected digest should pass");
+        verify_digest(None, None).expect("no expected digest should pass");
+        verify_digest(Some("deadbeef"), digests()).expect("matching digest should pass");
+        verify_digest(Some("badc0ffee"), digests()).expect_err("wrong digest should fail");
+        verify_digest(Some("deadbeef"), None).expect_err("missing digest should fail");
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    #[serial_test::serial]
+    async fn test_pull_verifies_local_image() {
+        let opts = PullOpts::default();
+        opts.pull("bash").await.expect("image should pull");
+
+        let opts = PullOpts {
+            sha256sum: Some("0000000000000000".into()),
+            ..Default::default()
+        };
+        opts.pull("bash")
+            .await
+            .expect_err("present image with wrong digest should fail");
     }
 }
@@ -0,0 +1,117 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+
+//! A limit on how many operations Houdini issues to the Docker daemon at once, shared
+//! by every trick that is running.
+
+use std::{
+    future::Future,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use anyhow::Result;
+use lazy_static::lazy_static;
+use tokio::sync::Semaphore;
+
+use crate::CONFIG;
+
+/// Limit set by [`limit_daemon_ops`], or zero if it was not called.
+static MAX_DAEMON_OPS: AtomicUsize = AtomicUsize::new(0);
+/// Whether [`DAEMON_OPS`] has been initialized, after which its limit is fixed.
+static DAEMON_OPS_INIT: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref DAEMON_OPS: OpLimit = {
+        DAEMON_OPS_INIT.store(true, Ordering::SeqCst);
+        match MAX_DAEMON_OPS.load(Ordering::SeqCst) {
+            0 => OpLimit::new(CONFIG.docker.max_concurrent_ops),
+            max => OpLimit::new(Some(max)),
+        }
+    };
+}
+
+/// Limit the number of concurrent daemon operations to `max`, overriding
+/// `docker.maxConcurrentOps` from Houdini's config. This must be called before the first
+/// operation is issued.
+pub fn limit_daemon_ops(max: usize) -> Result<()> {
+    if DAEMON_OPS_INIT.load(Ordering::SeqCst)
+        || MAX_DAEMON_OPS
+            .compare_exchange(0, max.max(1), Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+    {
+        anyhow::bail!("daemon operation limit is already set");
+    }
+    Ok(())
+}
+
+/// Run `op` once the daemon operation limit allows it. Operations must not nest, or they
+/// may wait on each other forever, and should be short API calls rather than anything
+/// that waits on a container, like an exec running to completion.
+pub(super) async fn limited<F: Future>(op: F) -> F::Output {
+    DAEMON_OPS.run(op).await
+}
+
+/// Caps how many operations run at once. Without a cap, operations run as soon as they
+/// are issued.
+struct OpLimit {
+    semaphore: Option<Semaphore>,
+}
+
+impl OpLimit {
+    fn new(max: Option<usize>) -> Self {
+        Self {
+            semaphore: max.map(|max| Semaphore::new(max.max(1))),
+        }
+    }
+
+    async fn run<F: Future>(&self, op: F) -> F::Output {
+        let _permit = match &self.semaphore {
+            Some(semaphore) => Some(
+                semaphore
+                    .acquire()
+                    .await
+                    .expect("daemon operation semaphore is never closed"),
+            ),
+            None => None,
+        };
+        op.await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use super::*;
+
+    /// Run `ops` stub operations under `limit` at once, returning the most that ran
+    /// simultaneously.
+    async fn max_simultaneous(limit: &OpLimit, ops: usize) -> usize {
+        let running = AtomicUsize::new(0);
+        let max = AtomicUsize::new(0);
+
+        let op = || async {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            max.fetch_max(now, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            running.fetch_sub(1, Ordering::SeqCst);
+        };
+        futures::future::join_all((0..ops).map(|_| limit.run(op()))).await;
+
+        max.load(Ordering::SeqCst)
+    }
+
+    #[tokio::test]
+    async fn test_op_limit() {
+        assert_eq!(max_simultaneous(&OpLimit::new(Some(2)), 6).await, 2);
+        assert_eq!(max_simultaneous(&OpLimit::new(Some(0)), 3).await, 1);
+        assert_eq!(max_simultaneous(&OpLimit::new(None), 6).await, 6);
+    }
+}
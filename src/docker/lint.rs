@@ -0,0 +1,251 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+
+//! A lightweight Dockerfile linter that flags common hygiene issues before an image is
+//! built. This is not meant to replace a full linter like hadolint, only to catch the
+//! most common mistakes in exploit images.
+
+use std::{fmt::Display, path::Path};
+
+use anyhow::{Context as _, Result};
+use serde::Serialize;
+
+/// A lint rule that a Dockerfile can violate.
+#[derive(Debug, Serialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum LintRule {
+    /// A base image is untagged or uses the `latest` tag.
+    LatestTag,
+    /// The final stage explicitly switches to the root user.
+    RootUser,
+    /// The final stage never sets a `USER`, so it runs as root.
+    MissingUser,
+}
+
+/// A single lint warning for a Dockerfile.
+#[derive(Debug, Serialize, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LintWarning {
+    /// The rule that was violated.
+    pub rule: LintRule,
+    /// The line of the offending instruction, starting from 1.
+    pub line: usize,
+    /// A human readable description of the problem.
+    pub message: String,
+}
+
+impl Display for LintWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Lint the Dockerfile at `path`.
+pub fn lint_file(path: &Path) -> Result<Vec<LintWarning>> {
+    let contents = std::fs::read_to_string(path)
+        .context(format!("could not read dockerfile {}", path.display()))?;
+    Ok(lint(&contents))
+}
+
+/// Lint the contents of a Dockerfile, returning a list of warnings in line order.
+pub fn lint(contents: &str) -> Vec<LintWarning> {
+    let mut warnings = vec![];
+
+    // Names of previous build stages, which may be used as base images
+    let mut stages: Vec<String> = vec![];
+    // Line of the last FROM and the last USER (with its line) in the current stage
+    let mut from_line = None;
+    let mut user: Option<(usize, String)> = None;
+
+    for (line, instruction, args) in instructions(contents) {
+        match instruction.as_str() {
+            "FROM" => {
+                from_line = Some(line);
+                user = None;
+
+                let mut args = args.split_whitespace().filter(|a| !a.starts_with("--"));
+                let image = args.next().unwrap_or_default();
+                if let (Some(as_), Some(name)) = (args.next(), args.next()) {
+                    if as_.eq_ignore_ascii_case("as") {
+                        stages.push(name.to_lowercase());
+                    }
+                }
+
+                if image != "scratch"
+                    && !stages.contains(&image.to_lowercase())
+                    && uses_latest(image)
+                {
+                    warnings.push(LintWarning {
+                        rule: LintRule::LatestTag,
+                        line,
+                        message: format!(
+                            "base image `{}` should be pinned to a specific tag or digest",
+                            image
+                        ),
+                    });
+                }
+            }
+            "USER" => user = Some((line, args)),
+            _ => {}
+        }
+    }
+
+    match (from_line, user) {
+        (Some(_), Some((line, user))) if is_root(&user) => warnings.push(LintWarning {
+            rule: LintRule::RootUser,
+            line,
+            message: "final stage runs as root".into(),
+        }),
+        (Some(line), None) => warnings.push(LintWarning {
+            rule: LintRule::MissingUser,
+            line,
+            message: "final stage does not set a USER and will run as root".into(),
+        }),
+        _ => {}
+    }
+
+    warnings.sort_by_key(|w| w.line);
+    warnings
+}
+
+/// Split a Dockerfile into `(line, INSTRUCTION, args)` triples, joining line
+/// continuations and skipping comments and blank lines.
+fn instructions(contents: &str) -> Vec<(usize, String, String)> {
+    let mut out = vec![];
+    let mut current: Option<(usize, String)> = None;
+
+    for (i, raw) in contents.lines().enumerate() {
+        let trimmed = raw.trim();
+        if trimmed.starts_with('#') || (trimmed.is_empty() && current.is_none()) {
+            continue;
+        }
+
+        let (start, mut buf) = current.take().unwrap_or((i + 1, String::new()));
+        match trimmed.strip_suffix('\\') {
+            Some(partial) => {
+                buf.push_str(partial);
+                buf.push(' ');
+                current = Some((start, buf));
+            }
+            None => {
+                buf.push_str(trimmed);
+                let (instruction, args) = buf.split_once(char::is_whitespace).unwrap_or((&buf, ""));
+                out.push((start, instruction.to_uppercase(), args.trim().to_owned()));
+            }
+        }
+    }
+
+    out
+}
+
+/// Does this image reference use an implicit or explicit `latest` tag?
+fn uses_latest(image: &str) -> bool {
+    if image.contains('@') {
+        return false;
+    }
+    // Only look for a tag after the last path component, since the registry may
+    // contain a port
+    let name = image.rsplit('/').next().unwrap_or(image);
+    match name.split_once(':') {
+        Some((_, tag)) => tag == "latest",
+        None => true,
+    }
+}
+
+/// Does this `USER` argument refer to root?
+fn is_root(user: &str) -> bool {
+    let name = user.split(':').next().unwrap_or(user).trim();
+    name == "root" || name == "0"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(contents: &str) -> Vec<(LintRule, usize)> {
+        lint(contents)
+            .into_iter()
+            .map(|w| (w.rule, w.line))
+            .collect()
+    }
+
+    #[test]
+    fn test_latest_tag() {
+        let dockerfile = "
+FROM ubuntu
+FROM ubuntu:latest
+FROM localhost:5000/ubuntu
+FROM ubuntu:22.04
+FROM ubuntu@sha256:deadbeef
+FROM scratch
+USER nobody
+";
+        assert_eq!(
+            rules(dockerfile),
+            vec![
+                (LintRule::LatestTag, 2),
+                (LintRule::LatestTag, 3),
+                (LintRule::LatestTag, 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stage_names_are_not_images() {
+        let dockerfile = "
+FROM --platform=linux/amd64 golang:1.19 AS builder
+RUN go build
+FROM builder
+USER 1000
+";
+        assert!(rules(dockerfile).is_empty());
+    }
+
+    #[test]
+    fn test_root_user() {
+        let dockerfile = "
+FROM alpine:3.16
+USER nobody
+# switch back to root
+user root:root
+";
+        assert_eq!(rules(dockerfile), vec![(LintRule::RootUser, 5)]);
+
+        let dockerfile = "FROM alpine:3.16\nUSER 0";
+        assert_eq!(rules(dockerfile), vec![(LintRule::RootUser, 2)]);
+    }
+
+    #[test]
+    fn test_missing_user() {
+        // A USER in an earlier stage does not count for the final stage
+        let dockerfile = "
+FROM alpine:3.16 AS build
+USER nobody
+FROM alpine:3.16
+RUN apk add \\
+    curl \\
+    bash
+";
+        assert_eq!(rules(dockerfile), vec![(LintRule::MissingUser, 4)]);
+    }
+
+    #[test]
+    fn test_lint_file() {
+        let mut d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("testdata/imgbuild/Dockerfile");
+
+        let warnings = lint_file(&d).expect("dockerfile should lint");
+        assert_eq!(
+            warnings.iter().map(|w| w.rule).collect::<Vec<_>>(),
+            vec![LintRule::MissingUser]
+        );
+        assert_eq!(
+            warnings[0].to_string(),
+            "line 1: final stage does not set a USER and will run as root"
+        );
+    }
+}
@@ -0,0 +1,116 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+
+//! Helpers for creating the networks that containers are attached to.
+
+use anyhow::{Context as _, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    container::is_not_found,
+    util::{client, MANAGED_LABEL},
+};
+
+/// The driver option that sets a bridge network's MTU.
+pub(super) const MTU_OPTION: &str = "com.docker.network.driver.mtu";
+
+/// Networks that Docker always has, which are never created or renamed.
+const PREDEFINED_NETWORKS: &[&str] = &["bridge", "host", "none"];
+
+/// A network to attach a spawned container to. Unless it is external, the network is
+/// created if it does not already exist, and renamed for each run like containers are.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct NetworkOpts {
+    /// Name of the network.
+    pub name: String,
+    /// DNS servers for the container to use instead of the host's.
+    #[serde(default)]
+    pub dns: Vec<String>,
+    /// MTU of the network, if it is created. Default is Docker's default.
+    #[serde(default)]
+    pub mtu: Option<u32>,
+    /// Attach to an existing network by its name, rather than to one the trick creates.
+    /// Docker's predefined `bridge`, `host`, and `none` networks are always external.
+    /// Default is false.
+    #[serde(default = "crate::serde_defaults::default_false")]
+    pub external: bool,
+}
+
+impl NetworkOpts {
+    /// Is this an existing network, which the trick neither creates nor renames?
+    pub fn is_external(&self) -> bool {
+        self.external || PREDEFINED_NETWORKS.contains(&self.name.as_str())
+    }
+}
+
+/// Remove a network, if Houdini created it. Networks that do not exist or that Houdini did
+/// not create are left alone.
+pub async fn remove_network(name: &str) -> Result<()> {
+    let client = client()?;
+
+    let network = match client.inspect_network::<&str>(name, None).await {
+        Err(e) if is_not_found(&e) => {
+            tracing::debug!(name = ?name, "network already removed");
+            return Ok(());
+        }
+        res => res.context("failed to inspect network")?,
+    };
+    let managed = network
+        .labels
+        .unwrap_or_default()
+        .contains_key(MANAGED_LABEL);
+    if !managed {
+        tracing::debug!(name = ?name, "not removing network that houdini did not create");
+        return Ok(());
+    }
+
+    match client.remove_network(name).await {
+        Err(e) if is_not_found(&e) => Ok(()),
+        res => res.context("failed to remove network"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::{assert_yaml_deserialize, assert_yaml_serialize};
+
+    #[test]
+    fn test_network_serde() {
+        let yaml = r#"
+            name: houdini-net
+            dns: ["1.1.1.1", "8.8.8.8"]
+            mtu: 1400
+            "#;
+        let network: NetworkOpts = assert_yaml_deserialize(yaml);
+        assert_eq!(
+            network,
+            NetworkOpts {
+                name: "houdini-net".into(),
+                dns: vec!["1.1.1.1".into(), "8.8.8.8".into()],
+                mtu: Some(1400),
+                external: false,
+            }
+        );
+        assert_yaml_serialize(&network);
+
+        let network: NetworkOpts = assert_yaml_deserialize("name: houdini-net");
+        assert!(network.dns.is_empty());
+        assert_eq!(network.mtu, None);
+        assert!(!network.is_external());
+
+        let network: NetworkOpts = assert_yaml_deserialize("name: shared\nexternal: true");
+        assert!(network.is_external());
+        let network: NetworkOpts = assert_yaml_deserialize("name: bridge");
+        assert!(network.is_external());
+
+        serde_yaml::from_str::<NetworkOpts>("name: houdini-net\nmtu: -1")
+            .expect_err("negative mtu should fail");
+    }
+}
@@ -7,10 +7,16 @@
 
 //! Helpers for managing the Docker client. For internal use.
 
+use std::path::Path;
+
 use anyhow::{Context, Result};
 use bollard::{Docker, API_DEFAULT_VERSION};
 
-use crate::config::CONFIG;
+use crate::{config::CONFIG, error::HarnessError};
+
+/// Label applied to containers, networks, and volumes that Houdini creates, so that it
+/// only ever removes its own.
+pub(super) const MANAGED_LABEL: &str = "houdini.managed";
 
 /// Spawn a bollard::Docker using the configured Unix socket and the default API version.
 pub fn client() -> Result<Docker> {
@@ -19,9 +25,49 @@ pub fn client() -> Result<Docker> {
             .docker
             .socket
             .to_str()
-            .ok_or_else(|| anyhow::anyhow!("bad docker socket path in config"))?,
+            .ok_or_else(|| anyhow::anyhow!("bad docker socket path in config"))
+            .context(HarnessError("invalid docker config"))?,
         60,
         API_DEFAULT_VERSION,
     )
-    .context("failed to spawn client")
+    .context(HarnessError("failed to spawn client"))
+}
+
+/// Check that the Docker daemon is reachable over the configured socket, failing with an
+/// actionable error if it is not.
+pub async fn check_daemon() -> Result<()> {
+    check_daemon_at(&CONFIG.docker.socket).await
+}
+
+async fn check_daemon_at(socket: &Path) -> Result<()> {
+    let res = match socket.to_str() {
+        Some(path) => match Docker::connect_with_unix(path, 10, API_DEFAULT_VERSION) {
+            Ok(client) => client.ping().await.map(|_| ()),
+            Err(e) => Err(e),
+        },
+        None => anyhow::bail!("bad docker socket path in config: {}", socket.display()),
+    };
+
+    res.context(HarnessError("failed to ping docker daemon"))
+        .context(format!(
+            "Docker daemon not reachable at {}; is it running?",
+            socket.display()
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_check_daemon_bogus_socket() {
+        let err = check_daemon_at(Path::new("/nonexistent/docker.sock"))
+            .await
+            .expect_err("bogus socket should not be reachable");
+        assert_eq!(
+            err.to_string(),
+            "Docker daemon not reachable at /nonexistent/docker.sock; is it running?"
+        );
+        assert!(crate::error::is_harness_error(&err));
+    }
 }
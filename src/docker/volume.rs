@@ -0,0 +1,65 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+
+//! Helpers for creating and removing the named volumes that containers mount.
+
+use anyhow::{Context as _, Result};
+use bollard::volume::RemoveVolumeOptions;
+
+use super::{
+    container::is_not_found,
+    util::{client, MANAGED_LABEL},
+};
+
+/// Get the name of the volume that a bind spec such as `data:/data:ro` mounts, if it
+/// mounts a named volume rather than a host path.
+pub fn named_volume(spec: &str) -> Option<&str> {
+    let (source, _) = spec.split_once(':')?;
+    let is_path = source.is_empty() || source.starts_with(['/', '.', '~']);
+    (!is_path).then_some(source)
+}
+
+/// Remove a volume, if Houdini created it. Volumes that do not exist or that Houdini did
+/// not create are left alone.
+pub async fn remove_volume(name: &str) -> Result<()> {
+    let client = client()?;
+
+    let volume = match client.inspect_volume(name).await {
+        Err(e) if is_not_found(&e) => {
+            tracing::debug!(name = ?name, "volume already removed");
+            return Ok(());
+        }
+        res => res.context("failed to inspect volume")?,
+    };
+    if !volume.labels.contains_key(MANAGED_LABEL) {
+        tracing::debug!(name = ?name, "not removing volume that houdini did not create");
+        return Ok(());
+    }
+
+    match client
+        .remove_volume(name, Some(RemoveVolumeOptions { force: false }))
+        .await
+    {
+        Err(e) if is_not_found(&e) => Ok(()),
+        res => res.context("failed to remove volume"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_named_volume() {
+        assert_eq!(named_volume("data:/data"), Some("data"));
+        assert_eq!(named_volume("houdini-data:/data:ro"), Some("houdini-data"));
+        assert_eq!(named_volume("/tmp:/host/tmp:ro"), None);
+        assert_eq!(named_volume("./out:/out"), None);
+        assert_eq!(named_volume("~/out:/out"), None);
+        assert_eq!(named_volume("/data"), None);
+    }
+}
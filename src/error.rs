@@ -0,0 +1,108 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+
+//! Error types used to classify failures in Houdini's own harness.
+
+use std::fmt::Display;
+
+/// An error in Houdini's own harness (e.g. Docker is unreachable or the config is bad),
+/// as opposed to the exploit under test genuinely failing. Attach it to an error chain
+/// using [`anyhow::Context::context`].
+#[derive(Debug)]
+pub struct HarnessError(pub &'static str);
+
+impl Display for HarnessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A mistake in how a trick is set up (e.g. an invalid regex), as opposed to the exploit
+/// under test genuinely failing. Steps that fail with it end in setup failure, whatever
+/// their failure status. Attach it to an error chain using [`anyhow::Context::context`].
+#[derive(Debug)]
+pub struct SetupError(pub &'static str);
+
+impl Display for SetupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Does an error chain originate from a mistake in how the trick is set up? This is the
+/// case if any error in the chain is a [`SetupError`].
+pub fn is_setup_error(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<SetupError>().is_some()
+}
+
+/// Does an error chain originate from Houdini's harness rather than the exploit? This is
+/// the case if any error in the chain is a [`HarnessError`] or a Docker transport error.
+/// Errors returned by the Docker daemon itself are not considered harness errors, since
+/// these often result from the exploit under test.
+pub fn is_harness_error(err: &anyhow::Error) -> bool {
+    if err.downcast_ref::<HarnessError>().is_some() {
+        return true;
+    }
+
+    err.chain().any(
+        |cause| match cause.downcast_ref::<bollard::errors::Error>() {
+            Some(bollard::errors::Error::DockerResponseServerError { .. }) => false,
+            Some(_) => true,
+            None => false,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context as _;
+    use bollard::{Docker, API_DEFAULT_VERSION};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_is_harness_error() {
+        let docker = Docker::connect_with_unix("/nonexistent/docker.sock", 1, API_DEFAULT_VERSION)
+            .expect("client should construct");
+        let err = docker
+            .ping()
+            .await
+            .context("failed to ping docker")
+            .expect_err("ping should fail");
+        assert!(
+            is_harness_error(&err),
+            "unreachable docker is a harness error"
+        );
+
+        let err = anyhow::anyhow!("command failed with exit code: 1");
+        assert!(
+            !is_harness_error(&err),
+            "plain errors are not harness errors"
+        );
+
+        let err = Err::<(), _>(anyhow::anyhow!("bad path")).context(HarnessError("bad config"));
+        assert!(is_harness_error(&err.unwrap_err()));
+
+        let err = anyhow::Error::from(bollard::errors::Error::DockerResponseServerError {
+            status_code: 404,
+            message: "no such container".into(),
+        });
+        assert!(
+            !is_harness_error(&err),
+            "docker daemon errors are not harness errors"
+        );
+    }
+
+    #[test]
+    fn test_is_setup_error() {
+        let err = Err::<(), _>(anyhow::anyhow!("bad regex")).context(SetupError("invalid regex"));
+        let err = err.context("outer").unwrap_err();
+        assert!(is_setup_error(&err));
+        assert!(!is_harness_error(&err));
+        assert!(!is_setup_error(&anyhow::anyhow!("command failed")));
+    }
+}
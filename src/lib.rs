@@ -12,6 +12,7 @@
 
 mod api;
 mod cli;
+mod error;
 mod serde_defaults;
 mod testutils;
 mod tricks;
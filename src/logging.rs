@@ -8,11 +8,23 @@
 
 //! This module contains helper functions to set up logging for Houdini.
 
-use crate::{cli, CONFIG};
-use anyhow::Result;
+use crate::{cli, config::RUNTIME, CONFIG};
+use anyhow::{Context as _, Result};
+use chrono::{DateTime, Utc};
 use clap_derive::ArgEnum;
-use std::{ffi::OsString, fmt::Display, path::PathBuf};
-use tracing::metadata::LevelFilter;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    ffi::OsString,
+    fmt::{Debug, Display},
+    fs::File,
+    future::Future,
+    io::{BufWriter, Write as _},
+    path::{Path, PathBuf},
+};
+use tracing::{field::Field, metadata::LevelFilter};
 use tracing_appender::non_blocking::WorkerGuard;
 use tracing_subscriber::{prelude::__tracing_subscriber_SubscriberExt, Layer, Registry};
 
@@ -38,11 +50,14 @@ impl Display for LoggingFormat {
     }
 }
 
-struct LevelFilterLayer {
+/// Filters what is logged to stdout according to the verbosity given on the command
+/// line. This filters only the stdout layer, so that the layers that embed logs in
+/// reports and write per-trick log files still see events at their own levels.
+struct StdoutLevelFilter {
     level: LevelFilter,
 }
 
-impl LevelFilterLayer {
+impl StdoutLevelFilter {
     pub fn from_args(args: &cli::Cli) -> Self {
         let level = match args.verbose {
             n if n < 0 => LevelFilter::OFF,
@@ -63,14 +78,202 @@ impl LevelFilterLayer {
     }
 }
 
-impl<S: tracing::Subscriber> Layer<S> for LevelFilterLayer {
+impl<S: tracing::Subscriber> tracing_subscriber::layer::Filter<S> for StdoutLevelFilter {
     fn enabled(
         &self,
         metadata: &tracing::Metadata<'_>,
-        ctx: tracing_subscriber::layer::Context<'_, S>,
+        _: &tracing_subscriber::layer::Context<'_, S>,
     ) -> bool {
-        self.level.enabled(metadata, ctx)
+        self.level >= *metadata.level()
     }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(self.level)
+    }
+}
+
+/// Filters the log file according to the runtime log level, which may be reloaded.
+struct RuntimeLevelFilter;
+
+impl<S: tracing::Subscriber> tracing_subscriber::layer::Filter<S> for RuntimeLevelFilter {
+    fn enabled(
+        &self,
+        metadata: &tracing::Metadata<'_>,
+        _: &tracing_subscriber::layer::Context<'_, S>,
+    ) -> bool {
+        file_level_enabled(metadata.level())
+    }
+}
+
+/// Is the given level enabled for the log file under the current runtime config?
+pub(crate) fn file_level_enabled(level: &tracing::Level) -> bool {
+    LevelFilter::from(RUNTIME.load().log_level) >= *level
+}
+
+tokio::task_local! {
+    /// Log messages captured for the trick running in the current task.
+    static CAPTURE: LogCapture;
+}
+
+/// A buffer of log messages captured at or above a given level.
+struct LogCapture {
+    level: LevelFilter,
+    entries: RefCell<Vec<LogEntry>>,
+}
+
+/// A log message embedded in a report.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct LogEntry {
+    /// When the message was logged.
+    pub timestamp: DateTime<Utc>,
+    /// Level of the message, e.g. `WARN`.
+    pub level: String,
+    /// Module that logged the message.
+    pub target: String,
+    /// The message itself.
+    pub message: String,
+    /// Any other fields attached to the message.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, String>,
+}
+
+impl Display for LogEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {:>5} {}: {}",
+            self.timestamp.to_rfc3339(),
+            self.level,
+            self.target,
+            self.message
+        )?;
+        for (name, value) in &self.fields {
+            write!(f, " {}={}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl LogEntry {
+    fn from_event(event: &tracing::Event<'_>) -> Self {
+        let metadata = event.metadata();
+        let mut entry = LogEntry {
+            timestamp: Utc::now(),
+            level: metadata.level().to_string(),
+            target: metadata.target().to_owned(),
+            message: String::new(),
+            fields: BTreeMap::new(),
+        };
+        event.record(&mut LogEntryVisitor(&mut entry));
+        entry
+    }
+}
+
+/// Collects the message and fields of an event into a [`LogEntry`].
+struct LogEntryVisitor<'a>(&'a mut LogEntry);
+
+impl tracing::field::Visit for LogEntryVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.0.message = value.to_owned(),
+            name => {
+                self.0.fields.insert(name.to_owned(), value.to_owned());
+            }
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        match field.name() {
+            "message" => self.0.message = format!("{:?}", value),
+            name => {
+                self.0
+                    .fields
+                    .insert(name.to_owned(), format!("{:?}", value));
+            }
+        }
+    }
+}
+
+/// Appends events to the [`LogCapture`] of the current task, if it has one.
+struct CaptureLayer;
+
+impl<S: tracing::Subscriber> Layer<S> for CaptureLayer {
+    fn on_event(&self, event: &tracing::Event<'_>, _: tracing_subscriber::layer::Context<'_, S>) {
+        let _ = CAPTURE.try_with(|capture| {
+            let metadata = event.metadata();
+            if capture.level < *metadata.level() {
+                return;
+            }
+
+            capture
+                .entries
+                .borrow_mut()
+                .push(LogEntry::from_event(event));
+        });
+    }
+}
+
+/// Run `fut`, capturing any log messages at or above `level` that it emits on the
+/// current task. Messages logged from other tasks are not captured.
+pub(crate) async fn capture<F: Future>(
+    level: crate::config::LevelFilter,
+    fut: F,
+) -> (F::Output, Vec<LogEntry>) {
+    let capture = LogCapture {
+        level: level.into(),
+        entries: RefCell::new(vec![]),
+    };
+
+    CAPTURE
+        .scope(capture, async {
+            let output = fut.await;
+            (output, CAPTURE.with(|capture| capture.entries.take()))
+        })
+        .await
+}
+
+tokio::task_local! {
+    /// File that log messages for the trick running in the current task are teed to.
+    static TEE: RefCell<BufWriter<File>>;
+}
+
+/// Writes events to the [`TEE`] file of the current task, if it has one, at the levels
+/// enabled for the log file.
+pub(crate) struct TeeLayer;
+
+impl<S: tracing::Subscriber> Layer<S> for TeeLayer {
+    fn on_event(&self, event: &tracing::Event<'_>, _: tracing_subscriber::layer::Context<'_, S>) {
+        let _ = TEE.try_with(|tee| {
+            if !file_level_enabled(event.metadata().level()) {
+                return;
+            }
+            // Losing a line of a trick's log is not worth failing the trick over
+            let _ = writeln!(tee.borrow_mut(), "{}", LogEntry::from_event(event));
+        });
+    }
+}
+
+/// Create a log file at `path` for [`tee`], along with its directory.
+pub(crate) fn create_tee_file(path: &Path) -> Result<File> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .context(format!("failed to create log directory {}", dir.display()))?;
+    }
+    File::create(path).context(format!("failed to create log file {}", path.display()))
+}
+
+/// Run `fut`, also writing any log messages that it emits on the current task to `file`.
+/// Messages logged from other tasks are not written.
+pub(crate) async fn tee<F: Future>(file: File, fut: F) -> F::Output {
+    TEE.scope(RefCell::new(BufWriter::new(file)), async {
+        let output = fut.await;
+        if let Err(e) = TEE.with(|tee| tee.borrow_mut().flush()) {
+            tracing::warn!(err = ?e, "failed to write log file");
+        }
+        output
+    })
+    .await
 }
 
 fn get_log_file() -> Result<(Option<PathBuf>, Option<OsString>)> {
@@ -107,13 +310,18 @@ fn init_human(args: &cli::Cli) -> Result<Option<WorkerGuard>> {
         .with_thread_ids(false)
         .with_line_number(true)
         .with_thread_names(true)
-        .and_then(LevelFilterLayer::from_args(args));
+        .with_filter(StdoutLevelFilter::from_args(args));
 
     if let Some(file_appender) = file_appender {
         let file_layer = tracing_subscriber::fmt::layer()
             .with_writer(file_appender)
-            .json();
-        let subscriber = Registry::default().with(stdout_layer).with(file_layer);
+            .json()
+            .with_filter(RuntimeLevelFilter);
+        let subscriber = Registry::default()
+            .with(stdout_layer)
+            .with(file_layer)
+            .with(CaptureLayer)
+            .with(TeeLayer);
         tracing::subscriber::set_global_default(subscriber)?;
     }
 
@@ -138,13 +346,18 @@ fn init_json(args: &cli::Cli) -> Result<Option<WorkerGuard>> {
         .with_line_number(true)
         .with_thread_names(true)
         .json()
-        .and_then(LevelFilterLayer::from_args(args));
+        .with_filter(StdoutLevelFilter::from_args(args));
 
     if let Some(file_appender) = file_appender {
         let file_layer = tracing_subscriber::fmt::layer()
             .with_writer(file_appender)
-            .json();
-        let subscriber = Registry::default().with(stdout_layer).with(file_layer);
+            .json()
+            .with_filter(RuntimeLevelFilter);
+        let subscriber = Registry::default()
+            .with(stdout_layer)
+            .with(file_layer)
+            .with(CaptureLayer)
+            .with(TeeLayer);
         tracing::subscriber::set_global_default(subscriber)?;
     }
 
@@ -168,13 +381,18 @@ fn init_compact(args: &cli::Cli) -> Result<Option<WorkerGuard>> {
         .with_line_number(true)
         .with_thread_names(true)
         .compact()
-        .and_then(LevelFilterLayer::from_args(args));
+        .with_filter(StdoutLevelFilter::from_args(args));
 
     if let Some(file_appender) = file_appender {
         let file_layer = tracing_subscriber::fmt::layer()
             .with_writer(file_appender)
-            .json();
-        let subscriber = Registry::default().with(stdout_layer).with(file_layer);
+            .json()
+            .with_filter(RuntimeLevelFilter);
+        let subscriber = Registry::default()
+            .with(stdout_layer)
+            .with(file_layer)
+            .with(CaptureLayer)
+            .with(TeeLayer);
         tracing::subscriber::set_global_default(subscriber)?;
     }
 
@@ -198,13 +416,18 @@ fn init_pretty(args: &cli::Cli) -> Result<Option<WorkerGuard>> {
         .with_line_number(true)
         .with_thread_names(true)
         .pretty()
-        .and_then(LevelFilterLayer::from_args(args));
+        .with_filter(StdoutLevelFilter::from_args(args));
 
     if let Some(file_appender) = file_appender {
         let file_layer = tracing_subscriber::fmt::layer()
             .with_writer(file_appender)
-            .json();
-        let subscriber = Registry::default().with(stdout_layer).with(file_layer);
+            .json()
+            .with_filter(RuntimeLevelFilter);
+        let subscriber = Registry::default()
+            .with(stdout_layer)
+            .with(file_layer)
+            .with(CaptureLayer)
+            .with(TeeLayer);
         tracing::subscriber::set_global_default(subscriber)?;
     }
 
@@ -236,3 +459,56 @@ pub fn init(args: &cli::Cli) -> Result<Option<WorkerGuard>> {
 
     Ok(guard)
 }
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser as _;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_capture() {
+        let subscriber = Registry::default().with(CaptureLayer);
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let ((), logs) = capture(crate::config::LevelFilter::Warn, async {
+            tracing::debug!("not embedded");
+            tracing::warn!(answer = 42, name = "foo", "embedded");
+            tracing::error!("also embedded");
+        })
+        .await;
+
+        tracing::error!("outside of capture");
+
+        assert_eq!(
+            logs.iter()
+                .map(|e| (e.level.as_str(), e.message.as_str()))
+                .collect::<Vec<_>>(),
+            vec![("WARN", "embedded"), ("ERROR", "also embedded")]
+        );
+        assert_eq!(logs[0].target, module_path!());
+        assert_eq!(logs[0].fields["answer"], "42");
+        assert_eq!(logs[0].fields["name"], "foo");
+    }
+
+    #[tokio::test]
+    async fn test_capture_below_stdout_level() {
+        let args =
+            cli::Cli::try_parse_from(["houdini", "run", "trick.yaml"]).expect("cli should parse");
+        let stdout_layer = tracing_subscriber::fmt::layer()
+            .with_writer(std::io::sink)
+            .with_filter(StdoutLevelFilter::from_args(&args));
+        let subscriber = Registry::default().with(stdout_layer).with(CaptureLayer);
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let ((), logs) = capture(crate::config::LevelFilter::Debug, async {
+            tracing::debug!("embedded despite the default verbosity");
+        })
+        .await;
+
+        assert_eq!(
+            logs.iter().map(|e| e.message.as_str()).collect::<Vec<_>>(),
+            vec!["embedded despite the default verbosity"]
+        );
+    }
+}
@@ -7,6 +7,8 @@
 
 //! Default helpers for serde types.
 
+use std::time::Duration;
+
 use crate::tricks::status::Status;
 
 pub fn default_true() -> bool {
@@ -25,17 +27,65 @@ pub fn default_skip() -> Status {
     Status::Skip
 }
 
+pub fn default_ok_exit_codes() -> Vec<i64> {
+    vec![0]
+}
+
+pub fn default_heartbeat() -> Duration {
+    Duration::from_secs(30)
+}
+
+pub fn default_poll_interval() -> Duration {
+    Duration::from_millis(500)
+}
+
+pub fn default_port_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+pub fn default_interpreter() -> String {
+    "sh".into()
+}
+
+pub fn default_max_inline_bytes() -> usize {
+    64 * 1024
+}
+
+pub fn default_locale() -> String {
+    "C".into()
+}
+
+pub fn default_timezone() -> String {
+    "UTC".into()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_defaults() {
-        assert_eq!(default_true(), true);
-        assert_eq!(default_false(), false);
+        assert!(default_true());
+        assert!(!default_false());
 
         assert_eq!(default_setup_failure(), Status::SetupFailure);
 
         assert_eq!(default_skip(), Status::Skip);
+
+        assert_eq!(default_ok_exit_codes(), vec![0]);
+
+        assert_eq!(default_heartbeat(), Duration::from_secs(30));
+
+        assert_eq!(default_poll_interval(), Duration::from_millis(500));
+
+        assert_eq!(default_port_timeout(), Duration::from_secs(30));
+
+        assert_eq!(default_interpreter(), "sh");
+
+        assert_eq!(default_max_inline_bytes(), 65536);
+
+        assert_eq!(default_locale(), "C");
+
+        assert_eq!(default_timezone(), "UTC");
     }
 }
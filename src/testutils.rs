@@ -47,3 +47,60 @@ pub fn assert_json_serialize<T: Serialize + DeserializeOwned + Debug + Eq>(obj:
     assert_eq!(obj, &obj2, "deserialized structs should be the same");
     json
 }
+
+/// Serialize a report as json and ensure that it conforms to the published report schema.
+pub fn assert_valid_report(report: &crate::tricks::report::Report) {
+    let json = serde_json::to_value(report).expect("should serialize");
+    if let Err(e) = crate::tricks::report::validate(&json) {
+        panic!("report should match schema: {:#}", e);
+    }
+}
+
+/// Ensure that a single trick's report conforms to the published report schema, as part
+/// of a report.
+pub fn assert_valid_trick_report(report: &crate::tricks::report::TrickReport) {
+    let json = serde_json::to_value(report).expect("should serialize");
+    let mut full = crate::tricks::report::Report::new();
+    full.add(serde_json::from_value(json).expect("should deserialize"));
+    assert_valid_report(&full);
+}
+
+/// Run a trick, ensuring that its report conforms to the published report schema.
+pub async fn run_valid(trick: &crate::tricks::Trick) -> crate::tricks::report::TrickReport {
+    run_valid_watched(trick, None).await
+}
+
+/// Run a trick until `cancel` is set, ensuring that its report conforms to the published
+/// report schema.
+pub async fn run_valid_watched(
+    trick: &crate::tricks::Trick,
+    cancel: Option<tokio::sync::watch::Receiver<bool>>,
+) -> crate::tricks::report::TrickReport {
+    let report = trick.run_watched(None, cancel).await;
+    assert_valid_trick_report(&report);
+    report
+}
+
+/// Wait up to five seconds for the process whose ID is in the file at `pidfile` to die,
+/// returning whether it did. Processes that have exited but not yet been reaped count as
+/// dead.
+pub async fn process_dies(pidfile: &std::path::Path) -> bool {
+    let pid = std::fs::read_to_string(pidfile).expect("pid should be written");
+    let stat = std::path::PathBuf::from(format!("/proc/{}/stat", pid.trim()));
+    for _ in 0..50 {
+        let dead = match std::fs::read_to_string(&stat) {
+            Ok(stat) => stat
+                .rsplit(')')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .starts_with('Z'),
+            Err(_) => true,
+        };
+        if dead {
+            return true;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    false
+}
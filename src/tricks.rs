@@ -12,52 +12,451 @@
 
 pub mod report;
 
+pub(crate) mod bundle;
+mod capture;
+mod cleanup;
+mod dmesg;
+mod host_state;
+pub(crate) mod parse;
+mod privileges;
+pub(crate) mod remote;
+mod schedule;
+pub(crate) mod starter;
 mod steps;
+mod template;
 
-use std::collections::HashSet;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
+use anyhow::{Context as _, Result};
+use futures::{stream::FuturesUnordered, StreamExt};
 use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
 
 use self::{
-    report::{StepReport, TrickReport},
+    capture::CaptureOpts,
+    cleanup::CleanupRegistry,
+    dmesg::DmesgCapture,
+    privileges::Privileges,
+    report::{Severity, StepReport, Termination, TrickReport},
+    schedule::{Schedule, ScheduledStep},
     status::Status,
-    steps::Step,
+    steps::{
+        command::ShellCommand,
+        container::{Container, SpawnContainer},
+        evidence::{self, evidence_dir},
+        kernel_module::unload_module,
+        shell::ShellTarget,
+        Step, StepOutcome,
+    },
+};
+use crate::{
+    docker::{
+        context, named_volume, reap_container, remove_network, remove_volume, ImagePullPolicy,
+    },
+    logging, CONFIG,
 };
-use crate::docker::reap_container;
 
 /// A series of steps for running and verifying the status of a container exploit.
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
 #[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct Trick {
     pub name: String,
-    steps: Vec<Step>,
+    /// The technique the trick attempts, e.g. a MITRE ATT&CK technique such as
+    /// `T1611`. Reports summarize coverage by technique.
+    #[serde(default)]
+    pub technique: Option<String>,
+    /// How severe it is for the trick's exploit to succeed: `low`, `medium`, `high`, or
+    /// `critical`. Reports summarize successful exploits by severity.
+    #[serde(default)]
+    pub severity: Option<Severity>,
+    /// Steps to run. Steps may be given an `id` and declare the IDs of steps they
+    /// `dependsOn`, in which case they wait for those steps to complete before running.
+    steps: Vec<ScheduledStep>,
+    /// Capture kernel log messages emitted while the trick runs. Default is false.
+    #[serde(default = "crate::serde_defaults::default_false")]
+    collect_dmesg: bool,
+    /// Run every host command in a fresh network namespace, as if each host and shell
+    /// step set `isolateNetwork`. This requires Houdini to run as root. Default is false.
+    #[serde(default = "crate::serde_defaults::default_false")]
+    isolate_network: bool,
+    /// File mode creation mask for every host, container, and shell command, as an octal
+    /// string, e.g. `"077"`, as if each of those steps set `umask`. Steps that set their
+    /// own take precedence.
+    #[serde(default, with = "steps::umask_serde")]
+    umask: Option<u32>,
+    /// How much of each step's command output to keep in the report, and whether to
+    /// spill the rest to files.
+    #[serde(default)]
+    capture: CaptureOpts,
+    /// Locale to run every host and container command in, set as `LANG` and `LC_ALL`.
+    /// Steps that set these in their own `env` take precedence. Default is `C`, so that
+    /// tools print output that can be parsed.
+    #[serde(default = "crate::serde_defaults::default_locale")]
+    locale: String,
+    /// Time zone to run every host and container command in, set as `TZ`, e.g.
+    /// `Asia/Tokyo` or `JST-9`. Steps that set `TZ` in their own `env` take
+    /// precedence. Default is `UTC`.
+    #[serde(default = "crate::serde_defaults::default_timezone")]
+    timezone: String,
+    /// The trick must run as root on the host. Checked before any step runs. Default is
+    /// false.
+    #[serde(default = "crate::serde_defaults::default_false")]
+    requires_root: bool,
+    /// Capabilities the trick needs on the host, e.g. `CAP_SYS_ADMIN`. Checked before
+    /// any step runs.
+    #[serde(default)]
+    required_capabilities: Vec<String>,
+    /// Parameters that can be substituted anywhere in the trick file as `{{name}}`,
+    /// mapped to their default values. Defaults can be overridden when the trick is
+    /// loaded.
+    #[serde(default)]
+    parameters: HashMap<String, String>,
+    /// File the trick was loaded from, if any. This is never read from or written to
+    /// the trick itself.
+    #[serde(skip)]
+    pub source_path: Option<PathBuf>,
+    /// Image substituted into every container this trick spawns, if the trick was
+    /// expanded over an image matrix. This is never read from or written to the trick
+    /// itself.
+    #[serde(skip)]
+    pub matrix_image: Option<String>,
+    /// Run steps whose dependencies have completed concurrently, rather than one at a
+    /// time. This only has an effect if the trick declares dependencies between its
+    /// steps. This is never read from or written to the trick itself.
+    #[serde(skip)]
+    pub parallel_steps: bool,
+    /// Directory to write a log file for each run of the trick to, holding the log
+    /// messages emitted while it ran. This is never read from or written to the trick
+    /// itself.
+    #[serde(skip)]
+    pub log_dir: Option<PathBuf>,
+    /// Directory to collect evidence and spilled output into, in a subdirectory for each
+    /// run of the trick. Default is `<reports.dir>/evidence`. This is never read from or
+    /// written to the trick itself.
+    #[serde(skip)]
+    pub evidence_dir: Option<PathBuf>,
 }
 
 impl Trick {
+    /// A built-in trick that exercises the full pipeline: pulling an image, spawning a
+    /// container, and running a command inside it. The container is reaped when the
+    /// trick completes. This trick should end in [`Status::ExploitSuccess`] on a
+    /// correctly configured host.
+    pub fn self_test() -> Self {
+        let name = "houdini-self-test".to_owned();
+
+        Self {
+            name: name.clone(),
+            technique: None,
+            severity: None,
+            collect_dmesg: false,
+            isolate_network: false,
+            umask: None,
+            capture: CaptureOpts::default(),
+            locale: crate::serde_defaults::default_locale(),
+            timezone: crate::serde_defaults::default_timezone(),
+            requires_root: false,
+            required_capabilities: vec![],
+            parameters: HashMap::new(),
+            source_path: None,
+            matrix_image: None,
+            parallel_steps: false,
+            log_dir: None,
+            evidence_dir: None,
+            steps: vec![
+                Step::SpawnContainer(Box::new(SpawnContainer {
+                    name: name.clone(),
+                    image: "bash".into(),
+                    image_policy: ImagePullPolicy::default(),
+                    cmd: Some("sleep infinity".into()),
+                    volumes: vec![],
+                    external_volumes: vec![],
+                    host_mounts: vec![],
+                    security: vec![],
+                    privileged: false,
+                    auto_remove: false,
+                    network: None,
+                    env: HashMap::new(),
+                    cgroup_parent: None,
+                    ulimits: vec![],
+                    userns_mode: None,
+                    after: vec![],
+                    timeout: None,
+                    failure: Status::SetupFailure,
+                    success: Status::Undecided,
+                }))
+                .into(),
+                Step::Container(Container {
+                    name,
+                    script: vec![ShellCommand {
+                        command: "echo".into(),
+                        args: vec!["hello from houdini".into()],
+                        ..Default::default()
+                    }],
+                    ok_exit_codes: crate::serde_defaults::default_ok_exit_codes(),
+                    privileged: false,
+                    tty: true,
+                    shell: None,
+                    env: HashMap::new(),
+                    detach_keys: None,
+                    trace: None,
+                    umask: None,
+                    capture_proc_status: false,
+                    after: vec![],
+                    timeout: None,
+                    retry: None,
+                    failure: Status::ExploitFailure,
+                    success: Status::ExploitSuccess,
+                })
+                .into(),
+            ],
+        }
+    }
+
+    /// Load a trick from a YAML file, substituting its parameters as with
+    /// [`Trick::from_yaml_with_params`].
+    pub async fn from_path(path: &Path, params: &HashMap<String, String>) -> Result<Self> {
+        let contents = tokio::fs::read_to_string(path)
+            .await
+            .context(format!("could not open trick file {}", path.display()))?;
+
+        let mut trick =
+            Self::from_yaml_with_params(&contents, &path.display().to_string(), params)?;
+        trick.source_path = Some(path.to_owned());
+
+        Ok(trick)
+    }
+
+    /// Parse a trick from YAML loaded from `source`. If the trick declares `parameters`,
+    /// `{{param}}` placeholders in its strings are substituted before it is parsed, using
+    /// the declared defaults overridden by `params`. Tricks without parameters are parsed
+    /// as they are.
+    pub(crate) fn from_yaml_with_params(
+        contents: &str,
+        source: &str,
+        params: &HashMap<String, String>,
+    ) -> Result<Self> {
+        let parameters = template::resolve(contents, params)
+            .context(format!("failed to render trick {}", source))?;
+        if parameters.is_empty() {
+            return parse::from_yaml(contents, source);
+        }
+
+        let value = template::render(contents, &parameters)
+            .context(format!("failed to render trick {}", source))?;
+        let mut trick = parse::from_yaml_value(value, source)?;
+        trick.parameters = parameters;
+
+        Ok(trick)
+    }
+
+    /// Return a copy of this trick that spawns every container from `image` instead of
+    /// the image given in its step. Its report is labelled with the image.
+    pub fn with_image(&self, image: &str) -> Self {
+        let mut trick = self.clone();
+        for step in &mut trick.steps {
+            if let Step::SpawnContainer(step) = &mut step.step {
+                step.image = image.to_owned();
+            }
+        }
+        trick.matrix_image = Some(image.to_owned());
+        trick
+    }
+
+    /// Override or add build arguments for every container image this trick builds.
+    pub fn override_build_args(&mut self, args: &HashMap<String, String>) {
+        for step in &mut self.steps {
+            if let Step::SpawnContainer(step) = &mut step.step {
+                step.image_policy.override_build_args(args);
+            }
+        }
+    }
+
     /// Run every step of the trick plan, returning a final status in the end.
     /// If any step returns a final status, we return that status early.
+    ///
+    /// Steps run in order, except that a step never runs before the steps it depends on.
+    /// If `parallel_steps` is set, every step whose dependencies have completed runs
+    /// concurrently, and once a step returns a final status, steps that are already
+    /// running are allowed to finish but no more are started.
+    ///
+    /// Container, network, and named volume names are suffixed with a random per-run
+    /// identifier so that concurrent runs of the same trick do not interfere with one
+    /// another. The report retains the logical names used in the trick. Host commands,
+    /// which cannot know the suffix, find each container's name in a
+    /// `HOUDINI_CONTAINER_<NAME>` environment variable. External networks and volumes
+    /// keep their names, so that containers can use ones the trick does not create.
+    ///
+    /// Steps register how to undo the changes they make to the host, such as spawning
+    /// containers or loading kernel modules, in a [`CleanupRegistry`]. These cleanups run
+    /// in reverse order once the trick ends, even if it panics or is cancelled.
+    ///
+    /// If `reports.embedLogs` is configured, log messages emitted while the trick runs are
+    /// embedded in its report. If `log_dir` is set, they are also written to
+    /// `<log_dir>/<trick>.<suffix>.log`, so that every run has a file of its own.
     pub async fn run(&self) -> TrickReport {
-        tracing::info!(name = ?&self.name, "running trick");
+        self.run_watched(None, None).await
+    }
+
+    /// Like [`Trick::run`], but also sends each step to `progress` as it starts, and
+    /// stops the trick once `cancel` is set. Steps that are still running when it is
+    /// cancelled end in [`Status::HarnessError`], and are reported as
+    /// [`Termination::Cancelled`]. The trick still cleans up after itself.
+    pub async fn run_watched(
+        &self,
+        progress: Option<&watch::Sender<Option<CurrentStep>>>,
+        cancel: Option<watch::Receiver<bool>>,
+    ) -> TrickReport {
+        let suffix = run_suffix();
+        let cleanup = CleanupRegistry::default();
+        let run = async {
+            let report = self.run_steps(&cleanup, &suffix, progress, cancel).await;
+            cleanup.run().await;
+            report
+        };
+        let run = async {
+            match CONFIG.reports.embed_logs {
+                Some(level) => {
+                    let (mut report, logs) = logging::capture(level, run).await;
+                    report.logs = logs;
+                    report
+                }
+                None => run.await,
+            }
+        };
+
+        let path = match &self.log_dir {
+            Some(dir) => dir.join(format!(
+                "{}.{}.log",
+                evidence::file_name(&self.name),
+                suffix
+            )),
+            None => return run.await,
+        };
+        match logging::create_tee_file(&path) {
+            Ok(file) => {
+                let mut report = logging::tee(file, run).await;
+                report.log_file = Some(path);
+                report
+            }
+            Err(e) => {
+                tracing::warn!(name = ?&self.name, err = ?e, "failed to create trick log file");
+                run.await
+            }
+        }
+    }
+
+    async fn run_steps(
+        &self,
+        cleanup: &CleanupRegistry,
+        suffix: &str,
+        progress: Option<&watch::Sender<Option<CurrentStep>>>,
+        cancel: Option<watch::Receiver<bool>>,
+    ) -> TrickReport {
+        tracing::info!(name = ?&self.name, suffix = ?suffix, "running trick");
 
-        let mut containers: HashSet<String> = HashSet::new();
         let mut status = Status::Undecided;
 
         let mut report = TrickReport::new(&self.name);
+        report.source_path = self.source_path.clone();
+        report.technique = self.technique.clone();
+        report.severity = self.severity;
+        report.matrix_image = self.matrix_image.clone();
         report.set_system_info();
 
-        for step in &self.steps {
-            status = step.run().await;
+        if let Err(e) = self.preflight(Privileges::current) {
+            tracing::error!(name = ?&self.name, err = ?e, "trick preflight failed");
+            report.set_reason(format!("{:#}", e));
+            report.set_status(Status::HarnessError);
+            return report;
+        }
 
-            if let Step::SpawnContainer(step) = step {
-                containers.insert(step.name.to_owned());
+        let schedule = match Schedule::new(&self.steps) {
+            Ok(schedule) => schedule,
+            Err(e) => {
+                tracing::error!(name = ?&self.name, err = ?e, "invalid step dependencies");
+                report.set_reason(format!("{:#}", e));
+                report.set_status(Status::HarnessError);
+                return report;
             }
+        };
+        let limit = match self.parallel_steps && schedule.has_deps() {
+            true => self.steps.len(),
+            false => 1,
+        };
 
-            let step_report = StepReport::new(step, status);
-            report.add(step_report);
+        let dmesg_before = self.collect_dmesg.then(dmesg::read);
 
-            if status.is_final() {
-                break;
+        let mut capture = self.capture.clone();
+        if capture.dir.is_none() {
+            capture.dir = Some(self.run_evidence_dir(suffix));
+        }
+
+        let mut started = vec![false; self.steps.len()];
+        let mut done = vec![false; self.steps.len()];
+        let mut running = FuturesUnordered::new();
+
+        loop {
+            if !status.is_final() {
+                let ready = schedule
+                    .ready(&started, &done)
+                    .take(limit - running.len())
+                    .collect::<Vec<_>>();
+                for index in ready {
+                    started[index] = true;
+                    if let Some(progress) = progress {
+                        progress.send_replace(Some(CurrentStep {
+                            index,
+                            kind: self.steps[index].step.kind().to_owned(),
+                            id: self.steps[index].id.clone(),
+                        }));
+                    }
+                    running.push(self.run_step(index, suffix, cleanup));
+                }
+            }
+
+            let finished = tokio::select! {
+                finished = running.next() => finished,
+                () = cancelled(cancel.clone()) => {
+                    tracing::warn!(name = ?&self.name, "trick cancelled");
+                    // Dropping the steps that are still running stops them
+                    running.clear();
+                    for index in (0..self.steps.len()).filter(|i| started[*i] && !done[*i]) {
+                        let outcome = StepOutcome {
+                            status: Status::HarnessError,
+                            reason: Some("cancelled".into()),
+                            termination: Termination::Cancelled,
+                            ..Default::default()
+                        };
+                        let mut step_report = StepReport::new(&self.steps[index].step, outcome);
+                        step_report.id = self.steps[index].id.clone();
+                        report.add(step_report);
+                    }
+                    if !status.is_final() {
+                        status = Status::HarnessError;
+                    }
+                    break;
+                }
+            };
+            let (index, mut outcome) = match finished {
+                Some(finished) => finished,
+                None => break,
+            };
+            done[index] = true;
+            if !status.is_final() {
+                status = outcome.status;
             }
+
+            capture
+                .apply(&mut outcome, &format!("step-{}-{}", index, suffix))
+                .await;
+            report.evidence.append(&mut outcome.details.evidence);
+            let mut step_report = StepReport::new(&self.steps[index].step, outcome);
+            step_report.id = self.steps[index].id.clone();
+            report.add(step_report);
         }
 
         match status {
@@ -70,28 +469,261 @@ impl Trick {
             Status::Skip => {
                 tracing::info!(status = ?status, "trick execution SKIPPED");
             }
+            Status::HarnessError => {
+                tracing::warn!(status = ?status, "trick execution ERRORED");
+            }
         }
 
         report.set_status(status);
 
-        // Clean up containers
-        for id in &containers {
-            if let Err(e) = reap_container(id).await {
-                tracing::warn!(err = ?e, "failed to reap container");
+        if let Some(before) = dmesg_before {
+            let capture = DmesgCapture::from_snapshots(&before, &dmesg::read());
+            if let DmesgCapture::Unavailable(reason) = &capture {
+                tracing::warn!(reason = ?reason, "unable to read kernel log");
             }
+            report.set_dmesg(capture);
         }
 
         report
     }
+
+    /// Run the step at `index` with its containers renamed for this run and trick-wide
+    /// options applied, registering cleanups for what it changed on the host.
+    async fn run_step(
+        &self,
+        index: usize,
+        suffix: &str,
+        cleanup: &CleanupRegistry,
+    ) -> (usize, StepOutcome) {
+        let mut resolved = self.steps[index]
+            .step
+            .rename_containers(|name| container_name(name, suffix));
+        match &mut resolved {
+            Step::Host(host) => {
+                host.isolate_network |= self.isolate_network;
+                host.umask = host.umask.or(self.umask);
+                self.add_locale(&mut host.env);
+                self.add_container_names(&mut host.env, suffix);
+            }
+            Step::Container(container) => {
+                container.umask = container.umask.or(self.umask);
+                self.add_locale(&mut container.env);
+            }
+            Step::Shell(shell) => {
+                shell.isolate_network |= self.isolate_network;
+                shell.umask = shell.umask.or(self.umask);
+                self.add_locale(&mut shell.env);
+                if shell.target == ShellTarget::Host {
+                    self.add_container_names(&mut shell.env, suffix);
+                }
+            }
+            Step::CollectEvidence(step) if step.dir.is_none() => {
+                step.dir = Some(self.run_evidence_dir(suffix))
+            }
+            _ => {}
+        }
+
+        // Cleanups are registered before the step runs, so that whatever it changed is
+        // still undone if the trick is cancelled part way through the step. Each cleanup
+        // leaves alone what does not exist.
+        match &resolved {
+            Step::SpawnContainer(step) => {
+                // Networks and volumes are registered first, so they are removed once no
+                // containers are using them. Only those that Houdini created are removed.
+                if let Some(network) = step.network.as_ref().filter(|n| !n.is_external()) {
+                    let name = network.name.clone();
+                    cleanup.register(format!("network {}", name), move || async move {
+                        remove_network(&name).await
+                    });
+                }
+                for volume in step
+                    .volumes
+                    .iter()
+                    .filter_map(|v| named_volume(v))
+                    .filter(|v| !step.is_external_volume(v))
+                {
+                    let volume = volume.to_owned();
+                    cleanup.register(format!("volume {}", volume), move || async move {
+                        remove_volume(&volume).await
+                    });
+                }
+                if let Some(handle) = step.image_policy.build_context() {
+                    let handle = handle.to_owned();
+                    cleanup.register(format!("build context {}", handle), move || async move {
+                        context::remove(&handle).await
+                    });
+                }
+                let name = step.name.clone();
+                cleanup.register(format!("container {}", name), move || async move {
+                    reap_container(&name).await
+                });
+            }
+            // Only unload modules that were not loaded before this trick loaded them
+            Step::KernelModule(step)
+                if step.unload_on_cleanup && !step.is_loaded().unwrap_or(true) =>
+            {
+                let step = step.clone();
+                cleanup.register(format!("kernel module {}", step.name), move || async move {
+                    match step.is_loaded()? {
+                        true => unload_module(&step.name),
+                        false => Ok(()),
+                    }
+                });
+            }
+            _ => {}
+        }
+
+        let outcome = resolved.run(&self.name, index).await;
+
+        (index, outcome)
+    }
+}
+
+impl Trick {
+    /// The images that this trick's containers are spawned from, along with the policy
+    /// used to acquire each, in step order.
+    pub fn images(&self) -> Vec<(&str, &ImagePullPolicy)> {
+        self.steps
+            .iter()
+            .filter_map(|step| match &step.step {
+                Step::SpawnContainer(step) => Some((step.image.as_str(), &step.image_policy)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Local files that this trick refers to: the Dockerfiles its images are built from
+    /// and the seccomp profiles its containers are confined by, without duplicates.
+    /// Relative paths are left relative to the working directory, as Houdini resolves
+    /// them when the trick runs.
+    pub fn local_files(&self) -> Vec<PathBuf> {
+        let mut files = Vec::<PathBuf>::new();
+        for step in &self.steps {
+            let step = match &step.step {
+                Step::SpawnContainer(step) => step,
+                _ => continue,
+            };
+            let dockerfile = step.image_policy.dockerfile().map(Path::to_owned);
+            let profiles = step
+                .security
+                .iter()
+                .filter_map(|opt| opt.strip_prefix("seccomp="))
+                .filter(|profile| *profile != "unconfined")
+                .map(PathBuf::from);
+            for file in dockerfile.into_iter().chain(profiles) {
+                if !files.contains(&file) {
+                    files.push(file);
+                }
+            }
+        }
+        files
+    }
+
+    /// The directory that evidence and spilled output of the run identified by `suffix`
+    /// are collected into.
+    fn run_evidence_dir(&self, suffix: &str) -> PathBuf {
+        let root = match &self.evidence_dir {
+            Some(dir) => dir.clone(),
+            None => CONFIG.reports.dir.join("evidence"),
+        };
+        evidence_dir(&root, &self.name, suffix)
+    }
+
+    /// Set the trick's locale and time zone in `env`, unless it already sets them.
+    fn add_locale(&self, env: &mut HashMap<String, String>) {
+        for (key, value) in [
+            ("LANG", &self.locale),
+            ("LC_ALL", &self.locale),
+            ("TZ", &self.timezone),
+        ] {
+            env.entry(key.to_owned())
+                .or_insert_with(|| value.to_owned());
+        }
+    }
+
+    /// Set `HOUDINI_CONTAINER_<NAME>` in `env` to the name that each container the trick
+    /// spawns has in the run identified by `suffix`. `<NAME>` is the container's name in
+    /// the trick, upper-cased, with characters other than letters and digits replaced
+    /// with `_`.
+    fn add_container_names(&self, env: &mut HashMap<String, String>, suffix: &str) {
+        for step in &self.steps {
+            if let Step::SpawnContainer(spawn) = &step.step {
+                let key = spawn
+                    .name
+                    .chars()
+                    .map(|c| match c.is_ascii_alphanumeric() {
+                        true => c.to_ascii_uppercase(),
+                        false => '_',
+                    })
+                    .collect::<String>();
+                env.insert(
+                    format!("HOUDINI_CONTAINER_{}", key),
+                    container_name(&spawn.name, suffix),
+                );
+            }
+        }
+    }
+
+    /// Check that Houdini has the privileges this trick declares it needs, as reported
+    /// by `privileges`.
+    fn preflight(&self, privileges: impl FnOnce() -> Result<Privileges>) -> Result<()> {
+        if !self.requires_root && self.required_capabilities.is_empty() {
+            return Ok(());
+        }
+        privileges()
+            .context("failed to determine privileges")?
+            .check(self.requires_root, &self.required_capabilities)
+    }
+}
+
+/// The step that a running trick most recently started. If the trick runs steps
+/// concurrently, others may be running too.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct CurrentStep {
+    /// Index of the step in the trick.
+    pub index: usize,
+    /// The kind of step, as it is named in trick files.
+    pub kind: String,
+    /// ID of the step, if it has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+}
+
+/// Wait until `cancel` is set, or forever if there is nothing to cancel the trick.
+async fn cancelled(cancel: Option<watch::Receiver<bool>>) {
+    if let Some(mut cancel) = cancel {
+        while !*cancel.borrow() {
+            if cancel.changed().await.is_err() {
+                break;
+            }
+        }
+        if *cancel.borrow() {
+            return;
+        }
+    }
+    std::future::pending().await
+}
+
+/// Generate a random suffix to distinguish the containers of one trick run from another.
+fn run_suffix() -> String {
+    uuid::Uuid::new_v4().simple().to_string()[..8].to_owned()
+}
+
+/// Map a logical container name to the name used for a given trick run.
+fn container_name(name: &str, suffix: &str) -> String {
+    format!("{}-{}", name, suffix)
 }
 
 pub(crate) mod status {
+    use schemars::JsonSchema;
     use serde::{Deserialize, Serialize};
 
-    #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+    #[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone, Copy, Default)]
     #[serde(rename_all = "camelCase", deny_unknown_fields)]
     pub enum Status {
         /// The status of the exploit test is undecided.
+        #[default]
         Undecided,
         /// Setup has failed.
         /// This is a final status that stops the plan.
@@ -105,6 +737,10 @@ pub(crate) mod status {
         /// Skip the exploit.
         /// Like SetupFailure but not considered a hard failure.
         Skip,
+        /// Houdini itself encountered an error (e.g. Docker is unreachable) and could not
+        /// run a step. This says nothing about whether the exploit works.
+        /// This is a final status that stops the plan.
+        HarnessError,
     }
 
     impl Status {
@@ -115,22 +751,20 @@ pub(crate) mod status {
                 Status::ExploitSuccess => true,
                 Status::ExploitFailure => true,
                 Status::Skip => true,
+                Status::HarnessError => true,
             }
         }
     }
-
-    impl Default for Status {
-        fn default() -> Self {
-            Status::Undecided
-        }
-    }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::{
-        testutils::{assert_json_serialize, assert_yaml_deserialize},
-        tricks::report::Report,
+        testutils::{
+            assert_json_serialize, assert_yaml_deserialize, assert_yaml_serialize, run_valid,
+            run_valid_watched,
+        },
+        tricks::report::{Report, Termination},
     };
 
     use super::*;
@@ -142,6 +776,8 @@ mod tests {
     fn test_yaml_plan_serde_smoke() {
         let yaml = r#"
             name: yaml smoke
+            collectDmesg: true
+            isolateNetwork: true
             steps:
             - versionCheck:
                 docker:
@@ -156,6 +792,8 @@ mod tests {
                 name: foo
                 image: bar
                 imagePolicy: never
+                hostMounts: [procSysrq, cgroupFs, dockerSock]
+                autoRemove: true
             - spawnContainer:
                 name: foo
                 image: bar
@@ -186,6 +824,7 @@ mod tests {
                 script:
                 - command: cat
                   args: ["/passwd"]
+                okExitCodes: [0, 1]
                 failure: exploitFailure
                 success: exploitSuccess
             - wait:
@@ -193,10 +832,150 @@ mod tests {
                     sleep: 2s
             - wait:
                 for: input
+            - kernelModule:
+                name: fuse
+                unloadOnCleanup: true
             "#;
         assert_yaml_deserialize::<Trick>(yaml);
     }
 
+    #[test]
+    fn test_self_test_trick() {
+        let trick = Trick::self_test();
+        assert_yaml_serialize(&trick);
+
+        let steps = trick.steps.iter().map(|s| &s.step).collect::<Vec<_>>();
+        let kinds = steps.iter().map(|s| s.kind()).collect::<Vec<_>>();
+        assert_eq!(kinds, vec!["spawnContainer", "container"]);
+
+        match &steps[..] {
+            [Step::SpawnContainer(spawn), Step::Container(exec)] => {
+                assert_eq!(
+                    spawn.name, exec.name,
+                    "exec should target spawned container"
+                );
+                assert_eq!(spawn.failure, Status::SetupFailure);
+                assert_eq!(exec.success, Status::ExploitSuccess);
+            }
+            steps => panic!("unexpected self-test steps {:?}", steps),
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_step_span_fields() {
+        let yaml = r#"
+            name: span test
+            steps:
+            - host:
+                script:
+                - command: "true"
+                  args: []
+            - host:
+                script:
+                - command: "false"
+                  args: []
+                failure: exploitFailure
+            "#;
+
+        let plan: Trick = assert_yaml_deserialize(yaml);
+        run_valid(&plan).await;
+
+        assert!(logs_contain(
+            r#"step{kind="host" index=0 trick="span test"}"#
+        ));
+        assert!(logs_contain(
+            r#"step{kind="host" index=1 trick="span test"}"#
+        ));
+        assert!(logs_contain("status=Undecided"));
+        assert!(logs_contain("status=ExploitFailure"));
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_failed_command_index() {
+        let yaml = r#"
+            name: failed command test
+            steps:
+            - host:
+                script:
+                - command: echo
+                  args: ["first"]
+                - command: sh
+                  args: ["-c", "echo second; echo oops >&2; exit 3"]
+                - command: echo
+                  args: ["third"]
+                failure: exploitFailure
+            "#;
+
+        let plan: Trick = assert_yaml_deserialize(yaml);
+        let report = run_valid(&plan).await;
+        assert_eq!(report.status, Status::ExploitFailure);
+
+        let step = &report.steps[0];
+        assert_eq!(step.failed_command_index, Some(1));
+        let output = step
+            .failed_command_output
+            .as_ref()
+            .expect("output should be captured");
+        assert_eq!(output.stdout, "second\n");
+        assert_eq!(output.stderr, "oops\n");
+        assert_json_serialize(&report);
+
+        assert_eq!(
+            report.explain(),
+            "trick failed command test failed at step 0 (host): command 1 in script failed: \
+             command failed with exit code: 3 (stderr: oops)"
+        );
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_explain() {
+        let yaml = r#"
+            name: explain
+            steps:
+            - host:
+                script:
+                - command: "true"
+                  args: []
+            - host:
+                script:
+                - command: "false"
+                  args: []
+                failure: setupFailure
+            - host:
+                script:
+                - command: "true"
+                  args: []
+                success: exploitSuccess
+            "#;
+
+        let plan: Trick = assert_yaml_deserialize(yaml);
+        let report = run_valid(&plan).await;
+        assert_eq!(
+            report.explain(),
+            "trick explain failed at step 1 (host): command 0 in script failed: \
+             command failed with exit code: 1"
+        );
+
+        let yaml = r#"
+            name: explain
+            steps:
+            - host:
+                script:
+                - command: "true"
+                  args: []
+            "#;
+
+        let plan: Trick = assert_yaml_deserialize(yaml);
+        let report = run_valid(&plan).await;
+        assert_eq!(
+            report.explain(),
+            "trick explain finished without a final status (Undecided)"
+        );
+    }
+
     #[tokio::test]
     #[traced_test]
     #[serial_test::serial]
@@ -223,17 +1002,1030 @@ mod tests {
                 - command: echo
                   args: ["goodbye"]
                 failure: exploitFailure
+            - container:
+                name: bash
+                script:
+                - command: "false"
+                  args: []
+                okExitCodes: [1]
+                failure: exploitFailure
+            - container:
+                name: bash
+                shell: sh -c
+                script:
+                - command: cd /tmp && test "$(pwd)" = /tmp
+                  args: []
+                failure: exploitFailure
                 success: exploitSuccess
             "#;
 
         let plan: Trick = assert_yaml_deserialize(yaml);
-        let report = plan.run().await;
+        let report = run_valid(&plan).await;
         assert!(
             matches!(report.status, Status::ExploitSuccess),
             "should succeed"
         );
     }
 
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_commit_container() {
+        let yaml = r#"
+            name: commit container test
+            steps:
+            - spawnContainer:
+                name: base
+                image: bash
+                cmd: sleep infinity
+            - container:
+                name: base
+                script:
+                - command: touch
+                  args: ["/houdini-committed"]
+            - commitContainer:
+                name: base
+                tag: houdini-commit-test:latest
+            - spawnContainer:
+                name: derived
+                image: houdini-commit-test:latest
+                imagePolicy: committed
+                cmd: sleep infinity
+            - container:
+                name: derived
+                script:
+                - command: test
+                  args: ["-f", "/houdini-committed"]
+                failure: exploitFailure
+                success: exploitSuccess
+            "#;
+
+        let plan: Trick = assert_yaml_deserialize(yaml);
+        let report = run_valid(&plan).await;
+        assert_eq!(report.status, Status::ExploitSuccess);
+        assert!(
+            report.steps[2].image_id.is_some(),
+            "committed image ID should be reported"
+        );
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_after_asserts() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let yaml = format!(
+            r#"
+            name: after asserts test
+            steps:
+            - spawnContainer:
+                name: bash
+                image: bash
+                cmd: sleep infinity
+                volumes: ["{dir}:/out"]
+                after:
+                - fileMissing: {dir}/flag
+            - container:
+                name: bash
+                script:
+                - sh -c "echo pwned > /out/flag"
+                after:
+                - fileContains:
+                    path: {dir}/flag
+                    contains: pwned
+                failure: exploitFailure
+                success: exploitSuccess
+            "#,
+            dir = dir.path().display()
+        );
+
+        let plan: Trick = assert_yaml_deserialize(&yaml);
+        let report = run_valid(&plan).await;
+        assert_eq!(report.status, Status::ExploitSuccess);
+        assert_eq!(report.steps[1].assertions.len(), 1);
+        assert!(report.steps[1].assertions[0].passed);
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_network_dns_and_mtu() {
+        let yaml = r#"
+            name: network test
+            steps:
+            - spawnContainer:
+                name: bash
+                image: bash
+                cmd: sleep infinity
+                network:
+                    name: houdini-test-net
+                    dns: ["1.1.1.1"]
+                    mtu: 1400
+            - container:
+                name: bash
+                script:
+                - sh -c "test $(cat /sys/class/net/eth0/mtu) = 1400"
+                - nslookup example.com
+                failure: exploitFailure
+                success: exploitSuccess
+            "#;
+
+        let plan: Trick = assert_yaml_deserialize(yaml);
+        let report = run_valid(&plan).await;
+        assert_eq!(report.status, Status::ExploitSuccess);
+    }
+
+    #[tokio::test]
+    async fn test_timed_out_termination() {
+        let yaml = r#"
+            name: timeout test
+            steps:
+            - wait:
+                for:
+                    sleep: 10s
+                timeout: 100ms
+            "#;
+
+        let plan: Trick = assert_yaml_deserialize(yaml);
+        let report = run_valid(&plan).await;
+        assert_eq!(report.termination, Termination::TimedOut);
+        assert_eq!(report.steps[0].termination, Termination::TimedOut);
+        assert_eq!(report.status, Status::Undecided);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_termination() {
+        let yaml = r#"
+            name: cancel test
+            steps:
+            - host:
+                script: ["true"]
+            - wait:
+                for:
+                    sleep: 10s
+            - host:
+                script: ["true"]
+                success: exploitSuccess
+            "#;
+
+        let plan: Trick = assert_yaml_deserialize(yaml);
+        let (cancel, cancelled) = watch::channel(false);
+        let started = std::time::Instant::now();
+        let (report, ()) = tokio::join!(run_valid_watched(&plan, Some(cancelled)), async {
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            cancel.send_replace(true);
+        });
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+        assert_eq!(report.termination, Termination::Cancelled);
+        assert_eq!(report.status, Status::HarnessError);
+        assert_eq!(report.steps.len(), 2, "no more steps should start");
+        assert_eq!(report.steps[0].termination, Termination::Completed);
+        assert_eq!(report.steps[1].termination, Termination::Cancelled);
+        assert_eq!(report.steps[1].status(), Status::HarnessError);
+    }
+
+    #[tokio::test]
+    async fn test_step_timeout_fails_step() {
+        let yaml = r#"
+            name: step timeout test
+            steps:
+            - host:
+                script: ["sleep 5"]
+                timeout: 1s
+                failure: setupFailure
+                success: exploitSuccess
+            "#;
+
+        let plan: Trick = assert_yaml_deserialize(yaml);
+        let started = std::time::Instant::now();
+        let report = run_valid(&plan).await;
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+        assert_eq!(report.status, Status::SetupFailure);
+        assert_eq!(report.steps[0].termination, Termination::TimedOut);
+        assert!(
+            report.steps[0]
+                .reason
+                .as_deref()
+                .is_some_and(|reason| reason.contains("timed out after 1s")),
+            "{:?}",
+            report.steps[0].reason
+        );
+    }
+
+    #[tokio::test]
+    async fn test_evidence_in_report() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let file = dir.path().join("flag");
+        std::fs::write(&file, "houdini\n").unwrap();
+
+        let yaml = format!(
+            r#"
+            name: evidence test
+            steps:
+            - collectEvidence:
+                sources:
+                - host: {}
+                success: exploitSuccess
+            "#,
+            file.display()
+        );
+        let mut plan: Trick = assert_yaml_deserialize(&yaml);
+        if let Step::CollectEvidence(step) = &mut plan.steps[0].step {
+            step.dir = Some(dir.path().join("evidence"));
+        }
+
+        let report = run_valid(&plan).await;
+        assert_eq!(report.status, Status::ExploitSuccess);
+        assert_eq!(report.evidence.len(), 1);
+        assert_eq!(report.evidence[0].path, dir.path().join("evidence/0-flag"));
+        assert_eq!(
+            report.evidence[0].sha256,
+            "60456faab45d2bbe753f666dcb85bf2f4e712abb1d7c3820fe703b2a17684537"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_trick_evidence_dir() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let file = dir.path().join("flag");
+        std::fs::write(&file, "houdini\n").unwrap();
+
+        let yaml = format!(
+            r#"
+            name: evidence test
+            steps:
+            - collectEvidence:
+                sources:
+                - host: {}
+                success: exploitSuccess
+            "#,
+            file.display()
+        );
+        let mut plan: Trick = assert_yaml_deserialize(&yaml);
+        plan.evidence_dir = Some(dir.path().join("evidence"));
+
+        let report = run_valid(&plan).await;
+        assert_eq!(report.status, Status::ExploitSuccess);
+        let path = &report.evidence[0].path;
+        assert!(
+            path.starts_with(dir.path().join("evidence/evidence-test")),
+            "{} is not in the trick's evidence directory",
+            path.display()
+        );
+        assert!(path.exists());
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_network_volume_cleanup() {
+        let yaml = r#"
+            name: cleanup test
+            steps:
+            - spawnContainer:
+                name: bash
+                image: bash
+                cmd: sleep infinity
+                volumes: ["houdini-cleanup-vol:/data"]
+                network:
+                    name: houdini-cleanup-net
+            - container:
+                name: bash
+                script:
+                - touch /data/canary
+                failure: exploitFailure
+                success: exploitSuccess
+            "#;
+
+        let plan: Trick = assert_yaml_deserialize(yaml);
+        let report = run_valid(&plan).await;
+        assert_eq!(report.status, Status::ExploitSuccess);
+
+        let client = bollard::Docker::connect_with_local_defaults().expect("failed to get client");
+        let networks = client
+            .list_networks(Some(bollard::network::ListNetworksOptions {
+                filters: [("name", vec!["houdini-cleanup-net"])].into(),
+            }))
+            .await
+            .expect("failed to list networks");
+        assert!(
+            networks.is_empty(),
+            "network was not removed: {:?}",
+            networks
+        );
+        let volumes = client
+            .list_volumes(Some(bollard::volume::ListVolumesOptions {
+                filters: [("name", vec!["houdini-cleanup-vol"])].into(),
+            }))
+            .await
+            .expect("failed to list volumes");
+        assert!(
+            volumes.volumes.unwrap_or_default().is_empty(),
+            "volume was not removed"
+        );
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_cancelled_spawn_cleanup() {
+        let yaml = r#"
+            name: cancelled spawn test
+            steps:
+            - spawnContainer:
+                name: bash
+                image: bash
+                cmd: sleep infinity
+                volumes: ["houdini-cancel-vol:/data"]
+                network:
+                    name: houdini-cancel-net
+            "#;
+
+        let plan: Trick = assert_yaml_deserialize(yaml);
+        let (cancel, cancelled) = watch::channel(false);
+        let (report, ()) = tokio::join!(run_valid_watched(&plan, Some(cancelled)), async {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            cancel.send_replace(true);
+        });
+        assert_eq!(report.termination, Termination::Cancelled);
+        crate::docker::assert_clean()
+            .await
+            .expect("cancelled spawn leaked a container");
+
+        let client = bollard::Docker::connect_with_local_defaults().expect("failed to get client");
+        let networks = client
+            .list_networks(Some(bollard::network::ListNetworksOptions {
+                filters: [("name", vec!["houdini-cancel-net"])].into(),
+            }))
+            .await
+            .expect("failed to list networks");
+        assert!(networks.is_empty(), "network was leaked: {:?}", networks);
+        let volumes = client
+            .list_volumes(Some(bollard::volume::ListVolumesOptions {
+                filters: [("name", vec!["houdini-cancel-vol"])].into(),
+            }))
+            .await
+            .expect("failed to list volumes");
+        assert!(
+            volumes.volumes.unwrap_or_default().is_empty(),
+            "volume was leaked"
+        );
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_container_output() {
+        let yaml = r#"
+            name: container output test
+            steps:
+            - spawnContainer:
+                name: bash
+                image: bash
+                cmd: sleep infinity
+            - container:
+                name: bash
+                script: ["echo hello"]
+                tty: false
+                success: exploitSuccess
+            "#;
+
+        let plan: Trick = assert_yaml_deserialize(yaml);
+        let report = run_valid(&plan).await;
+        assert_eq!(report.status, Status::ExploitSuccess);
+        assert_eq!(report.steps[1].stdout.as_deref(), Some("hello\n"));
+        assert_eq!(report.steps[1].stderr.as_deref(), Some(""));
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_spawn_env() {
+        let yaml = r#"
+            name: spawn env test
+            steps:
+            - spawnContainer:
+                name: bash
+                image: bash
+                cmd: sleep infinity
+                env:
+                    FOO: bar baz
+            - container:
+                name: bash
+                script: ["printenv FOO"]
+                tty: false
+                success: exploitSuccess
+            "#;
+
+        let plan: Trick = assert_yaml_deserialize(yaml);
+        let report = run_valid(&plan).await;
+        assert_eq!(report.status, Status::ExploitSuccess);
+        assert_eq!(report.steps[1].stdout.as_deref(), Some("bar baz\n"));
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_exec_env() {
+        let yaml = r#"
+            name: exec env test
+            steps:
+            - spawnContainer:
+                name: bash
+                image: bash
+                cmd: sleep infinity
+            - container:
+                name: bash
+                script:
+                - sh -c 'test "$(printenv GREETING)" = "hello world"'
+                env:
+                    GREETING: hello world
+                detachKeys: ctrl-x
+                failure: exploitFailure
+                success: exploitSuccess
+            "#;
+
+        let plan: Trick = assert_yaml_deserialize(yaml);
+        let report = run_valid(&plan).await;
+        assert_eq!(report.status, Status::ExploitSuccess);
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_process_check() {
+        let yaml = r#"
+            name: process check test
+            steps:
+            - spawnContainer:
+                name: bash
+                image: bash
+                cmd: sleep infinity
+            - processCheck:
+                container: bash
+                name: sleep
+                pattern: infinity
+                failure: exploitFailure
+            - processCheck:
+                container: bash
+                name: nginx
+                expect: absent
+                failure: exploitFailure
+                success: exploitSuccess
+            "#;
+
+        let plan: Trick = assert_yaml_deserialize(yaml);
+        let report = run_valid(&plan).await;
+        assert_eq!(report.status, Status::ExploitSuccess);
+        assert_eq!(report.steps[1].matched_pids, vec![1]);
+        assert!(report.steps[2].matched_pids.is_empty());
+    }
+
+    #[test]
+    fn test_preflight() {
+        let yaml = r#"
+            name: privileged
+            requiresRoot: true
+            requiredCapabilities: [CAP_SYS_ADMIN]
+            steps: []
+            "#;
+        let trick: Trick = assert_yaml_deserialize(yaml);
+
+        let user = || {
+            Ok(Privileges {
+                euid: 1000,
+                effective_caps: 0,
+            })
+        };
+        let err = trick
+            .preflight(user)
+            .expect_err("unprivileged user should fail preflight");
+        assert_eq!(
+            err.to_string(),
+            "trick requires root (running as uid 1000), CAP_SYS_ADMIN"
+        );
+
+        let root = || {
+            Ok(Privileges {
+                euid: 0,
+                effective_caps: 1 << 21,
+            })
+        };
+        trick.preflight(root).expect("root should pass preflight");
+
+        let trick: Trick = assert_yaml_deserialize("name: unprivileged\nsteps: []");
+        trick
+            .preflight(|| anyhow::bail!("privileges should not be checked"))
+            .expect("trick without requirements should pass preflight");
+    }
+
+    #[tokio::test]
+    async fn test_locale() {
+        let yaml = r#"
+            name: locale test
+            timezone: JST-9
+            steps:
+            - host:
+                script: ["sh -c 'echo $LANG $LC_ALL && date +%Z'"]
+            - host:
+                script: ["sh -c 'echo $LC_ALL $TZ'"]
+                env:
+                    LC_ALL: POSIX
+            - shell:
+                script: echo $TZ
+                success: exploitSuccess
+            "#;
+        let plan: Trick = assert_yaml_deserialize(yaml);
+        let report = run_valid(&plan).await;
+        assert_eq!(report.status, Status::ExploitSuccess);
+
+        let stdout = report
+            .steps
+            .iter()
+            .map(|step| step.stdout.as_deref().unwrap_or_default())
+            .collect::<Vec<_>>();
+        assert_eq!(stdout, vec!["C C\nJST\n", "POSIX JST-9\n", "JST-9\n"]);
+    }
+
+    #[tokio::test]
+    async fn test_trick_umask() {
+        let yaml = r#"
+            name: umask test
+            umask: "027"
+            steps:
+            - host:
+                script: ["sh -c umask"]
+            - host:
+                script: ["sh -c umask"]
+                umask: "077"
+            - shell:
+                script: umask
+                success: exploitSuccess
+            "#;
+        let plan: Trick = assert_yaml_deserialize(yaml);
+        let report = run_valid(&plan).await;
+        assert_eq!(report.status, Status::ExploitSuccess);
+
+        let stdout = report
+            .steps
+            .iter()
+            .map(|step| step.stdout.as_deref().unwrap_or_default())
+            .collect::<Vec<_>>();
+        assert_eq!(stdout, vec!["0027\n", "0077\n", "0027\n"]);
+    }
+
+    #[tokio::test]
+    async fn test_trick_isolate_network() {
+        let yaml = r#"
+            name: isolate network test
+            isolateNetwork: true
+            steps:
+            - shell:
+                script: |
+                    test "$(tail -n +3 /proc/net/dev | cut -d: -f1 | tr -d ' ')" = lo
+                failure: exploitFailure
+                success: exploitSuccess
+            "#;
+        let plan: Trick = assert_yaml_deserialize(yaml);
+        let report = run_valid(&plan).await;
+        assert_eq!(report.status, Status::ExploitSuccess);
+    }
+
+    #[tokio::test]
+    async fn test_per_trick_logs() {
+        use tracing_subscriber::layer::SubscriberExt as _;
+
+        let subscriber = tracing_subscriber::Registry::default().with(logging::TeeLayer);
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let trick = |name: &str| {
+            let mut trick: Trick = assert_yaml_deserialize(&format!(
+                r#"
+                name: {}
+                steps:
+                - host:
+                    script: ["true"]
+                    success: exploitSuccess
+                "#,
+                name
+            ));
+            trick.log_dir = Some(dir.path().join("logs"));
+            trick
+        };
+        let (foo, bar) = (trick("foo trick"), trick("bar trick"));
+
+        let (foo, bar) = tokio::join!(foo.run(), bar.run());
+        assert_eq!(foo.status, Status::ExploitSuccess);
+        assert_eq!(bar.status, Status::ExploitSuccess);
+
+        let (foo, bar) = (foo.log_file.unwrap(), bar.log_file.unwrap());
+        assert_ne!(foo, bar);
+        for (path, name, other) in [
+            (foo, "foo trick", "bar trick"),
+            (bar, "bar trick", "foo trick"),
+        ] {
+            assert_eq!(path.parent(), Some(&*dir.path().join("logs")));
+            let logs = std::fs::read_to_string(&path).expect("log file should exist");
+            assert!(!logs.is_empty(), "{} should not be empty", path.display());
+            assert!(logs.contains(&format!("name=\"{}\"", name)), "{}", logs);
+            assert!(!logs.contains(other), "{}", logs);
+        }
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_container_ulimits() {
+        let yaml = r#"
+            name: container ulimits test
+            steps:
+            - spawnContainer:
+                name: bash
+                image: bash
+                cmd: sleep infinity
+                ulimits:
+                - name: nofile
+                  soft: 512
+                  hard: 1024
+            - container:
+                name: bash
+                script: ["sh -c 'ulimit -n && ulimit -Hn'"]
+                tty: false
+                success: exploitSuccess
+            "#;
+        let plan: Trick = assert_yaml_deserialize(yaml);
+        let report = run_valid(&plan).await;
+        assert_eq!(report.status, Status::ExploitSuccess);
+        assert_eq!(report.steps[1].stdout.as_deref(), Some("512\n1024\n"));
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_container_userns_mode() {
+        let yaml = r#"
+            name: container userns mode test
+            steps:
+            - spawnContainer:
+                name: bash
+                image: bash
+                cmd: sleep infinity
+                usernsMode: host
+            - container:
+                name: bash
+                script: ["cat /proc/self/uid_map"]
+                tty: false
+                success: exploitSuccess
+            "#;
+        let plan: Trick = assert_yaml_deserialize(yaml);
+        let report = run_valid(&plan).await;
+        assert_eq!(report.status, Status::ExploitSuccess);
+        // The host's user namespace maps every uid to itself, even if the daemon remaps
+        // users
+        let uid_map = report.steps[1].stdout.as_deref().unwrap_or_default();
+        assert_eq!(
+            uid_map.split_whitespace().collect::<Vec<_>>(),
+            vec!["0", "0", "4294967295"]
+        );
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_capture_proc_status() {
+        let yaml = r#"
+            name: proc status test
+            steps:
+            - spawnContainer:
+                name: bash
+                image: bash
+                cmd: sleep infinity
+                security: ["no-new-privileges"]
+            - container:
+                name: bash
+                script: ["true"]
+                tty: false
+                captureProcStatus: true
+                success: exploitSuccess
+            "#;
+        let plan: Trick = assert_yaml_deserialize(yaml);
+        let report = run_valid(&plan).await;
+        assert_eq!(report.status, Status::ExploitSuccess);
+
+        let status = report.steps[1]
+            .proc_status
+            .as_ref()
+            .expect("status should be captured");
+        assert_eq!(status.no_new_privs, Some(true));
+        // Docker's default seccomp profile is a filter
+        assert_eq!(status.seccomp, Some(2));
+        assert!(status.cap_eff.is_some());
+        assert!(report.steps[0].proc_status.is_none());
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_container_timezone() {
+        let yaml = r#"
+            name: container timezone test
+            timezone: JST-9
+            steps:
+            - spawnContainer:
+                name: bash
+                image: bash
+                cmd: sleep infinity
+            - container:
+                name: bash
+                script: ["date +%Z"]
+                tty: false
+                success: exploitSuccess
+            "#;
+        let plan: Trick = assert_yaml_deserialize(yaml);
+        let report = run_valid(&plan).await;
+        assert_eq!(report.status, Status::ExploitSuccess);
+        assert_eq!(report.steps[1].stdout.as_deref(), Some("JST\n"));
+    }
+
+    #[tokio::test]
+    async fn test_capture_spill() {
+        let yaml = r#"
+            name: capture test
+            capture:
+                maxInlineBytes: 1024
+                spillToFile: true
+            steps:
+            - host:
+                script: ["sh -c 'yes houdini | head -c 100000'"]
+                success: exploitSuccess
+            "#;
+        let mut plan: Trick = assert_yaml_deserialize(yaml);
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        plan.capture.dir = Some(dir.path().to_owned());
+
+        let report = run_valid(&plan).await;
+        assert_eq!(report.status, Status::ExploitSuccess);
+
+        let step = &report.steps[0];
+        let inline = step.stdout.as_deref().expect("stdout should be captured");
+        assert_eq!(inline.len(), 1024);
+        let path = step.stdout_file.as_ref().expect("stdout should spill");
+        assert!(path.starts_with(dir.path()));
+        let full = std::fs::read_to_string(path).expect("spill file should exist");
+        assert_eq!(full.len(), 100000);
+        assert!(full.starts_with(inline));
+        assert_eq!(step.stdout_bytes, Some(100000));
+        assert_eq!(step.stderr_file, None);
+    }
+
+    #[tokio::test]
+    async fn test_step_dependencies() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let log = dir.path().join("log");
+        let yaml = format!(
+            r#"
+            name: diamond
+            steps:
+            - host:
+                script: ["sh -c 'echo d >> {log}'"]
+                success: exploitSuccess
+              dependsOn: [b, c]
+              id: d
+            - host:
+                script: ["sh -c 'sleep 1 && echo b >> {log}'"]
+              id: b
+              dependsOn: [a]
+            - host:
+                script: ["sh -c 'sleep 1 && echo c >> {log}'"]
+              id: c
+              dependsOn: [a]
+            - host:
+                script: ["sh -c 'echo a >> {log}'"]
+              id: a
+            "#,
+            log = log.display()
+        );
+        let mut plan: Trick = assert_yaml_deserialize(&yaml);
+
+        // Sequentially, steps run in the order their dependencies allow
+        let report = run_valid(&plan).await;
+        assert_eq!(report.status, Status::ExploitSuccess);
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "a\nb\nc\nd\n");
+        let ids = report
+            .steps
+            .iter()
+            .map(|step| step.id.as_deref().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["a", "b", "c", "d"]);
+
+        // In parallel, b and c run at the same time once a completes
+        std::fs::remove_file(&log).unwrap();
+        plan.parallel_steps = true;
+        let start = std::time::Instant::now();
+        let report = run_valid(&plan).await;
+        let elapsed = start.elapsed();
+
+        assert_eq!(report.status, Status::ExploitSuccess);
+        let lines = std::fs::read_to_string(&log).unwrap();
+        let lines = lines.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "a");
+        assert_eq!(lines[3], "d");
+        assert!(
+            elapsed < std::time::Duration::from_millis(1900),
+            "b and c should run concurrently, but the trick took {:?}",
+            elapsed
+        );
+
+        let yaml = r#"
+            name: cycle
+            steps:
+            - host:
+                script: ["true"]
+              id: a
+              dependsOn: [a]
+            "#;
+        let plan: Trick = assert_yaml_deserialize(yaml);
+        let report = run_valid(&plan).await;
+        assert_eq!(report.status, Status::HarnessError);
+        assert!(report.steps.is_empty());
+        assert_eq!(
+            report.reason.as_deref(),
+            Some("step 0 is part of a dependency cycle")
+        );
+    }
+
+    #[test]
+    fn test_container_names() {
+        let yaml = r#"
+            name: names
+            steps:
+            - spawnContainer:
+                name: bash
+                image: bash
+            - container:
+                name: bash
+                script: []
+            - killContainer:
+                name: bash
+            "#;
+        let trick: Trick = assert_yaml_deserialize(yaml);
+
+        let (s1, s2) = (run_suffix(), run_suffix());
+        assert_ne!(s1, s2, "suffixes should differ between runs");
+
+        let names = |suffix: &str| {
+            trick
+                .steps
+                .iter()
+                .map(|step| {
+                    step.step
+                        .rename_containers(|name| container_name(name, suffix))
+                })
+                .map(|step| match step {
+                    Step::SpawnContainer(s) => s.name,
+                    Step::Container(s) => s.name,
+                    Step::KillContainer(s) => s.name,
+                    _ => unreachable!(),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let run1 = names(&s1);
+        let run2 = names(&s2);
+        assert!(
+            run1.iter().all(|n| n == &run1[0]),
+            "names consistent in a run"
+        );
+        assert!(
+            run2.iter().all(|n| n == &run2[0]),
+            "names consistent in a run"
+        );
+        assert_ne!(run1[0], run2[0], "names should differ across runs");
+        assert!(run1[0].starts_with("bash-"));
+    }
+
+    #[test]
+    fn test_rename_volumes() {
+        let step: Step = assert_yaml_deserialize(
+            r#"
+            spawnContainer:
+                name: bash
+                image: bash
+                volumes: ["data:/data:ro", "/tmp:/host/tmp", "shared:/shared"]
+                externalVolumes: [shared]
+            "#,
+        );
+
+        match step.rename_containers(|name| container_name(name, "abc")) {
+            Step::SpawnContainer(s) => {
+                assert_eq!(s.name, "bash-abc");
+                assert_eq!(
+                    s.volumes,
+                    vec!["data-abc:/data:ro", "/tmp:/host/tmp", "shared:/shared"]
+                );
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_rename_networks() {
+        for (network, renamed) in [
+            ("name: houdini-net", "houdini-net-abc"),
+            ("name: shared\n                  external: true", "shared"),
+            ("name: bridge", "bridge"),
+        ] {
+            let step: Step = assert_yaml_deserialize(&format!(
+                r#"
+            spawnContainer:
+                name: bash
+                image: bash
+                network:
+                  {}
+            "#,
+                network
+            ));
+
+            match step.rename_containers(|name| container_name(name, "abc")) {
+                Step::SpawnContainer(s) => assert_eq!(s.network.unwrap().name, renamed),
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_container_names_for_host() {
+        let yaml = r#"
+            name: container names test
+            steps:
+            - spawnContainer:
+                name: bash
+                image: bash
+            - spawnContainer:
+                name: my-victim.1
+                image: bash
+            "#;
+        let trick: Trick = assert_yaml_deserialize(yaml);
+
+        let mut env = HashMap::new();
+        trick.add_container_names(&mut env, "abc");
+        assert_eq!(
+            env,
+            [
+                ("HOUDINI_CONTAINER_BASH".to_owned(), "bash-abc".to_owned()),
+                (
+                    "HOUDINI_CONTAINER_MY_VICTIM_1".to_owned(),
+                    "my-victim.1-abc".to_owned()
+                ),
+            ]
+            .into()
+        );
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    #[serial_test::serial]
+    async fn test_concurrent_runs() {
+        let yaml = r#"
+            name: concurrent test
+            steps:
+            - spawnContainer:
+                name: bash
+                image: bash
+                cmd: sleep infinity
+            - container:
+                name: bash
+                script:
+                - command: echo
+                  args: ["hello"]
+                failure: exploitFailure
+                success: exploitSuccess
+            "#;
+
+        let plan: Trick = assert_yaml_deserialize(yaml);
+        let (r1, r2) = tokio::join!(plan.run(), plan.run());
+        assert_eq!(r1.status, Status::ExploitSuccess);
+        assert_eq!(r2.status, Status::ExploitSuccess);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    #[serial_test::serial]
+    async fn test_host_mounts() {
+        let yaml = r#"
+            name: host mounts test
+            steps:
+            - spawnContainer:
+                name: bash
+                image: bash
+                cmd: sleep infinity
+                hostMounts: [dockerSock]
+            - container:
+                name: bash
+                script:
+                - command: ls
+                  args: ["/var/run/docker.sock"]
+                failure: exploitFailure
+                success: exploitSuccess
+            "#;
+
+        let plan: Trick = assert_yaml_deserialize(yaml);
+        let report = run_valid(&plan).await;
+        assert!(
+            matches!(report.status, Status::ExploitSuccess),
+            "docker socket should be mounted"
+        );
+    }
+
     #[tokio::test]
     #[traced_test]
     #[serial_test::serial]
@@ -257,7 +2049,7 @@ mod tests {
                 .into_std()
                 .await;
             let plan: Trick = serde_yaml::from_reader(&file).expect("should deserialize");
-            let trick_report = plan.run().await;
+            let trick_report = run_valid(&plan).await;
             let status = trick_report.status;
             report.add(trick_report);
             assert!(
@@ -0,0 +1,160 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+
+//! Bundling a trick, the local files it refers to, and the report of running it into a
+//! single archive, so that exploits can be shared and reproduced.
+
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context as _, Result};
+use flate2::{write::GzEncoder, Compression};
+
+use super::{report::Report, Trick};
+
+/// Name of the report in a bundle.
+const REPORT_NAME: &str = "report.json";
+/// Directory that the files a trick refers to are stored under in a bundle.
+const FILES_DIR: &str = "files";
+
+/// Write a gzipped tarball to `out` holding the trick file at `trick_file`, the local
+/// files that `trick` refers to, and `report`. The trick is stored under its file name,
+/// the report as `report.json`, and each file under `files/` at the path the trick
+/// refers to it by. Files that do not exist are left out with a warning.
+pub(crate) fn write(out: &Path, trick_file: &Path, trick: &Trick, report: &Report) -> Result<()> {
+    let file =
+        std::fs::File::create(out).context(format!("failed to create bundle {}", out.display()))?;
+    let mut tar = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+
+    let trick_name = trick_file
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("invalid trick path {}", trick_file.display()))?;
+    tar.append_path_with_name(trick_file, trick_name)
+        .context(format!("failed to add trick {}", trick_file.display()))?;
+
+    for file in trick.local_files() {
+        if !file.is_file() {
+            tracing::warn!(file = ?file, "file referenced by trick does not exist, leaving it out");
+            continue;
+        }
+        tar.append_path_with_name(&file, member_name(&file))
+            .context(format!("failed to add {}", file.display()))?;
+    }
+
+    let buf = serde_json::to_vec_pretty(report).context("failed to serialize report")?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(buf.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(report.date.timestamp().max(0) as u64);
+    header.set_cksum();
+    tar.append_data(&mut header, REPORT_NAME, buf.as_slice())
+        .context("failed to add report")?;
+
+    tar.into_inner()
+        .and_then(|enc| enc.finish())
+        .context(format!("failed to write bundle {}", out.display()))?;
+
+    Ok(())
+}
+
+/// Where a file that a trick refers to by `path` is stored in a bundle. Only the normal
+/// components of `path` are kept, so that the member stays under `files/` whether the
+/// path is absolute or climbs out of the working directory.
+fn member_name(path: &Path) -> PathBuf {
+    let relative = path.components().filter_map(|c| match c {
+        Component::Normal(c) => Some(c),
+        _ => None,
+    });
+    Path::new(FILES_DIR).join(relative.collect::<PathBuf>())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read as _;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+    use crate::tricks::report::TrickReport;
+
+    #[test]
+    fn test_member_name() {
+        assert_eq!(
+            member_name(Path::new("/foo/Dockerfile")),
+            Path::new("files/foo/Dockerfile")
+        );
+        assert_eq!(
+            member_name(Path::new("./docker/../Dockerfile")),
+            Path::new("files/docker/Dockerfile")
+        );
+    }
+
+    #[test]
+    fn test_write_bundle() {
+        let dir = tempfile::tempdir().unwrap();
+        let dockerfile = dir.path().join("Dockerfile");
+        std::fs::write(&dockerfile, "FROM bash\n").unwrap();
+        let profile = dir.path().join("seccomp.json");
+        std::fs::write(&profile, "{}\n").unwrap();
+
+        let trick_file = dir.path().join("bundle.yaml");
+        let yaml = format!(
+            r#"
+            name: bundle test
+            steps:
+            - spawnContainer:
+                name: foo
+                image: houdini-bundle
+                imagePolicy:
+                  build:
+                    dockerfile: {}
+                security: ["seccomp={}", "apparmor=unconfined"]
+            - spawnContainer:
+                name: bar
+                image: houdini-bundle
+                imagePolicy:
+                  build:
+                    dockerfile: {}
+                security: ["seccomp=/nonexistent/seccomp.json"]
+            "#,
+            dockerfile.display(),
+            profile.display(),
+            dockerfile.display(),
+        );
+        std::fs::write(&trick_file, &yaml).unwrap();
+        let trick: Trick = serde_yaml::from_str(&yaml).unwrap();
+
+        let mut report = Report::new();
+        report.add(TrickReport::new("bundle test"));
+
+        let out = dir.path().join("bundle.tar.gz");
+        write(&out, &trick_file, &trick, &report).expect("bundle should be written");
+
+        let mut archive = tar::Archive::new(GzDecoder::new(std::fs::File::open(&out).unwrap()));
+        let mut members = vec![];
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().into_owned();
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents).unwrap();
+            members.push((name, contents));
+        }
+        let names = members.iter().map(|(name, _)| name).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                Path::new("bundle.yaml"),
+                &member_name(&dockerfile),
+                &member_name(&profile),
+                Path::new("report.json"),
+            ]
+        );
+        assert_eq!(members[0].1, yaml);
+        assert_eq!(members[1].1, "FROM bash\n");
+        let bundled: Report = serde_json::from_str(&members[3].1).unwrap();
+        assert_eq!(bundled.exploits[0].name, "bundle test");
+    }
+}
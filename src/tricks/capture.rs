@@ -0,0 +1,251 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+//
+
+//! Limits on how much command output is kept inline in a trick's report.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context as _, Result};
+use serde::{Deserialize, Serialize};
+
+use super::steps::StepOutcome;
+
+/// How the output of each step's commands is captured in the report.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub(crate) struct CaptureOpts {
+    /// Most bytes of a step's standard output, and of its standard error, to keep inline
+    /// in the report. This also limits the output of the command that a step failed on.
+    /// Anything beyond this is cut off, and the report records the full size of the
+    /// output. Default is 64 KiB.
+    #[serde(default = "crate::serde_defaults::default_max_inline_bytes")]
+    pub max_inline_bytes: usize,
+    /// Write the full output of steps whose output is cut off to files in the trick's
+    /// evidence directory, and record their paths in the report. Default is false.
+    #[serde(default = "crate::serde_defaults::default_false")]
+    pub spill_to_file: bool,
+    /// Directory to spill output to. This is set by the trick when it runs, to
+    /// `<evidence dir>/<trick>/<run>`.
+    #[serde(skip)]
+    pub dir: Option<PathBuf>,
+}
+
+impl Default for CaptureOpts {
+    fn default() -> Self {
+        Self {
+            max_inline_bytes: crate::serde_defaults::default_max_inline_bytes(),
+            spill_to_file: false,
+            dir: None,
+        }
+    }
+}
+
+impl CaptureOpts {
+    /// Cut the output of a step, and of the command that it failed on, down to size,
+    /// recording the full size of any output that is cut off, and spilling it to files
+    /// named after `name` if configured.
+    pub async fn apply(&self, outcome: &mut StepOutcome, name: &str) {
+        let details = &mut outcome.details;
+        let outputs = [
+            (
+                &mut details.stdout,
+                &mut details.stdout_file,
+                &mut details.stdout_bytes,
+                "stdout",
+            ),
+            (
+                &mut details.stderr,
+                &mut details.stderr_file,
+                &mut details.stderr_bytes,
+                "stderr",
+            ),
+        ];
+        for (output, file, bytes, stream) in outputs {
+            if let Some(output) = output {
+                self.cut(output, file, bytes, &format!("{}.{}", name, stream))
+                    .await;
+            }
+        }
+
+        if let Some(failure) = &mut outcome.failed_command {
+            let output = &mut failure.output;
+            let outputs = [
+                (
+                    &mut output.stdout,
+                    &mut output.stdout_file,
+                    &mut output.stdout_bytes,
+                    "stdout",
+                ),
+                (
+                    &mut output.stderr,
+                    &mut output.stderr_file,
+                    &mut output.stderr_bytes,
+                    "stderr",
+                ),
+            ];
+            for (output, file, bytes, stream) in outputs {
+                self.cut(output, file, bytes, &format!("{}.failed.{}", name, stream))
+                    .await;
+            }
+        }
+    }
+
+    /// Cut `output` down to size if it is too long, recording its full size in `bytes`,
+    /// and spilling it to `file_name` in `dir` if configured.
+    async fn cut(
+        &self,
+        output: &mut String,
+        file: &mut Option<PathBuf>,
+        bytes: &mut Option<usize>,
+        file_name: &str,
+    ) {
+        if output.len() <= self.max_inline_bytes {
+            return;
+        }
+
+        if self.spill_to_file {
+            let path = self
+                .dir
+                .as_deref()
+                .unwrap_or_else(|| Path::new("."))
+                .join(file_name);
+            match spill(&path, output).await {
+                Ok(()) => *file = Some(path),
+                Err(e) => tracing::warn!(err = ?e, "failed to spill step output to file"),
+            }
+        }
+
+        *bytes = Some(output.len());
+        output.truncate(floor_char_boundary(output, self.max_inline_bytes));
+    }
+}
+
+/// Write `output` to `path`, creating its directory if needed.
+async fn spill(path: &Path, output: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .context(format!("failed to create directory {}", dir.display()))?;
+    }
+    tokio::fs::write(path, output)
+        .await
+        .context(format!("failed to write {}", path.display()))
+}
+
+/// The largest index no greater than `index` that starts a character in `s`.
+fn floor_char_boundary(s: &str, index: usize) -> usize {
+    (0..=index.min(s.len()))
+        .rev()
+        .find(|i| s.is_char_boundary(*i))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testutils::assert_yaml_deserialize,
+        tricks::steps::command::{CommandFailure, CommandOutput},
+    };
+
+    #[test]
+    fn test_capture_opts_serde() {
+        let opts: CaptureOpts = assert_yaml_deserialize("{}");
+        assert_eq!(opts, CaptureOpts::default());
+        assert_eq!(opts.max_inline_bytes, 64 * 1024);
+
+        let opts: CaptureOpts = assert_yaml_deserialize("maxInlineBytes: 16\nspillToFile: true");
+        assert_eq!(opts.max_inline_bytes, 16);
+        assert!(opts.spill_to_file);
+    }
+
+    #[test]
+    fn test_floor_char_boundary() {
+        assert_eq!(floor_char_boundary("hello", 3), 3);
+        assert_eq!(floor_char_boundary("hello", 10), 5);
+        // `é` is two bytes long, starting at index 1
+        assert_eq!(floor_char_boundary("héllo", 2), 1);
+    }
+
+    #[tokio::test]
+    async fn test_spill_output() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let opts = CaptureOpts {
+            max_inline_bytes: 4,
+            spill_to_file: true,
+            dir: Some(dir.path().join("evidence")),
+        };
+
+        let mut outcome = StepOutcome::default();
+        outcome.details.add_output(b"0123456789", b"err");
+        opts.apply(&mut outcome, "step-0").await;
+        let details = outcome.details;
+
+        assert_eq!(details.stdout.as_deref(), Some("0123"));
+        assert_eq!(details.stdout_bytes, Some(10));
+        assert_eq!(
+            details.stdout_file,
+            Some(dir.path().join("evidence/step-0.stdout"))
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("evidence/step-0.stdout")).unwrap(),
+            "0123456789"
+        );
+        assert_eq!(details.stderr.as_deref(), Some("err"));
+        assert_eq!(details.stderr_file, None);
+        assert_eq!(details.stderr_bytes, None);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_output() {
+        let opts = CaptureOpts {
+            max_inline_bytes: 4,
+            ..Default::default()
+        };
+
+        let mut outcome = StepOutcome::default();
+        outcome.details.add_output(b"0123456789", b"");
+        opts.apply(&mut outcome, "step-0").await;
+        let details = outcome.details;
+
+        assert_eq!(details.stdout.as_deref(), Some("0123"));
+        assert_eq!(details.stdout_bytes, Some(10));
+        assert_eq!(details.stdout_file, None);
+    }
+
+    #[tokio::test]
+    async fn test_failed_command_output() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let opts = CaptureOpts {
+            max_inline_bytes: 4,
+            spill_to_file: true,
+            dir: Some(dir.path().to_owned()),
+        };
+
+        let mut outcome = StepOutcome {
+            failed_command: Some(CommandFailure {
+                index: 0,
+                output: CommandOutput::from_bytes(b"out", b"0123456789"),
+                result: None,
+            }),
+            ..Default::default()
+        };
+        opts.apply(&mut outcome, "step-0").await;
+        let output = outcome.failed_command.unwrap().output;
+
+        assert_eq!(output.stdout, "out");
+        assert_eq!(output.stdout_bytes, None);
+        assert_eq!(output.stdout_file, None);
+        assert_eq!(output.stderr, "0123");
+        assert_eq!(output.stderr_bytes, Some(10));
+        assert_eq!(
+            std::fs::read_to_string(output.stderr_file.unwrap()).unwrap(),
+            "0123456789"
+        );
+    }
+}
@@ -0,0 +1,159 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+//
+
+//! Undoing the changes that a trick's steps make to the host.
+
+use std::{future::Future, sync::Mutex};
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+
+/// An action that undoes a change made by a step, along with a description of what it
+/// undoes.
+struct Cleanup {
+    what: String,
+    undo: Box<dyn FnOnce() -> BoxFuture<'static, Result<()>> + Send>,
+}
+
+/// Cleanups registered by the steps of a trick as they run. Cleanups run in the reverse
+/// of the order they were registered in, once [`CleanupRegistry::run`] is called. If the
+/// registry is dropped before then, e.g. because the trick panicked or was cancelled,
+/// the remaining cleanups are spawned onto the current runtime instead.
+#[derive(Default)]
+pub(crate) struct CleanupRegistry {
+    cleanups: Mutex<Vec<Cleanup>>,
+}
+
+impl CleanupRegistry {
+    /// Register `undo` to run when the trick ends. `what` describes what it undoes, e.g.
+    /// `container foo`. If a cleanup for `what` is already registered, only that first
+    /// registration is kept, so that a resource shared between steps is undone after
+    /// all of them.
+    pub fn register<F, Fut>(&self, what: impl Into<String>, undo: F)
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let what = what.into();
+        let mut cleanups = self.cleanups.lock().expect("cleanup registry poisoned");
+        if cleanups.iter().any(|cleanup| cleanup.what == what) {
+            return;
+        }
+        cleanups.push(Cleanup {
+            what,
+            undo: Box::new(move || Box::pin(undo())),
+        });
+    }
+
+    /// Run every registered cleanup in reverse registration order. A cleanup that fails
+    /// is logged and does not stop the rest from running.
+    pub async fn run(&self) {
+        let cleanups = self.take();
+        run_all(cleanups).await;
+    }
+
+    /// Take every registered cleanup, most recent first.
+    fn take(&self) -> Vec<Cleanup> {
+        let mut cleanups = match self.cleanups.lock() {
+            Ok(mut cleanups) => std::mem::take(&mut *cleanups),
+            Err(poisoned) => std::mem::take(&mut *poisoned.into_inner()),
+        };
+        cleanups.reverse();
+        cleanups
+    }
+}
+
+impl Drop for CleanupRegistry {
+    fn drop(&mut self) {
+        let cleanups = self.take();
+        if cleanups.is_empty() {
+            return;
+        }
+
+        tracing::warn!(count = cleanups.len(), "trick ended before cleaning up");
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn(run_all(cleanups));
+            }
+            Err(_) => {
+                for cleanup in &cleanups {
+                    tracing::error!(what = ?cleanup.what, "unable to clean up outside of a runtime");
+                }
+            }
+        }
+    }
+}
+
+async fn run_all(cleanups: Vec<Cleanup>) {
+    for cleanup in cleanups {
+        tracing::debug!(what = ?cleanup.what, "cleaning up");
+        if let Err(e) = (cleanup.undo)().await {
+            tracing::warn!(err = ?e, what = ?cleanup.what, "failed to clean up");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn record(log: &Arc<Mutex<Vec<String>>>, registry: &CleanupRegistry, what: &str) {
+        let log = log.clone();
+        let name = what.to_owned();
+        registry.register(what, move || async move {
+            log.lock().unwrap().push(name.clone());
+            match name.starts_with("fail") {
+                true => anyhow::bail!("{} failed", name),
+                false => Ok(()),
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_order() {
+        let log = Arc::new(Mutex::new(vec![]));
+        let registry = CleanupRegistry::default();
+        record(&log, &registry, "network");
+        record(&log, &registry, "container");
+        record(&log, &registry, "fail module");
+        record(&log, &registry, "network");
+
+        registry.run().await;
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["fail module", "container", "network"]
+        );
+
+        // Cleanups only run once
+        registry.run().await;
+        assert_eq!(log.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_on_early_return() {
+        let log = Arc::new(Mutex::new(vec![]));
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+
+        {
+            let registry = CleanupRegistry::default();
+            registry.register("done", move || async move {
+                let _ = tx.send(());
+                Ok(())
+            });
+            record(&log, &registry, "network");
+            record(&log, &registry, "container");
+            // The trick fails before it gets to run its cleanups
+        }
+
+        rx.await
+            .expect("cleanups should run after the registry is dropped");
+        assert_eq!(*log.lock().unwrap(), vec!["container", "network"]);
+    }
+}
@@ -0,0 +1,126 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+
+//! Capture kernel log messages emitted while a trick runs.
+
+use std::{collections::HashSet, process::Command};
+
+use anyhow::{bail, Context as _, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Kernel log messages captured around a trick run.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub enum DmesgCapture {
+    /// Lines that were added to the kernel log while the trick ran.
+    Delta(Vec<String>),
+    /// The kernel log could not be read, e.g. because of `kernel.dmesg_restrict`.
+    Unavailable(String),
+}
+
+impl DmesgCapture {
+    /// Compute a capture from the kernel log read before and after the trick ran.
+    pub fn from_snapshots(before: &Result<String>, after: &Result<String>) -> Self {
+        match (before, after) {
+            (Ok(before), Ok(after)) => DmesgCapture::Delta(delta(before, after)),
+            (Err(e), _) | (_, Err(e)) => DmesgCapture::Unavailable(format!("{:#}", e)),
+        }
+    }
+}
+
+/// Read the kernel log using `dmesg`.
+pub fn read() -> Result<String> {
+    let out = Command::new("dmesg")
+        .output()
+        .context("failed to run dmesg")?;
+
+    if !out.status.success() {
+        bail!(
+            "dmesg failed with {}: {}",
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+/// Compute the lines that were added to the kernel log between two snapshots. If the
+/// last line of `before` is still present in `after`, everything following it is new.
+/// Otherwise the ring buffer has wrapped, and any line not present in `before` is new.
+fn delta(before: &str, after: &str) -> Vec<String> {
+    let after = after.lines().collect::<Vec<_>>();
+
+    if let Some(last) = before.lines().last() {
+        if let Some(pos) = after.iter().rposition(|line| *line == last) {
+            return after[pos + 1..].iter().map(|s| s.to_string()).collect();
+        }
+    }
+
+    let before = before.lines().collect::<HashSet<_>>();
+    after
+        .into_iter()
+        .filter(|line| !before.contains(line))
+        .map(|s| s.to_owned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BEFORE: &str = "\
+[    0.000000] Linux version 5.18.9-arch1-1
+[    1.234567] overlayfs: module loaded
+[   12.000000] eth0: link up
+";
+
+    #[test]
+    fn test_delta() {
+        let after = format!(
+            "{}[   20.000000] BUG: kernel NULL pointer dereference\n\
+             [   20.000001] Oops: 0000 [#1] PREEMPT SMP\n",
+            BEFORE
+        );
+        assert_eq!(
+            delta(BEFORE, &after),
+            vec![
+                "[   20.000000] BUG: kernel NULL pointer dereference",
+                "[   20.000001] Oops: 0000 [#1] PREEMPT SMP",
+            ]
+        );
+
+        assert!(delta(BEFORE, BEFORE).is_empty(), "no new lines");
+        assert_eq!(delta("", "foo\nbar\n"), vec!["foo", "bar"]);
+    }
+
+    #[test]
+    fn test_delta_wrapped() {
+        let after = "\
+[   12.000000] eth0: link up
+[   30.000000] new message
+";
+        let before = format!("{}[   13.000000] rotated out\n", BEFORE);
+        assert_eq!(delta(&before, after), vec!["[   30.000000] new message"]);
+    }
+
+    #[test]
+    fn test_from_snapshots() {
+        let capture =
+            DmesgCapture::from_snapshots(&Ok(BEFORE.to_owned()), &Ok(format!("{}new\n", BEFORE)));
+        assert_eq!(capture, DmesgCapture::Delta(vec!["new".into()]));
+
+        let capture = DmesgCapture::from_snapshots(
+            &Err(anyhow::anyhow!("Operation not permitted")),
+            &Ok(BEFORE.to_owned()),
+        );
+        assert!(
+            matches!(capture, DmesgCapture::Unavailable(reason) if reason.contains("not permitted"))
+        );
+    }
+}
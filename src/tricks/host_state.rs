@@ -0,0 +1,216 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+
+//! Snapshot host state that decides whether container escapes can work, so that results
+//! can be reproduced.
+
+use std::{collections::BTreeMap, path::Path};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// The LSMs that the kernel has enabled, as a comma-separated list.
+const LSM_PATH: &str = "sys/kernel/security/lsm";
+/// The mounts visible to Houdini.
+const MOUNTS_PATH: &str = "proc/self/mounts";
+/// Whether unprivileged users may create user namespaces, on kernels that can restrict it.
+const USERNS_CLONE_PATH: &str = "proc/sys/kernel/unprivileged_userns_clone";
+
+/// Paths whose mounts are recorded, since exploits commonly abuse them.
+const KEY_PATHS: &[&str] = &[
+    "/",
+    "/proc",
+    "/sys",
+    "/sys/fs/cgroup",
+    "/dev/shm",
+    "/tmp",
+    "/var/lib/docker",
+];
+
+/// Security-relevant state of the host that ran the exploits. Anything that could not be
+/// read is left unset.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone, Default)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct HostState {
+    /// LSMs that the kernel has enabled, in the order they were initialized.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lsms: Option<Vec<String>>,
+    /// The mounts that key paths such as `/proc` and `/sys/fs/cgroup` are on, keyed by
+    /// path.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub mounts: BTreeMap<String, MountState>,
+    /// May unprivileged users create user namespaces? Unset if the kernel does not
+    /// support restricting this.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unprivileged_userns: Option<bool>,
+}
+
+/// The mount that a path is on.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct MountState {
+    /// Where the mount is mounted.
+    pub mount_point: String,
+    /// Filesystem type, e.g. `cgroup2`.
+    pub fs_type: String,
+    /// Mount options, e.g. `ro` and `nosuid`.
+    pub options: Vec<String>,
+}
+
+impl HostState {
+    /// Snapshot the state of the host.
+    pub fn read() -> Self {
+        Self::read_from(Path::new("/"))
+    }
+
+    /// Snapshot the state of the host, with `/sys` and `/proc` found under `root`.
+    fn read_from(root: &Path) -> Self {
+        let read = |path: &str| -> Option<String> {
+            let path = root.join(path);
+            match std::fs::read_to_string(&path) {
+                Ok(contents) => Some(contents),
+                Err(e) => {
+                    tracing::debug!(err = ?e, path = ?path, "failed to read host state");
+                    None
+                }
+            }
+        };
+
+        Self {
+            lsms: read(LSM_PATH).map(|lsms| parse_lsms(&lsms)),
+            mounts: read(MOUNTS_PATH)
+                .map(|mounts| parse_mounts(&mounts, KEY_PATHS))
+                .unwrap_or_default(),
+            unprivileged_userns: read(USERNS_CLONE_PATH).and_then(|flag| parse_flag(&flag)),
+        }
+    }
+}
+
+/// Parse the contents of `/sys/kernel/security/lsm`, e.g. `lockdown,capability,apparmor`.
+fn parse_lsms(lsms: &str) -> Vec<String> {
+    lsms.trim()
+        .split(',')
+        .map(str::trim)
+        .filter(|lsm| !lsm.is_empty())
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
+/// Find the mount that each of `paths` is on in the contents of `/proc/self/mounts`. If
+/// several are mounted over one another, the last one mounted wins.
+fn parse_mounts(mounts: &str, paths: &[&str]) -> BTreeMap<String, MountState> {
+    let mounts = mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (_, mount_point, fs_type, options) = (
+                fields.next()?,
+                fields.next()?,
+                fields.next()?,
+                fields.next()?,
+            );
+            Some(MountState {
+                mount_point: mount_point.to_owned(),
+                fs_type: fs_type.to_owned(),
+                options: options.split(',').map(ToOwned::to_owned).collect(),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    paths
+        .iter()
+        .filter_map(|path| {
+            let mount = mounts
+                .iter()
+                .filter(|mount| Path::new(path).starts_with(&mount.mount_point))
+                .max_by_key(|mount| Path::new(&mount.mount_point).components().count())?;
+            Some((path.to_string(), mount.clone()))
+        })
+        .collect()
+}
+
+/// Parse a sysctl holding `0` or `1`.
+fn parse_flag(flag: &str) -> Option<bool> {
+    match flag.trim() {
+        "0" => Some(false),
+        "1" => Some(true),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_lsms() {
+        assert_eq!(
+            parse_lsms("lockdown,capability,landlock,yama,apparmor,bpf\n"),
+            vec![
+                "lockdown",
+                "capability",
+                "landlock",
+                "yama",
+                "apparmor",
+                "bpf"
+            ]
+        );
+        assert!(parse_lsms("\n").is_empty());
+    }
+
+    #[test]
+    fn test_parse_mounts() {
+        let mounts = "\
+/dev/nvme0n1p2 / ext4 rw,relatime 0 0
+proc /proc proc rw,nosuid,nodev,noexec,relatime 0 0
+sysfs /sys sysfs rw,nosuid,nodev,noexec,relatime 0 0
+cgroup2 /sys/fs/cgroup cgroup2 rw,nosuid,nodev,noexec,relatime,nsdelegate 0 0
+tmpfs /tmp tmpfs rw,nosuid,nodev 0 0
+tmpfs /tmp tmpfs ro,nosuid,nodev 0 0
+";
+        let mounts = parse_mounts(mounts, &["/sys/fs/cgroup", "/tmp", "/var/lib/docker"]);
+        assert_eq!(
+            mounts["/sys/fs/cgroup"],
+            MountState {
+                mount_point: "/sys/fs/cgroup".into(),
+                fs_type: "cgroup2".into(),
+                options: vec![
+                    "rw".into(),
+                    "nosuid".into(),
+                    "nodev".into(),
+                    "noexec".into(),
+                    "relatime".into(),
+                    "nsdelegate".into()
+                ],
+            }
+        );
+        // The mount on top wins
+        assert_eq!(mounts["/tmp"].options[0], "ro");
+        // Paths without a mount of their own are on their parent's
+        assert_eq!(mounts["/var/lib/docker"].mount_point, "/");
+        assert_eq!(mounts["/var/lib/docker"].fs_type, "ext4");
+    }
+
+    #[test]
+    fn test_read_host_state() {
+        let root = tempfile::tempdir().expect("failed to create tempdir");
+        assert_eq!(HostState::read_from(root.path()), HostState::default());
+
+        std::fs::create_dir_all(root.path().join("sys/kernel/security")).unwrap();
+        std::fs::write(root.path().join(LSM_PATH), "capability,selinux\n").unwrap();
+        std::fs::create_dir_all(root.path().join("proc/sys/kernel")).unwrap();
+        std::fs::write(root.path().join(USERNS_CLONE_PATH), "0\n").unwrap();
+
+        let state = HostState::read_from(root.path());
+        assert_eq!(
+            state.lsms,
+            Some(vec!["capability".into(), "selinux".into()])
+        );
+        assert_eq!(state.unprivileged_userns, Some(false));
+        assert!(state.mounts.is_empty());
+    }
+}
@@ -0,0 +1,86 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+
+//! Parsing of tricks, with errors that point at where in their source they went wrong.
+
+use anyhow::Result;
+
+use super::Trick;
+
+/// Parse a trick from YAML loaded from `source`, e.g. its file path. On failure, the
+/// error names the line and column of the problem.
+pub(crate) fn from_yaml(contents: &str, source: &str) -> Result<Trick> {
+    serde_yaml::from_str(contents).map_err(|e| {
+        let location = e.location().map(|l| (l.line(), l.column()));
+        located(e.into(), source, location)
+    })
+}
+
+/// Parse a trick from YAML that has already been read into `value` from `source`. Errors
+/// are located if serde_yaml knows where they are.
+pub(crate) fn from_yaml_value(value: serde_yaml::Value, source: &str) -> Result<Trick> {
+    serde_yaml::from_value(value).map_err(|e| {
+        let location = e.location().map(|l| (l.line(), l.column()));
+        located(e.into(), source, location)
+    })
+}
+
+/// Parse a trick from JSON loaded from `source`, e.g. its URL. On failure, the error
+/// names the line and column of the problem.
+pub(crate) fn from_json(contents: &[u8], source: &str) -> Result<Trick> {
+    serde_json::from_slice(contents).map_err(|e| {
+        // serde_json reports line 0 for errors that are not tied to the input
+        let location = (e.line() > 0).then(|| (e.line(), e.column()));
+        located(e.into(), source, location)
+    })
+}
+
+/// Wrap a parse error with `source` and, if known, the `(line, column)` that it was at,
+/// formatted as `source:line:column` so that editors can jump to it.
+fn located(err: anyhow::Error, source: &str, location: Option<(usize, usize)>) -> anyhow::Error {
+    let context = match location {
+        Some((line, column)) => format!("failed to parse trick {}:{}:{}", source, line, column),
+        None => format!("failed to parse trick {}", source),
+    };
+    err.context(context)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_error_location() {
+        let yaml = "\
+name: foo
+steps:
+- host:
+    script: [\"true\"]
+    sucess: exploitSuccess
+";
+        let err = from_yaml(yaml, "foo.yaml").expect_err("trick should not parse");
+        assert!(
+            err.to_string()
+                .starts_with("failed to parse trick foo.yaml:5:"),
+            "{:#}",
+            err
+        );
+        assert!(format!("{:#}", err).contains("sucess"), "{:#}", err);
+
+        let json = "{\n  \"name\": \"foo\",\n  \"steps\": 42\n}";
+        let err = from_json(json.as_bytes(), "foo.json").expect_err("trick should not parse");
+        assert!(
+            err.to_string()
+                .starts_with("failed to parse trick foo.json:3:"),
+            "{:#}",
+            err
+        );
+
+        let trick = from_yaml("name: foo\nsteps: []\n", "foo.yaml").expect("trick should parse");
+        assert_eq!(trick.name, "foo");
+    }
+}
@@ -0,0 +1,177 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+//
+
+//! Preflight checks for the privileges that a [`super::Trick`] declares it needs.
+
+use anyhow::{Context as _, Result};
+
+/// Capability names, indexed by capability number.
+const CAPABILITIES: &[&str] = &[
+    "chown",
+    "dac_override",
+    "dac_read_search",
+    "fowner",
+    "fsetid",
+    "kill",
+    "setgid",
+    "setuid",
+    "setpcap",
+    "linux_immutable",
+    "net_bind_service",
+    "net_broadcast",
+    "net_admin",
+    "net_raw",
+    "ipc_lock",
+    "ipc_owner",
+    "sys_module",
+    "sys_rawio",
+    "sys_chroot",
+    "sys_ptrace",
+    "sys_pacct",
+    "sys_admin",
+    "sys_boot",
+    "sys_nice",
+    "sys_resource",
+    "sys_time",
+    "sys_tty_config",
+    "mknod",
+    "lease",
+    "audit_write",
+    "audit_control",
+    "setfcap",
+    "mac_override",
+    "mac_admin",
+    "syslog",
+    "wake_alarm",
+    "block_suspend",
+    "audit_read",
+    "perfmon",
+    "bpf",
+    "checkpoint_restore",
+];
+
+/// The privileges Houdini is running with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Privileges {
+    /// Effective user ID.
+    pub euid: u32,
+    /// Effective capability set, as a bitmask indexed by capability number.
+    pub effective_caps: u64,
+}
+
+impl Privileges {
+    /// Get the privileges of the current process.
+    pub fn current() -> Result<Self> {
+        let status = std::fs::read_to_string("/proc/self/status")
+            .context("failed to read /proc/self/status")?;
+
+        Ok(Self {
+            euid: nix::unistd::geteuid().as_raw(),
+            effective_caps: parse_cap_eff(&status)
+                .context("failed to parse effective capabilities")?,
+        })
+    }
+
+    /// Check that these privileges satisfy a trick's requirements, returning an error
+    /// that lists everything missing if not.
+    pub fn check(&self, requires_root: bool, capabilities: &[String]) -> Result<()> {
+        let mut missing = vec![];
+
+        if requires_root && self.euid != 0 {
+            missing.push(format!("root (running as uid {})", self.euid));
+        }
+        for cap in capabilities {
+            let bit = cap_number(cap).context(format!("unknown capability {}", cap))?;
+            if self.effective_caps & (1 << bit) == 0 {
+                missing.push(format!("CAP_{}", CAPABILITIES[bit].to_uppercase()));
+            }
+        }
+
+        if !missing.is_empty() {
+            anyhow::bail!("trick requires {}", missing.join(", "))
+        }
+        Ok(())
+    }
+}
+
+/// Look up a capability's number from a name like `CAP_SYS_ADMIN` or `sys_admin`.
+fn cap_number(name: &str) -> Option<usize> {
+    let name = name.to_lowercase();
+    let name = name.strip_prefix("cap_").unwrap_or(&name);
+    CAPABILITIES.iter().position(|cap| *cap == name)
+}
+
+/// Parse the `CapEff` mask from the contents of `/proc/<pid>/status`.
+fn parse_cap_eff(status: &str) -> Option<u64> {
+    let mask = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))?;
+    u64::from_str_radix(mask.trim(), 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_root() {
+        let root = Privileges {
+            euid: 0,
+            effective_caps: u64::MAX,
+        };
+        let user = Privileges {
+            euid: 1000,
+            effective_caps: 0,
+        };
+
+        root.check(true, &[])
+            .expect("root should satisfy requiresRoot");
+        user.check(false, &[])
+            .expect("no requirements should always be satisfied");
+
+        let err = user
+            .check(true, &[])
+            .expect_err("non-root should not satisfy requiresRoot");
+        assert_eq!(err.to_string(), "trick requires root (running as uid 1000)");
+    }
+
+    #[test]
+    fn test_check_capabilities() {
+        // CAP_NET_ADMIN and CAP_SYS_ADMIN only
+        let privs = Privileges {
+            euid: 0,
+            effective_caps: (1 << 12) | (1 << 21),
+        };
+
+        privs
+            .check(true, &["CAP_SYS_ADMIN".into(), "net_admin".into()])
+            .expect("held capabilities should be satisfied");
+
+        let err = privs
+            .check(
+                false,
+                &["cap_sys_admin".into(), "sys_module".into(), "bpf".into()],
+            )
+            .expect_err("missing capabilities should fail");
+        assert_eq!(err.to_string(), "trick requires CAP_SYS_MODULE, CAP_BPF");
+
+        privs
+            .check(false, &["CAP_BOGUS".into()])
+            .expect_err("unknown capabilities should fail");
+    }
+
+    #[test]
+    fn test_parse_cap_eff() {
+        let status =
+            "Name:\tcat\nUid:\t0\t0\t0\t0\nCapPrm:\t000001ffffffffff\nCapEff:\t0000000000200000\n";
+        assert_eq!(parse_cap_eff(status), Some(1 << 21));
+        assert_eq!(parse_cap_eff("Name:\tcat\n"), None);
+
+        Privileges::current().expect("should read our own privileges");
+    }
+}
@@ -0,0 +1,207 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+//
+
+//! Fetching [`Trick`]s from HTTP(S) URLs.
+
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::{Context as _, Result};
+use hyper::{body::HttpBody as _, header::CONTENT_TYPE, Client, Uri};
+
+use super::{parse, Trick};
+
+/// Largest trick that will be fetched.
+const MAX_SIZE: usize = 1024 * 1024;
+
+/// How long to wait for a trick to be fetched.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Does this trick source refer to an HTTP(S) URL rather than a file?
+pub(crate) fn is_remote(source: &str) -> bool {
+    source.starts_with("http://") || source.starts_with("https://")
+}
+
+/// Fetch and parse a trick from an HTTP(S) URL. The trick is parsed as JSON if it is
+/// served as `application/json` or its path ends in `.json`, and as YAML otherwise.
+/// Its parameters are substituted as with [`Trick::from_yaml_with_params`].
+pub(crate) async fn fetch_trick(url: &str, params: &HashMap<String, String>) -> Result<Trick> {
+    fetch_trick_with(url, params, MAX_SIZE, TIMEOUT).await
+}
+
+async fn fetch_trick_with(
+    url: &str,
+    params: &HashMap<String, String>,
+    max_size: usize,
+    timeout: Duration,
+) -> Result<Trick> {
+    let uri: Uri = url.parse().context(format!("invalid trick URL {}", url))?;
+
+    let (content_type, body) = tokio::time::timeout(timeout, fetch(&uri, max_size))
+        .await
+        .map_err(|_| anyhow::anyhow!("timed out after {:?}", timeout))
+        .and_then(|res| res)
+        .context(format!("failed to fetch trick {}", url))?;
+
+    let is_json = content_type.is_some_and(|t| t.starts_with("application/json"))
+        || uri.path().ends_with(".json");
+    if is_json {
+        let trick = parse::from_json(&body, url)?;
+        if trick.parameters.is_empty() {
+            return Ok(trick);
+        }
+    }
+    // JSON is also YAML, so tricks with parameters are rendered the same way either way
+    let body = std::str::from_utf8(&body).context(format!("trick {} is not UTF-8", url))?;
+    Trick::from_yaml_with_params(body, url, params)
+}
+
+/// Fetch `uri`, returning its content type and body. Fails if the body is larger than
+/// `max_size`.
+async fn fetch(uri: &Uri, max_size: usize) -> Result<(Option<String>, Vec<u8>)> {
+    let https = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    let client: Client<_> = Client::builder().build(https);
+
+    let res = client.get(uri.to_owned()).await?;
+    if !res.status().is_success() {
+        anyhow::bail!("server responded with {}", res.status());
+    }
+
+    let content_type = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|t| t.to_str().ok())
+        .map(ToOwned::to_owned);
+
+    let mut body = res.into_body();
+    let mut buf = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if buf.len() + chunk.len() > max_size {
+            anyhow::bail!("trick is larger than {} bytes", max_size);
+        }
+        buf.extend_from_slice(&chunk);
+    }
+
+    Ok((content_type, buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::{http::header, routing::get, Router};
+
+    use super::*;
+
+    const TRICK: &str = r#"
+name: remote trick
+steps:
+- host:
+    script:
+    - command: "true"
+      args: []
+"#;
+
+    const JSON_TRICK: &str = r#"{"name": "remote json trick", "steps": []}"#;
+
+    const PARAM_TRICK: &str =
+        r#"{"name": "remote {{ what }}", "parameters": {"what": "trick"}, "steps": []}"#;
+
+    /// Serve tricks on a random local port, returning the server's address.
+    async fn serve() -> SocketAddr {
+        let app = Router::new()
+            .route("/trick.yaml", get(|| async { TRICK }))
+            .route(
+                "/trick",
+                get(|| async { ([(header::CONTENT_TYPE, "application/json")], JSON_TRICK) }),
+            )
+            .route("/trick.json", get(|| async { JSON_TRICK }))
+            .route("/params.json", get(|| async { PARAM_TRICK }))
+            .route(
+                "/slow.yaml",
+                get(|| async {
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                    TRICK
+                }),
+            );
+
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    #[test]
+    fn test_is_remote() {
+        assert!(is_remote("https://example.com/trick.yaml"));
+        assert!(is_remote("http://example.com/trick.yaml"));
+        assert!(!is_remote("tricks/http/trick.yaml"));
+        assert!(!is_remote("/tmp/https://trick.yaml"));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_trick() {
+        let addr = serve().await;
+
+        let trick = fetch_trick(&format!("http://{}/trick.yaml", addr), &HashMap::new())
+            .await
+            .expect("yaml trick should fetch");
+        assert_eq!(trick.name, "remote trick");
+        assert_eq!(trick.steps.len(), 1);
+
+        let trick = fetch_trick(&format!("http://{}/trick", addr), &HashMap::new())
+            .await
+            .expect("trick served as json should fetch");
+        assert_eq!(trick.name, "remote json trick");
+
+        let trick = fetch_trick(&format!("http://{}/trick.json", addr), &HashMap::new())
+            .await
+            .expect("json trick should fetch");
+        assert_eq!(trick.name, "remote json trick");
+
+        let params = [("what".to_owned(), "override".to_owned())].into();
+        let trick = fetch_trick(&format!("http://{}/params.json", addr), &params)
+            .await
+            .expect("trick with parameters should fetch");
+        assert_eq!(trick.name, "remote override");
+
+        fetch_trick(&format!("http://{}/missing.yaml", addr), &HashMap::new())
+            .await
+            .expect_err("missing trick should fail");
+    }
+
+    #[tokio::test]
+    async fn test_fetch_limits() {
+        let addr = serve().await;
+
+        let err = fetch_trick_with(
+            &format!("http://{}/trick.yaml", addr),
+            &HashMap::new(),
+            16,
+            TIMEOUT,
+        )
+        .await
+        .expect_err("oversized trick should fail");
+        assert!(format!("{:#}", err).contains("larger than 16 bytes"));
+
+        let err = fetch_trick_with(
+            &format!("http://{}/slow.yaml", addr),
+            &HashMap::new(),
+            MAX_SIZE,
+            Duration::from_millis(200),
+        )
+        .await
+        .expect_err("slow trick should time out");
+        assert!(format!("{:#}", err).contains("timed out"));
+    }
+}
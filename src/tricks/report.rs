@@ -7,35 +7,65 @@
 
 //! Generate reports summarizing exploit runs.
 
+pub mod diff;
+mod sarif;
+
 use std::{
-    collections::hash_map::DefaultHasher,
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
     ffi::OsString,
     hash::{Hash, Hasher},
+    io::Write as _,
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
 };
 
 use anyhow::{Context, Result};
 use chrono::DateTime;
-use nix::sys::utsname;
+use nix::{
+    fcntl::{flock, FlockArg},
+    sys::utsname,
+};
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{RootSchema, Schema},
+    JsonSchema,
+};
 use serde::{Deserialize, Serialize};
-use tokio::fs::File;
 use versions::Versioning;
 
-use crate::CONFIG;
+use crate::{
+    docker::{ExecResult, ImageProvenance},
+    logging::LogEntry,
+    CONFIG,
+};
 
 use super::{
+    dmesg::DmesgCapture,
+    host_state::HostState,
     status::Status,
-    steps::version::{get_docker_version, get_linux_version, get_runc_version},
+    steps::{
+        apparmor::AppArmorResult,
+        assert::AssertResult,
+        command::CommandOutput,
+        evidence::EvidenceArtifact,
+        proc_status::ProcStatus,
+        version::{get_docker_version, get_linux_version, get_runc_version},
+        StepOutcome,
+    },
     Step,
 };
 
 /// A serializable report on one or more exploits.
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
 #[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct Report {
     /// Date at which the report was generated.
     pub date: DateTime<chrono::Utc>,
     /// A series of reports on trick execution.
     pub exploits: Vec<TrickReport>,
+    /// Where the run that produced the report came from, for correlating it with CI.
+    #[serde(default)]
+    pub run_metadata: RunMetadata,
 }
 
 impl Report {
@@ -43,6 +73,7 @@ impl Report {
         Self {
             date: chrono::offset::Utc::now(),
             exploits: Default::default(),
+            run_metadata: RunMetadata::from_env(),
         }
     }
 
@@ -50,50 +81,364 @@ impl Report {
         self.exploits.push(exploit)
     }
 
-    pub async fn write_to_disk(&self) -> Result<()> {
-        let mut s = DefaultHasher::new();
-        self.date.hash(&mut s);
-        let hash = s.finish();
+    /// Summarize how many tricks attempted each technique, and how many of those
+    /// succeeded. Tricks without a technique are not counted.
+    pub fn coverage_by_technique(&self) -> HashMap<String, Coverage> {
+        let mut coverage: HashMap<String, Coverage> = HashMap::new();
+        for exploit in &self.exploits {
+            if let Some(technique) = &exploit.technique {
+                let entry = coverage.entry(technique.to_owned()).or_default();
+                entry.tricks += 1;
+                if exploit.status == Status::ExploitSuccess {
+                    entry.succeeded += 1;
+                }
+            }
+        }
+        coverage
+    }
+
+    /// Count the tricks that ended in exploit success by their severity. Tricks without
+    /// a severity are not counted.
+    pub fn severity_summary(&self) -> BTreeMap<Severity, usize> {
+        let mut summary = BTreeMap::new();
+        for exploit in &self.exploits {
+            if let (Status::ExploitSuccess, Some(severity)) = (exploit.status, exploit.severity) {
+                *summary.entry(severity).or_default() += 1;
+            }
+        }
+        summary
+    }
+
+    /// Summarize how many tricks ran, how many succeeded, and how severe the successful
+    /// ones are.
+    pub fn summary(&self) -> Summary {
+        Summary {
+            tricks: self.exploits.len(),
+            succeeded: self
+                .exploits
+                .iter()
+                .filter(|exploit| exploit.status == Status::ExploitSuccess)
+                .count(),
+            skipped: self.skipped(),
+            severity: self.severity_summary(),
+            failed: false,
+        }
+    }
+
+    /// Number of tricks that ended in [`Status::Skip`].
+    pub fn skipped(&self) -> usize {
+        self.exploits
+            .iter()
+            .filter(|exploit| exploit.status == Status::Skip)
+            .count()
+    }
+
+    /// Read a report from the JSON file at `path`.
+    pub fn read_from(path: &Path) -> Result<Self> {
+        let f = std::fs::File::open(path)
+            .context(format!("could not open report {}", path.display()))?;
+        serde_json::from_reader(std::io::BufReader::new(f))
+            .context(format!("failed to parse report {}", path.display()))
+    }
 
-        let filename = format!("report.{}.json", hash);
-        let path = CONFIG.reports.dir.join(filename);
+    /// Write the report to `path`, replacing its contents. The file is locked while it is
+    /// written, so concurrent writers to the same path cannot interleave.
+    pub async fn write_to(&self, path: &Path) -> Result<()> {
+        let buf = serde_json::to_vec(self).context("failed to serialize report")?;
+        let path = path.to_owned();
 
-        let file = File::create(&path)
+        tokio::task::spawn_blocking(move || write_locked(&path, &buf))
             .await
-            .context(format!("failed to open file {:?}", &path))?;
-        serde_json::to_writer(file.into_std().await, self).context("failed to write report")?;
+            .context("report writer panicked")?
+    }
+
+    /// Write the report to a new file in `dir`, returning the file's path. The file is
+    /// named by the run ID if `reports.nameByRunId` is configured, and by a hash of the
+    /// report's date otherwise. If that file already exists, e.g. because several runs
+    /// share a `HOUDINI_RUN_ID`, a sequence number is added to the name rather than
+    /// replacing the earlier report.
+    pub async fn write_to_disk(&self, dir: &Path) -> Result<PathBuf> {
+        let by_run_id = CONFIG.reports.name_by_run_id;
+        let mut seq = 0;
+        let path = loop {
+            let path = dir.join(self.filename(by_run_id, seq));
+            match tokio::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+                .await
+            {
+                Ok(_) => break path,
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => seq += 1,
+                Err(e) => {
+                    return Err(e).context(format!("failed to create report {}", path.display()))
+                }
+            }
+        };
+
+        self.write_to(&path).await?;
 
         tracing::info!(file = ?&path, "wrote exploit report");
 
+        Ok(path)
+    }
+
+    /// The name of the report's file, with `seq` added to it if it is not zero. Characters
+    /// of the run ID other than `[A-Za-z0-9._-]` are replaced with `_`, so that the file
+    /// always lands in the reports directory.
+    fn filename(&self, by_run_id: bool, seq: usize) -> String {
+        let stem = if by_run_id && !self.run_metadata.run_id.is_empty() {
+            self.run_metadata
+                .run_id
+                .chars()
+                .map(|c| match c {
+                    'A'..='Z' | 'a'..='z' | '0'..='9' | '.' | '_' | '-' => c,
+                    _ => '_',
+                })
+                .collect()
+        } else {
+            let mut s = DefaultHasher::new();
+            self.date.hash(&mut s);
+            s.finish().to_string()
+        };
+
+        match seq {
+            0 => format!("report.{}.json", stem),
+            seq => format!("report.{}.{}.json", stem, seq),
+        }
+    }
+}
+
+/// Identifies the run that produced a report, and the CI job and commit it ran for.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone, Default)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct RunMetadata {
+    /// ID of the run, from `HOUDINI_RUN_ID` if set, or generated otherwise.
+    pub run_id: String,
+    /// Git commit under test, from `GITHUB_SHA` or `CI_COMMIT_SHA`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_commit: Option<String>,
+    /// CI job that ran Houdini, from `CI_JOB_ID` or `GITHUB_RUN_ID`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ci_job_id: Option<String>,
+}
+
+impl RunMetadata {
+    /// Gather run metadata from Houdini's environment.
+    pub fn from_env() -> Self {
+        Self::from_vars(|key| std::env::var(key).ok())
+    }
+
+    /// Gather run metadata from environment variables looked up with `var`. Empty
+    /// variables are treated as unset.
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let first = |keys: &[&str]| {
+            keys.iter()
+                .find_map(|key| var(key).filter(|value| !value.is_empty()))
+        };
+
+        Self {
+            run_id: first(&["HOUDINI_RUN_ID"]).unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+            git_commit: first(&["GITHUB_SHA", "CI_COMMIT_SHA"]),
+            ci_job_id: first(&["CI_JOB_ID", "GITHUB_RUN_ID"]),
+        }
+    }
+}
+
+/// The JSON schema that serialized [`Report`]s conform to.
+pub fn schema() -> RootSchema {
+    let mut schema = schemars::schema_for!(Report);
+
+    // `StepReport` flattens the step into itself, which JSON schema cannot express while
+    // also forbidding additional properties on either side
+    if let Some(Schema::Object(step)) = schema.definitions.get_mut("StepReport") {
+        step.object().additional_properties = None;
+        for variant in step.subschemas().one_of.iter_mut().flatten() {
+            if let Schema::Object(variant) = variant {
+                variant.object().additional_properties = None;
+            }
+        }
+    }
+
+    schema
+}
+
+/// Validate a JSON report against [`schema`], returning an error listing every violation.
+pub fn validate(report: &serde_json::Value) -> Result<()> {
+    let schema = serde_json::to_value(schema()).context("failed to serialize report schema")?;
+    let schema = jsonschema::JSONSchema::compile(&schema)
+        .map_err(|e| anyhow::anyhow!("invalid report schema: {}", e))?;
+
+    if let Err(errors) = schema.validate(report) {
+        let errors = errors
+            .map(|e| format!("{}: {}", e.instance_path, e))
+            .collect::<Vec<_>>();
+        anyhow::bail!("report does not match schema:\n{}", errors.join("\n"));
+    }
+
+    Ok(())
+}
+
+/// How many tricks attempted a technique, and how many of those succeeded.
+#[derive(Debug, Serialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Coverage {
+    /// Number of tricks that attempted the technique.
+    pub tricks: usize,
+    /// Number of those tricks that ended in exploit success.
+    pub succeeded: usize,
+}
+
+/// How severe it is for a trick's exploit to succeed.
+#[derive(
+    Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, PartialOrd, Ord, Clone, Copy,
+)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub enum Severity {
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Severity::Low => "low",
+            Severity::Medium => "medium",
+            Severity::High => "high",
+            Severity::Critical => "critical",
+        };
+        f.write_str(s)
+    }
+}
+
+/// A summary of a report's results.
+#[derive(Debug, Serialize, PartialEq, Eq, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Summary {
+    /// Number of tricks that ran.
+    pub tricks: usize,
+    /// Number of those tricks that ended in exploit success.
+    pub succeeded: usize,
+    /// Number of those tricks that were skipped.
+    pub skipped: usize,
+    /// Number of tricks that ended in exploit success, by severity.
+    pub severity: BTreeMap<Severity, usize>,
+    /// Did the run fail, e.g. because tricks were skipped under `--strict-skip`?
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub failed: bool,
+}
+
+/// Prints the summary as text. The alternate form, `{:#}`, marks a failed run in red for
+/// terminals.
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let headline = format!("{}/{} tricks succeeded", self.succeeded, self.tricks);
+        match (self.failed, f.alternate()) {
+            (true, true) => writeln!(f, "\x1b[31m{} (FAILED)\x1b[0m", headline)?,
+            (true, false) => writeln!(f, "{} (FAILED)", headline)?,
+            (false, _) => writeln!(f, "{}", headline)?,
+        }
+        if self.skipped > 0 {
+            writeln!(f, "skipped: {}", self.skipped)?;
+        }
+        for (severity, count) in self.severity.iter().rev() {
+            writeln!(f, "{}: {}", severity, count)?;
+        }
         Ok(())
     }
 }
 
+/// Replace the contents of the file at `path` with `buf` while holding an exclusive
+/// advisory lock on it. The lock is released when the file is closed, including on error.
+fn write_locked(path: &Path, buf: &[u8]) -> Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .context(format!("failed to open file {:?}", path))?;
+
+    flock(file.as_raw_fd(), FlockArg::LockExclusive)
+        .context(format!("failed to lock file {:?}", path))?;
+
+    // Only truncate once we hold the lock, otherwise we could clobber another writer
+    file.set_len(0).context("failed to truncate report")?;
+    file.write_all(buf).context("failed to write report")?;
+    file.sync_all().context("failed to sync report")
+}
+
 /// A serializable exploit report.
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
 #[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct TrickReport {
     /// Name of the exploit.
     pub name: String,
+    /// File the exploit was loaded from, if it was loaded from a file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_path: Option<PathBuf>,
+    /// Technique the exploit attempts, if given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub technique: Option<String>,
+    /// How severe it is for the exploit to succeed, if given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub severity: Option<Severity>,
+    /// Image the exploit's containers were spawned from, if it was run over an image
+    /// matrix.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub matrix_image: Option<String>,
     /// Information about the system
     pub system_info: SystemInfo,
     /// A series of reports on exploit steps.
     pub steps: Vec<StepReport>,
     /// Final status of the exploit.
     pub status: Status,
+    /// Why the exploit could not run at all, if it could not.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Whether the exploit ran to completion, or was cut short by its final step timing
+    /// out or being cancelled.
+    #[serde(default, skip_serializing_if = "Termination::is_completed")]
+    pub termination: Termination,
+    /// Kernel log messages emitted while the exploit ran, if requested.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dmesg: Option<DmesgCapture>,
+    /// Log messages emitted while the exploit ran, if embedding logs is configured.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub logs: Vec<LogEntry>,
+    /// Evidence artifacts collected while the exploit ran.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub evidence: Vec<EvidenceArtifact>,
+    /// File that log messages emitted while the exploit ran were written to, if per-trick
+    /// logs were requested.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_file: Option<PathBuf>,
 }
 
 impl TrickReport {
     pub fn new(name: &str) -> Self {
         Self {
             name: name.to_owned(),
+            source_path: None,
+            technique: None,
+            severity: None,
+            matrix_image: None,
             steps: Default::default(),
             status: Default::default(),
+            reason: None,
+            termination: Default::default(),
             system_info: Default::default(),
+            dmesg: None,
+            logs: vec![],
+            evidence: vec![],
+            log_file: None,
         }
     }
 
+    /// Add a step's report. The exploit's termination follows that of its last step.
     pub fn add(&mut self, step: StepReport) {
+        self.termination = step.termination;
         self.steps.push(step)
     }
 
@@ -104,10 +449,91 @@ impl TrickReport {
     pub fn set_system_info(&mut self) {
         self.system_info.populate()
     }
+
+    pub fn set_dmesg(&mut self, dmesg: DmesgCapture) {
+        self.dmesg = Some(dmesg)
+    }
+
+    pub fn set_reason(&mut self, reason: String) {
+        self.reason = Some(reason)
+    }
+
+    /// Explain, in one line, why the trick ended in its final status by pointing at the
+    /// first step whose status was final.
+    pub fn explain(&self) -> String {
+        let (index, step) = match self
+            .steps
+            .iter()
+            .enumerate()
+            .find(|(_, step)| step.status.is_final())
+        {
+            Some(step) => step,
+            None => {
+                return match &self.reason {
+                    Some(reason) => format!("trick {} could not run: {}", self.name, reason),
+                    None => format!(
+                        "trick {} finished without a final status ({:?})",
+                        self.name, self.status
+                    ),
+                }
+            }
+        };
+
+        let outcome = match (step.termination, step.status) {
+            (Termination::TimedOut, _) => "timed out",
+            (Termination::Cancelled, _) => "was cancelled",
+            (_, Status::ExploitSuccess) => "succeeded",
+            (_, Status::Skip) => "was skipped",
+            (_, Status::HarnessError) => "errored",
+            _ => "failed",
+        };
+
+        let mut reason = step
+            .reason
+            .clone()
+            .unwrap_or_else(|| format!("step ended in {:?}", step.status));
+        if let Some(output) = step
+            .failed_command_output
+            .as_ref()
+            .map(|o| o.stderr.trim())
+            .filter(|stderr| !stderr.is_empty())
+        {
+            reason = format!("{} (stderr: {})", reason, output);
+        }
+
+        format!(
+            "trick {} {} at step {} ({}): {}",
+            self.name,
+            outcome,
+            index,
+            step.kind(),
+            reason
+        )
+    }
+}
+
+/// How an exploit or one of its steps ended.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub enum Termination {
+    /// It ran to completion, whether or not it succeeded.
+    #[default]
+    Completed,
+    /// It ran for longer than its timeout and was stopped.
+    TimedOut,
+    /// It was cancelled before it could complete.
+    Cancelled,
+}
+
+impl Termination {
+    /// Did it run to completion?
+    pub fn is_completed(&self) -> bool {
+        *self == Termination::Completed
+    }
 }
 
 /// A serializable exploit step report.
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
 #[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct StepReport {
     /// Inner exploit step.
@@ -115,32 +541,128 @@ pub struct StepReport {
     inner: Step,
     /// Status of the exploit step.
     status: Status,
+    /// ID of the step in the trick, if it has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Index of the command in the step's script that failed, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failed_command_index: Option<usize>,
+    /// Captured output of the command in the step's script that failed, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failed_command_output: Option<CommandOutput>,
+    /// How the command in the step's script that failed exited, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failed_command_result: Option<ExecResult>,
+    /// Why the step failed, if it did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Whether the step ran to completion or was cut short.
+    #[serde(default, skip_serializing_if = "Termination::is_completed")]
+    pub termination: Termination,
+    /// ID of the image the step committed, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_id: Option<String>,
+    /// Trace files written by the step's commands. Files inside a container are
+    /// prefixed with the container name, as in `docker cp`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trace_files: Vec<String>,
+    /// Results of host assertions run after the step, in order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub assertions: Vec<AssertResult>,
+    /// Whether the AppArmor profile requested by the step was loaded, if it requested
+    /// one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub apparmor: Option<AppArmorResult>,
+    /// PIDs of the processes the step matched, if it checks for processes. PIDs are as
+    /// seen from inside the container, if any.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub matched_pids: Vec<u32>,
+    /// Capabilities and seccomp state that the step's commands ran with, if the step
+    /// captured them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proc_status: Option<ProcStatus>,
+    /// Where the image of the container the step spawned came from, if it spawned one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<ImageProvenance>,
+    /// Standard output of the commands the step ran, if it ran any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stdout: Option<String>,
+    /// Standard error of the commands the step ran, if it ran any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stderr: Option<String>,
+    /// File holding the step's full standard output, if it was cut off in `stdout`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stdout_file: Option<PathBuf>,
+    /// File holding the step's full standard error, if it was cut off in `stderr`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stderr_file: Option<PathBuf>,
+    /// Full size in bytes of the step's standard output, if it was cut off in `stdout`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stdout_bytes: Option<usize>,
+    /// Full size in bytes of the step's standard error, if it was cut off in `stderr`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stderr_bytes: Option<usize>,
 }
 
 impl StepReport {
-    pub(crate) fn new(step: &Step, status: Status) -> Self {
+    pub(crate) fn new(step: &Step, outcome: StepOutcome) -> Self {
+        let (failed_command_index, failed_command_output, failed_command_result) =
+            match outcome.failed_command {
+                Some(failure) => (Some(failure.index), Some(failure.output), failure.result),
+                None => (None, None, None),
+            };
+
         Self {
             inner: step.to_owned(),
-            status,
+            status: outcome.status,
+            id: None,
+            failed_command_index,
+            failed_command_output,
+            failed_command_result,
+            reason: outcome.reason,
+            termination: outcome.termination,
+            image_id: outcome.details.image_id,
+            trace_files: outcome.details.trace_files,
+            assertions: outcome.details.assertions,
+            apparmor: outcome.details.apparmor,
+            matched_pids: outcome.details.matched_pids,
+            proc_status: outcome.details.proc_status,
+            provenance: outcome.details.provenance,
+            stdout: outcome.details.stdout,
+            stderr: outcome.details.stderr,
+            stdout_file: outcome.details.stdout_file,
+            stderr_file: outcome.details.stderr_file,
+            stdout_bytes: outcome.details.stdout_bytes,
+            stderr_bytes: outcome.details.stderr_bytes,
         }
     }
+
+    /// The kind of step this report is for.
+    pub fn kind(&self) -> &'static str {
+        self.inner.kind()
+    }
+
+    /// Status of the exploit step.
+    pub fn status(&self) -> Status {
+        self.status
+    }
 }
 
-/// Information about the system that ran the exploits.
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
-#[serde(rename_all = "camelCase", deny_unknown_fields)]
+/// Information about the system that ran the exploits. It is serialized as
+/// [`RawSystemInfo`], which keeps the layout of older reports.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Default, Clone)]
+#[serde(from = "RawSystemInfo", into = "RawSystemInfo")]
 pub struct SystemInfo {
     /// Host name.
     pub host: String,
     /// Kernel version.
-    #[serde(with = "super::steps::version::versioning_serde")]
-    pub kernel: Option<Versioning>,
+    pub kernel: VersionField,
     /// Docker version.
-    #[serde(with = "super::steps::version::versioning_serde")]
-    pub docker: Option<Versioning>,
+    pub docker: VersionField,
     /// Runc version.
-    #[serde(with = "super::steps::version::versioning_serde")]
-    pub runc: Option<Versioning>,
+    pub runc: VersionField,
+    /// Security-relevant state of the host, if `reports.hostState` is configured.
+    pub host_state: Option<HostState>,
 }
 
 impl SystemInfo {
@@ -152,20 +674,135 @@ impl SystemInfo {
     }
 
     pub fn populate(&mut self) {
+        self.populate_with(get_linux_version, get_docker_version, get_runc_version)
+    }
+
+    /// Like [`SystemInfo::populate`], but looks up versions with the given getters.
+    fn populate_with(
+        &mut self,
+        kernel: impl FnOnce() -> Result<Versioning>,
+        docker: impl FnOnce() -> Result<Versioning>,
+        runc: impl FnOnce() -> Result<Versioning>,
+    ) {
         self.host = utsname::uname()
             .map(|name| name.nodename().to_owned())
             .unwrap_or_else(|_| OsString::from("Unknown"))
             .to_string_lossy()
             .to_string();
-        self.kernel = get_linux_version().ok();
-        self.docker = get_docker_version().ok();
-        self.runc = get_runc_version().ok();
+        self.kernel = VersionField::from_result("kernel", kernel());
+        self.docker = VersionField::from_result("docker", docker());
+        self.runc = VersionField::from_result("runc", runc());
+        self.host_state = CONFIG.reports.host_state.then(HostState::read);
+    }
+}
+
+impl JsonSchema for SystemInfo {
+    fn schema_name() -> String {
+        RawSystemInfo::schema_name()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        RawSystemInfo::json_schema(gen)
+    }
+}
+
+/// A version looked up on the system that ran the exploits, or why it could not be.
+#[derive(Debug, PartialEq, Eq, Default, Clone)]
+pub struct VersionField {
+    /// The version, if it was found.
+    pub value: Option<Versioning>,
+    /// Why the version could not be found, if it was not.
+    pub error: Option<String>,
+}
+
+impl VersionField {
+    /// Record the result of looking up the version of `what`, logging why if it failed.
+    fn from_result(what: &str, res: Result<Versioning>) -> Self {
+        match res {
+            Ok(value) => Self {
+                value: Some(value),
+                error: None,
+            },
+            Err(e) => {
+                tracing::debug!(err = ?e, "failed to get {} version", what);
+                Self {
+                    value: None,
+                    error: Some(format!("{:#}", e)),
+                }
+            }
+        }
+    }
+}
+
+/// How [`SystemInfo`] is serialized: each version is flattened into a version string and
+/// an optional error, so reports without errors look just like older ones.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+#[schemars(rename = "SystemInfo")]
+struct RawSystemInfo {
+    /// Host name.
+    host: String,
+    /// Kernel version.
+    #[serde(with = "super::steps::version::versioning_serde")]
+    #[schemars(with = "Option<String>")]
+    kernel: Option<Versioning>,
+    /// Why the kernel version is missing, if it is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kernel_error: Option<String>,
+    /// Docker version.
+    #[serde(with = "super::steps::version::versioning_serde")]
+    #[schemars(with = "Option<String>")]
+    docker: Option<Versioning>,
+    /// Why the Docker version is missing, if it is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    docker_error: Option<String>,
+    /// Runc version.
+    #[serde(with = "super::steps::version::versioning_serde")]
+    #[schemars(with = "Option<String>")]
+    runc: Option<Versioning>,
+    /// Why the runc version is missing, if it is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    runc_error: Option<String>,
+    /// Security-relevant state of the host, if it was snapshotted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    host_state: Option<HostState>,
+}
+
+impl From<RawSystemInfo> for SystemInfo {
+    fn from(raw: RawSystemInfo) -> Self {
+        let field = |value, error| VersionField { value, error };
+        Self {
+            host: raw.host,
+            kernel: field(raw.kernel, raw.kernel_error),
+            docker: field(raw.docker, raw.docker_error),
+            runc: field(raw.runc, raw.runc_error),
+            host_state: raw.host_state,
+        }
+    }
+}
+
+impl From<SystemInfo> for RawSystemInfo {
+    fn from(info: SystemInfo) -> Self {
+        Self {
+            host: info.host,
+            kernel: info.kernel.value,
+            kernel_error: info.kernel.error,
+            docker: info.docker.value,
+            docker_error: info.docker.error,
+            runc: info.runc.value,
+            runc_error: info.runc.error,
+            host_state: info.host_state,
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{testutils::assert_json_serialize, tricks::steps::host::Host};
+    use crate::{
+        docker::Platform,
+        testutils::{assert_json_serialize, assert_valid_report},
+        tricks::steps::host::Host,
+    };
 
     use super::*;
 
@@ -173,21 +810,370 @@ mod tests {
     fn report_serde_test() {
         let report = Report {
             date: chrono::Utc::now(),
+            run_metadata: RunMetadata {
+                run_id: "run-1234".into(),
+                git_commit: Some("deadbeef".into()),
+                ci_job_id: Some("42".into()),
+            },
             exploits: vec![TrickReport {
                 name: "foo".into(),
-                system_info: SystemInfo::from_system(),
+                source_path: Some("/tricks/foo.yaml".into()),
+                technique: Some("T1611".into()),
+                severity: Some(Severity::Critical),
+                matrix_image: Some("bash:5.1".into()),
+                system_info: SystemInfo {
+                    host_state: Some(HostState::read()),
+                    ..SystemInfo::from_system()
+                },
                 steps: vec![StepReport {
                     inner: Step::Host(Host {
                         script: vec![],
+                        ok_exit_codes: vec![0],
+                        isolate_network: false,
+                        trace: None,
+                        umask: None,
+                        env: HashMap::new(),
+                        timeout: None,
+                        retry: None,
                         failure: Status::ExploitFailure,
                         success: Status::ExploitSuccess,
                     }),
                     status: Status::ExploitSuccess,
+                    id: Some("exploit".into()),
+                    failed_command_index: Some(0),
+                    failed_command_output: Some(CommandOutput {
+                        stdout: "foo".into(),
+                        stderr: "bar".into(),
+                        ..Default::default()
+                    }),
+                    failed_command_result: Some(ExecResult {
+                        code: Some(137),
+                        signal: Some(9),
+                        oom_killed: true,
+                    }),
+                    reason: Some("command failed".into()),
+                    termination: Termination::TimedOut,
+                    image_id: Some("sha256:deadbeef".into()),
+                    trace_files: vec!["/tmp/houdini-foo-0.trace".into()],
+                    assertions: vec![AssertResult {
+                        passed: false,
+                        reason: Some("/tmp/flag does not exist".into()),
+                    }],
+                    apparmor: Some(AppArmorResult {
+                        name: "docker-default".into(),
+                        loaded: true,
+                        already_present: true,
+                    }),
+                    matched_pids: vec![1],
+                    proc_status: Some(ProcStatus {
+                        cap_eff: Some("00000000a80425fb".into()),
+                        cap_prm: Some("00000000a80425fb".into()),
+                        cap_bnd: Some("00000000a80425fb".into()),
+                        no_new_privs: Some(true),
+                        seccomp: Some(2),
+                    }),
+                    provenance: Some(ImageProvenance {
+                        id: "sha256:1234".into(),
+                        digest: Some("bash@sha256:deadbeef".into()),
+                        created: Some("2022-08-01T00:00:00Z".into()),
+                        labels: [(
+                            "org.opencontainers.image.source".to_owned(),
+                            "https://github.com/willfindlay/houdini".to_owned(),
+                        )]
+                        .into(),
+                        selected_platform: Some(Platform {
+                            os: "linux".into(),
+                            architecture: "amd64".into(),
+                            variant: None,
+                        }),
+                        available_platforms: vec![Platform {
+                            os: "linux".into(),
+                            architecture: "arm64".into(),
+                            variant: Some("v8".into()),
+                        }],
+                    }),
+                    stdout: Some("foo".into()),
+                    stderr: Some("bar".into()),
+                    stdout_file: Some("/tmp/evidence/step-0.stdout".into()),
+                    stderr_file: None,
+                    stdout_bytes: Some(100000),
+                    stderr_bytes: None,
                 }],
                 status: Status::ExploitSuccess,
+                reason: None,
+                termination: Termination::TimedOut,
+                dmesg: Some(DmesgCapture::Delta(vec!["foo".into()])),
+                logs: vec![LogEntry {
+                    timestamp: chrono::Utc::now(),
+                    level: "WARN".into(),
+                    target: "houdini::tricks".into(),
+                    message: "step could not run".into(),
+                    fields: [("status".to_owned(), "HarnessError".to_owned())].into(),
+                }],
+                evidence: vec![EvidenceArtifact {
+                    source: "bash:/etc/shadow".into(),
+                    path: "/tmp/evidence/0-shadow".into(),
+                    sha256: "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+                        .into(),
+                }],
+                log_file: Some("/tmp/logs/foo.abcdef.log".into()),
             }],
         };
 
         assert_json_serialize(&report);
+        assert_valid_report(&report);
+    }
+
+    #[test]
+    fn test_report_omits_registry_secrets() {
+        let step: Step = serde_yaml::from_str(
+            r#"
+            spawnContainer:
+                name: foo
+                image: registry.example.com/foo
+                imagePolicy:
+                    pull:
+                        username: houdini
+                        password: hunter2
+                        identityToken: s3cr3t-token
+            "#,
+        )
+        .unwrap();
+        let mut trick = TrickReport::new("secrets");
+        trick.add(StepReport::new(&step, StepOutcome::default()));
+        let mut report = Report::new();
+        report.add(trick);
+
+        let json = serde_json::to_string(&report).unwrap();
+        assert!(json.contains("houdini"), "{}", json);
+        assert!(!json.contains("hunter2"), "{}", json);
+        assert!(!json.contains("s3cr3t-token"), "{}", json);
+    }
+
+    #[test]
+    fn test_report_schema() {
+        let mut report = Report::new();
+        report.add(TrickReport::new("foo"));
+        assert_valid_report(&report);
+
+        let mut json = serde_json::to_value(&report).unwrap();
+        json["exploits"][0]["status"] = "bogus".into();
+        json.as_object_mut().unwrap().remove("date");
+        let err = validate(&json).expect_err("invalid report should not validate");
+        let err = err.to_string();
+        assert!(err.contains("/exploits/0/status"), "{}", err);
+        assert!(err.contains("date"), "{}", err);
+    }
+
+    #[test]
+    fn test_coverage_by_technique() {
+        let trick = |technique: Option<&str>, status| TrickReport {
+            technique: technique.map(ToOwned::to_owned),
+            status,
+            ..TrickReport::new("foo")
+        };
+
+        let mut report = Report::new();
+        report.add(trick(Some("T1611"), Status::ExploitSuccess));
+        report.add(trick(Some("T1611"), Status::ExploitFailure));
+        report.add(trick(Some("T1611"), Status::ExploitSuccess));
+        report.add(trick(Some("T1068"), Status::SetupFailure));
+        report.add(trick(None, Status::ExploitSuccess));
+
+        let coverage = report.coverage_by_technique();
+        assert_eq!(coverage.len(), 2);
+        assert_eq!(
+            coverage["T1611"],
+            Coverage {
+                tricks: 3,
+                succeeded: 2,
+            }
+        );
+        assert_eq!(
+            coverage["T1068"],
+            Coverage {
+                tricks: 1,
+                succeeded: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_system_info_errors() {
+        let mut info = SystemInfo::default();
+        info.populate_with(
+            || Ok(Versioning::new("5.15.0").unwrap()),
+            || Err(anyhow::anyhow!("docker: not found")).context("failed to spawn docker command"),
+            || Ok(Versioning::new("1.1.2").unwrap()),
+        );
+        assert_eq!(info.kernel.value, Versioning::new("5.15.0"));
+        assert_eq!(info.kernel.error, None);
+        assert_eq!(info.docker.value, None);
+        assert_eq!(
+            info.docker.error.as_deref(),
+            Some("failed to spawn docker command: docker: not found")
+        );
+
+        let json = serde_json::to_value(&info).unwrap();
+        assert_eq!(json["kernel"], "5.15.0");
+        assert_eq!(json["docker"], serde_json::Value::Null);
+        assert_eq!(
+            json["dockerError"],
+            "failed to spawn docker command: docker: not found"
+        );
+        assert!(json.get("kernelError").is_none());
+        assert_json_serialize(&info);
+
+        // Reports from before errors were recorded still deserialize
+        let old: SystemInfo = serde_json::from_str(
+            r#"{"host": "foo", "kernel": "5.15.0", "docker": null, "runc": "1.1.2"}"#,
+        )
+        .expect("old system info should deserialize");
+        assert_eq!(old.docker, VersionField::default());
+        assert_eq!(old.runc.value, Versioning::new("1.1.2"));
+    }
+
+    #[test]
+    fn test_severity_summary() {
+        let trick = |severity, status| TrickReport {
+            severity,
+            status,
+            ..TrickReport::new("foo")
+        };
+
+        let mut report = Report::new();
+        report.add(trick(Some(Severity::Critical), Status::ExploitSuccess));
+        report.add(trick(Some(Severity::Critical), Status::ExploitSuccess));
+        report.add(trick(Some(Severity::Critical), Status::ExploitFailure));
+        report.add(trick(Some(Severity::Low), Status::ExploitSuccess));
+        report.add(trick(Some(Severity::High), Status::Skip));
+        report.add(trick(Some(Severity::Medium), Status::HarnessError));
+        report.add(trick(None, Status::ExploitSuccess));
+
+        assert_eq!(
+            report.severity_summary(),
+            [(Severity::Low, 1), (Severity::Critical, 2)].into()
+        );
+
+        let summary = report.summary();
+        assert_eq!(summary.tricks, 7);
+        assert_eq!(summary.succeeded, 4);
+        assert_eq!(
+            serde_json::to_value(&summary).unwrap(),
+            serde_json::json!({
+                "tricks": 7,
+                "succeeded": 4,
+                "skipped": 1,
+                "severity": { "low": 1, "critical": 2 },
+            })
+        );
+        assert_eq!(
+            summary.to_string(),
+            "4/7 tricks succeeded\nskipped: 1\ncritical: 2\nlow: 1\n"
+        );
+
+        let failed = Summary {
+            failed: true,
+            ..summary
+        };
+        assert_eq!(
+            serde_json::to_value(&failed).unwrap()["failed"],
+            serde_json::json!(true)
+        );
+        assert!(failed
+            .to_string()
+            .starts_with("4/7 tricks succeeded (FAILED)\n"));
+        assert!(format!("{:#}", failed).starts_with("\x1b[31m"));
+    }
+
+    #[test]
+    fn test_run_metadata() {
+        let env = HashMap::from([
+            ("HOUDINI_RUN_ID", "run-1234"),
+            ("GITHUB_SHA", "deadbeef"),
+            ("CI_COMMIT_SHA", "cafef00d"),
+            ("CI_JOB_ID", ""),
+            ("GITHUB_RUN_ID", "42"),
+        ]);
+        let metadata = RunMetadata::from_vars(|key| env.get(key).map(|v| v.to_string()));
+        assert_eq!(
+            metadata,
+            RunMetadata {
+                run_id: "run-1234".into(),
+                git_commit: Some("deadbeef".into()),
+                ci_job_id: Some("42".into()),
+            }
+        );
+        assert_eq!(
+            serde_json::to_value(&metadata).unwrap(),
+            serde_json::json!({
+                "runId": "run-1234",
+                "gitCommit": "deadbeef",
+                "ciJobId": "42",
+            })
+        );
+
+        let metadata = RunMetadata::from_vars(|_| None);
+        assert!(uuid::Uuid::parse_str(&metadata.run_id).is_ok());
+        assert_eq!(metadata.git_commit, None);
+        assert_eq!(metadata.ci_job_id, None);
+
+        let mut report = Report::new();
+        report.run_metadata = RunMetadata::from_vars(|key| env.get(key).map(|v| v.to_string()));
+        assert_eq!(report.filename(true, 0), "report.run-1234.json");
+        assert_eq!(report.filename(true, 2), "report.run-1234.2.json");
+        assert_ne!(report.filename(false, 0), "report.run-1234.json");
+
+        report.run_metadata.run_id = "../../etc/cron.d/x y".into();
+        assert_eq!(report.filename(true, 0), "report..._.._etc_cron.d_x_y.json");
+    }
+
+    #[tokio::test]
+    async fn test_write_to_disk() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+
+        let report = Report::new();
+        let path = report
+            .write_to_disk(dir.path())
+            .await
+            .expect("report should be written");
+        assert_eq!(path.parent(), Some(dir.path()));
+
+        let contents = std::fs::read_to_string(&path).expect("report should exist");
+        let parsed: Report = serde_json::from_str(&contents).expect("report should parse");
+        assert_eq!(parsed, report);
+        assert_valid_report(&parsed);
+
+        let again = report
+            .write_to_disk(dir.path())
+            .await
+            .expect("report should be written");
+        assert_ne!(again, path, "earlier report should not be replaced");
+        let contents = std::fs::read_to_string(&path).expect("earlier report should exist");
+        assert_eq!(serde_json::from_str::<Report>(&contents).unwrap(), report);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_writers() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let path = dir.path().join("report.json");
+
+        let writers = (0..16).map(|i| {
+            let path = path.clone();
+            tokio::spawn(async move {
+                let mut report = Report::new();
+                for _ in 0..i {
+                    report.add(TrickReport::new(&"x".repeat(4096)));
+                }
+                report.write_to(&path).await
+            })
+        });
+        for res in futures::future::join_all(writers).await {
+            res.expect("writer should not panic")
+                .expect("report should be written");
+        }
+
+        let contents = std::fs::read_to_string(&path).expect("report should exist");
+        serde_json::from_str::<Report>(&contents).expect("report should be valid JSON");
     }
 }
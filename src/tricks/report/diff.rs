@@ -0,0 +1,246 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+//
+
+//! Comparing a [`Report`] against a baseline, e.g. to see which exploits a kernel or
+//! Docker upgrade fixed or broke.
+
+use std::{collections::BTreeMap, fmt::Display};
+
+use super::Report;
+use crate::tricks::status::Status;
+
+/// Identifies a trick across reports: by its name, and by the image it was run on if it
+/// was run over an image matrix.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub struct TrickKey {
+    /// Name of the trick.
+    pub name: String,
+    /// Image the trick's containers were spawned from, if it was run over an image
+    /// matrix.
+    pub matrix_image: Option<String>,
+}
+
+impl Display for TrickKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.matrix_image {
+            Some(image) => write!(f, "{} [{}]", self.name, image),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// A trick whose status differs between two reports.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct StatusChange {
+    /// The trick.
+    pub trick: TrickKey,
+    /// Status in the baseline report.
+    pub before: Status,
+    /// Status in the current report.
+    pub after: Status,
+}
+
+impl StatusChange {
+    /// Did an exploit that succeeded in the baseline stop succeeding?
+    pub fn is_regression(&self) -> bool {
+        self.before == Status::ExploitSuccess && self.after != Status::ExploitSuccess
+    }
+}
+
+impl Display for StatusChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {:?} → {:?}", self.trick, self.before, self.after)
+    }
+}
+
+/// The differences between a baseline report and a current report. Tricks are matched
+/// by name and, for tricks run over an image matrix, by image.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct ReportDiff {
+    /// Tricks whose status changed, in name order.
+    pub changes: Vec<StatusChange>,
+    /// Tricks only in the current report, in name order.
+    pub added: Vec<TrickKey>,
+    /// Tricks only in the baseline report, in name order.
+    pub removed: Vec<TrickKey>,
+}
+
+impl ReportDiff {
+    /// Compare `current` against `baseline`. If a report contains several tricks with
+    /// the same name and image, the last one wins.
+    pub fn new(baseline: &Report, current: &Report) -> Self {
+        let statuses = |report: &Report| {
+            report
+                .exploits
+                .iter()
+                .map(|e| {
+                    let key = TrickKey {
+                        name: e.name.to_owned(),
+                        matrix_image: e.matrix_image.to_owned(),
+                    };
+                    (key, e.status)
+                })
+                .collect::<BTreeMap<_, _>>()
+        };
+        let (before, after) = (statuses(baseline), statuses(current));
+
+        let mut diff = Self::default();
+        for (trick, before) in &before {
+            match after.get(trick) {
+                Some(after) if after != before => diff.changes.push(StatusChange {
+                    trick: trick.to_owned(),
+                    before: *before,
+                    after: *after,
+                }),
+                Some(_) => {}
+                None => diff.removed.push(trick.to_owned()),
+            }
+        }
+        diff.added = after
+            .into_keys()
+            .filter(|trick| !before.contains_key(trick))
+            .collect();
+
+        diff
+    }
+
+    /// Status changes that are regressions.
+    pub fn regressions(&self) -> impl Iterator<Item = &StatusChange> {
+        self.changes.iter().filter(|c| c.is_regression())
+    }
+}
+
+impl Display for ReportDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for change in &self.changes {
+            writeln!(f, "{}", change)?;
+        }
+        for trick in &self.added {
+            writeln!(f, "{}: new", trick)?;
+        }
+        for trick in &self.removed {
+            writeln!(f, "{}: removed", trick)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tricks::report::TrickReport;
+
+    fn report(tricks: &[(&str, Status)]) -> Report {
+        matrix_report(
+            &tricks
+                .iter()
+                .map(|(n, s)| (*n, None, *s))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    fn matrix_report(tricks: &[(&str, Option<&str>, Status)]) -> Report {
+        let mut report = Report::new();
+        for (name, image, status) in tricks {
+            let mut trick = TrickReport::new(name);
+            trick.matrix_image = image.map(ToOwned::to_owned);
+            trick.set_status(*status);
+            report.add(trick);
+        }
+        report
+    }
+
+    fn key(name: &str, image: Option<&str>) -> TrickKey {
+        TrickKey {
+            name: name.into(),
+            matrix_image: image.map(ToOwned::to_owned),
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let baseline = report(&[
+            ("regressed", Status::ExploitSuccess),
+            ("fixed", Status::ExploitFailure),
+            ("unchanged", Status::ExploitSuccess),
+            ("removed", Status::ExploitSuccess),
+        ]);
+        let current = report(&[
+            ("unchanged", Status::ExploitSuccess),
+            ("fixed", Status::ExploitSuccess),
+            ("regressed", Status::ExploitFailure),
+            ("added", Status::Skip),
+        ]);
+
+        let diff = ReportDiff::new(&baseline, &current);
+        assert_eq!(
+            diff.changes,
+            vec![
+                StatusChange {
+                    trick: key("fixed", None),
+                    before: Status::ExploitFailure,
+                    after: Status::ExploitSuccess,
+                },
+                StatusChange {
+                    trick: key("regressed", None),
+                    before: Status::ExploitSuccess,
+                    after: Status::ExploitFailure,
+                },
+            ]
+        );
+        assert_eq!(diff.added, vec![key("added", None)]);
+        assert_eq!(diff.removed, vec![key("removed", None)]);
+        assert_eq!(
+            diff.regressions()
+                .map(|c| &*c.trick.name)
+                .collect::<Vec<_>>(),
+            vec!["regressed"]
+        );
+        assert_eq!(
+            diff.to_string(),
+            "fixed: ExploitFailure → ExploitSuccess\n\
+             regressed: ExploitSuccess → ExploitFailure\n\
+             added: new\n\
+             removed: removed\n"
+        );
+
+        let diff = ReportDiff::new(&current, &current);
+        assert_eq!(diff, ReportDiff::default());
+    }
+
+    #[test]
+    fn test_diff_matrix() {
+        let baseline = matrix_report(&[
+            ("escape", Some("bash:5.0"), Status::ExploitSuccess),
+            ("escape", Some("bash:5.1"), Status::ExploitSuccess),
+        ]);
+        let current = matrix_report(&[
+            ("escape", Some("bash:5.0"), Status::ExploitSuccess),
+            ("escape", Some("bash:5.1"), Status::ExploitFailure),
+            ("escape", Some("bash:5.2"), Status::ExploitSuccess),
+        ]);
+
+        let diff = ReportDiff::new(&baseline, &current);
+        assert_eq!(
+            diff.changes,
+            vec![StatusChange {
+                trick: key("escape", Some("bash:5.1")),
+                before: Status::ExploitSuccess,
+                after: Status::ExploitFailure,
+            }]
+        );
+        assert_eq!(diff.regressions().count(), 1);
+        assert_eq!(diff.added, vec![key("escape", Some("bash:5.2"))]);
+        assert!(diff.removed.is_empty());
+        assert_eq!(
+            diff.to_string(),
+            "escape [bash:5.1]: ExploitSuccess → ExploitFailure\n\
+             escape [bash:5.2]: new\n"
+        );
+    }
+}
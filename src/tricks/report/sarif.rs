@@ -0,0 +1,208 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+//
+
+//! Exporting a [`Report`] as SARIF, so that code scanning dashboards can ingest it.
+
+use serde_json::{json, Value};
+
+use super::{Report, TrickReport};
+use crate::tricks::status::Status;
+
+/// Version of the SARIF format that [`Report::to_sarif`] emits.
+const SARIF_VERSION: &str = "2.1.0";
+/// Schema of the SARIF format that [`Report::to_sarif`] emits.
+const SARIF_SCHEMA: &str = "https://json.schemastore.org/sarif-2.1.0.json";
+
+impl Report {
+    /// Convert the report to a SARIF log with one result per exploit. Exploits that
+    /// succeeded are errors, exploits that could not be set up or errored are warnings,
+    /// and exploits that failed or were skipped are notes.
+    pub fn to_sarif(&self) -> Value {
+        let mut rules: Vec<Value> = vec![];
+        let mut results = vec![];
+
+        for exploit in &self.exploits {
+            let rule_id = rule_id(&exploit.name);
+            if !rules.iter().any(|rule| rule["id"] == rule_id) {
+                rules.push(rule(&rule_id, exploit));
+            }
+            results.push(result(&rule_id, exploit));
+        }
+
+        json!({
+            "$schema": SARIF_SCHEMA,
+            "version": SARIF_VERSION,
+            "runs": [{
+                "tool": {
+                    "driver": {
+                        "name": env!("CARGO_PKG_NAME"),
+                        "version": env!("CARGO_PKG_VERSION"),
+                        "rules": rules,
+                    }
+                },
+                "invocations": [{
+                    "executionSuccessful": true,
+                    "endTimeUtc": self.date.to_rfc3339(),
+                }],
+                "results": results,
+            }]
+        })
+    }
+}
+
+/// The SARIF rule ID for a trick: the first CVE ID in its name if there is one, such as
+/// `CVE-2019-5736`, or else its name with anything but alphanumerics replaced by `-`.
+fn rule_id(name: &str) -> String {
+    let upper = name.to_ascii_uppercase();
+    for (start, _) in upper.match_indices("CVE-") {
+        let rest = &upper[start + 4..];
+        let year = rest.chars().take_while(char::is_ascii_digit).count();
+        if year != 4 || !rest[year..].starts_with('-') {
+            continue;
+        }
+        let number = rest[year + 1..]
+            .chars()
+            .take_while(char::is_ascii_digit)
+            .count();
+        if number >= 4 {
+            return upper[start..start + 4 + year + 1 + number].to_owned();
+        }
+    }
+
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect()
+}
+
+/// The SARIF level of a trick's result.
+fn level(status: Status) -> &'static str {
+    match status {
+        Status::ExploitSuccess => "error",
+        Status::SetupFailure | Status::HarnessError | Status::Undecided => "warning",
+        Status::ExploitFailure | Status::Skip => "note",
+    }
+}
+
+/// The SARIF rule describing a trick.
+fn rule(rule_id: &str, exploit: &TrickReport) -> Value {
+    let mut rule = json!({
+        "id": rule_id,
+        "name": exploit.name,
+        "shortDescription": { "text": format!("Container escape: {}", exploit.name) },
+    });
+    if let Some(technique) = &exploit.technique {
+        rule["properties"] = json!({ "tags": [technique] });
+    }
+    rule
+}
+
+/// The SARIF result for a trick, with its steps as related locations in the file it was
+/// loaded from.
+fn result(rule_id: &str, exploit: &TrickReport) -> Value {
+    let artifact = exploit
+        .source_path
+        .as_ref()
+        .map(|path| json!({ "artifactLocation": { "uri": path.display().to_string() } }));
+
+    let steps = exploit
+        .steps
+        .iter()
+        .enumerate()
+        .map(|(index, step)| {
+            let mut text = format!("step {} ({}): {:?}", index, step.kind(), step.status());
+            if let Some(reason) = &step.reason {
+                text = format!("{}: {}", text, reason);
+            }
+            let mut location = json!({ "id": index, "message": { "text": text } });
+            if let Some(artifact) = &artifact {
+                location["physicalLocation"] = artifact.clone();
+            }
+            location
+        })
+        .collect::<Vec<_>>();
+
+    let mut result = json!({
+        "ruleId": rule_id,
+        "level": level(exploit.status),
+        "message": { "text": exploit.explain() },
+        "relatedLocations": steps,
+        "properties": { "status": exploit.status },
+    });
+    if let Some(artifact) = artifact {
+        result["locations"] = json!([{ "physicalLocation": artifact }]);
+    }
+    if let Some(image) = &exploit.matrix_image {
+        result["properties"]["image"] = json!(image);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rule_id() {
+        assert_eq!(rule_id("runc cve-2019-5736 overwrite"), "CVE-2019-5736");
+        assert_eq!(rule_id("CVE-22-1 then CVE-2022-0492"), "CVE-2022-0492");
+        assert_eq!(rule_id("docker sock escape"), "docker-sock-escape");
+    }
+
+    #[test]
+    fn test_to_sarif() {
+        let mut report = Report::new();
+        for (name, status) in [
+            ("cve-2019-5736", Status::ExploitSuccess),
+            ("docker sock escape", Status::ExploitFailure),
+            ("cgroup release agent", Status::SetupFailure),
+            ("skipped", Status::Skip),
+        ] {
+            let mut exploit = TrickReport::new(name);
+            exploit.set_status(status);
+            report.add(exploit);
+        }
+        report.exploits[0].source_path = Some("tricks/runc.yaml".into());
+        report.exploits[0].technique = Some("T1611".into());
+
+        let sarif = report.to_sarif();
+        assert_eq!(sarif["version"], "2.1.0");
+
+        let results = sarif["runs"][0]["results"]
+            .as_array()
+            .expect("results should be an array");
+        assert_eq!(results.len(), 4);
+        let levels = results
+            .iter()
+            .map(|result| {
+                (
+                    result["ruleId"].as_str().unwrap(),
+                    result["level"].as_str().unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            levels,
+            vec![
+                ("CVE-2019-5736", "error"),
+                ("docker-sock-escape", "note"),
+                ("cgroup-release-agent", "warning"),
+                ("skipped", "note"),
+            ]
+        );
+        assert_eq!(
+            results[0]["locations"][0]["physicalLocation"]["artifactLocation"]["uri"],
+            "tricks/runc.yaml"
+        );
+
+        let rules = sarif["runs"][0]["tool"]["driver"]["rules"]
+            .as_array()
+            .expect("rules should be an array");
+        assert_eq!(rules.len(), 4);
+        assert_eq!(rules[0]["properties"]["tags"][0], "T1611");
+    }
+}
@@ -0,0 +1,323 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+//
+
+//! Dependencies between the steps of a trick, and the order to run them in.
+
+use std::collections::HashMap;
+
+use anyhow::{Context as _, Result};
+use serde::{
+    de::{
+        value::MapAccessDeserializer, DeserializeSeed, Error as _, IntoDeserializer, MapAccess,
+        Visitor,
+    },
+    Deserialize, Deserializer, Serialize,
+};
+
+use super::steps::Step;
+
+/// A step in a trick, along with how other steps may refer to it and the steps that
+/// must complete before it runs.
+///
+/// ```yaml
+/// - host:
+///     script: ["touch /tmp/flag"]
+///   id: flag
+/// - host:
+///     script: ["test -e /tmp/flag"]
+///   dependsOn: [flag]
+/// ```
+#[derive(Debug, Serialize, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ScheduledStep {
+    #[serde(flatten)]
+    pub step: Step,
+    /// Name that other steps refer to this step by in `dependsOn`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// IDs of the steps that must complete before this one runs.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub depends_on: Vec<String>,
+}
+
+impl From<Step> for ScheduledStep {
+    fn from(step: Step) -> Self {
+        Self {
+            step,
+            id: None,
+            depends_on: vec![],
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ScheduledStep {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_map(ScheduledStepVisitor)
+    }
+}
+
+/// Deserializes a [`ScheduledStep`] from a map holding the step's kind alongside its
+/// `id` and `dependsOn`, in any order. The step is deserialized straight from the map,
+/// so that its fields are parsed exactly as if it stood alone.
+struct ScheduledStepVisitor;
+
+impl<'de> Visitor<'de> for ScheduledStepVisitor {
+    type Value = ScheduledStep;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a step, optionally with an id and dependsOn")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut keys = ScheduleKeys {
+            map: &mut map,
+            id: None,
+            depends_on: None,
+        };
+        let step = Step::deserialize(MapAccessDeserializer::new(&mut keys))?;
+        let (mut id, mut depends_on) = (keys.id, keys.depends_on);
+
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "id" => id = Some(map.next_value()?),
+                "dependsOn" => depends_on = Some(map.next_value()?),
+                key => {
+                    return Err(A::Error::custom(format!(
+                        "unexpected key `{}` after step {}, expected id or dependsOn",
+                        key,
+                        step.kind()
+                    )))
+                }
+            }
+        }
+
+        Ok(ScheduledStep {
+            step,
+            id,
+            depends_on: depends_on.unwrap_or_default(),
+        })
+    }
+}
+
+/// Map access that sets aside `id` and `dependsOn` while the step kind is looked up.
+struct ScheduleKeys<'a, A> {
+    map: &'a mut A,
+    id: Option<String>,
+    depends_on: Option<Vec<String>>,
+}
+
+impl<'de, 'a, A> MapAccess<'de> for ScheduleKeys<'a, A>
+where
+    A: MapAccess<'de>,
+{
+    type Error = A::Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error>
+    where
+        K: DeserializeSeed<'de>,
+    {
+        while let Some(key) = self.map.next_key::<String>()? {
+            match key.as_str() {
+                "id" => self.id = Some(self.map.next_value()?),
+                "dependsOn" => self.depends_on = Some(self.map.next_value()?),
+                _ => return seed.deserialize(key.into_deserializer()).map(Some),
+            }
+        }
+        Ok(None)
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value, Self::Error>
+    where
+        V: DeserializeSeed<'de>,
+    {
+        self.map.next_value_seed(seed)
+    }
+}
+
+/// The dependencies between a trick's steps, by index.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Schedule {
+    deps: Vec<Vec<usize>>,
+}
+
+impl Schedule {
+    /// Resolve the dependencies of `steps`, checking that every ID is unique, that every
+    /// dependency refers to a step's ID, and that no steps depend on each other in a
+    /// cycle.
+    pub fn new(steps: &[ScheduledStep]) -> Result<Self> {
+        let mut ids = HashMap::new();
+        for (index, step) in steps.iter().enumerate() {
+            if let Some(id) = &step.id {
+                if ids.insert(id.as_str(), index).is_some() {
+                    anyhow::bail!("duplicate step id `{}`", id);
+                }
+            }
+        }
+
+        let deps = steps
+            .iter()
+            .enumerate()
+            .map(|(index, step)| {
+                step.depends_on
+                    .iter()
+                    .map(|dep| {
+                        ids.get(dep.as_str()).copied().with_context(|| {
+                            format!("step {} depends on unknown step id `{}`", index, dep)
+                        })
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let schedule = Self { deps };
+        schedule.check_acyclic()?;
+        Ok(schedule)
+    }
+
+    /// Does any step declare a dependency?
+    pub fn has_deps(&self) -> bool {
+        self.deps.iter().any(|deps| !deps.is_empty())
+    }
+
+    /// Steps that have not started and whose dependencies have all completed, in step
+    /// order.
+    pub fn ready<'a>(
+        &'a self,
+        started: &'a [bool],
+        done: &'a [bool],
+    ) -> impl Iterator<Item = usize> + 'a {
+        self.deps
+            .iter()
+            .enumerate()
+            .filter(move |(index, deps)| !started[*index] && deps.iter().all(|dep| done[*dep]))
+            .map(|(index, _)| index)
+    }
+
+    /// Fail if any steps depend on each other in a cycle.
+    fn check_acyclic(&self) -> Result<()> {
+        let mut started = vec![false; self.deps.len()];
+        let mut done = vec![false; self.deps.len()];
+
+        loop {
+            let index = match self.ready(&started, &done).next() {
+                Some(index) => index,
+                None => break,
+            };
+            started[index] = true;
+            done[index] = true;
+        }
+
+        if let Some(index) = done.iter().position(|done| !done) {
+            anyhow::bail!("step {} is part of a dependency cycle", index);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::{assert_yaml_deserialize, assert_yaml_serialize};
+
+    fn steps(yaml: &str) -> Vec<ScheduledStep> {
+        assert_yaml_deserialize(yaml)
+    }
+
+    #[test]
+    fn test_scheduled_step_serde() {
+        let steps = steps(
+            r#"
+            - host:
+                script: ["true"]
+              id: first
+            - host:
+                script: ["true"]
+              dependsOn: [first]
+            - wait:
+                for:
+                    sleep: 1s
+            "#,
+        );
+        assert_eq!(steps[0].id.as_deref(), Some("first"));
+        assert_eq!(steps[1].depends_on, vec!["first"]);
+        assert_eq!(steps[2].step.kind(), "wait");
+        for step in &steps {
+            assert_yaml_serialize(step);
+        }
+
+        for yaml in [
+            "host:\n  script: [\"true\"]\ndependOn: [first]",
+            "host:\n  script: [\"true\"]\nwait:\n  for: input",
+            "id: lonely",
+        ] {
+            serde_yaml::from_str::<ScheduledStep>(yaml)
+                .expect_err(&format!("{:?} should fail to deserialize", yaml));
+        }
+    }
+
+    #[test]
+    fn test_schedule() {
+        let diamond = steps(
+            r#"
+            - host: { script: ["true"] }
+              id: a
+            - host: { script: ["true"] }
+              id: b
+              dependsOn: [a]
+            - host: { script: ["true"] }
+              id: c
+              dependsOn: [a]
+            - host: { script: ["true"] }
+              dependsOn: [b, c]
+            "#,
+        );
+        let schedule = Schedule::new(&diamond).expect("diamond should schedule");
+        assert!(schedule.has_deps());
+        assert_eq!(schedule.deps, vec![vec![], vec![0], vec![0], vec![1, 2]]);
+
+        let mut started = vec![true, false, false, false];
+        let done = vec![true, false, false, false];
+        assert_eq!(
+            schedule.ready(&started, &done).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        started[1] = true;
+        assert_eq!(schedule.ready(&started, &done).collect::<Vec<_>>(), vec![2]);
+
+        let plain = steps("[{host: {script: [\"true\"]}}, {host: {script: [\"true\"]}}]");
+        assert!(!Schedule::new(&plain).unwrap().has_deps());
+
+        let cycle = steps(
+            r#"
+            - host: { script: ["true"] }
+              id: a
+              dependsOn: [b]
+            - host: { script: ["true"] }
+              id: b
+              dependsOn: [a]
+            "#,
+        );
+        let err = Schedule::new(&cycle).expect_err("cycle should fail");
+        assert_eq!(err.to_string(), "step 0 is part of a dependency cycle");
+
+        let unknown = steps("[{host: {script: [\"true\"]}, dependsOn: [nope]}]");
+        let err = Schedule::new(&unknown).expect_err("unknown dependency should fail");
+        assert_eq!(err.to_string(), "step 0 depends on unknown step id `nope`");
+
+        let duplicate =
+            steps("[{host: {script: [\"true\"]}, id: a}, {host: {script: [\"true\"]}, id: a}]");
+        let err = Schedule::new(&duplicate).expect_err("duplicate id should fail");
+        assert_eq!(err.to_string(), "duplicate step id `a`");
+    }
+}
@@ -0,0 +1,114 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+//
+
+//! Commented starter tricks for authors to fill in.
+
+use clap_derive::ArgEnum;
+
+/// The kind of exploit a starter trick is for.
+#[derive(Debug, ArgEnum, Clone, Copy, PartialEq, Eq)]
+pub enum StarterKind {
+    /// Break out of a container onto the host.
+    Escape,
+    /// Gain privileges inside a container that it was not granted.
+    Privesc,
+    /// Read host information that a container should not be able to see.
+    InfoLeak,
+}
+
+impl StarterKind {
+    /// Name, MITRE ATT&CK technique, container options, and exploit command of the
+    /// starter trick.
+    fn parts(&self) -> (&'static str, &'static str, &'static str, &'static str) {
+        match self {
+            StarterKind::Escape => (
+                "my-container-escape",
+                "T1611",
+                "      # Most escapes need a misconfiguration to exploit, such as a host mount\n      \
+                 hostMounts: [dockerSock]\n",
+                "test -S /var/run/docker.sock",
+            ),
+            StarterKind::Privesc => (
+                "my-privilege-escalation",
+                "T1068",
+                "      # Drop privileges the exploit should not need\n      \
+                 security: [\"no-new-privileges\"]\n",
+                "test \"$(id -u)\" = 0",
+            ),
+            StarterKind::InfoLeak => (
+                "my-information-leak",
+                "T1082",
+                "",
+                "cat /proc/1/environ",
+            ),
+        }
+    }
+}
+
+/// A commented starter trick of the given kind. It is a valid trick as is, but its
+/// exploit step is only a placeholder.
+pub fn starter_trick(kind: StarterKind) -> String {
+    let (name, technique, container_opts, exploit) = kind.parts();
+
+    format!(
+        r#"# A trick is a list of steps that Houdini runs in order. Each step ends in a status:
+# the trick's final status is that of the first step to end in a final one
+# (setupFailure, exploitSuccess, exploitFailure, or skip).
+name: {name}
+# MITRE ATT&CK technique the trick attempts. Reports summarize coverage by technique.
+technique: {technique}
+steps:
+  # Skip the trick on hosts that are not vulnerable. Remove any bounds that do not
+  # apply.
+  - versionCheck:
+      kernel:
+        min: "5.4"
+      docker:
+        max: "20.10.99"
+      failure: skip
+  # Spawn the container to run the exploit in. Other steps refer to it by name.
+  - spawnContainer:
+      name: target
+      image: bash
+      cmd: sleep infinity
+{container_opts}      failure: setupFailure
+  # Run the exploit. Replace this placeholder with the real commands; the trick
+  # succeeds if they all exit with a status in okExitCodes.
+  - container:
+      name: target
+      script:
+        - {exploit:?}
+      failure: exploitFailure
+      success: exploitSuccess
+"#,
+        name = name,
+        technique = technique,
+        container_opts = container_opts,
+        exploit = exploit,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tricks::Trick;
+    use clap::ArgEnum as _;
+
+    #[test]
+    fn test_starter_trick() {
+        for kind in StarterKind::value_variants() {
+            let yaml = starter_trick(*kind);
+            let trick: Trick = serde_yaml::from_str(&yaml)
+                .unwrap_or_else(|e| panic!("{:?} starter should deserialize: {}", kind, e));
+            assert_eq!(trick.steps.len(), 3);
+            assert_eq!(trick.steps[0].step.kind(), "versionCheck");
+            assert_eq!(trick.steps[1].step.kind(), "spawnContainer");
+            assert_eq!(trick.steps[2].step.kind(), "container");
+        }
+    }
+}
@@ -8,75 +8,432 @@
 
 //! This module defines the steps used in Houdini [`super::Trick`]s.
 
-use std::fmt::Debug;
+use std::{
+    fmt::{Debug, Display},
+    num::NonZeroU32,
+    path::PathBuf,
+    time::Duration,
+};
 
 use anyhow::Result;
 use async_trait::async_trait;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use tracing::Instrument;
 
-use super::status::Status;
+use super::{report::Termination, status::Status};
+use crate::{
+    docker::{named_volume, ImageProvenance},
+    error::{is_harness_error, is_setup_error},
+};
 
 use self::{
-    container::{Container, KillContainer, SpawnContainer},
+    binary::BinaryCheck,
+    command::CommandFailure,
+    container::{CommitContainer, Container, KillContainer, SpawnContainer},
+    copy::CopyFile,
+    evidence::CollectEvidence,
     host::Host,
+    kernel_module::KernelModule,
+    process::ProcessCheck,
+    shell::Shell,
     version::VersionCheck,
     wait::Wait,
 };
 
+pub(crate) mod apparmor;
+pub(crate) mod assert;
+pub(crate) mod binary;
 pub(crate) mod command;
 pub(crate) mod container;
+pub(crate) mod copy;
+pub(crate) mod evidence;
 pub(crate) mod host;
+pub(crate) mod kernel_module;
+pub(crate) mod proc_status;
+pub(crate) mod process;
+pub(crate) mod shell;
+pub(crate) mod trace;
 pub(crate) mod version;
 pub(crate) mod wait;
 
 /// A series of steps for running and verifying the status of a container exploit.
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone)]
 #[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub(crate) enum Step {
     VersionCheck(Box<VersionCheck>),
-    SpawnContainer(SpawnContainer),
+    BinaryCheck(BinaryCheck),
+    SpawnContainer(Box<SpawnContainer>),
     KillContainer(KillContainer),
+    CommitContainer(CommitContainer),
     Container(Container),
     Host(Host),
     Wait(Wait),
+    KernelModule(KernelModule),
+    ProcessCheck(ProcessCheck),
+    Shell(Shell),
+    CollectEvidence(CollectEvidence),
+    CopyFile(CopyFile),
 }
 
 impl Step {
-    pub async fn run(&self) -> Status {
+    /// Run the step inside a `step` span recording the step's kind, its index in the
+    /// trick, and the trick's name. The span's `status` field is recorded once the step
+    /// completes.
+    pub async fn run(&self, trick: &str, index: usize) -> StepOutcome {
+        let span = tracing::info_span!(
+            "step",
+            kind = self.kind(),
+            index,
+            trick,
+            status = tracing::field::Empty
+        );
+
         match self {
             Step::VersionCheck(step) => step.run(),
+            Step::BinaryCheck(step) => step.run(),
             Step::SpawnContainer(step) => step.run(),
             Step::KillContainer(step) => step.run(),
+            Step::CommitContainer(step) => step.run(),
             Step::Container(step) => step.run(),
             Step::Host(step) => step.run(),
             Step::Wait(step) => step.run(),
+            Step::KernelModule(step) => step.run(),
+            Step::ProcessCheck(step) => step.run(),
+            Step::Shell(step) => step.run(),
+            Step::CollectEvidence(step) => step.run(),
+            Step::CopyFile(step) => step.run(),
         }
+        .instrument(span)
         .await
     }
+
+    /// Return a copy of this step with every container name, and the names of the
+    /// non-external named volumes and networks that containers use, mapped through
+    /// `rename`.
+    pub fn rename_containers(&self, rename: impl Fn(&str) -> String) -> Step {
+        let mut step = self.clone();
+        match &mut step {
+            Step::SpawnContainer(step) => {
+                step.name = rename(&step.name);
+                if let Some(network) = step.network.as_mut().filter(|n| !n.is_external()) {
+                    network.name = rename(&network.name);
+                }
+                let external = step.external_volumes.clone();
+                for volume in &mut step.volumes {
+                    match named_volume(volume) {
+                        Some(name) if !external.iter().any(|v| v == name) => {
+                            *volume = format!("{}{}", rename(name), &volume[name.len()..]);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Step::KillContainer(step) => step.name = rename(&step.name),
+            Step::CommitContainer(step) => step.name = rename(&step.name),
+            Step::Container(step) => step.name = rename(&step.name),
+            Step::ProcessCheck(step) => {
+                if let Some(name) = &mut step.container {
+                    *name = rename(name);
+                }
+            }
+            Step::Shell(step) => {
+                if let shell::ShellTarget::Container(name) = &mut step.target {
+                    *name = rename(name);
+                }
+            }
+            Step::CollectEvidence(step) => {
+                for source in &mut step.sources {
+                    if let evidence::EvidenceSource::Container { name, .. } = source {
+                        *name = rename(name);
+                    }
+                }
+            }
+            Step::Wait(step) => {
+                if let Some(name) = step.container_mut() {
+                    *name = rename(name);
+                }
+            }
+            Step::CopyFile(step) => {
+                for location in [&mut step.from, &mut step.to] {
+                    if let Some(name) = location.container_mut() {
+                        *name = rename(name);
+                    }
+                }
+            }
+            Step::VersionCheck(_)
+            | Step::BinaryCheck(_)
+            | Step::Host(_)
+            | Step::KernelModule(_) => {}
+        }
+        step
+    }
+
+    /// The kind of step, as it is named in trick files.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Step::VersionCheck(_) => "versionCheck",
+            Step::BinaryCheck(_) => "binaryCheck",
+            Step::SpawnContainer(_) => "spawnContainer",
+            Step::KillContainer(_) => "killContainer",
+            Step::CommitContainer(_) => "commitContainer",
+            Step::Container(_) => "container",
+            Step::Host(_) => "host",
+            Step::Wait(_) => "wait",
+            Step::KernelModule(_) => "kernelModule",
+            Step::ProcessCheck(_) => "processCheck",
+            Step::Shell(_) => "shell",
+            Step::CollectEvidence(_) => "collectEvidence",
+            Step::CopyFile(_) => "copyFile",
+        }
+    }
+}
+
+/// The outcome of running a step.
+#[derive(Debug, Default)]
+pub(crate) struct StepOutcome {
+    /// Status the step ended in.
+    pub status: Status,
+    /// The command in the step's script that failed, if any.
+    pub failed_command: Option<CommandFailure>,
+    /// Why the step failed, if it did.
+    pub reason: Option<String>,
+    /// How the step ended: whether it ran to completion or timed out.
+    pub termination: Termination,
+    /// Extra information produced by the step.
+    pub details: StepDetails,
+}
+
+/// Extra information that a step can produce when it succeeds.
+#[derive(Debug, Default)]
+pub(crate) struct StepDetails {
+    /// ID of an image that the step created.
+    pub image_id: Option<String>,
+    /// Trace files written by the step's commands.
+    pub trace_files: Vec<String>,
+    /// Results of host assertions run after the step.
+    pub assertions: Vec<assert::AssertResult>,
+    /// Whether the AppArmor profile requested by the step was loaded.
+    pub apparmor: Option<apparmor::AppArmorResult>,
+    /// PIDs of the processes that the step matched.
+    pub matched_pids: Vec<u32>,
+    /// Capabilities and seccomp state that the step's commands ran with.
+    pub proc_status: Option<proc_status::ProcStatus>,
+    /// Evidence artifacts that the step collected.
+    pub evidence: Vec<evidence::EvidenceArtifact>,
+    /// Where the image of a container that the step spawned came from.
+    pub provenance: Option<ImageProvenance>,
+    /// Standard output of the commands that the step ran, in order.
+    pub stdout: Option<String>,
+    /// Standard error of the commands that the step ran, in order.
+    pub stderr: Option<String>,
+    /// File holding the full standard output, if it was too long to keep inline.
+    pub stdout_file: Option<PathBuf>,
+    /// File holding the full standard error, if it was too long to keep inline.
+    pub stderr_file: Option<PathBuf>,
+    /// Full size in bytes of the standard output, if it was too long to keep inline.
+    pub stdout_bytes: Option<usize>,
+    /// Full size in bytes of the standard error, if it was too long to keep inline.
+    pub stderr_bytes: Option<usize>,
+}
+
+impl StepDetails {
+    /// Append a command's captured output to the step's. Output that is not valid UTF-8
+    /// is converted lossily.
+    pub fn add_output(&mut self, stdout: &[u8], stderr: &[u8]) {
+        self.stdout
+            .get_or_insert_with(String::new)
+            .push_str(&String::from_utf8_lossy(stdout));
+        self.stderr
+            .get_or_insert_with(String::new)
+            .push_str(&String::from_utf8_lossy(stderr));
+    }
+}
+
+/// How to retry a step that fails, e.g. for exploits that only win a race some of the
+/// time. The step's failure status only applies once every attempt has failed.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub(crate) struct RetryPolicy {
+    /// Most times to run the step, including the first. Must be at least 1.
+    pub attempts: NonZeroU32,
+    /// How long to wait between attempts, e.g. "500ms". Default is not to wait.
+    #[serde(default, with = "humantime_serde")]
+    #[schemars(with = "Option<String>")]
+    pub delay: Duration,
+}
+
+/// (De)serialize an optional file mode creation mask as an octal string, e.g. `"077"` or
+/// `"0o077"`. Integers are accepted as they are, so YAML's `0o077` also works, but an
+/// unquoted `077` is decimal. Masks above `0o777` are rejected.
+pub(crate) mod umask_serde {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Int(u32),
+        Str(String),
+    }
+
+    pub fn serialize<S>(umask: &Option<u32>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        umask
+            .map(|mask| format!("{:04o}", mask))
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let mask = match Option::<Repr>::deserialize(deserializer)? {
+            None => return Ok(None),
+            Some(Repr::Int(mask)) => mask,
+            Some(Repr::Str(s)) => {
+                let digits = s.strip_prefix("0o").unwrap_or(&s);
+                u32::from_str_radix(digits, 8).map_err(|_| {
+                    serde::de::Error::custom(format!("umask {:?} is not an octal number", s))
+                })?
+            }
+        };
+        if mask > 0o777 {
+            return Err(serde::de::Error::custom(format!(
+                "umask {:#o} is greater than 0o777",
+                mask
+            )));
+        }
+        Ok(Some(mask))
+    }
+}
+
+/// A step, or a command in a step, ran for longer than it was allowed to. Steps that time
+/// out end in their failure status, and their report records that they timed out.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TimedOut(pub Duration);
+
+impl Display for TimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "timed out after {:?}", self.0)
+    }
 }
 
 #[async_trait]
 pub(crate) trait RunStep: Debug {
     /// Run the step, returning the corresponding exploit status depending on whether it
     /// succeeded or failed.
-    async fn run(&self) -> Status {
-        match self.do_run().await {
-            Ok(_) => {
+    async fn run(&self) -> StepOutcome {
+        tracing::debug!(step = ?self, "running step");
+
+        let mut details = StepDetails::default();
+        let outcome = match self.do_run_retried(&mut details).await {
+            Ok(()) => {
                 let status = self.on_success();
-                tracing::info!(step = ?self, status = ?status, "step succeeded");
-                status
+                tracing::info!(status = ?status, "step succeeded");
+                StepOutcome {
+                    status,
+                    details,
+                    ..Default::default()
+                }
             }
             Err(e) => {
-                let status = self.on_failure();
-                tracing::info!(error = ?e, step = ?self, status = ?status, "step failed");
-                status
+                let status = if is_harness_error(&e) {
+                    let status = Status::HarnessError;
+                    tracing::warn!(error = ?e, status = ?status, "step could not run");
+                    status
+                } else if is_setup_error(&e) {
+                    let status = Status::SetupFailure;
+                    tracing::warn!(error = ?e, status = ?status, "step is set up wrongly");
+                    status
+                } else {
+                    let status = self.on_failure();
+                    tracing::info!(error = ?e, status = ?status, "step failed");
+                    status
+                };
+                StepOutcome {
+                    status,
+                    failed_command: e.downcast_ref::<CommandFailure>().cloned(),
+                    reason: Some(format!("{:#}", e)),
+                    termination: match e.downcast_ref::<TimedOut>() {
+                        Some(_) => Termination::TimedOut,
+                        None => Termination::Completed,
+                    },
+                    details,
+                }
             }
-        }
+        };
+
+        tracing::Span::current().record("status", tracing::field::debug(&outcome.status));
+        outcome
     }
 
-    /// Internal implementation of [`RunStep::run`].
+    /// Internal implementation of [`RunStep::run`]. Errors that originate from Houdini
+    /// itself rather than the exploit should be tagged with
+    /// [`crate::error::HarnessError`] or be Docker transport errors, in which case the step
+    /// ends in [`Status::HarnessError`] instead of its failure status. Likewise, errors
+    /// tagged with [`crate::error::SetupError`] end the step in [`Status::SetupFailure`].
     async fn do_run(&self) -> Result<()>;
 
+    /// Like [`RunStep::do_run`], but also records extra information about what the step
+    /// did into `details`, whether or not it succeeds. Steps that produce such
+    /// information should override this.
+    async fn do_run_detailed(&self, details: &mut StepDetails) -> Result<()> {
+        let _ = details;
+        self.do_run().await
+    }
+
+    /// Like [`RunStep::do_run_detailed`], but fails with [`TimedOut`] if the step runs for
+    /// longer than [`RunStep::timeout`].
+    async fn do_run_timed(&self, details: &mut StepDetails) -> Result<()> {
+        let timeout = match self.timeout() {
+            Some(timeout) => timeout,
+            None => return self.do_run_detailed(details).await,
+        };
+
+        match tokio::time::timeout(timeout, self.do_run_detailed(details)).await {
+            Ok(res) => res,
+            Err(_) => Err(anyhow::anyhow!(TimedOut(timeout))),
+        }
+    }
+
+    /// Like [`RunStep::do_run_timed`], but runs the step again after a failure, up to
+    /// the attempts allowed by [`RunStep::retry`]. Only the last attempt's details are
+    /// kept. Harness and setup errors are not retried, since they would only recur.
+    async fn do_run_retried(&self, details: &mut StepDetails) -> Result<()> {
+        let (attempts, delay) = match self.retry() {
+            Some(retry) => (retry.attempts.get(), retry.delay),
+            None => return self.do_run_timed(details).await,
+        };
+
+        let mut attempt = 1;
+        loop {
+            *details = StepDetails::default();
+            match self.do_run_timed(details).await {
+                Err(e) if attempt < attempts && !is_harness_error(&e) && !is_setup_error(&e) => {
+                    tracing::debug!(attempt, attempts, error = ?e, "step attempt failed, retrying");
+                }
+                res => {
+                    tracing::debug!(attempt, attempts, ok = res.is_ok(), "step attempt finished");
+                    return res;
+                }
+            }
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// How to retry the step if it fails. Default is not to retry it.
+    fn retry(&self) -> Option<&RetryPolicy> {
+        None
+    }
+
+    /// How long the step may run for before it fails. Default is no limit.
+    fn timeout(&self) -> Option<Duration> {
+        None
+    }
+
     /// This function is run on success and should return the appropriate status.
     fn on_success(&self) -> Status;
 
@@ -0,0 +1,109 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+//
+
+//! Helpers for recording which AppArmor profile a spawned container was confined by.
+
+use std::path::Path;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// The list of profiles currently loaded into the kernel.
+const PROFILES_PATH: &str = "/sys/kernel/security/apparmor/profiles";
+
+/// Whether the AppArmor profile requested for a container was loaded.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct AppArmorResult {
+    /// Name of the requested profile.
+    pub name: String,
+    /// Was the profile loaded once the container was spawned?
+    pub loaded: bool,
+    /// Was the profile already loaded before the container was spawned?
+    pub already_present: bool,
+}
+
+/// Get the AppArmor profile requested by a list of docker security options, if any.
+/// An `unconfined` profile is not a real profile, so it is ignored.
+pub(crate) fn requested_profile(security: &[String]) -> Option<&str> {
+    security
+        .iter()
+        .rev()
+        .filter_map(|opt| opt.split_once(['=', ':']))
+        .filter(|(key, _)| *key == "apparmor")
+        .map(|(_, profile)| profile)
+        .next()
+        .filter(|profile| *profile != "unconfined")
+}
+
+/// Is the named profile currently loaded? Returns false if AppArmor is not enabled.
+pub(crate) fn profile_loaded(name: &str) -> bool {
+    profile_loaded_in(Path::new(PROFILES_PATH), name)
+}
+
+fn profile_loaded_in(path: &Path, name: &str) -> bool {
+    match std::fs::read_to_string(path) {
+        Ok(profiles) => parse_profiles(&profiles).any(|profile| profile == name),
+        Err(e) => {
+            tracing::debug!(err = ?e, path = ?path, "failed to read apparmor profiles");
+            false
+        }
+    }
+}
+
+/// Parse the names of loaded profiles from lines like `docker-default (enforce)`.
+fn parse_profiles(profiles: &str) -> impl Iterator<Item = &str> {
+    profiles.lines().filter_map(|line| {
+        let line = line.trim();
+        let name = match line.rsplit_once(" (") {
+            Some((name, mode)) if mode.ends_with(')') => name,
+            _ => line,
+        };
+        (!name.is_empty()).then_some(name)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_presence() {
+        let mut d = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("testdata/apparmor/profiles");
+
+        assert!(profile_loaded_in(&d, "docker-default"));
+        assert!(profile_loaded_in(&d, "houdini-escape"));
+        assert!(profile_loaded_in(
+            &d,
+            "/usr/lib/snapd/snap-confine//mount-namespace-capture-helper"
+        ));
+        assert!(!profile_loaded_in(&d, "docker"));
+        assert!(!profile_loaded_in(&d, "enforce"));
+        assert!(!profile_loaded_in(
+            Path::new("/nonexistent/profiles"),
+            "docker-default"
+        ));
+    }
+
+    #[test]
+    fn test_requested_profile() {
+        let opts = |opts: &[&str]| opts.iter().map(|o| o.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(
+            requested_profile(&opts(&["no-new-privileges", "apparmor=houdini-escape"])),
+            Some("houdini-escape")
+        );
+        assert_eq!(
+            requested_profile(&opts(&["apparmor:docker-default"])),
+            Some("docker-default")
+        );
+        assert_eq!(requested_profile(&opts(&["apparmor=unconfined"])), None);
+        assert_eq!(requested_profile(&opts(&["seccomp=unconfined"])), None);
+    }
+}
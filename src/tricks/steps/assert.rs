@@ -0,0 +1,190 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+//
+
+//! Host-side assertions that container steps can run after they complete, to check for
+//! effects that should be observable from the host.
+
+use std::{
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use anyhow::{Context as _, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::{command::ShellCommand, StepDetails};
+
+/// An assertion about the state of the host.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub(crate) enum Assert {
+    /// A file exists at this path.
+    FileExists(PathBuf),
+    /// No file exists at this path.
+    FileMissing(PathBuf),
+    /// A file contains a string.
+    FileContains {
+        /// Path to the file.
+        path: PathBuf,
+        /// String that should appear somewhere in the file.
+        contains: String,
+    },
+    /// A command exits with status 0, and its stdout satisfies its matchers, if any.
+    Command(ShellCommand),
+}
+
+impl Assert {
+    /// Check the assertion, returning an error describing why it does not hold.
+    pub fn check(&self) -> Result<()> {
+        match self {
+            Assert::FileExists(path) => {
+                if !path.exists() {
+                    anyhow::bail!("{} does not exist", path.display())
+                }
+            }
+            Assert::FileMissing(path) => {
+                if path.exists() {
+                    anyhow::bail!("{} exists", path.display())
+                }
+            }
+            Assert::FileContains { path, contains } => {
+                let data =
+                    std::fs::read(path).context(format!("failed to read {}", path.display()))?;
+                if !String::from_utf8_lossy(&data).contains(contains.as_str()) {
+                    anyhow::bail!("{} does not contain {:?}", path.display(), contains)
+                }
+            }
+            Assert::Command(cmd) => {
+                let output = Command::new(&cmd.command)
+                    .args(&cmd.args)
+                    .stdin(Stdio::null())
+                    .stderr(Stdio::null())
+                    .output()
+                    .context(format!("failed to run {}", cmd.command))?;
+                if !output.status.success() {
+                    anyhow::bail!("{} failed with {}", cmd.command, output.status)
+                }
+                cmd.check_stdout(&String::from_utf8_lossy(&output.stdout))
+                    .context(format!("{} printed unexpected output", cmd.command))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The result of checking an [`Assert`].
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct AssertResult {
+    /// Did the assertion hold?
+    pub passed: bool,
+    /// Why the assertion did not hold, if it did not.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Check every assertion, recording each result in `details`. Returns an error if any
+/// assertion does not hold.
+pub(crate) fn check_all(asserts: &[Assert], details: &mut StepDetails) -> Result<()> {
+    let mut failed = vec![];
+
+    for (index, assert) in asserts.iter().enumerate() {
+        let res = assert.check();
+        tracing::debug!(assert = ?assert, res = ?res, "checked host assertion");
+
+        let reason = res.err().map(|e| format!("{:#}", e));
+        if let Some(reason) = &reason {
+            failed.push(format!("assertion {}: {}", index, reason));
+        }
+        details.assertions.push(AssertResult {
+            passed: reason.is_none(),
+            reason,
+        });
+    }
+
+    if !failed.is_empty() {
+        anyhow::bail!("host assertions failed ({})", failed.join("; "))
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::assert_yaml_deserialize;
+
+    #[test]
+    fn test_check_all() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        std::fs::write(dir.path().join("flag"), "pwned\n").unwrap();
+
+        let yaml = format!(
+            r#"
+            - fileExists: {dir}/flag
+            - fileMissing: {dir}/nope
+            - fileContains:
+                path: {dir}/flag
+                contains: pwned
+            - command: test -s {dir}/flag
+            "#,
+            dir = dir.path().display()
+        );
+        let asserts: Vec<Assert> = assert_yaml_deserialize(&yaml);
+
+        let mut details = StepDetails::default();
+        check_all(&asserts, &mut details).expect("assertions should hold");
+        assert!(details.assertions.iter().all(|a| a.passed));
+
+        let yaml = format!(
+            r#"
+            - fileExists: {dir}/flag
+            - fileContains:
+                path: {dir}/flag
+                contains: benign
+            "#,
+            dir = dir.path().display()
+        );
+        let asserts: Vec<Assert> = assert_yaml_deserialize(&yaml);
+
+        let mut details = StepDetails::default();
+        let err = check_all(&asserts, &mut details).expect_err("assertion should fail");
+        assert!(err.to_string().contains("assertion 1"));
+        assert_eq!(
+            details
+                .assertions
+                .iter()
+                .map(|a| a.passed)
+                .collect::<Vec<_>>(),
+            vec![true, false]
+        );
+    }
+
+    #[test]
+    fn test_command_stdout_matchers() {
+        let yaml = r#"
+            - command:
+                command: echo
+                args: [uid=0]
+                stdoutContains: uid=0
+            - command:
+                command: echo
+                args: [uid=1000]
+                stdoutRegex: "^uid=0$"
+            "#;
+        let asserts: Vec<Assert> = assert_yaml_deserialize(yaml);
+
+        asserts[0].check().expect("stdout should match");
+        let err = asserts[1].check().expect_err("stdout should not match");
+        assert!(
+            format!("{:#}", err).contains("stdout did not match"),
+            "{:#}",
+            err
+        );
+    }
+}
@@ -0,0 +1,231 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+//
+
+//! This module defines a step that verifies the hash of a binary on the host, e.g. to
+//! detect a runtime that has been swapped or patched.
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{bail, Context as _, Result};
+use async_trait::async_trait;
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::RunStep;
+use crate::{error::SetupError, tricks::status::Status};
+
+/// Check that a binary on the host has the expected SHA256 digest.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub(crate) struct BinaryCheck {
+    /// The binary to check, e.g. `runc`. Names without a `/` are looked up in `PATH`.
+    pub binary: String,
+    /// Expected SHA256 digest of the binary, hex encoded.
+    #[serde(deserialize_with = "deserialize_sha256")]
+    pub sha256: String,
+    /// Fail the step if it runs for longer than this, e.g. "30s". Default is no timeout.
+    #[serde(default, with = "humantime_serde")]
+    #[schemars(with = "Option<String>")]
+    pub timeout: Option<Duration>,
+    /// Status on failure. Default is Skip.
+    #[serde(default = "crate::serde_defaults::default_skip")]
+    pub failure: Status,
+    /// Status on success. Default is Undecided.
+    #[serde(default)]
+    pub success: Status,
+}
+
+#[async_trait]
+impl RunStep for BinaryCheck {
+    async fn do_run(&self) -> Result<()> {
+        let expected = self.sha256.to_ascii_lowercase();
+        if !is_sha256(&expected) {
+            return Err(anyhow::anyhow!(
+                "{:?} is not a hex-encoded SHA256 digest",
+                self.sha256
+            ))
+            .context(SetupError("invalid sha256"));
+        }
+
+        let path = resolve_binary(&self.binary, std::env::var_os("PATH").as_deref())?;
+        let actual = {
+            let path = path.clone();
+            tokio::task::spawn_blocking(move || sha256_file(&path))
+                .await
+                .context("hashing task panicked")??
+        };
+        tracing::info!(binary = ?self.binary, path = ?path, sha256 = ?actual, "hashed binary");
+
+        if actual != expected {
+            bail!(
+                "{} has SHA256 {}, expected {}",
+                path.display(),
+                actual,
+                expected
+            );
+        }
+
+        Ok(())
+    }
+
+    fn on_success(&self) -> Status {
+        self.success
+    }
+
+    fn on_failure(&self) -> Status {
+        self.failure
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+}
+
+/// Is `digest` a hex-encoded SHA256 digest?
+fn is_sha256(digest: &str) -> bool {
+    digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Deserialize a hex-encoded SHA256 digest, so that a malformed one is caught when the
+/// trick is parsed rather than skipping the step when it runs.
+fn deserialize_sha256<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let digest = String::deserialize(deserializer)?;
+    if !is_sha256(&digest) {
+        return Err(serde::de::Error::custom(format!(
+            "{:?} is not a hex-encoded SHA256 digest",
+            digest
+        )));
+    }
+    Ok(digest)
+}
+
+/// Find `binary` the way a shell would: as given if it contains a `/`, and in the
+/// directories of `path` otherwise.
+fn resolve_binary(binary: &str, path: Option<&std::ffi::OsStr>) -> Result<PathBuf> {
+    if binary.contains('/') {
+        let binary = Path::new(binary);
+        if !binary.is_file() {
+            bail!("binary {} does not exist", binary.display());
+        }
+        return Ok(binary.to_owned());
+    }
+
+    path.map(std::env::split_paths)
+        .into_iter()
+        .flatten()
+        .map(|dir| dir.join(binary))
+        .find(|candidate| is_executable(candidate))
+        .ok_or_else(|| anyhow::anyhow!("binary {} not found in PATH", binary))
+}
+
+/// Is `path` a file that someone may execute?
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    path.metadata()
+        .map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+/// Hex-encoded SHA256 digest of the file at `path`.
+fn sha256_file(path: &Path) -> Result<String> {
+    let mut file =
+        std::fs::File::open(path).context(format!("failed to open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).context(format!("failed to read {}", path.display()))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, os::unix::fs::PermissionsExt};
+
+    use super::*;
+    use crate::testutils::assert_yaml_deserialize;
+
+    #[tokio::test]
+    async fn test_binary_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = dir.path().join("runc");
+        let mut f = std::fs::File::create(&binary).unwrap();
+        write!(f, "#!/bin/sh\necho runc\n").unwrap();
+        std::fs::set_permissions(&binary, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let sha256 = format!("{:x}", Sha256::digest(b"#!/bin/sh\necho runc\n"));
+
+        let step: BinaryCheck = assert_yaml_deserialize(&format!(
+            "binary: {}\nsha256: {}\n",
+            binary.display(),
+            sha256.to_ascii_uppercase()
+        ));
+        assert_eq!(step.failure, Status::Skip);
+        step.do_run().await.expect("hash should match");
+
+        let step = BinaryCheck {
+            sha256: format!("{:x}", Sha256::digest(b"patched")),
+            ..step
+        };
+        let err = step.do_run().await.expect_err("hash should not match");
+        assert!(err.to_string().contains(&sha256), "{:#}", err);
+
+        let step = BinaryCheck {
+            sha256: "deadbeef".into(),
+            ..step
+        };
+        let err = step
+            .do_run()
+            .await
+            .expect_err("malformed digest should fail");
+        assert!(crate::error::is_setup_error(&err), "{:#}", err);
+    }
+
+    #[test]
+    fn test_malformed_sha256() {
+        for sha256 in ["deadbeef", &"g".repeat(64), &"a".repeat(65)] {
+            let err = serde_yaml::from_str::<BinaryCheck>(&format!(
+                "binary: runc
+sha256: {}
+",
+                sha256
+            ))
+            .expect_err("malformed digest should not parse");
+            assert!(
+                err.to_string().contains("not a hex-encoded SHA256 digest"),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_resolve_binary() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = dir.path().join("containerd");
+        std::fs::write(&binary, "").unwrap();
+        let path = std::env::join_paths(["/nonexistent", dir.path().to_str().unwrap()]).unwrap();
+
+        // Not executable yet
+        resolve_binary("containerd", Some(&path)).expect_err("binary should not resolve");
+
+        std::fs::set_permissions(&binary, std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(resolve_binary("containerd", Some(&path)).unwrap(), binary);
+        assert_eq!(
+            resolve_binary(binary.to_str().unwrap(), None).unwrap(),
+            binary
+        );
+        resolve_binary("containerd", None).expect_err("binary should not resolve");
+        resolve_binary("/nonexistent/runc", Some(&path))
+            .expect_err("missing binary should not resolve");
+    }
+}
@@ -8,12 +8,282 @@
 
 //! Helper types for defining commands to run.
 
+use std::{fmt::Display, path::PathBuf};
+
+use anyhow::{bail, Context as _, Result};
+use regex::Regex;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-/// Defines a command to run in a container or on the host.
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+use crate::{docker::ExecResult, error::SetupError};
+
+/// Defines a command to run in a container or on the host. This can be written either as
+/// a `command` with a list of `args`, or as a single string such as `"echo 'hello world'"`,
+/// which is split like a shell would. It is always serialized in the former form, which
+/// may also set matchers that the command's stdout must satisfy.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone, Default)]
 #[serde(rename_all = "camelCase", deny_unknown_fields)]
+#[serde(try_from = "ShellCommandRepr")]
 pub struct ShellCommand {
     pub command: String,
     pub args: Vec<String>,
+    /// Fail the command, even if it exits successfully, unless its stdout contains this.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stdout_contains: Option<String>,
+    /// Fail the command, even if it exits successfully, unless this regex matches its
+    /// stdout.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stdout_regex: Option<String>,
+}
+
+impl ShellCommand {
+    /// Check the command's stdout against its matchers.
+    pub fn check_stdout(&self, stdout: &str) -> Result<()> {
+        if let Some(contains) = &self.stdout_contains {
+            if !stdout.contains(contains.as_str()) {
+                bail!("stdout did not contain {:?}", contains);
+            }
+        }
+        if let Some(regex) = self.compiled_regex()? {
+            if !regex.is_match(stdout) {
+                bail!("stdout did not match /{}/", regex);
+            }
+        }
+        Ok(())
+    }
+
+    /// The stdout regex, compiled. Regexes in trick files are checked when they are
+    /// parsed, so this only fails for commands built by Houdini itself.
+    fn compiled_regex(&self) -> Result<Option<Regex>> {
+        self.stdout_regex
+            .as_deref()
+            .map(|regex| {
+                Regex::new(regex)
+                    .map_err(anyhow::Error::from)
+                    .context(SetupError("invalid stdout regex"))
+            })
+            .transpose()
+    }
+}
+
+/// The forms a [`ShellCommand`] may be written in.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ShellCommandRepr {
+    Line(String),
+    Struct(ShellCommandStruct),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct ShellCommandStruct {
+    command: String,
+    args: Vec<String>,
+    #[serde(default)]
+    stdout_contains: Option<String>,
+    #[serde(default)]
+    stdout_regex: Option<String>,
+}
+
+impl TryFrom<ShellCommandRepr> for ShellCommand {
+    type Error = String;
+
+    fn try_from(repr: ShellCommandRepr) -> Result<Self, Self::Error> {
+        match repr {
+            ShellCommandRepr::Struct(ShellCommandStruct {
+                command,
+                args,
+                stdout_contains,
+                stdout_regex,
+            }) => {
+                if let Some(regex) = &stdout_regex {
+                    Regex::new(regex).map_err(|e| format!("invalid stdoutRegex: {}", e))?;
+                }
+                Ok(Self {
+                    command,
+                    args,
+                    stdout_contains,
+                    stdout_regex,
+                })
+            }
+            ShellCommandRepr::Line(line) => {
+                let mut words = shlex::split(&line)
+                    .ok_or_else(|| format!("invalid quoting in command `{}`", line))?
+                    .into_iter();
+                let command = words
+                    .next()
+                    .ok_or_else(|| "command must not be empty".to_owned())?;
+
+                Ok(Self {
+                    command,
+                    args: words.collect(),
+                    ..Default::default()
+                })
+            }
+        }
+    }
+}
+
+/// Captured output of a command.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone, Default)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct CommandOutput {
+    pub stdout: String,
+    pub stderr: String,
+    /// Full size in bytes of the standard output, if it was cut off in `stdout`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stdout_bytes: Option<usize>,
+    /// Full size in bytes of the standard error, if it was cut off in `stderr`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stderr_bytes: Option<usize>,
+    /// File that the full standard output was spilled to, if it was cut off.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stdout_file: Option<PathBuf>,
+    /// File that the full standard error was spilled to, if it was cut off.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stderr_file: Option<PathBuf>,
+}
+
+impl CommandOutput {
+    /// Capture raw command output, replacing any invalid UTF-8.
+    pub fn from_bytes(stdout: &[u8], stderr: &[u8]) -> Self {
+        Self {
+            stdout: String::from_utf8_lossy(stdout).into_owned(),
+            stderr: String::from_utf8_lossy(stderr).into_owned(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Describes which command in a step's script failed, along with its output. Steps
+/// attach this as context to the error they return so that it can be reported.
+#[derive(Debug, Clone)]
+pub struct CommandFailure {
+    /// Index of the failed command in the script.
+    pub index: usize,
+    /// Captured output of the failed command.
+    pub output: CommandOutput,
+    /// How the failed command exited, if it ran at all.
+    pub result: Option<ExecResult>,
+}
+
+impl Display for CommandFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "command {} in script failed", self.index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::{assert_yaml_deserialize, assert_yaml_serialize};
+
+    #[test]
+    fn test_string_form() {
+        let cmd: ShellCommand = assert_yaml_deserialize("echo hello world");
+        assert_eq!(
+            cmd,
+            ShellCommand {
+                command: "echo".into(),
+                args: vec!["hello".into(), "world".into()],
+                ..Default::default()
+            }
+        );
+
+        let cmd: ShellCommand = assert_yaml_deserialize("whoami");
+        assert_eq!(cmd.command, "whoami");
+        assert!(cmd.args.is_empty());
+    }
+
+    #[test]
+    fn test_quoted_args() {
+        let cmd: ShellCommand =
+            assert_yaml_deserialize(r#"'sh -c "echo ''hello world''" "it''s"'"#);
+        assert_eq!(
+            cmd,
+            ShellCommand {
+                command: "sh".into(),
+                args: vec!["-c".into(), "echo 'hello world'".into(), "it's".into()],
+                ..Default::default()
+            }
+        );
+
+        serde_yaml::from_str::<ShellCommand>(r#"echo "unterminated"#)
+            .expect_err("unbalanced quotes should fail");
+        serde_yaml::from_str::<ShellCommand>(r#""""#).expect_err("empty command should fail");
+    }
+
+    #[test]
+    fn test_struct_form() {
+        let yaml = r#"
+            command: echo
+            args: ["hello world"]
+            "#;
+        let cmd: ShellCommand = assert_yaml_deserialize(yaml);
+        assert_eq!(
+            cmd,
+            ShellCommand {
+                command: "echo".into(),
+                args: vec!["hello world".into()],
+                ..Default::default()
+            }
+        );
+
+        // Both forms serialize back to the struct form
+        assert_yaml_serialize(&cmd);
+        let line: ShellCommand = serde_yaml::from_str("echo 'hello world'").unwrap();
+        assert_eq!(
+            serde_yaml::to_string(&line).unwrap(),
+            serde_yaml::to_string(&cmd).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_stdout_matchers() {
+        let yaml = r#"
+            command: id
+            args: []
+            stdoutContains: uid=0
+            stdoutRegex: "gid=\\d+"
+            "#;
+        let cmd: ShellCommand = assert_yaml_deserialize(yaml);
+        assert_eq!(cmd.stdout_contains.as_deref(), Some("uid=0"));
+        cmd.check_stdout("uid=0(root) gid=0(root)\n")
+            .expect("stdout should match");
+
+        let err = cmd
+            .check_stdout("uid=1000(user) gid=1000(user)\n")
+            .expect_err("substring should not match");
+        assert_eq!(err.to_string(), r#"stdout did not contain "uid=0""#);
+
+        let cmd = ShellCommand {
+            stdout_contains: None,
+            ..cmd
+        };
+        let err = cmd
+            .check_stdout("uid=0(root)\n")
+            .expect_err("regex should not match");
+        assert_eq!(err.to_string(), r"stdout did not match /gid=\d+/");
+
+        let err = serde_yaml::from_str::<ShellCommand>(
+            r#"
+            command: id
+            args: []
+            stdoutRegex: "gid=("
+            "#,
+        )
+        .expect_err("invalid regex should not parse");
+        assert!(err.to_string().contains("invalid stdoutRegex"), "{}", err);
+
+        let cmd = ShellCommand {
+            stdout_regex: Some("gid=(".into()),
+            ..cmd
+        };
+        let err = cmd.check_stdout("").expect_err("regex should be invalid");
+        assert!(crate::error::is_setup_error(&err));
+
+        // Commands without matchers accept any output
+        let cmd: ShellCommand = assert_yaml_deserialize("echo hi");
+        cmd.check_stdout("anything").expect("stdout should match");
+    }
 }
@@ -8,22 +8,40 @@
 
 //! This module defines the steps that manipulate containers.
 
-use anyhow::Result;
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::{Context as _, Result};
 use async_trait::async_trait;
-use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize};
 
-use super::{command::ShellCommand, RunStep};
+use super::{
+    apparmor::{profile_loaded, requested_profile, AppArmorResult},
+    assert::{check_all, Assert},
+    command::{CommandFailure, CommandOutput, ShellCommand},
+    proc_status::ProcStatus,
+    trace::TraceOpts,
+    RetryPolicy, RunStep, StepDetails,
+};
 use crate::{
-    docker::{kill_container, run_command, spawn_container, ImagePullPolicy},
+    docker::{
+        commit_container, image_provenance, kill_container, run_command, spawn_container,
+        CgroupParent, ExecOpts, HostMount, ImageProvenance, ImagePullPolicy, NetworkOpts,
+        SpawnOpts, Ulimit, UsernsMode,
+    },
     tricks::status::Status,
+    CONFIG,
 };
 
 /// Spawn a container using the docker api.
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone)]
 #[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub(crate) struct SpawnContainer {
-    /// Name to assign the container. This is done using the docker api, so commands
-    /// like `docker exec -it <name> <command>` will work.
+    /// Name to assign the container. Each trick run appends a random suffix to this
+    /// name, so that concurrent runs of the same trick do not collide. Other container
+    /// steps in the trick refer to the container by this logical name. Must not be
+    /// `host`, which copy steps use to refer to the host.
+    #[serde(deserialize_with = "deserialize_container_name")]
     pub name: String,
     /// Container image to use for the spawned container.
     pub image: String,
@@ -33,15 +51,64 @@ pub(crate) struct SpawnContainer {
     pub image_policy: ImagePullPolicy,
     /// Command to run in the container.
     pub cmd: Option<String>,
-    /// Docker volumes for the container.
+    /// Docker volumes for the container. Named volumes are suffixed for each run, like
+    /// container names, so they are shared by the trick's containers but not across runs.
     #[serde(default)]
     pub volumes: Vec<String>,
-    /// List of string options to customize LSM systems like SELinux.
+    /// Names of the named volumes in `volumes` that already exist, e.g. to mount data
+    /// prepared outside of Houdini. These keep their names and are not removed when the
+    /// trick ends.
+    #[serde(default)]
+    pub external_volumes: Vec<String>,
+    /// Well-known host paths to bind mount into the container. These are appended to
+    /// `volumes`.
+    #[serde(default)]
+    pub host_mounts: Vec<HostMount>,
+    /// List of string options to customize LSM systems like SELinux. If this requests
+    /// an AppArmor profile, e.g. `apparmor=docker-default`, the step report records
+    /// whether that profile was loaded.
     #[serde(default)]
     pub security: Vec<String>,
     /// Spawn the container with extra privileges.
     #[serde(default = "crate::serde_defaults::default_false")]
     pub privileged: bool,
+    /// Have Docker remove the container as soon as it exits. Default is false, in which
+    /// case Houdini removes the container when the trick completes.
+    #[serde(default = "crate::serde_defaults::default_false")]
+    pub auto_remove: bool,
+    /// Network to attach the container to, which is created if it does not exist.
+    /// Like container names, network names are suffixed per trick run. Default is
+    /// Docker's default bridge network.
+    #[serde(default)]
+    pub network: Option<NetworkOpts>,
+    /// Environment variables to set in the container, e.g. `LD_PRELOAD`. Values may
+    /// refer to the host's environment as `${VAR}`, which is expanded when the
+    /// container is spawned.
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Cgroup to place the container under, either a cgroupfs path such as `/houdini`
+    /// or a systemd slice such as `houdini.slice`, depending on Docker's cgroup
+    /// driver. Default is Docker's own parent.
+    #[serde(default)]
+    pub cgroup_parent: Option<CgroupParent>,
+    /// Resource limits to set in the container, e.g. `{name: nofile, soft: 1024, hard:
+    /// 2048}`. Default is Docker's own limits.
+    #[serde(default)]
+    pub ulimits: Vec<Ulimit>,
+    /// User namespace to run the container in: `host` to share the host's even if the
+    /// daemon remaps users, `private` for one of its own, or `""` for the daemon's
+    /// default, which is also the default here. Docker only supports `private` when its
+    /// daemon runs with userns-remap, and the step fails to set up otherwise.
+    #[serde(default)]
+    pub userns_mode: Option<UsernsMode>,
+    /// Host-side assertions to check once the step succeeds. If any do not hold, the
+    /// step fails.
+    #[serde(default)]
+    pub after: Vec<Assert>,
+    /// Fail the step if it runs for longer than this, e.g. "30s". Default is no timeout.
+    #[serde(default, with = "humantime_serde")]
+    #[schemars(with = "Option<String>")]
+    pub timeout: Option<Duration>,
     /// Status on failure. Default is SetupFailure.
     #[serde(default = "crate::serde_defaults::default_setup_failure")]
     pub failure: Status,
@@ -50,19 +117,95 @@ pub(crate) struct SpawnContainer {
     pub success: Status,
 }
 
+impl SpawnContainer {
+    /// Is `name` a named volume that already exists, which the trick neither creates nor
+    /// renames?
+    pub fn is_external_volume(&self, name: &str) -> bool {
+        self.external_volumes.iter().any(|volume| volume == name)
+    }
+
+    /// The container's environment as sorted `KEY=VALUE` pairs, with `${VAR}` in values
+    /// expanded from the host's environment.
+    fn expanded_env(&self) -> Result<Vec<String>> {
+        let mut env = self
+            .env
+            .iter()
+            .map(|(key, value)| {
+                let value = shellexpand::env(value)
+                    .with_context(|| format!("failed to expand environment variable {}", key))?;
+                Ok(format!("{}={}", key, value))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        env.sort();
+        Ok(env)
+    }
+}
+
 #[async_trait]
 impl RunStep for SpawnContainer {
     async fn do_run(&self) -> Result<()> {
-        spawn_container(
-            &self.name,
-            &self.image,
-            &self.image_policy,
-            self.cmd.as_deref(),
-            &self.volumes,
-            self.privileged,
-            &self.security,
-        )
-        .await
+        self.do_run_detailed(&mut StepDetails::default()).await
+    }
+
+    async fn do_run_detailed(&self, details: &mut StepDetails) -> Result<()> {
+        let volumes = self
+            .volumes
+            .iter()
+            .cloned()
+            .chain(self.host_mounts.iter().map(HostMount::bind))
+            .collect::<Vec<_>>();
+
+        let env = self.expanded_env()?;
+        for ulimit in &self.ulimits {
+            ulimit.validate()?;
+        }
+
+        let apparmor = requested_profile(&self.security);
+        let already_present = apparmor.map(profile_loaded);
+
+        let info = spawn_container(&SpawnOpts {
+            name: &self.name,
+            image: &self.image,
+            image_policy: &self.image_policy,
+            cmd: self.cmd.as_deref(),
+            volumes: &volumes,
+            privileged: self.privileged,
+            security_options: &self.security,
+            auto_remove: self.auto_remove,
+            network: self.network.as_ref(),
+            env: &env,
+            cgroup_parent: self.cgroup_parent.as_ref(),
+            ulimits: &self.ulimits,
+            userns_mode: self.userns_mode,
+        })
+        .await?;
+
+        // Provenance is informational, so failing to find it should not fail the step
+        match image_provenance(&self.image).await {
+            Ok(provenance) => {
+                details.provenance = Some(ImageProvenance {
+                    available_platforms: info.available_platforms,
+                    ..provenance
+                })
+            }
+            Err(e) => {
+                tracing::warn!(err = ?e, image = ?self.image, "failed to record image provenance")
+            }
+        }
+
+        if let (Some(name), Some(already_present)) = (apparmor, already_present) {
+            let loaded = profile_loaded(name);
+            if !loaded {
+                tracing::warn!(profile = name, "requested apparmor profile is not loaded");
+            }
+            details.apparmor = Some(AppArmorResult {
+                name: name.to_owned(),
+                loaded,
+                already_present,
+            });
+        }
+
+        check_all(&self.after, details)
     }
 
     fn on_success(&self) -> Status {
@@ -72,14 +215,22 @@ impl RunStep for SpawnContainer {
     fn on_failure(&self) -> Status {
         self.failure
     }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
 }
 
 /// Kill a container using the docker api.
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone)]
 #[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub(crate) struct KillContainer {
     /// Name of the container to kill.
     pub name: String,
+    /// Fail the step if it runs for longer than this, e.g. "30s". Default is no timeout.
+    #[serde(default, with = "humantime_serde")]
+    #[schemars(with = "Option<String>")]
+    pub timeout: Option<Duration>,
     /// Status on failure. Default is Undecided.
     #[serde(default)]
     pub failure: Status,
@@ -101,10 +252,63 @@ impl RunStep for KillContainer {
     fn on_failure(&self) -> Status {
         self.failure
     }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+}
+
+/// Commit a container's current state to an image using the docker api. Later
+/// `spawnContainer` steps can use the image with the `committed` image policy, including
+/// in later runs.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub(crate) struct CommitContainer {
+    /// Name of the container to commit.
+    pub name: String,
+    /// Tag to give the committed image, e.g. `houdini-base:latest`.
+    pub tag: String,
+    /// Fail the step if it runs for longer than this, e.g. "30s". Default is no timeout.
+    #[serde(default, with = "humantime_serde")]
+    #[schemars(with = "Option<String>")]
+    pub timeout: Option<Duration>,
+    /// Status on failure. Default is SetupFailure.
+    #[serde(default = "crate::serde_defaults::default_setup_failure")]
+    pub failure: Status,
+    /// Status on success. Default is Undecided.
+    #[serde(default)]
+    pub success: Status,
+}
+
+#[async_trait]
+impl RunStep for CommitContainer {
+    async fn do_run(&self) -> Result<()> {
+        self.do_run_detailed(&mut StepDetails::default()).await
+    }
+
+    async fn do_run_detailed(&self, details: &mut StepDetails) -> Result<()> {
+        let id = commit_container(&self.name, &self.tag).await?;
+        tracing::info!(name = ?self.name, tag = ?self.tag, id = ?id, "committed container");
+
+        details.image_id = Some(id);
+        Ok(())
+    }
+
+    fn on_success(&self) -> Status {
+        self.success
+    }
+
+    fn on_failure(&self) -> Status {
+        self.failure
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
 }
 
 /// Run a command in a spawned container using the docker api.
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone)]
 #[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub(crate) struct Container {
     /// Name of the container to run the command in. Must be the name of a previously
@@ -113,12 +317,52 @@ pub(crate) struct Container {
     /// Script to run in the container. A non-zero exit status triggers `failure`,
     /// while a zero exit status triggers `success`.
     pub script: Vec<ShellCommand>,
+    /// Exit codes that are considered successful. Default is `[0]`.
+    #[serde(default = "crate::serde_defaults::default_ok_exit_codes")]
+    pub ok_exit_codes: Vec<i64>,
     /// Should we run the commands with elevated privileges in the container?
     #[serde(default = "crate::serde_defaults::default_false")]
     pub privileged: bool,
     /// Should we spawn and attach a TTY for these commands?
     #[serde(default = "crate::serde_defaults::default_true")]
     pub tty: bool,
+    /// Shell used to wrap each command, e.g. `sh -lc`. The command is passed to the shell
+    /// verbatim and its arguments are quoted. Defaults to the `docker.defaultExecShell`
+    /// config, or no shell if that is unset.
+    #[serde(default)]
+    pub shell: Option<String>,
+    /// Environment variables to set for each command, in addition to the container's.
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Key sequence for detaching from each command, e.g. `ctrl-p,ctrl-q`. Default is
+    /// Docker's default.
+    #[serde(default)]
+    pub detach_keys: Option<String>,
+    /// Run each command under a tracer such as `strace`, if it is available in the
+    /// container. Trace files are left in the container.
+    #[serde(default)]
+    pub trace: Option<TraceOpts>,
+    /// File mode creation mask to run each command with, as an octal string, e.g.
+    /// `"077"`. This requires `sh` in the container. Default is the container's umask.
+    #[serde(default, with = "super::umask_serde")]
+    #[schemars(with = "Option<String>")]
+    pub umask: Option<u32>,
+    /// After running the script, read `/proc/self/status` in the container the same way
+    /// the script's commands were run, and record the capabilities, `NoNewPrivs`, and
+    /// seccomp mode it reports. This requires `cat` in the container. Default is false.
+    #[serde(default = "crate::serde_defaults::default_false")]
+    pub capture_proc_status: bool,
+    /// Host-side assertions to check once the step succeeds. If any do not hold, the
+    /// step fails.
+    #[serde(default)]
+    pub after: Vec<Assert>,
+    /// Fail the step if it runs for longer than this, e.g. "30s". Default is no timeout.
+    #[serde(default, with = "humantime_serde")]
+    #[schemars(with = "Option<String>")]
+    pub timeout: Option<Duration>,
+    /// How to retry the step if it fails. Default is to run it once.
+    #[serde(default)]
+    pub retry: Option<RetryPolicy>,
     /// Status on failure. Default is Undecided.
     #[serde(default)]
     pub failure: Status,
@@ -127,21 +371,100 @@ pub(crate) struct Container {
     pub success: Status,
 }
 
+impl Container {
+    /// Is the tracer available in the container?
+    async fn has_tracer(&self, trace: &TraceOpts) -> bool {
+        let script = format!("command -v {}", trace.tracer);
+        let out = run_command(&ExecOpts {
+            name: &self.name,
+            cmd: "sh",
+            args: &["-c", &script],
+            privileged: false,
+            tty: false,
+            shell: None,
+            wrapper: &[],
+            env: &[],
+            detach_keys: None,
+        })
+        .await;
+
+        matches!(out, Ok(out) if out.check(&[0]).is_ok())
+    }
+
+    /// Read `/proc/self/status` with the same privileges, environment, and umask as the
+    /// script's commands.
+    async fn proc_status(&self, env: &[String]) -> Result<ProcStatus> {
+        let wrapper = self.umask.map(umask_wrapper).unwrap_or_default();
+        let out = run_command(&ExecOpts {
+            name: &self.name,
+            cmd: "cat",
+            args: &["/proc/self/status"],
+            privileged: self.privileged,
+            tty: false,
+            shell: None,
+            wrapper: &wrapper.iter().map(|x| &**x).collect::<Vec<_>>(),
+            env,
+            detach_keys: None,
+        })
+        .await?;
+        out.check(&[0])?;
+        Ok(ProcStatus::parse(&String::from_utf8_lossy(&out.stdout)))
+    }
+}
+
+/// Arguments that run a command under `mask`, by setting the umask in a shell and then
+/// replacing the shell with the command.
+fn umask_wrapper(mask: u32) -> Vec<String> {
+    vec![
+        "sh".into(),
+        "-c".into(),
+        format!("umask {:04o} && exec \"$@\"", mask),
+        "sh".into(),
+    ]
+}
+
 #[async_trait]
 impl RunStep for Container {
     async fn do_run(&self) -> Result<()> {
-        for cmd in &self.script {
-            run_command(
-                &self.name,
-                &cmd.command,
-                &cmd.args.iter().map(|x| &**x).collect::<Vec<_>>(),
-                self.privileged,
-                self.tty,
-            )
-            .await?;
+        self.do_run_detailed(&mut StepDetails::default()).await
+    }
+
+    async fn do_run_detailed(&self, details: &mut StepDetails) -> Result<()> {
+        let trace = match &self.trace {
+            Some(trace) if self.has_tracer(trace).await => Some(trace),
+            Some(trace) => {
+                tracing::warn!(
+                    name = ?self.name,
+                    tracer = ?trace.tracer,
+                    "tracer not found in container, running commands without it"
+                );
+                None
+            }
+            None => None,
+        };
+        let id = uuid::Uuid::new_v4().simple().to_string();
+
+        let mut env = self
+            .env
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>();
+        env.sort();
+
+        let res = self.run_script(&env, trace, &id, details).await;
+
+        // The status is most useful when the script fails, so capture it either way
+        if self.capture_proc_status {
+            match self.proc_status(&env).await {
+                Ok(status) => details.proc_status = Some(status),
+                Err(e) => {
+                    tracing::warn!(err = ?e, name = ?self.name, "failed to capture /proc/self/status")
+                }
+            }
         }
+        res?;
 
-        Ok(())
+        check_all(&self.after, details)
     }
 
     fn on_success(&self) -> Status {
@@ -151,4 +474,208 @@ impl RunStep for Container {
     fn on_failure(&self) -> Status {
         self.failure
     }
+
+    fn retry(&self) -> Option<&RetryPolicy> {
+        self.retry.as_ref()
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+}
+
+impl Container {
+    /// Run each command in the script in turn, stopping at the first that fails.
+    async fn run_script(
+        &self,
+        env: &[String],
+        trace: Option<&TraceOpts>,
+        id: &str,
+        details: &mut StepDetails,
+    ) -> Result<()> {
+        for (index, cmd) in self.script.iter().enumerate() {
+            let mut wrapper = self.umask.map(umask_wrapper).unwrap_or_default();
+            if let Some(trace) = trace {
+                let file = trace.file(id, index);
+                details
+                    .trace_files
+                    .push(format!("{}:{}", self.name, file.display()));
+                wrapper.extend(trace.wrap(&file));
+            }
+
+            let out = run_command(&ExecOpts {
+                name: &self.name,
+                cmd: &cmd.command,
+                args: &cmd.args.iter().map(|x| &**x).collect::<Vec<_>>(),
+                privileged: self.privileged,
+                tty: self.tty,
+                shell: self
+                    .shell
+                    .as_deref()
+                    .or(CONFIG.docker.default_exec_shell.as_deref()),
+                wrapper: &wrapper.iter().map(|x| &**x).collect::<Vec<_>>(),
+                env,
+                detach_keys: self.detach_keys.as_deref(),
+            })
+            .await?;
+            details.add_output(&out.stdout, &out.stderr);
+
+            out.check(&self.ok_exit_codes)
+                .and_then(|()| cmd.check_stdout(&String::from_utf8_lossy(&out.stdout)))
+                .with_context(|| CommandFailure {
+                    index,
+                    output: CommandOutput::from_bytes(&out.stdout, &out.stderr),
+                    result: Some(out.result),
+                })?;
+        }
+        Ok(())
+    }
+}
+
+/// Deserialize the name of a spawned container, rejecting `host` so that copy steps can
+/// always tell the host apart from a container.
+fn deserialize_container_name<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let name = String::deserialize(deserializer)?;
+    if name == "host" {
+        return Err(serde::de::Error::custom(
+            "a container cannot be named `host`, which refers to the host",
+        ));
+    }
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{docker::UlimitName, testutils::assert_yaml_deserialize};
+
+    #[test]
+    fn test_spawn_host_name() {
+        assert!(serde_yaml::from_str::<SpawnContainer>("name: host\nimage: bash").is_err());
+    }
+
+    #[test]
+    fn test_spawn_env() {
+        std::env::set_var("HOUDINI_TEST_PRELOAD", "/tmp/evil.so");
+        let yaml = r#"
+            name: bash
+            image: bash
+            env:
+                LD_PRELOAD: ${HOUDINI_TEST_PRELOAD}
+                PATH: /evil/bin:/usr/bin
+            "#;
+        let step: SpawnContainer = assert_yaml_deserialize(yaml);
+        assert_eq!(
+            step.expanded_env().expect("env should expand"),
+            vec!["LD_PRELOAD=/tmp/evil.so", "PATH=/evil/bin:/usr/bin"]
+        );
+
+        let yaml = r#"
+            name: bash
+            image: bash
+            env:
+                FOO: ${HOUDINI_TEST_UNSET}
+            "#;
+        let step: SpawnContainer = assert_yaml_deserialize(yaml);
+        let err = step
+            .expanded_env()
+            .expect_err("unset variables should fail to expand");
+        assert!(
+            format!("{:#}", err).contains("HOUDINI_TEST_UNSET"),
+            "{:#}",
+            err
+        );
+
+        let yaml = r#"
+            name: bash
+            image: bash
+            "#;
+        let step: SpawnContainer = assert_yaml_deserialize(yaml);
+        assert!(step.env.is_empty());
+    }
+
+    #[test]
+    fn test_spawn_ulimits() {
+        let yaml = r#"
+            name: bash
+            image: bash
+            ulimits:
+            - name: nofile
+              soft: 1024
+              hard: 2048
+            - name: core
+              soft: -1
+              hard: -1
+            "#;
+        let step: SpawnContainer = assert_yaml_deserialize(yaml);
+        assert_eq!(
+            step.ulimits,
+            vec![
+                Ulimit {
+                    name: UlimitName::Nofile,
+                    soft: 1024,
+                    hard: 2048,
+                },
+                Ulimit {
+                    name: UlimitName::Core,
+                    soft: -1,
+                    hard: -1,
+                },
+            ]
+        );
+        for ulimit in &step.ulimits {
+            ulimit.validate().expect("ulimit should be valid");
+        }
+
+        let ulimit = Ulimit {
+            name: UlimitName::Nproc,
+            soft: 100,
+            hard: 10,
+        };
+        ulimit
+            .validate()
+            .expect_err("soft limit should not exceed hard limit");
+
+        let yaml = r#"
+            name: bash
+            image: bash
+            ulimits:
+            - name: nofiles
+              soft: 1024
+              hard: 2048
+            "#;
+        serde_yaml::from_str::<SpawnContainer>(yaml).expect_err("unknown ulimit should fail");
+    }
+
+    #[test]
+    fn test_spawn_userns_mode() {
+        for (mode, expected) in [
+            ("host", Some(UsernsMode::Host)),
+            ("private", Some(UsernsMode::Private)),
+            ("\"\"", Some(UsernsMode::Default)),
+        ] {
+            let yaml = format!("name: bash\nimage: bash\nusernsMode: {}\n", mode);
+            let step: SpawnContainer = assert_yaml_deserialize(&yaml);
+            assert_eq!(step.userns_mode, expected);
+        }
+
+        let step: SpawnContainer = assert_yaml_deserialize("name: bash\nimage: bash\n");
+        assert_eq!(step.userns_mode, None);
+
+        serde_yaml::from_str::<SpawnContainer>(
+            "name: bash\nimage: bash\nusernsMode: container:foo\n",
+        )
+        .expect_err("unknown userns mode should fail");
+    }
+
+    #[test]
+    fn test_umask_wrapper() {
+        assert_eq!(
+            umask_wrapper(0o077),
+            vec!["sh", "-c", "umask 0077 && exec \"$@\"", "sh"]
+        );
+    }
 }
@@ -0,0 +1,272 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+//
+
+//! This module defines a step that copies a file between the host and a container, e.g.
+//! to drop a payload into a container or pull a file back out of one.
+
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context as _, Result};
+use async_trait::async_trait;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::RunStep;
+use crate::{
+    docker::{copy_from_container, copy_to_container},
+    tricks::status::Status,
+};
+
+/// Copy a regular file from the host or a container to the host or a container.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub(crate) struct CopyFile {
+    /// The file to copy, as `host:<path>` or `<container>:<path>`.
+    #[schemars(with = "String")]
+    pub from: FileLocation,
+    /// Where to copy the file to, as `host:<path>` or `<container>:<path>`. An existing
+    /// file is replaced. Files copied into a container keep their permissions.
+    #[schemars(with = "String")]
+    pub to: FileLocation,
+    /// Fail the step if it runs for longer than this, e.g. "30s". Default is no timeout.
+    #[serde(default, with = "humantime_serde")]
+    #[schemars(with = "Option<String>")]
+    pub timeout: Option<Duration>,
+    /// Failure mode for when this step fails. Default is Undecided.
+    #[serde(default)]
+    pub failure: Status,
+    /// Success mode for when this step succeeds. Default is Undecided.
+    #[serde(default)]
+    pub success: Status,
+}
+
+/// Where a file copied by a [`CopyFile`] step lives.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) enum FileLocation {
+    /// An absolute path on the host, written as `host:<path>`.
+    Host(PathBuf),
+    /// An absolute path in a container that was spawned by an earlier step, written as
+    /// `<container>:<path>`.
+    Container {
+        /// Name of the container.
+        name: String,
+        /// Path of the file in the container.
+        path: PathBuf,
+    },
+}
+
+impl FileLocation {
+    /// Read the file's contents and permissions.
+    async fn read(&self) -> Result<(Vec<u8>, u32)> {
+        match self {
+            FileLocation::Host(path) => {
+                use std::os::unix::fs::PermissionsExt as _;
+
+                let contents = tokio::fs::read(path)
+                    .await
+                    .context(format!("failed to read {}", path.display()))?;
+                let mode = tokio::fs::metadata(path)
+                    .await
+                    .context(format!("failed to stat {}", path.display()))?
+                    .permissions()
+                    .mode();
+                Ok((contents, mode & 0o7777))
+            }
+            FileLocation::Container { name, path } => copy_from_container(name, path)
+                .await
+                .context(format!("failed to copy {} from {}", path.display(), name)),
+        }
+    }
+
+    /// Write `contents` to the file, with permissions `mode` if it is in a container.
+    async fn write(&self, contents: &[u8], mode: u32) -> Result<()> {
+        match self {
+            FileLocation::Host(path) => tokio::fs::write(path, contents)
+                .await
+                .context(format!("failed to write {}", path.display())),
+            FileLocation::Container { name, path } => copy_to_container(name, path, contents, mode)
+                .await
+                .context(format!("failed to copy {} to {}", path.display(), name)),
+        }
+    }
+
+    /// The container the file is in, if any.
+    pub fn container_mut(&mut self) -> Option<&mut String> {
+        match self {
+            FileLocation::Host(_) => None,
+            FileLocation::Container { name, .. } => Some(name),
+        }
+    }
+}
+
+impl TryFrom<String> for FileLocation {
+    type Error = String;
+
+    fn try_from(location: String) -> Result<Self, Self::Error> {
+        let (prefix, path) = location.split_once(':').ok_or_else(|| {
+            format!(
+                "file location {} must be `host:<path>` or `<container>:<path>`",
+                location
+            )
+        })?;
+        if prefix.is_empty() {
+            return Err(format!("file location {} has no container name", location));
+        }
+        let path = Path::new(path);
+        if !path.is_absolute() || path.file_name().is_none() {
+            return Err(format!(
+                "file location {} must have an absolute path to a file",
+                location
+            ));
+        }
+
+        Ok(match prefix {
+            "host" => FileLocation::Host(path.to_owned()),
+            name => FileLocation::Container {
+                name: name.to_owned(),
+                path: path.to_owned(),
+            },
+        })
+    }
+}
+
+impl Display for FileLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileLocation::Host(path) => write!(f, "host:{}", path.display()),
+            FileLocation::Container { name, path } => write!(f, "{}:{}", name, path.display()),
+        }
+    }
+}
+
+impl From<FileLocation> for String {
+    fn from(location: FileLocation) -> Self {
+        location.to_string()
+    }
+}
+
+#[async_trait]
+impl RunStep for CopyFile {
+    async fn do_run(&self) -> Result<()> {
+        let (contents, mode) = self.from.read().await?;
+        self.to.write(&contents, mode).await?;
+        tracing::info!(from = %self.from, to = %self.to, size = contents.len(), "copied file");
+
+        Ok(())
+    }
+
+    fn on_success(&self) -> Status {
+        self.success
+    }
+
+    fn on_failure(&self) -> Status {
+        self.failure
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    use super::*;
+    use crate::{
+        testutils::{assert_yaml_deserialize, run_valid},
+        tricks::Trick,
+    };
+
+    #[test]
+    fn test_file_location() {
+        let step: CopyFile = assert_yaml_deserialize(
+            r#"
+            from: host:/tmp/payload
+            to: bash:/usr/local/bin/payload
+            "#,
+        );
+        assert_eq!(step.from, FileLocation::Host("/tmp/payload".into()));
+        assert_eq!(
+            step.to,
+            FileLocation::Container {
+                name: "bash".into(),
+                path: "/usr/local/bin/payload".into(),
+            }
+        );
+
+        for location in [
+            "/tmp/payload",
+            ":/tmp/payload",
+            "host:tmp/payload",
+            "bash:/",
+        ] {
+            assert!(
+                FileLocation::try_from(location.to_owned()).is_err(),
+                "{} should not parse",
+                location
+            );
+        }
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_copy_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let payload = dir.path().join("payload.sh");
+        std::fs::write(&payload, "#!/bin/sh\necho pwned\n").unwrap();
+        std::fs::set_permissions(&payload, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let copied = dir.path().join("copied.sh");
+
+        let yaml = format!(
+            r#"
+            name: copy file test
+            steps:
+            - spawnContainer:
+                name: bash
+                image: bash
+                cmd: sleep infinity
+            - copyFile:
+                from: host:{}
+                to: bash:/tmp/payload.sh
+            - container:
+                name: bash
+                script: ["/tmp/payload.sh"]
+                tty: false
+            - copyFile:
+                from: bash:/tmp/payload.sh
+                to: bash:/tmp/again.sh
+            - container:
+                name: bash
+                script: ["/tmp/again.sh"]
+                tty: false
+            - copyFile:
+                from: bash:/tmp/payload.sh
+                to: host:{}
+                success: exploitSuccess
+            "#,
+            payload.display(),
+            copied.display()
+        );
+
+        let plan: Trick = assert_yaml_deserialize(&yaml);
+        let report = run_valid(&plan).await;
+        assert_eq!(report.status, Status::ExploitSuccess);
+        assert_eq!(report.steps[2].stdout.as_deref(), Some("pwned\n"));
+        assert_eq!(report.steps[4].stdout.as_deref(), Some("pwned\n"));
+        assert_eq!(
+            std::fs::read_to_string(&copied).unwrap(),
+            "#!/bin/sh\necho pwned\n"
+        );
+    }
+}
@@ -0,0 +1,328 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+//
+
+//! This module defines a step that collects proof that an exploit worked.
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context as _, Result};
+use async_trait::async_trait;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{command::ShellCommand, RunStep, StepDetails};
+use crate::{docker::copy_from_container, tricks::status::Status, CONFIG};
+
+/// Copy files and command output into the trick's evidence directory, recording each
+/// artifact's path and SHA-256 in the report.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub(crate) struct CollectEvidence {
+    /// What to collect, in order.
+    pub sources: Vec<EvidenceSource>,
+    /// Directory to write artifacts to. This is set by the trick when it runs, to
+    /// `<evidence dir>/<trick>/<run>`.
+    #[serde(skip)]
+    #[schemars(skip)]
+    pub dir: Option<PathBuf>,
+    /// Fail the step if it runs for longer than this, e.g. "30s". Default is no timeout.
+    #[serde(default, with = "humantime_serde")]
+    #[schemars(with = "Option<String>")]
+    pub timeout: Option<Duration>,
+    /// Failure mode for when this step fails. Default is Undecided.
+    #[serde(default)]
+    pub failure: Status,
+    /// Success mode for when this step succeeds. Default is Undecided.
+    #[serde(default)]
+    pub success: Status,
+}
+
+/// Something that a [`CollectEvidence`] step collects.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub(crate) enum EvidenceSource {
+    /// A file on the host.
+    Host(PathBuf),
+    /// A file in a container, which must have been spawned by an earlier step.
+    Container {
+        /// Name of the container.
+        name: String,
+        /// Path of the file in the container.
+        path: PathBuf,
+    },
+    /// The standard output of a command run on the host. If the command sets stdout
+    /// matchers, the step fails unless its output satisfies them, after the output is
+    /// saved.
+    Command {
+        /// Name of the artifact the output is saved as.
+        name: String,
+        /// Command to run.
+        command: ShellCommand,
+    },
+}
+
+impl EvidenceSource {
+    /// Describe where the evidence came from, e.g. `bash:/etc/shadow`.
+    fn describe(&self) -> String {
+        match self {
+            EvidenceSource::Host(path) => path.display().to_string(),
+            EvidenceSource::Container { name, path } => format!("{}:{}", name, path.display()),
+            EvidenceSource::Command { command, .. } => std::iter::once(&command.command)
+                .chain(&command.args)
+                .map(|s| &**s)
+                .collect::<Vec<_>>()
+                .join(" "),
+        }
+    }
+
+    /// Name of the artifact file, which is prefixed with `index` so that sources with the
+    /// same file name do not clobber each other.
+    fn file_name(&self, index: usize) -> String {
+        let name = match self {
+            EvidenceSource::Host(path) | EvidenceSource::Container { path, .. } => path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "file".into()),
+            EvidenceSource::Command { name, .. } => format!("{}.out", name),
+        };
+        format!("{}-{}", index, name)
+    }
+
+    /// Read the evidence.
+    async fn read(&self) -> Result<Vec<u8>> {
+        match self {
+            EvidenceSource::Host(path) => tokio::fs::read(path)
+                .await
+                .context(format!("failed to read {}", path.display())),
+            EvidenceSource::Container { name, path } => copy_from_container(name, path)
+                .await
+                .map(|(contents, _)| contents)
+                .context(format!("failed to copy {} from {}", path.display(), name)),
+            EvidenceSource::Command { command, .. } => {
+                let output = tokio::process::Command::new(&command.command)
+                    .args(&command.args)
+                    .output()
+                    .await
+                    .context(format!("failed to run {}", command.command))?;
+                if !output.status.success() {
+                    tracing::warn!(command = ?command, status = ?output.status, "evidence command failed");
+                }
+                Ok(output.stdout)
+            }
+        }
+    }
+}
+
+/// An artifact collected by a [`CollectEvidence`] step.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct EvidenceArtifact {
+    /// Where the evidence came from: a host path, a `container:path`, or a command line.
+    pub source: String,
+    /// Path the artifact was written to.
+    pub path: PathBuf,
+    /// Hex-encoded SHA-256 of the artifact's contents.
+    pub sha256: String,
+}
+
+/// The directory under `root` that evidence for the run of `trick` identified by `run` is
+/// collected into. Each run gets a directory of its own, so that runs of the same trick
+/// do not overwrite each other's evidence.
+pub(crate) fn evidence_dir(root: &Path, trick: &str, run: &str) -> PathBuf {
+    root.join(file_name(trick)).join(file_name(run))
+}
+
+/// `trick`'s name, made safe to use as a file name. Characters other than
+/// `[A-Za-z0-9._-]` are replaced with `-`, and names that would refer to a directory
+/// itself, like `..`, are replaced with `_`.
+pub(crate) fn file_name(trick: &str) -> String {
+    let name: String = trick
+        .chars()
+        .map(|c| match c {
+            'A'..='Z' | 'a'..='z' | '0'..='9' | '.' | '_' | '-' => c,
+            _ => '-',
+        })
+        .collect();
+    match name.as_str() {
+        "" | "." | ".." => "_".into(),
+        _ => name,
+    }
+}
+
+/// Write `contents` to `path`, returning the artifact describing it.
+async fn write_artifact(source: String, path: &Path, contents: &[u8]) -> Result<EvidenceArtifact> {
+    tokio::fs::write(path, contents)
+        .await
+        .context(format!("failed to write {}", path.display()))?;
+
+    Ok(EvidenceArtifact {
+        source,
+        path: path.to_owned(),
+        sha256: format!("{:x}", Sha256::digest(contents)),
+    })
+}
+
+#[async_trait]
+impl RunStep for CollectEvidence {
+    async fn do_run(&self) -> Result<()> {
+        self.do_run_detailed(&mut StepDetails::default()).await
+    }
+
+    async fn do_run_detailed(&self, details: &mut StepDetails) -> Result<()> {
+        let dir = self
+            .dir
+            .clone()
+            .unwrap_or_else(|| CONFIG.reports.dir.join("evidence"));
+        tokio::fs::create_dir_all(&dir).await.context(format!(
+            "failed to create evidence directory {}",
+            dir.display()
+        ))?;
+
+        for (index, source) in self.sources.iter().enumerate() {
+            let contents = source.read().await?;
+            let artifact = write_artifact(
+                source.describe(),
+                &dir.join(source.file_name(index)),
+                &contents,
+            )
+            .await?;
+            tracing::debug!(artifact = ?artifact, "collected evidence");
+            details.evidence.push(artifact);
+
+            if let EvidenceSource::Command { name, command } = source {
+                command
+                    .check_stdout(&String::from_utf8_lossy(&contents))
+                    .context(format!("evidence {} did not match", name))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn on_success(&self) -> Status {
+        self.success
+    }
+
+    fn on_failure(&self) -> Status {
+        self.failure
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::assert_yaml_deserialize;
+
+    #[tokio::test]
+    async fn test_collect_host_evidence() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let file = dir.path().join("flag");
+        std::fs::write(&file, "houdini\n").unwrap();
+
+        let yaml = format!(
+            r#"
+            sources:
+            - host: {}
+            - command:
+                name: greeting
+                command: echo hello
+            "#,
+            file.display()
+        );
+        let mut step: CollectEvidence = assert_yaml_deserialize(&yaml);
+        step.dir = Some(dir.path().join("evidence"));
+
+        let mut details = StepDetails::default();
+        step.do_run_detailed(&mut details)
+            .await
+            .expect("collecting evidence should succeed");
+
+        assert_eq!(
+            details.evidence,
+            vec![
+                EvidenceArtifact {
+                    source: file.display().to_string(),
+                    path: dir.path().join("evidence/0-flag"),
+                    sha256: "60456faab45d2bbe753f666dcb85bf2f4e712abb1d7c3820fe703b2a17684537"
+                        .into(),
+                },
+                EvidenceArtifact {
+                    source: "echo hello".into(),
+                    path: dir.path().join("evidence/1-greeting.out"),
+                    sha256: format!("{:x}", Sha256::digest(b"hello\n")),
+                },
+            ]
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("evidence/0-flag")).unwrap(),
+            "houdini\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_evidence_stdout_matchers() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let yaml = r#"
+            sources:
+            - command:
+                name: whoami
+                command:
+                    command: echo
+                    args: [nobody]
+                    stdoutContains: root
+            "#;
+        let mut step: CollectEvidence = assert_yaml_deserialize(yaml);
+        step.dir = Some(dir.path().to_owned());
+
+        let mut details = StepDetails::default();
+        let err = step
+            .do_run_detailed(&mut details)
+            .await
+            .expect_err("unmatched evidence should fail the step");
+        assert!(
+            format!("{:#}", err).contains("stdout did not contain"),
+            "{:#}",
+            err
+        );
+        // The output is still kept as evidence
+        assert_eq!(details.evidence.len(), 1);
+    }
+
+    #[test]
+    fn test_evidence_dir() {
+        let root = Path::new("/tmp/ci/evidence");
+        assert_eq!(
+            evidence_dir(root, "docker sock/escape", "abc123"),
+            root.join("docker-sock-escape/abc123")
+        );
+        assert_ne!(
+            evidence_dir(root, "docker sock/escape", "abc123"),
+            evidence_dir(root, "docker sock/escape", "def456")
+        );
+
+        for name in ["..", "../../etc", "/etc/cron.d", "."] {
+            let dir = evidence_dir(root, name, "..");
+            assert!(
+                dir.starts_with(root)
+                    && dir
+                        .components()
+                        .all(|c| !matches!(c, std::path::Component::ParentDir)),
+                "{} escapes the evidence directory",
+                dir.display()
+            );
+        }
+    }
+}
@@ -9,24 +9,67 @@
 //! This module defines the steps that manipulate the host system.
 
 use std::{
-    os::unix::process::ExitStatusExt,
+    collections::HashMap,
+    os::unix::process::{CommandExt as _, ExitStatusExt},
     process::{Command, Stdio},
+    time::Duration,
 };
 
-use anyhow::{bail, Context as _, Result};
+use anyhow::{Context as _, Result};
 use async_trait::async_trait;
+use nix::{
+    sys::{
+        signal::{killpg, Signal},
+        stat::{umask, Mode},
+    },
+    unistd::Pid,
+};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use super::{command::ShellCommand, RunStep};
-use crate::tricks::status::Status;
+use super::{
+    command::{CommandFailure, CommandOutput, ShellCommand},
+    trace::TraceOpts,
+    RetryPolicy, RunStep, StepDetails, TimedOut,
+};
+use crate::{docker::ExecResult, tricks::status::Status};
 
 /// Run a command or commands on the host.
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone)]
 #[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub(crate) struct Host {
     /// Script to run on the host. A non-zero exit status triggers `failure`,
     /// while a zero exit status triggers `success`.
     pub script: Vec<ShellCommand>,
+    /// Exit codes that are considered successful. Default is `[0]`.
+    #[serde(default = "crate::serde_defaults::default_ok_exit_codes")]
+    pub ok_exit_codes: Vec<i64>,
+    /// Run each command in a fresh network namespace using `unshare --net`. This
+    /// requires Houdini to run as root. Default is false.
+    #[serde(default = "crate::serde_defaults::default_false")]
+    pub isolate_network: bool,
+    /// Run each command under a tracer such as `strace`.
+    #[serde(default)]
+    pub trace: Option<TraceOpts>,
+    /// File mode creation mask to run each command with, as an octal string, e.g.
+    /// `"077"`. Default is Houdini's own umask.
+    #[serde(default, with = "super::umask_serde")]
+    #[schemars(with = "Option<String>")]
+    pub umask: Option<u32>,
+    /// Environment variables to set for each command, in addition to Houdini's. The
+    /// trick also sets `HOUDINI_CONTAINER_<NAME>` to the name of each container it
+    /// spawns, since containers are renamed for every run.
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Kill each command if it runs for longer than this, e.g. "30s". Each command is
+    /// placed in its own process group, so that any children it spawns are killed along
+    /// with it when it times out or the step is cancelled. Default is no timeout.
+    #[serde(default, with = "humantime_serde")]
+    #[schemars(with = "Option<String>")]
+    pub timeout: Option<Duration>,
+    /// How to retry the step if it fails. Default is to run it once.
+    #[serde(default)]
+    pub retry: Option<RetryPolicy>,
     /// Failure mode for when this step fails. Default is Undecided.
     #[serde(default)]
     pub failure: Status,
@@ -35,19 +78,102 @@ pub(crate) struct Host {
     pub success: Status,
 }
 
+impl Host {
+    /// Run `command`, which must lead its own process group, to completion and collect
+    /// its output. If a timeout is set and expires, or the returned future is dropped
+    /// first, e.g. because the step timed out, the command's entire process group is
+    /// killed.
+    async fn output(&self, command: Command) -> Result<std::process::Output> {
+        let child = tokio::process::Command::from(command)
+            .kill_on_drop(true)
+            .spawn()
+            .context("failed to run command")?;
+        let mut group = KillProcessGroup(Some(Pid::from_raw(
+            child.id().context("command exited early")? as i32,
+        )));
+
+        let out = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, child.wait_with_output())
+                .await
+                .map_err(|_| anyhow::anyhow!(TimedOut(timeout)))??,
+            None => child.wait_with_output().await?,
+        };
+
+        // Children that the command left running in the background are left alone
+        group.0 = None;
+        Ok(out)
+    }
+}
+
+/// Kills a process group when dropped, unless it has been cleared.
+struct KillProcessGroup(Option<Pid>);
+
+impl Drop for KillProcessGroup {
+    fn drop(&mut self) {
+        if let Some(pgid) = self.0 {
+            if let Err(e) = killpg(pgid, Signal::SIGKILL) {
+                tracing::warn!(err = ?e, pgid = ?pgid, "failed to kill process group");
+            }
+        }
+    }
+}
+
 #[async_trait]
 impl RunStep for Host {
     async fn do_run(&self) -> Result<()> {
-        for cmd in &self.script {
-            let out = Command::new(&cmd.command)
+        self.do_run_detailed(&mut StepDetails::default()).await
+    }
+
+    async fn do_run_detailed(&self, details: &mut StepDetails) -> Result<()> {
+        if let Some(trace) = &self.trace {
+            trace.check_host()?;
+        }
+        let id = uuid::Uuid::new_v4().simple().to_string();
+
+        for (index, cmd) in self.script.iter().enumerate() {
+            let mut argv: Vec<String> = vec![];
+            if self.isolate_network {
+                argv.extend(["unshare".into(), "--net".into(), "--".into()]);
+            }
+            if let Some(trace) = &self.trace {
+                let file = trace.file(&id, index);
+                details.trace_files.push(file.display().to_string());
+                argv.extend(trace.wrap(&file));
+            }
+            argv.push(cmd.command.clone());
+
+            // The command leads its own process group so that it can be killed along with
+            // its children, which also keeps it from reading the terminal
+            let mut command = Command::new(&argv[0]);
+            command
+                .args(&argv[1..])
+                .stdin(Stdio::null())
                 .stdout(Stdio::piped())
                 .stderr(Stdio::piped())
                 .args(&cmd.args)
-                .output()
-                .map_err(anyhow::Error::from)
-                .context("failed to run command")?;
+                .envs(&self.env)
+                .process_group(0);
+            if let Some(mask) = self.umask {
+                let mask = Mode::from_bits_truncate(mask);
+                // Only the child's umask is changed, so Houdini's own umask is untouched.
+                // SAFETY: umask is async-signal-safe.
+                unsafe {
+                    command.pre_exec(move || {
+                        umask(mask);
+                        Ok(())
+                    });
+                }
+            }
+
+            let out = self.output(command).await.with_context(|| CommandFailure {
+                index,
+                output: CommandOutput::default(),
+                result: None,
+            })?;
+
+            details.add_output(&out.stdout, &out.stderr);
 
-            match String::from_utf8(out.stdout) {
+            match std::str::from_utf8(&out.stdout) {
                 Ok(stdout) => {
                     tracing::debug!(cmd = ?cmd.command, args = ?cmd.args, "command stdout:\n{}", stdout)
                 }
@@ -56,7 +182,7 @@ impl RunStep for Host {
                 }
             }
 
-            match String::from_utf8(out.stderr) {
+            match std::str::from_utf8(&out.stderr) {
                 Ok(stderr) => {
                     tracing::debug!(cmd = ?cmd.command, args = ?cmd.args, "command stderr:\n{}", stderr)
                 }
@@ -66,19 +192,27 @@ impl RunStep for Host {
             }
 
             let status = out.status;
-            if !status.success() {
-                match status.code() {
-                    Some(code) => bail!("command failed with exit code: {}", code),
-                    None => {
-                        bail!(
-                            "command exited with signal: {}",
-                            status
-                                .signal()
-                                .expect("No signal or exit code for process!?")
-                        )
-                    }
-                }
+            let res = match status.code() {
+                Some(code) if self.ok_exit_codes.contains(&i64::from(code)) => Ok(()),
+                Some(code) => Err(anyhow::anyhow!("command failed with exit code: {}", code)),
+                None => Err(anyhow::anyhow!(
+                    "command exited with signal: {}",
+                    status
+                        .signal()
+                        .expect("No signal or exit code for process!?")
+                )),
             }
+            .and_then(|()| cmd.check_stdout(&String::from_utf8_lossy(&out.stdout)));
+
+            res.with_context(|| CommandFailure {
+                index,
+                output: CommandOutput::from_bytes(&out.stdout, &out.stderr),
+                result: Some(ExecResult {
+                    code: status.code().map(i64::from),
+                    signal: status.signal(),
+                    oom_killed: false,
+                }),
+            })?;
         }
 
         Ok(())
@@ -91,4 +225,324 @@ impl RunStep for Host {
     fn on_failure(&self) -> Status {
         self.failure
     }
+
+    fn retry(&self) -> Option<&RetryPolicy> {
+        self.retry.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use super::*;
+    use crate::testutils::{assert_yaml_deserialize, process_dies};
+
+    #[tokio::test]
+    async fn test_ok_exit_codes() {
+        let yaml = r#"
+            script:
+            - command: "false"
+              args: []
+            "#;
+        let step: Host = assert_yaml_deserialize(yaml);
+        assert_eq!(step.ok_exit_codes, vec![0]);
+        step.do_run()
+            .await
+            .expect_err("exit code 1 should fail by default");
+
+        let yaml = r#"
+            script:
+            - command: "false"
+              args: []
+            okExitCodes: [0, 1]
+            "#;
+        let step: Host = assert_yaml_deserialize(yaml);
+        step.do_run()
+            .await
+            .expect("exit code 1 should be accepted when in okExitCodes");
+
+        let yaml = r#"
+            script:
+            - command: "true"
+              args: []
+            okExitCodes: [1]
+            "#;
+        let step: Host = assert_yaml_deserialize(yaml);
+        step.do_run()
+            .await
+            .expect_err("exit code 0 should fail when not in okExitCodes");
+    }
+
+    #[tokio::test]
+    async fn test_isolate_network() {
+        let yaml = r#"
+            script:
+            - command: sh
+              args: ["-c", "test \"$(tail -n +3 /proc/net/dev | cut -d: -f1 | tr -d ' ')\" = lo"]
+            isolateNetwork: true
+            "#;
+        let step: Host = assert_yaml_deserialize(yaml);
+        step.do_run()
+            .await
+            .expect("only loopback should be present in an isolated netns");
+    }
+
+    #[tokio::test]
+    async fn test_signal_result() {
+        let yaml = r#"
+            script:
+            - command: sh
+              args: ["-c", "kill -9 $$"]
+            "#;
+        let step: Host = assert_yaml_deserialize(yaml);
+        let err = step.do_run().await.expect_err("killed command should fail");
+        let failure = err
+            .downcast_ref::<CommandFailure>()
+            .expect("error should carry the failed command");
+        assert_eq!(
+            failure.result,
+            Some(ExecResult {
+                code: None,
+                signal: Some(9),
+                oom_killed: false,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_trace() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let yaml = format!(
+            r#"
+            script:
+            - command: "true"
+              args: []
+            trace:
+                dir: {}
+            "#,
+            dir.path().display()
+        );
+        let step: Host = assert_yaml_deserialize(&yaml);
+
+        let mut details = StepDetails::default();
+        step.do_run_detailed(&mut details)
+            .await
+            .expect("traced command should succeed");
+
+        assert_eq!(details.trace_files.len(), 1);
+        let trace = std::fs::read_to_string(&details.trace_files[0]).expect("trace should exist");
+        assert!(trace.contains("execve"), "trace should record syscalls");
+    }
+
+    #[tokio::test]
+    async fn test_output_captured() {
+        let yaml = r#"
+            script:
+            - echo hello
+            - sh -c "echo oops >&2; echo world"
+            "#;
+        let step: Host = assert_yaml_deserialize(yaml);
+
+        let mut details = StepDetails::default();
+        step.do_run_detailed(&mut details)
+            .await
+            .expect("commands should succeed");
+        assert_eq!(details.stdout.as_deref(), Some("hello\nworld\n"));
+        assert_eq!(details.stderr.as_deref(), Some("oops\n"));
+
+        // Output that is not valid UTF-8 is kept, with the invalid bytes replaced
+        let yaml = r#"
+            script:
+            - printf '\377hello\n'
+            "#;
+        let step: Host = assert_yaml_deserialize(yaml);
+
+        let mut details = StepDetails::default();
+        step.do_run_detailed(&mut details)
+            .await
+            .expect("command should succeed");
+        assert_eq!(details.stdout.as_deref(), Some("\u{fffd}hello\n"));
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let dir = tempfile::tempdir().unwrap();
+        let count = dir.path().join("count");
+        let yaml = format!(
+            r#"
+            script:
+            - sh -c 'n=$(cat {0} 2>/dev/null || echo 0); n=$((n+1)); echo $n > {0}; echo $n; test $n -ge 3'
+            retry:
+              attempts: 3
+              delay: 10ms
+            failure: exploitFailure
+            success: exploitSuccess
+            "#,
+            count.display()
+        );
+        let step: Host = assert_yaml_deserialize(&yaml);
+        assert_eq!(
+            step.retry,
+            Some(RetryPolicy {
+                attempts: NonZeroU32::new(3).unwrap(),
+                delay: Duration::from_millis(10)
+            })
+        );
+
+        // Fails twice, then succeeds on the third attempt
+        let outcome = step.run().await;
+        assert_eq!(outcome.status, Status::ExploitSuccess);
+        assert_eq!(outcome.reason, None);
+        assert_eq!(outcome.details.stdout.as_deref(), Some("3\n"));
+
+        // Two attempts are not enough
+        std::fs::remove_file(&count).unwrap();
+        let step = Host {
+            retry: Some(RetryPolicy {
+                attempts: NonZeroU32::new(2).unwrap(),
+                delay: Duration::ZERO,
+            }),
+            ..step
+        };
+        let outcome = step.run().await;
+        assert_eq!(outcome.status, Status::ExploitFailure);
+        assert_eq!(outcome.details.stdout.as_deref(), Some("2\n"));
+    }
+
+    #[test]
+    fn test_zero_retry_attempts() {
+        let err = serde_yaml::from_str::<Host>("script: [\"true\"]\nretry:\n  attempts: 0")
+            .expect_err("zero attempts should be rejected");
+        assert!(err.to_string().contains("nonzero"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_stdout_matchers() {
+        let step = |matcher: &str| -> Host {
+            let yaml = format!(
+                r#"
+                script:
+                - command: echo
+                  args: ["hello world"]
+                  {}
+                failure: exploitFailure
+                success: exploitSuccess
+                "#,
+                matcher
+            );
+            assert_yaml_deserialize(&yaml)
+        };
+
+        let outcome = step("stdoutContains: lo wo").run().await;
+        assert_eq!(outcome.status, Status::ExploitSuccess);
+
+        let outcome = step("stdoutContains: goodbye").run().await;
+        assert_eq!(outcome.status, Status::ExploitFailure);
+        assert!(
+            outcome
+                .reason
+                .as_deref()
+                .is_some_and(|r| r.contains(r#"stdout did not contain "goodbye""#)),
+            "{:?}",
+            outcome.reason
+        );
+        assert_eq!(outcome.failed_command.map(|f| f.index), Some(0));
+
+        // Mistakes in the regex are caught when the trick is parsed
+        serde_yaml::from_str::<Host>(
+            r#"
+            script:
+            - command: echo
+              args: []
+              stdoutRegex: "[unclosed"
+            "#,
+        )
+        .expect_err("invalid regex should not parse");
+    }
+
+    #[tokio::test]
+    async fn test_umask() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let file = dir.path().join("secret");
+        let yaml = format!(
+            r#"
+            script:
+            - touch {}
+            umask: 0o077
+            "#,
+            file.display()
+        );
+        let step: Host = assert_yaml_deserialize(&yaml);
+        assert_eq!(step.umask, Some(0o077));
+        for (umask, expected) in [("\"022\"", 0o022), ("\"0o027\"", 0o027), ("0o777", 0o777)] {
+            let step: Host = assert_yaml_deserialize(&format!("script: []\numask: {}", umask));
+            assert_eq!(step.umask, Some(expected), "{}", umask);
+        }
+        for umask in ["\"0778\"", "\"1000\"", "0o1000", "\"u=rwx\""] {
+            serde_yaml::from_str::<Host>(&format!("script: []\numask: {}", umask))
+                .expect_err(&format!("{} should not parse", umask));
+        }
+        step.do_run().await.expect("command should succeed");
+
+        let mode = std::fs::metadata(&file)
+            .expect("file should be created")
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[tokio::test]
+    async fn test_timeout_kills_process_group() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let pidfile = dir.path().join("child.pid");
+        let yaml = format!(
+            r#"
+            script:
+            - command: sh
+              args: ["-c", "sleep 60 & echo $! > {}; wait"]
+            timeout: 500ms
+            "#,
+            pidfile.display()
+        );
+        let step: Host = assert_yaml_deserialize(&yaml);
+        assert_eq!(step.timeout, Some(Duration::from_millis(500)));
+
+        let start = std::time::Instant::now();
+        let err = step.do_run().await.expect_err("command should time out");
+        assert!(err.downcast_ref::<TimedOut>().is_some());
+        assert!(start.elapsed() < Duration::from_secs(30));
+
+        // The backgrounded child should have been killed along with its parent
+        assert!(
+            process_dies(&pidfile).await,
+            "child of timed out command should be killed"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cancel_kills_process_group() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let pidfile = dir.path().join("child.pid");
+        let yaml = format!(
+            r#"
+            script:
+            - command: sh
+              args: ["-c", "sleep 60 & echo $! > {}; wait"]
+            "#,
+            pidfile.display()
+        );
+        let step: Host = assert_yaml_deserialize(&yaml);
+
+        // As when a step without a timeout of its own is cancelled
+        tokio::time::timeout(Duration::from_millis(500), step.do_run())
+            .await
+            .expect_err("command should still be running");
+        assert!(
+            process_dies(&pidfile).await,
+            "child of cancelled command should be killed"
+        );
+    }
 }
@@ -0,0 +1,204 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+//
+
+//! This module defines a step that loads or unloads kernel modules on the host.
+
+use std::{
+    path::Path,
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+use anyhow::{bail, Context as _, Result};
+use async_trait::async_trait;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::RunStep;
+use crate::tricks::status::Status;
+
+/// Path to the list of loaded kernel modules.
+const PROC_MODULES: &str = "/proc/modules";
+
+/// Ensure a kernel module is loaded or unloaded on the host.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub(crate) struct KernelModule {
+    /// Name of the kernel module.
+    pub name: String,
+    /// Parameters to pass to `modprobe` when loading the module.
+    #[serde(default)]
+    pub params: Vec<String>,
+    /// Whether the module should be loaded or unloaded. Default is `ensureLoaded`.
+    #[serde(default)]
+    pub mode: KernelModuleMode,
+    /// Unload the module when the trick completes, if this step loaded it.
+    #[serde(default = "crate::serde_defaults::default_false")]
+    pub unload_on_cleanup: bool,
+    /// Fail the step if it runs for longer than this, e.g. "30s". Default is no timeout.
+    #[serde(default, with = "humantime_serde")]
+    #[schemars(with = "Option<String>")]
+    pub timeout: Option<Duration>,
+    /// Status on failure. Default is SetupFailure.
+    #[serde(default = "crate::serde_defaults::default_setup_failure")]
+    pub failure: Status,
+    /// Status on success. Default is Undecided.
+    #[serde(default)]
+    pub success: Status,
+}
+
+impl KernelModule {
+    /// Is the module currently loaded?
+    pub fn is_loaded(&self) -> Result<bool> {
+        is_module_loaded(Path::new(PROC_MODULES), &self.name)
+    }
+}
+
+#[async_trait]
+impl RunStep for KernelModule {
+    async fn do_run(&self) -> Result<()> {
+        let loaded = self.is_loaded()?;
+
+        match self.mode {
+            KernelModuleMode::EnsureLoaded if !loaded => {
+                run("modprobe", std::iter::once(&self.name).chain(&self.params))?;
+                if !self.is_loaded()? {
+                    bail!("module {} is not loaded after modprobe", self.name);
+                }
+            }
+            KernelModuleMode::EnsureUnloaded if loaded => {
+                unload_module(&self.name)?;
+            }
+            _ => {
+                tracing::debug!(module = ?self.name, mode = ?self.mode, "module already in desired state");
+            }
+        }
+
+        Ok(())
+    }
+
+    fn on_success(&self) -> Status {
+        self.success
+    }
+
+    fn on_failure(&self) -> Status {
+        self.failure
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+}
+
+/// Whether a kernel module should be loaded or unloaded.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub enum KernelModuleMode {
+    /// Load the module with `modprobe` if it is not already loaded.
+    #[default]
+    EnsureLoaded,
+    /// Unload the module with `rmmod` if it is loaded.
+    EnsureUnloaded,
+}
+
+/// Unload a kernel module and verify that it is no longer loaded.
+pub fn unload_module(name: &str) -> Result<()> {
+    run("rmmod", std::iter::once(name))?;
+    if is_module_loaded(Path::new(PROC_MODULES), name)? {
+        bail!("module {} is still loaded after rmmod", name);
+    }
+    Ok(())
+}
+
+/// Run a module utility, failing if it exits unsuccessfully.
+fn run<I, S>(cmd: &str, args: I) -> Result<()>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<std::ffi::OsStr>,
+{
+    let out = Command::new(cmd)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .args(args)
+        .output()
+        .context(format!("failed to run {}", cmd))?;
+
+    if !out.status.success() {
+        bail!(
+            "{} failed with {}: {}",
+            cmd,
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
+
+/// Check whether a module is listed in a `/proc/modules`-formatted file. Dashes and
+/// underscores in module names are treated as equivalent, as they are by the kernel.
+fn is_module_loaded(modules: &Path, name: &str) -> Result<bool> {
+    let contents = std::fs::read_to_string(modules)
+        .context(format!("failed to read {}", modules.display()))?;
+    let name = name.replace('-', "_");
+
+    Ok(contents
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .any(|module| module == name))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::testutils::assert_yaml_deserialize;
+
+    #[test]
+    fn test_is_module_loaded() {
+        let mut f = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            f,
+            "overlay 151552 0 - Live 0x0000000000000000\n\
+             nf_conntrack 176128 4 xt_conntrack,nf_nat, Live 0x0000000000000000\n\
+             fuse 163840 3 - Live 0x0000000000000000\n"
+        )
+        .unwrap();
+
+        assert!(is_module_loaded(f.path(), "overlay").unwrap());
+        assert!(is_module_loaded(f.path(), "fuse").unwrap());
+        assert!(is_module_loaded(f.path(), "nf-conntrack").unwrap());
+        assert!(!is_module_loaded(f.path(), "xt_conntrack").unwrap());
+        assert!(!is_module_loaded(f.path(), "over").unwrap());
+
+        is_module_loaded(Path::new("/nonexistent/modules"), "fuse")
+            .expect_err("missing modules file should be err");
+    }
+
+    #[test]
+    fn test_kernel_module_serde() {
+        let step: KernelModule = assert_yaml_deserialize(
+            r#"
+            name: fuse
+            "#,
+        );
+        assert_eq!(step.mode, KernelModuleMode::EnsureLoaded);
+        assert_eq!(step.failure, Status::SetupFailure);
+        assert!(!step.unload_on_cleanup);
+
+        assert_yaml_deserialize::<KernelModule>(
+            r#"
+            name: overlay
+            params: ["redirect_dir=on"]
+            mode: ensureUnloaded
+            unloadOnCleanup: true
+            "#,
+        );
+    }
+}
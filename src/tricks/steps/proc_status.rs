@@ -0,0 +1,95 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+//
+
+//! Helpers for recording the capabilities and seccomp state that commands in a container
+//! run with, as reported by `/proc/self/status`.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Security-relevant fields of a process's `/proc/<pid>/status`. Fields that the kernel
+/// did not report are left unset.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone, Default)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ProcStatus {
+    /// Effective capability set, as a hex mask, e.g. `00000000a80425fb`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cap_eff: Option<String>,
+    /// Permitted capability set, as a hex mask.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cap_prm: Option<String>,
+    /// Bounding capability set, as a hex mask.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cap_bnd: Option<String>,
+    /// Was the process prevented from gaining privileges, e.g. through setuid binaries?
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub no_new_privs: Option<bool>,
+    /// Seccomp mode: 0 is disabled, 1 is strict, and 2 is filtered.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seccomp: Option<u32>,
+}
+
+impl ProcStatus {
+    /// Parse the fields of interest out of the contents of a `/proc/<pid>/status` file.
+    pub fn parse(status: &str) -> Self {
+        let mut parsed = ProcStatus::default();
+        for (key, value) in status
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .map(|(key, value)| (key.trim(), value.trim()))
+        {
+            match key {
+                "CapEff" => parsed.cap_eff = Some(value.to_owned()),
+                "CapPrm" => parsed.cap_prm = Some(value.to_owned()),
+                "CapBnd" => parsed.cap_bnd = Some(value.to_owned()),
+                "NoNewPrivs" => parsed.no_new_privs = value.parse::<u8>().ok().map(|v| v != 0),
+                "Seccomp" => parsed.seccomp = value.parse().ok(),
+                _ => {}
+            }
+        }
+        parsed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_proc_status() {
+        let status = "Name:\tcat\n\
+                      Umask:\t0022\n\
+                      State:\tR (running)\n\
+                      CapInh:\t0000000000000000\n\
+                      CapPrm:\t00000000a80425fb\n\
+                      CapEff:\t00000000a80425fb\n\
+                      CapBnd:\t00000000a80425fb\n\
+                      NoNewPrivs:\t1\n\
+                      Seccomp:\t2\n\
+                      Seccomp_filters:\t1\n";
+        assert_eq!(
+            ProcStatus::parse(status),
+            ProcStatus {
+                cap_eff: Some("00000000a80425fb".into()),
+                cap_prm: Some("00000000a80425fb".into()),
+                cap_bnd: Some("00000000a80425fb".into()),
+                no_new_privs: Some(true),
+                seccomp: Some(2),
+            }
+        );
+
+        // Older kernels do not report every field
+        assert_eq!(
+            ProcStatus::parse("CapEff:\t0000003fffffffff\n"),
+            ProcStatus {
+                cap_eff: Some("0000003fffffffff".into()),
+                ..Default::default()
+            }
+        );
+    }
+}
@@ -0,0 +1,305 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+//
+
+//! This module defines a step that checks whether a process is running.
+
+use std::time::Duration;
+
+use anyhow::{Context as _, Result};
+use async_trait::async_trait;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::{RetryPolicy, RunStep, StepDetails};
+use crate::{
+    docker::{run_command, ExecOpts},
+    tricks::status::Status,
+};
+
+/// Passed as `$0` to the listing script so that its own shells can be told apart from
+/// the processes being checked.
+const LIST_MARKER: &str = "houdini-process-check";
+
+/// Prints `pid\tcomm\tcmdline` for every process visible in a container.
+const LIST_SCRIPT: &str = r#"for d in /proc/[0-9]*; do
+    read -r comm < "$d/comm" 2>/dev/null || continue
+    args=$(tr '\0' ' ' < "$d/cmdline" 2>/dev/null)
+    printf '%s\t%s\t%s\n' "${d#/proc/}" "$comm" "$args"
+done"#;
+
+/// Check whether a process is running in a container or on the host.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub(crate) struct ProcessCheck {
+    /// Name of the container to check. Default is to check the host.
+    #[serde(default)]
+    pub container: Option<String>,
+    /// Match processes whose executable has exactly this name, e.g. `sleep`.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Match processes whose command line contains this string, e.g. `sleep 60`.
+    #[serde(default)]
+    pub pattern: Option<String>,
+    /// Whether a matching process should be present or absent. Default is present.
+    #[serde(default)]
+    pub expect: ProcessExpectation,
+    /// Fail the step if it runs for longer than this, e.g. "30s". Default is no timeout.
+    #[serde(default, with = "humantime_serde")]
+    #[schemars(with = "Option<String>")]
+    pub timeout: Option<Duration>,
+    /// How to retry the step if it fails. Default is to run it once.
+    #[serde(default)]
+    pub retry: Option<RetryPolicy>,
+    /// Status on failure. Default is Undecided.
+    #[serde(default)]
+    pub failure: Status,
+    /// Status on success. Default is Undecided.
+    #[serde(default)]
+    pub success: Status,
+}
+
+/// Whether a [`ProcessCheck`] expects a matching process to be running.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub(crate) enum ProcessExpectation {
+    /// At least one matching process is running.
+    #[default]
+    Present,
+    /// No matching process is running.
+    Absent,
+}
+
+/// A running process.
+#[derive(Debug, PartialEq, Eq)]
+struct Process {
+    pid: u32,
+    comm: String,
+    cmdline: String,
+}
+
+impl ProcessCheck {
+    /// Does the process match this check's name and pattern?
+    fn matches(&self, process: &Process) -> bool {
+        let name = self.name.as_deref().is_none_or(|name| {
+            let exe = process.cmdline.split(' ').next().unwrap_or_default();
+            process.comm == name || exe.rsplit('/').next() == Some(name)
+        });
+        let pattern = self
+            .pattern
+            .as_deref()
+            .is_none_or(|pattern| process.cmdline.contains(pattern));
+        name && pattern
+    }
+
+    /// List the processes running in the container, or on the host.
+    async fn processes(&self) -> Result<Vec<Process>> {
+        let container = match &self.container {
+            Some(container) => container,
+            None => return host_processes(),
+        };
+
+        let out = run_command(&ExecOpts {
+            name: container,
+            cmd: "sh",
+            args: &["-c", LIST_SCRIPT, LIST_MARKER],
+            privileged: false,
+            tty: false,
+            shell: None,
+            wrapper: &[],
+            env: &[],
+            detach_keys: None,
+        })
+        .await?;
+        out.check(&[0]).context("failed to list processes")?;
+
+        Ok(parse_processes(&String::from_utf8_lossy(&out.stdout))
+            .filter(|process| !process.cmdline.contains(LIST_MARKER))
+            .collect())
+    }
+}
+
+#[async_trait]
+impl RunStep for ProcessCheck {
+    async fn do_run(&self) -> Result<()> {
+        self.do_run_detailed(&mut StepDetails::default()).await
+    }
+
+    async fn do_run_detailed(&self, details: &mut StepDetails) -> Result<()> {
+        if self.name.is_none() && self.pattern.is_none() {
+            anyhow::bail!("processCheck requires a name or a pattern")
+        }
+
+        details.matched_pids = self
+            .processes()
+            .await?
+            .iter()
+            .filter(|process| self.matches(process))
+            .map(|process| process.pid)
+            .collect();
+        tracing::debug!(pids = ?details.matched_pids, "matched processes");
+
+        match (self.expect, details.matched_pids.is_empty()) {
+            (ProcessExpectation::Present, true) => {
+                anyhow::bail!("no matching process is running")
+            }
+            (ProcessExpectation::Absent, false) => anyhow::bail!(
+                "{} matching process(es) are running",
+                details.matched_pids.len()
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    fn on_success(&self) -> Status {
+        self.success
+    }
+
+    fn on_failure(&self) -> Status {
+        self.failure
+    }
+
+    fn retry(&self) -> Option<&RetryPolicy> {
+        self.retry.as_ref()
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+}
+
+/// List the processes running on the host, excluding Houdini itself.
+fn host_processes() -> Result<Vec<Process>> {
+    let own = std::process::id();
+    let mut processes = vec![];
+
+    for entry in std::fs::read_dir("/proc").context("failed to read /proc")? {
+        let entry = entry?;
+        let pid = match entry.file_name().to_str().and_then(|s| s.parse().ok()) {
+            Some(pid) if pid != own => pid,
+            _ => continue,
+        };
+
+        // The process may exit while we are reading it
+        let (comm, cmdline) = match (
+            std::fs::read_to_string(entry.path().join("comm")),
+            std::fs::read(entry.path().join("cmdline")),
+        ) {
+            (Ok(comm), Ok(cmdline)) => (comm, cmdline),
+            _ => continue,
+        };
+
+        processes.push(Process {
+            pid,
+            comm: comm.trim_end().to_owned(),
+            cmdline: String::from_utf8_lossy(&cmdline)
+                .replace('\0', " ")
+                .trim_end()
+                .to_owned(),
+        });
+    }
+
+    Ok(processes)
+}
+
+/// Parse the output of [`LIST_SCRIPT`].
+fn parse_processes(out: &str) -> impl Iterator<Item = Process> + '_ {
+    out.lines().filter_map(|line| {
+        let mut fields = line.splitn(3, '\t');
+        Some(Process {
+            pid: fields.next()?.parse().ok()?,
+            comm: fields.next()?.to_owned(),
+            cmdline: fields.next().unwrap_or_default().trim_end().to_owned(),
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::assert_yaml_deserialize;
+
+    #[test]
+    fn test_parse_processes() {
+        let out = "1\tsleep\tsleep 60 \n12\tsh\tsh -c echo hi \nbogus\n30\tkworker\t\n";
+        assert_eq!(
+            parse_processes(out).collect::<Vec<_>>(),
+            vec![
+                Process {
+                    pid: 1,
+                    comm: "sleep".into(),
+                    cmdline: "sleep 60".into(),
+                },
+                Process {
+                    pid: 12,
+                    comm: "sh".into(),
+                    cmdline: "sh -c echo hi".into(),
+                },
+                Process {
+                    pid: 30,
+                    comm: "kworker".into(),
+                    cmdline: "".into(),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_host_present_and_absent() {
+        let mut child = std::process::Command::new("sleep")
+            .arg("31337")
+            .spawn()
+            .expect("failed to spawn sleep");
+
+        // Wait for the child to exec
+        let comm = format!("/proc/{}/comm", child.id());
+        for _ in 0..50 {
+            if std::fs::read_to_string(&comm).is_ok_and(|comm| comm.trim_end() == "sleep") {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let yaml = r#"
+            name: sleep
+            pattern: "31337"
+            "#;
+        let step: ProcessCheck = assert_yaml_deserialize(yaml);
+        let mut details = StepDetails::default();
+        let res = step.do_run_detailed(&mut details).await;
+
+        let yaml = r#"
+            pattern: "31337"
+            expect: absent
+            "#;
+        let absent: ProcessCheck = assert_yaml_deserialize(yaml);
+        let absent_res = absent.do_run().await;
+
+        child.kill().expect("failed to kill sleep");
+        child.wait().expect("failed to wait for sleep");
+
+        res.expect("sleep should be running");
+        assert_eq!(details.matched_pids, vec![child.id()]);
+        absent_res.expect_err("sleep should not be absent");
+
+        absent
+            .do_run()
+            .await
+            .expect("sleep should be absent once killed");
+        step.do_run()
+            .await
+            .expect_err("sleep should not be present once killed");
+
+        let yaml = r#"
+            expect: absent
+            "#;
+        let step: ProcessCheck = assert_yaml_deserialize(yaml);
+        step.do_run()
+            .await
+            .expect_err("a name or pattern should be required");
+    }
+}
@@ -0,0 +1,292 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+//
+
+//! This module defines a step that runs a shell script on the host or in a container.
+
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    command::ShellCommand, container::Container, host::Host, RetryPolicy, RunStep, StepDetails,
+};
+use crate::tricks::status::Status;
+
+/// Run a multi-line script with an interpreter, either on the host or in a container.
+/// This is more convenient than a `host` or `container` step when a script needs
+/// variables, pipes, or control flow.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub(crate) struct Shell {
+    /// The script to run. It is passed to the interpreter with `-c`.
+    pub script: String,
+    /// Where to run the script. Default is the host.
+    #[serde(default)]
+    pub target: ShellTarget,
+    /// Interpreter to run the script with, e.g. `bash`. Default is `sh`.
+    #[serde(default = "crate::serde_defaults::default_interpreter")]
+    pub interpreter: String,
+    /// Exit codes that are considered successful. Default is `[0]`.
+    #[serde(default = "crate::serde_defaults::default_ok_exit_codes")]
+    pub ok_exit_codes: Vec<i64>,
+    /// Run the script in a fresh network namespace using `unshare --net`, if it runs on
+    /// the host. This requires Houdini to run as root. Default is false.
+    #[serde(default = "crate::serde_defaults::default_false")]
+    pub isolate_network: bool,
+    /// Fail the step if it runs for longer than this, e.g. "30s". Default is no timeout.
+    #[serde(default, with = "humantime_serde")]
+    #[schemars(with = "Option<String>")]
+    pub timeout: Option<Duration>,
+    /// How to retry the step if it fails. Default is to run it once.
+    #[serde(default)]
+    pub retry: Option<RetryPolicy>,
+    /// File mode creation mask to run the script with, as an octal string, e.g. `"077"`.
+    /// Default is Houdini's own umask on the host, and the container's in a container.
+    #[serde(default, with = "super::umask_serde")]
+    #[schemars(with = "Option<String>")]
+    pub umask: Option<u32>,
+    /// Failure mode for when this step fails. Default is Undecided.
+    #[serde(default)]
+    pub failure: Status,
+    /// Success mode for when this step succeeds. Default is Undecided.
+    #[serde(default)]
+    pub success: Status,
+    /// Environment variables to run the script with. This is set by the trick when it
+    /// runs, to its locale and time zone, and on the host to the names of its containers.
+    #[serde(skip)]
+    #[schemars(skip)]
+    pub env: HashMap<String, String>,
+}
+
+/// Where a [`Shell`] step runs its script.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone, Default)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub(crate) enum ShellTarget {
+    /// Run the script on the host.
+    #[default]
+    Host,
+    /// Run the script in the named container, which must have been spawned by an
+    /// earlier step.
+    Container(String),
+}
+
+impl Shell {
+    /// The script as a single command for the interpreter.
+    fn command(&self) -> ShellCommand {
+        let mut words = self.interpreter.split_whitespace().map(ToOwned::to_owned);
+        ShellCommand {
+            command: words.next().unwrap_or_else(|| "sh".into()),
+            args: words.chain(["-c".into(), self.script.clone()]).collect(),
+            ..Default::default()
+        }
+    }
+}
+
+#[async_trait]
+impl RunStep for Shell {
+    async fn do_run(&self) -> Result<()> {
+        self.do_run_detailed(&mut StepDetails::default()).await
+    }
+
+    async fn do_run_detailed(&self, details: &mut StepDetails) -> Result<()> {
+        match &self.target {
+            ShellTarget::Host => {
+                Host {
+                    script: vec![self.command()],
+                    ok_exit_codes: self.ok_exit_codes.clone(),
+                    isolate_network: self.isolate_network,
+                    trace: None,
+                    umask: self.umask,
+                    env: self.env.clone(),
+                    timeout: self.timeout,
+                    retry: None,
+                    failure: self.failure,
+                    success: self.success,
+                }
+                .do_run_detailed(details)
+                .await
+            }
+            ShellTarget::Container(name) => {
+                Container {
+                    name: name.to_owned(),
+                    script: vec![self.command()],
+                    ok_exit_codes: self.ok_exit_codes.clone(),
+                    privileged: false,
+                    tty: false,
+                    shell: None,
+                    env: self.env.clone(),
+                    detach_keys: None,
+                    trace: None,
+                    umask: self.umask,
+                    capture_proc_status: false,
+                    after: vec![],
+                    timeout: None,
+                    retry: None,
+                    failure: self.failure,
+                    success: self.success,
+                }
+                .do_run_detailed(details)
+                .await
+            }
+        }
+    }
+
+    fn on_success(&self) -> Status {
+        self.success
+    }
+
+    fn on_failure(&self) -> Status {
+        self.failure
+    }
+
+    fn retry(&self) -> Option<&RetryPolicy> {
+        self.retry.as_ref()
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        // Scripts on the host are timed out by the host step they run as, which also kills
+        // whatever they started
+        match self.target {
+            ShellTarget::Host => None,
+            ShellTarget::Container(_) => self.timeout,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testutils::{assert_yaml_deserialize, process_dies},
+        tricks::{report::Termination, steps::command::CommandFailure},
+    };
+
+    #[tokio::test]
+    async fn test_host_script_timeout_kills_processes() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let pidfile = dir.path().join("child.pid");
+        let yaml = format!(
+            r#"
+            script: |
+                sleep 60 &
+                echo $! > {}
+                wait
+            timeout: 500ms
+            "#,
+            pidfile.display()
+        );
+        let step: Shell = assert_yaml_deserialize(&yaml);
+
+        let outcome = step.run().await;
+        assert_eq!(outcome.termination, Termination::TimedOut);
+        assert!(
+            process_dies(&pidfile).await,
+            "process started by timed out script should be killed"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_host_script() {
+        let yaml = r#"
+            script: |
+                greeting="hello world"
+                echo "$greeting" | tr a-z A-Z | grep -q "HELLO WORLD"
+                for word in $greeting; do
+                    test -n "$word"
+                done
+            "#;
+        let step: Shell = assert_yaml_deserialize(yaml);
+        assert_eq!(step.target, ShellTarget::Host);
+        assert_eq!(step.interpreter, "sh");
+        step.do_run().await.expect("script should succeed");
+    }
+
+    #[tokio::test]
+    async fn test_host_script_isolate_network() {
+        let yaml = r#"
+            script: |
+                test "$(tail -n +3 /proc/net/dev | cut -d: -f1 | tr -d ' ')" = lo
+            isolateNetwork: true
+            "#;
+        let step: Shell = assert_yaml_deserialize(yaml);
+        step.do_run()
+            .await
+            .expect("only loopback should be present in an isolated netns");
+    }
+
+    #[tokio::test]
+    async fn test_host_script_failure() {
+        let yaml = r#"
+            script: |
+                echo "to stdout"
+                echo "to stderr" >&2
+                exit 3
+            interpreter: bash -e
+            "#;
+        let step: Shell = assert_yaml_deserialize(yaml);
+        let err = step.do_run().await.expect_err("script should fail");
+        let failure = err
+            .downcast_ref::<CommandFailure>()
+            .expect("error should carry the script's output");
+        assert_eq!(failure.output.stdout, "to stdout\n");
+        assert_eq!(failure.output.stderr, "to stderr\n");
+        assert_eq!(failure.result.as_ref().and_then(|r| r.code), Some(3));
+
+        let yaml = r#"
+            script: exit 3
+            okExitCodes: [3]
+            "#;
+        let step: Shell = assert_yaml_deserialize(yaml);
+        step.do_run()
+            .await
+            .expect("exit code 3 should be accepted when in okExitCodes");
+    }
+
+    #[tokio::test]
+    async fn test_step_timeout() {
+        let yaml = r#"
+            script: sleep 10
+            timeout: 200ms
+            failure: exploitFailure
+            "#;
+        let step: Shell = assert_yaml_deserialize(yaml);
+        assert_eq!(step.timeout, Some(Duration::from_millis(200)));
+
+        let start = std::time::Instant::now();
+        let outcome = step.run().await;
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(outcome.status, Status::ExploitFailure);
+        assert_eq!(outcome.termination, Termination::TimedOut);
+        assert_eq!(
+            outcome.reason.as_deref(),
+            Some("command 0 in script failed: timed out after 200ms")
+        );
+    }
+
+    #[test]
+    fn test_container_target() {
+        let yaml = r#"
+            script: echo hi
+            target:
+                container: bash
+            "#;
+        let step: Shell = assert_yaml_deserialize(yaml);
+        assert_eq!(step.target, ShellTarget::Container("bash".into()));
+        assert_eq!(
+            step.command(),
+            ShellCommand {
+                command: "sh".into(),
+                args: vec!["-c".into(), "echo hi".into()],
+                ..Default::default()
+            }
+        );
+    }
+}
@@ -0,0 +1,101 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+//
+
+//! Options for running a step's commands under a tracer like `strace` or `ltrace`.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context as _, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::error::HarnessError;
+
+/// Run each command under a tracer, writing one trace file per command.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub(crate) struct TraceOpts {
+    /// Tracer binary to use. It must accept `-f -o <file>`, as `strace` and `ltrace`
+    /// do. Default is `strace`.
+    #[serde(default = "default_tracer")]
+    pub tracer: String,
+    /// Directory to write trace files to. For container steps, this is a directory
+    /// inside the container. Default is `/tmp`.
+    #[serde(default)]
+    pub dir: Option<PathBuf>,
+}
+
+fn default_tracer() -> String {
+    "strace".into()
+}
+
+impl TraceOpts {
+    /// Path of the trace file for the command at `index` in a script. `id` should be
+    /// unique to this run of the step.
+    pub fn file(&self, id: &str, index: usize) -> PathBuf {
+        self.dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("/tmp"))
+            .join(format!("houdini-{}-{}.trace", id, index))
+    }
+
+    /// Arguments to prepend to a command so that it runs under the tracer, writing its
+    /// trace to `file`.
+    pub fn wrap(&self, file: &Path) -> Vec<String> {
+        vec![
+            self.tracer.clone(),
+            "-f".into(),
+            "-o".into(),
+            file.display().to_string(),
+            "--".into(),
+        ]
+    }
+
+    /// Check that the tracer is installed on the host.
+    pub fn check_host(&self) -> Result<()> {
+        which(&self.tracer)
+            .with_context(|| format!("tracer {} not found in PATH", self.tracer))
+            .context(HarnessError("tracer is not installed"))
+            .map(|_| ())
+    }
+}
+
+/// Find a binary in `PATH`.
+fn which(bin: &str) -> Option<PathBuf> {
+    if bin.contains('/') {
+        return Some(PathBuf::from(bin)).filter(|p| p.is_file());
+    }
+
+    std::env::var_os("PATH").and_then(|paths| {
+        std::env::split_paths(&paths)
+            .map(|dir| dir.join(bin))
+            .find(|p| p.is_file())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutils::assert_yaml_deserialize;
+
+    #[test]
+    fn test_wrap() {
+        let opts: TraceOpts = assert_yaml_deserialize("dir: /var/tmp");
+        assert_eq!(opts.tracer, "strace");
+
+        let file = opts.file("abc", 2);
+        assert_eq!(file, Path::new("/var/tmp/houdini-abc-2.trace"));
+        assert_eq!(
+            opts.wrap(&file),
+            vec!["strace", "-f", "-o", "/var/tmp/houdini-abc-2.trace", "--"]
+        );
+
+        let opts: TraceOpts = assert_yaml_deserialize("tracer: no-such-tracer");
+        assert!(opts.check_host().is_err());
+    }
+}
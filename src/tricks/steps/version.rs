@@ -8,11 +8,12 @@
 
 //! This module defines a step that can be used as a version check.
 
-use std::{io::BufRead, process::Command};
+use std::{io::BufRead, process::Command, time::Duration};
 
 use anyhow::{bail, Context, Result};
 use async_trait::async_trait;
 use nix::sys::utsname::uname;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use versions::Versioning;
 
@@ -20,13 +21,17 @@ use crate::tricks::{status::Status, steps::RunStep};
 
 /// Check software versions in the exploit environment. This can be useful for skipping
 /// tests or failing setup when a required minimum version is not met.
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone)]
 #[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub(crate) struct VersionCheck {
     /// Version check for.
     pub kernel: Option<VersionComparison>,
     pub docker: Option<VersionComparison>,
     pub runc: Option<VersionComparison>,
+    /// Fail the step if it runs for longer than this, e.g. "30s". Default is no timeout.
+    #[serde(default, with = "humantime_serde")]
+    #[schemars(with = "Option<String>")]
+    pub timeout: Option<Duration>,
     /// Status on failure. Default is Skip.
     #[serde(default = "crate::serde_defaults::default_skip")]
     pub failure: Status,
@@ -67,20 +72,32 @@ impl RunStep for VersionCheck {
     fn on_failure(&self) -> Status {
         self.failure
     }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
 }
 
 /// Specify a minimum and/or maximum version to compare to.
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone)]
 #[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct VersionComparison {
     #[serde(default)]
     #[serde(with = "versioning_serde")]
+    #[schemars(with = "Option<String>")]
     #[serde(alias = "minimum")]
     pub min: Option<Versioning>,
     #[serde(default)]
     #[serde(with = "versioning_serde")]
+    #[schemars(with = "Option<String>")]
     #[serde(alias = "maximum")]
     pub max: Option<Versioning>,
+    /// Treat `min` as an exclusive bound. Default is inclusive.
+    #[serde(default = "crate::serde_defaults::default_false")]
+    pub min_exclusive: bool,
+    /// Treat `max` as an exclusive bound. Default is inclusive.
+    #[serde(default = "crate::serde_defaults::default_false")]
+    pub max_exclusive: bool,
 }
 
 impl VersionComparison {
@@ -90,6 +107,13 @@ impl VersionComparison {
 
         if let Some(max) = &self.max {
             let max = &strip_version(max.clone());
+            if self.max_exclusive && &version >= max {
+                bail!(
+                    "version {:?} is not less than exclusive maximum {:?}",
+                    version,
+                    max
+                );
+            }
             if &version > max {
                 bail!("version {:?} is greater than maximum {:?}", version, max);
             }
@@ -97,6 +121,13 @@ impl VersionComparison {
 
         if let Some(min) = &self.min {
             let min = &strip_version(min.clone());
+            if self.min_exclusive && &version <= min {
+                bail!(
+                    "version {:?} is not greater than exclusive minimum {:?}",
+                    version,
+                    min
+                );
+            }
             if &version < min {
                 bail!("version {:?} is less than minimum {:?}", version, min);
             }
@@ -116,9 +147,17 @@ pub fn get_linux_version() -> Result<Versioning> {
     parse_version(version)
 }
 
+/// A command that runs `program` in the C locale, so that its output can be parsed
+/// regardless of the host's locale.
+fn c_locale_command(program: &str) -> Command {
+    let mut command = Command::new(program);
+    command.env("LANG", "C").env("LC_ALL", "C");
+    command
+}
+
 /// Get runc version.
 pub fn get_runc_version() -> Result<Versioning> {
-    let output = Command::new("runc")
+    let output = c_locale_command("runc")
         .arg("--version")
         .output()
         .context("failed to spawn runc command")?;
@@ -136,7 +175,7 @@ pub fn get_runc_version() -> Result<Versioning> {
 
 /// Get dockerd version.
 pub fn get_docker_version() -> Result<Versioning> {
-    let output = Command::new("docker")
+    let output = c_locale_command("docker")
         .arg("--version")
         .output()
         .context("failed to spawn docker command")?;
@@ -225,12 +264,15 @@ pub mod versioning_serde {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testutils::assert_yaml_deserialize;
 
     #[test]
     fn test_version_compare() {
         let vc = VersionComparison {
             min: None,
             max: Some("5.18.9-arch1-1".try_into().unwrap()),
+            min_exclusive: false,
+            max_exclusive: false,
         };
 
         vc.compare(Versioning::try_from("5.18.9-arch1-1").unwrap())
@@ -248,6 +290,54 @@ mod tests {
             .expect_err("higher major should be err");
     }
 
+    #[test]
+    fn test_version_compare_exclusive() {
+        let v = |s: &str| Versioning::try_from(s).unwrap();
+        let vc = |min_exclusive, max_exclusive| VersionComparison {
+            min: Some(v("5.10")),
+            max: Some(v("5.12")),
+            min_exclusive,
+            max_exclusive,
+        };
+
+        for (min_exclusive, max_exclusive) in
+            [(false, false), (true, false), (false, true), (true, true)]
+        {
+            let vc = vc(min_exclusive, max_exclusive);
+
+            assert_eq!(
+                vc.compare(v("5.10")).is_ok(),
+                !min_exclusive,
+                "min boundary with min_exclusive={}",
+                min_exclusive
+            );
+            assert_eq!(
+                vc.compare(v("5.12")).is_ok(),
+                !max_exclusive,
+                "max boundary with max_exclusive={}",
+                max_exclusive
+            );
+            vc.compare(v("5.11"))
+                .expect("interior version should be ok");
+            vc.compare(v("5.9")).expect_err("below range should be err");
+            vc.compare(v("5.13"))
+                .expect_err("above range should be err");
+        }
+    }
+
+    #[test]
+    fn test_version_comparison_serde() {
+        let vc: VersionComparison = assert_yaml_deserialize(
+            r#"
+            min: "5.10"
+            max: "5.12"
+            minExclusive: true
+            "#,
+        );
+        assert!(vc.min_exclusive);
+        assert!(!vc.max_exclusive, "max should default to inclusive");
+    }
+
     #[test]
     fn test_get_linux_version() {
         let version = get_linux_version().expect("should be able to get linux version");
@@ -265,4 +355,26 @@ mod tests {
         let version = get_docker_version().expect("should be able to get docker version");
         assert!(version.is_ideal());
     }
+
+    #[test]
+    #[serial_test::serial]
+    fn test_version_getters_forced_locale() {
+        let previous = std::env::var_os("LC_ALL");
+        std::env::set_var("LC_ALL", "de_DE.UTF-8");
+
+        let locale = c_locale_command("sh")
+            .args(["-c", "echo $LANG $LC_ALL"])
+            .output();
+        let version = get_docker_version();
+
+        match previous {
+            Some(previous) => std::env::set_var("LC_ALL", previous),
+            None => std::env::remove_var("LC_ALL"),
+        }
+
+        let locale = locale.expect("sh should run");
+        assert_eq!(String::from_utf8_lossy(&locale.stdout), "C C\n");
+        let version = version.expect("should be able to get docker version");
+        assert!(version.is_ideal());
+    }
 }
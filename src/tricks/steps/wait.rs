@@ -10,30 +10,88 @@
 
 use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{bail, Context as _, Result};
 use async_trait::async_trait;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use tokio::io::AsyncReadExt;
+use tokio::{io::AsyncReadExt, net::TcpStream, time::Instant};
 
 use super::RunStep;
-use crate::tricks::status::Status;
+use crate::{
+    docker::{run_command, ExecOpts},
+    tricks::status::Status,
+};
 
 /// Pause Houdini until a condition occurs.
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone)]
 #[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub(crate) struct Wait {
     #[serde(rename = "for")]
     for_: WaitFor,
+    /// How often to log that we are still sleeping. Must not be zero. Default is 30s.
+    #[serde(default = "crate::serde_defaults::default_heartbeat")]
+    #[serde(with = "nonzero_duration_serde")]
+    #[schemars(with = "String")]
+    heartbeat: Duration,
+    /// Fail the step if it runs for longer than this, e.g. "30s". Default is no timeout.
+    #[serde(default, with = "humantime_serde")]
+    #[schemars(with = "Option<String>")]
+    timeout: Option<Duration>,
 }
 
 #[async_trait]
 impl RunStep for Wait {
     async fn do_run(&self) -> Result<()> {
-        match self.for_ {
-            WaitFor::Sleep(dur) => tokio::time::sleep(dur).await,
+        match &self.for_ {
+            WaitFor::Sleep(dur) => {
+                let start = Instant::now();
+                let deadline = start + *dur;
+                let mut heartbeat =
+                    tokio::time::interval_at(start + self.heartbeat, self.heartbeat);
+
+                loop {
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(deadline) => break,
+                        _ = heartbeat.tick() => {
+                            tracing::info!(
+                                elapsed = ?start.elapsed(),
+                                remaining = ?deadline.saturating_duration_since(Instant::now()),
+                                "still sleeping"
+                            );
+                        }
+                    }
+                }
+            }
             WaitFor::Input => {
+                tracing::info!("waiting for stdin, press enter to continue");
                 let _ = tokio::io::stdin().read(&mut [0]).await;
             }
+            WaitFor::Port {
+                host,
+                port,
+                container,
+                interval,
+                timeout,
+            } => {
+                let start = Instant::now();
+                loop {
+                    let listening = match container {
+                        Some(name) => container_port_listening(name, *port).await?,
+                        None => {
+                            let host = host.as_deref().unwrap_or("127.0.0.1");
+                            host_port_listening(host, *port, *interval).await
+                        }
+                    };
+                    if listening {
+                        tracing::info!(port, elapsed = ?start.elapsed(), "port is listening");
+                        break;
+                    }
+                    if start.elapsed() + *interval > *timeout {
+                        bail!("port {} was not listening after {:?}", port, timeout);
+                    }
+                    tokio::time::sleep(*interval).await;
+                }
+            }
         }
         Ok(())
     }
@@ -45,13 +103,223 @@ impl RunStep for Wait {
     fn on_failure(&self) -> Status {
         Status::Undecided
     }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+}
+
+impl Wait {
+    /// The container that the step waits on, if any.
+    pub fn container_mut(&mut self) -> Option<&mut String> {
+        match &mut self.for_ {
+            WaitFor::Port { container, .. } => container.as_mut(),
+            WaitFor::Sleep(_) | WaitFor::Input => None,
+        }
+    }
 }
 
 /// A condition to wait for.
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Clone)]
 #[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub enum WaitFor {
     #[serde(with = "humantime_serde")]
+    #[schemars(with = "String")]
     Sleep(Duration),
     Input,
+    /// Wait until a TCP port is listening, on the host or inside a container.
+    #[serde(rename_all = "camelCase")]
+    Port {
+        /// Host to connect to. Default is `127.0.0.1`. Ignored if `container` is set.
+        #[serde(default)]
+        host: Option<String>,
+        /// Port to wait for.
+        port: u16,
+        /// Container to wait for the port to be listening in. The port is found in the
+        /// container's `/proc/net/tcp` rather than connected to, so it need not be
+        /// published.
+        #[serde(default)]
+        container: Option<String>,
+        /// How often to check the port. Default is 500ms.
+        #[serde(
+            default = "crate::serde_defaults::default_poll_interval",
+            with = "humantime_serde"
+        )]
+        #[schemars(with = "String")]
+        interval: Duration,
+        /// Fail if the port is not listening after this long. Default is 30s.
+        #[serde(
+            default = "crate::serde_defaults::default_port_timeout",
+            with = "humantime_serde"
+        )]
+        #[schemars(with = "String")]
+        timeout: Duration,
+    },
+}
+
+/// Like `humantime_serde`, but rejects a zero duration.
+mod nonzero_duration_serde {
+    use std::time::Duration;
+
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        humantime_serde::serialize(duration, serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let duration: Duration = humantime_serde::deserialize(deserializer)?;
+        if duration.is_zero() {
+            return Err(serde::de::Error::custom("duration must not be zero"));
+        }
+        Ok(duration)
+    }
+}
+
+/// Can a TCP connection to `host:port` be made within `timeout`?
+async fn host_port_listening(host: &str, port: u16, timeout: Duration) -> bool {
+    matches!(
+        tokio::time::timeout(timeout, TcpStream::connect((host, port))).await,
+        Ok(Ok(_))
+    )
+}
+
+/// Is `port` listening in the network namespace of the container `name`?
+async fn container_port_listening(name: &str, port: u16) -> Result<bool> {
+    let out = run_command(&ExecOpts {
+        name,
+        cmd: "sh",
+        args: &["-c", "cat /proc/net/tcp /proc/net/tcp6 2>/dev/null"],
+        privileged: false,
+        tty: false,
+        shell: None,
+        wrapper: &[],
+        env: &[],
+        detach_keys: None,
+    })
+    .await
+    .context("failed to read container's TCP sockets")?;
+
+    let table = String::from_utf8_lossy(&out.stdout);
+    let listening = listening_ports(&table).any(|p| p == port);
+    Ok(listening)
+}
+
+/// Local ports of the listening sockets in a `/proc/net/tcp` or `/proc/net/tcp6` table.
+fn listening_ports(table: &str) -> impl Iterator<Item = u16> + '_ {
+    // Lines look like `0: 00000000:1F90 00000000:0000 0A ...`, where `0A` is LISTEN
+    const TCP_LISTEN: &str = "0A";
+
+    table.lines().filter_map(|line| {
+        let mut fields = line.split_whitespace().skip(1);
+        let local = fields.next()?;
+        let state = fields.nth(1)?;
+        if state != TCP_LISTEN {
+            return None;
+        }
+        let (_, port) = local.rsplit_once(':')?;
+        u16::from_str_radix(port, 16).ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_test::traced_test;
+
+    use super::*;
+    use crate::testutils::assert_yaml_deserialize;
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_sleep_heartbeat() {
+        let step: Wait = assert_yaml_deserialize(
+            r#"
+            for:
+                sleep: 250ms
+            heartbeat: 100ms
+            "#,
+        );
+        step.do_run().await.expect("sleep should succeed");
+        assert!(logs_contain("still sleeping"));
+    }
+
+    #[test]
+    fn test_zero_heartbeat() {
+        let err = serde_yaml::from_str::<Wait>(
+            r#"
+            for:
+                sleep: 1s
+            heartbeat: 0s
+            "#,
+        )
+        .expect_err("zero heartbeat should not parse");
+        assert!(err.to_string().contains("must not be zero"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_host_port() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("should bind an ephemeral port");
+        let port = listener.local_addr().unwrap().port();
+
+        let step: Wait = assert_yaml_deserialize(&format!(
+            r#"
+            for:
+                port:
+                    port: {}
+                    interval: 50ms
+                    timeout: 1s
+            "#,
+            port
+        ));
+        step.do_run().await.expect("port should be listening");
+
+        drop(listener);
+        let err = step
+            .do_run()
+            .await
+            .expect_err("closed port should time out");
+        assert_eq!(
+            err.to_string(),
+            format!("port {} was not listening after 1s", port)
+        );
+    }
+
+    #[test]
+    fn test_wait_for_port_serde() {
+        let step: Wait = assert_yaml_deserialize("for:\n  port:\n    port: 80\n    container: web");
+        assert_eq!(
+            step.for_,
+            WaitFor::Port {
+                host: None,
+                port: 80,
+                container: Some("web".into()),
+                interval: Duration::from_millis(500),
+                timeout: Duration::from_secs(30),
+            }
+        );
+    }
+
+    #[test]
+    fn test_listening_ports() {
+        let table = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 00000000:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 1234 1 0000000000000000 100 0 0 10 0
+   1: 0100007F:0035 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 1235 1 0000000000000000 100 0 0 10 0
+   2: 0100007F:C350 0100007F:1F90 01 00000000:00000000 00:00000000 00000000     0        0 1236 1 0000000000000000 20 4 30 10 -1
+";
+        assert_eq!(listening_ports(table).collect::<Vec<_>>(), vec![8080, 53]);
+    }
+
+    #[test]
+    fn test_default_heartbeat() {
+        let step: Wait = assert_yaml_deserialize("for: input");
+        assert_eq!(step.heartbeat, Duration::from_secs(30));
+    }
 }
@@ -0,0 +1,221 @@
+// SPDX-License-Identifier: Apache-2.0
+//
+// Houdini  A container escape artist
+// Copyright (c) 2022  William Findlay
+//
+// February 25, 2022  William Findlay  Created this.
+
+//! Substitute `{{param}}` placeholders in trick files before they are parsed.
+
+use std::collections::HashMap;
+
+use anyhow::{Context as _, Result};
+use serde_yaml::Value;
+
+/// Parameters declared in a trick file's `parameters` block, mapped to their defaults.
+fn declared_parameters(contents: &str) -> Result<HashMap<String, String>> {
+    #[derive(serde::Deserialize)]
+    struct Declared {
+        #[serde(default)]
+        parameters: HashMap<String, String>,
+    }
+
+    let declared: Declared =
+        serde_yaml::from_str(contents).context("failed to read trick parameters")?;
+    Ok(declared.parameters)
+}
+
+/// Resolve the values of a trick's parameters, with `overrides` taking precedence over
+/// the defaults declared in the trick. Overrides for parameters that the trick does not
+/// declare are ignored, so that the same overrides can be passed to many tricks.
+pub(crate) fn resolve(
+    contents: &str,
+    overrides: &HashMap<String, String>,
+) -> Result<HashMap<String, String>> {
+    let mut params = declared_parameters(contents)?;
+    for (key, value) in overrides {
+        match params.get_mut(key) {
+            Some(param) => *param = value.to_owned(),
+            None => tracing::debug!(param = ?key, "ignoring override for undeclared parameter"),
+        }
+    }
+    Ok(params)
+}
+
+/// Parse the YAML in `contents` and replace every `{{param}}` in its string values with
+/// the parameter's value, leaving the `parameters` block itself alone. Values are
+/// substituted into the parsed strings rather than the source, so they cannot change the
+/// structure of the trick however they are quoted.
+pub(crate) fn render(contents: &str, params: &HashMap<String, String>) -> Result<Value> {
+    let mut value: Value = serde_yaml::from_str(contents).context("failed to read trick")?;
+    match &mut value {
+        Value::Mapping(mapping) => {
+            for (key, value) in mapping.iter_mut() {
+                if key.as_str() != Some("parameters") {
+                    render_value(value, params)?;
+                }
+            }
+        }
+        value => render_value(value, params)?,
+    }
+    Ok(value)
+}
+
+/// Substitute parameters in every string under `value`.
+fn render_value(value: &mut Value, params: &HashMap<String, String>) -> Result<()> {
+    match value {
+        Value::String(s) => *s = render_str(s, params)?,
+        Value::Sequence(seq) => {
+            for value in seq {
+                render_value(value, params)?;
+            }
+        }
+        Value::Mapping(mapping) => {
+            for (_, value) in mapping.iter_mut() {
+                render_value(value, params)?;
+            }
+        }
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+    Ok(())
+}
+
+/// Replace every `{{param}}` in `contents` with the parameter's value. Whitespace inside
+/// the braces is ignored. Placeholders that are not parameter names, such as the Go
+/// templates in `docker inspect -f '{{.State.Pid}}'`, are left as they are. Referencing a
+/// parameter name that is not in `params` is an error.
+fn render_str(contents: &str, params: &HashMap<String, String>) -> Result<String> {
+    let mut rendered = String::with_capacity(contents.len());
+    let mut rest = contents;
+
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find("}}")
+            .with_context(|| format!("unclosed `{{{{` in `{}`", line_of(contents, rest, start)))?;
+
+        let name = after[..end].trim();
+        if is_param_name(name) {
+            let value = params
+                .get(name)
+                .with_context(|| format!("undefined parameter `{}`", name))?;
+            rendered.push_str(value);
+        } else {
+            rendered.push_str(&rest[start..start + end + 4]);
+        }
+        rest = &after[end + 2..];
+    }
+    rendered.push_str(rest);
+
+    Ok(rendered)
+}
+
+/// Could `name` be the name of a parameter?
+fn is_param_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// The line of `contents` containing the byte at `offset` in its suffix `rest`.
+fn line_of<'a>(contents: &'a str, rest: &str, offset: usize) -> &'a str {
+    let at = contents.len() - rest.len() + offset;
+    let start = contents[..at].rfind('\n').map_or(0, |i| i + 1);
+    let end = contents[at..].find('\n').map_or(contents.len(), |i| at + i);
+    contents[start..end].trim()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRICK: &str = r#"
+        name: "cve-2019-5736 on {{ kernel }}"
+        parameters:
+            tag: "18.09"
+            kernel: "5.4"
+        steps:
+        - spawnContainer:
+            name: docker
+            image: "docker:{{tag}}-dind"
+        "#;
+
+    /// The image of the first step of a rendered [`TRICK`].
+    fn image(rendered: &Value) -> &str {
+        rendered["steps"][0]["spawnContainer"]["image"]
+            .as_str()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_render_defaults() {
+        let params = resolve(TRICK, &HashMap::new()).expect("parameters should resolve");
+        let rendered = render(TRICK, &params).expect("trick should render");
+        assert_eq!(rendered["name"].as_str(), Some("cve-2019-5736 on 5.4"));
+        assert_eq!(image(&rendered), "docker:18.09-dind");
+    }
+
+    #[test]
+    fn test_render_overrides() {
+        let overrides = [
+            ("tag".to_owned(), "19.03".to_owned()),
+            ("unused".to_owned(), "ignored".to_owned()),
+        ]
+        .into();
+        let params = resolve(TRICK, &overrides).expect("parameters should resolve");
+        assert_eq!(params.get("tag").map(String::as_str), Some("19.03"));
+        assert!(!params.contains_key("unused"));
+
+        let rendered = render(TRICK, &params).expect("trick should render");
+        assert_eq!(image(&rendered), "docker:19.03-dind");
+    }
+
+    #[test]
+    fn test_render_is_not_injectable() {
+        let overrides = [(
+            "tag".to_owned(),
+            "x\"\n  privileged: true\n  y: \"".to_owned(),
+        )]
+        .into();
+        let params = resolve(TRICK, &overrides).expect("parameters should resolve");
+        let rendered = render(TRICK, &params).expect("trick should render");
+        assert_eq!(
+            image(&rendered),
+            "docker:x\"\n  privileged: true\n  y: \"-dind"
+        );
+        assert_eq!(
+            rendered["steps"][0]["spawnContainer"]["privileged"],
+            Value::Null
+        );
+    }
+
+    #[test]
+    fn test_render_leaves_go_templates() {
+        let trick = r#"
+            parameters:
+                name: bash
+            steps:
+            - host:
+                script: ["docker inspect -f '{{.State.Pid}} {{ json .Config }}' {{name}}"]
+            "#;
+        let params = resolve(trick, &HashMap::new()).expect("parameters should resolve");
+        let rendered = render(trick, &params).expect("trick should render");
+        assert_eq!(
+            rendered["steps"][0]["host"]["script"][0].as_str(),
+            Some("docker inspect -f '{{.State.Pid}} {{ json .Config }}' bash")
+        );
+    }
+
+    #[test]
+    fn test_render_errors() {
+        let err = render("image: \"bash:{{ tag }}\"", &HashMap::new())
+            .expect_err("undefined parameter should fail");
+        assert_eq!(err.to_string(), "undefined parameter `tag`");
+
+        let err = render("name: foo\nimage: \"bash:{{tag\"\n", &HashMap::new())
+            .expect_err("unclosed placeholder should fail");
+        assert_eq!(err.to_string(), "unclosed `{{` in `bash:{{tag`");
+    }
+}